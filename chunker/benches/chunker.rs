@@ -0,0 +1,39 @@
+//! Rough timings for chunking large notes: `cargo bench -p trame-chunker`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use trame_chunker::{chunk_and_hash, parse_chunks};
+
+const SECTION: &str = "## Section\n\nSome paragraph text with a few words in it, and then some more.\n\n- [ ] first task\n  - nested item\n- second item\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\n---\n\n";
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    f();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iter = started.elapsed() / iterations;
+    println!("{:<32} {:>12?}/iter", name, per_iter);
+}
+
+fn main() {
+    for sections in [10, 100, 1000] {
+        let note = SECTION.repeat(sections);
+        let iterations = (10_000 / sections as u32).max(10);
+        bench(
+            &format!("parse_chunks/{}KB", note.len() / 1024),
+            iterations,
+            || {
+                black_box(parse_chunks(black_box(&note)));
+            },
+        );
+        bench(
+            &format!("chunk_and_hash/{}KB", note.len() / 1024),
+            iterations,
+            || {
+                black_box(chunk_and_hash(black_box(&note)));
+            },
+        );
+    }
+}
@@ -0,0 +1,1333 @@
+//! Splits Markdown notes into the chunks Trame stores, diffs and searches,
+//! and hashes them. Shared by the server and any other tool that needs the
+//! same chunk boundaries and hashes.
+//!
+//! Offsets are char positions in the note, with the byte range alongside for
+//! slicing the UTF-8 text. Enable the `serde` feature to serialize chunks;
+//! chunk types then use the same names as [`ChunkType::as_str`]. The `wasm`
+//! feature adds JavaScript bindings so the web editor can chunk and hash
+//! locally.
+
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChunkType {
+    Heading,
+    Paragraph,
+    CodeBlock,
+    List,
+    TaskList,
+    #[cfg_attr(feature = "serde", serde(rename = "hr"))]
+    HorizontalRule,
+}
+
+impl ChunkType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChunkType::Heading => "heading",
+            ChunkType::Paragraph => "paragraph",
+            ChunkType::CodeBlock => "code_block",
+            ChunkType::List => "list",
+            ChunkType::TaskList => "task_list",
+            ChunkType::HorizontalRule => "hr",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParsedChunk {
+    pub chunk_type: ChunkType,
+    pub heading_level: Option<u8>,
+    /// Deepest nesting level of a list chunk, 1 for a flat list.
+    pub list_depth: Option<u8>,
+    pub content: String,
+    pub start_offset: usize,
+    pub end_offset: usize,
+    /// The same range in bytes of the UTF-8 content, for slicing it.
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkWithHash {
+    pub chunk: ParsedChunk,
+    pub content_hash: String,
+}
+
+/// A `[[Heading Name]]` link from one chunk to a heading of the same note.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WikiLink {
+    /// Index of the linking chunk.
+    pub source: usize,
+    /// Text between the brackets, trimmed.
+    pub target: String,
+    /// Index of the first heading whose text matches `target`, ignoring case
+    /// and spacing, or `None` if no heading does.
+    pub heading: Option<usize>,
+}
+
+/// Compute SHA-256 hash of content (first 32 hex chars)
+pub fn compute_hash(content: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content.trim().as_bytes());
+    let result = hasher.finalize();
+    hex::encode(&result[..16]) // 16 bytes = 32 hex chars
+}
+
+/// Stable id of a chunk: derived from the note, the chunk's content hash and
+/// how many chunks with the same hash come before it in the note, so any
+/// client computes the same id for the same content. A server with a master
+/// key derives ids from keyed hashes instead, which clients can't compute.
+pub fn chunk_id(note_id: &str, content_hash: &str, occurrence: u32) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(note_id.as_bytes());
+    hasher.update(b":");
+    hasher.update(content_hash.as_bytes());
+    hasher.update(b":");
+    hasher.update(occurrence.to_string().as_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+/// Stable id of a flashcard: derived like a chunk's from its question, so
+/// editing the answer or moving the card keeps it.
+pub fn card_id(note_id: &str, question: &str, occurrence: u32) -> String {
+    chunk_id(note_id, &compute_hash(question), occurrence)
+}
+
+/// Parse note content into logical chunks
+pub fn parse_chunks(content: &str) -> Vec<ParsedChunk> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    let chars: Vec<char> = content.chars().collect();
+    let len = chars.len();
+    let byte_at = byte_offsets(&chars);
+
+    while offset < len {
+        // Skip blank lines and leading whitespace between chunks
+        while offset < len
+            && (chars[offset] == ' ' || chars[offset] == '\t' || chars[offset] == '\n')
+        {
+            offset += 1;
+        }
+
+        if offset >= len {
+            break;
+        }
+
+        // Check for fenced code block
+        if offset + 2 < len
+            && chars[offset] == '`'
+            && chars[offset + 1] == '`'
+            && chars[offset + 2] == '`'
+        {
+            let start = offset;
+            let fence = backtick_run(&chars, offset);
+            offset += fence;
+            // Skip language identifier line
+            while offset < len && chars[offset] != '\n' {
+                offset += 1;
+            }
+            if offset < len {
+                offset += 1; // skip newline
+            }
+            // Find closing fence, at least as long as the opening one
+            loop {
+                if offset >= len {
+                    break;
+                }
+                let run = backtick_run(&chars, offset);
+                if run >= fence {
+                    offset += run;
+                    // Skip rest of line
+                    while offset < len && chars[offset] != '\n' {
+                        offset += 1;
+                    }
+                    if offset < len {
+                        offset += 1;
+                    }
+                    break;
+                }
+                offset += run.max(1);
+            }
+            let content_str: String = chars[start..offset].iter().collect();
+            chunks.push(ParsedChunk {
+                chunk_type: ChunkType::CodeBlock,
+                heading_level: None,
+                list_depth: None,
+                content: content_str,
+                start_offset: start,
+                end_offset: offset,
+                start_byte: byte_at[start],
+                end_byte: byte_at[offset],
+            });
+            continue;
+        }
+
+        // Check for heading
+        if chars[offset] == '#' {
+            let start = offset;
+            let mut level = 0u8;
+            while offset < len && chars[offset] == '#' && level < 6 {
+                level += 1;
+                offset += 1;
+            }
+            // Must have space after hashes for valid heading
+            if offset < len && chars[offset] == ' ' {
+                // Consume rest of line
+                while offset < len && chars[offset] != '\n' {
+                    offset += 1;
+                }
+                if offset < len {
+                    offset += 1; // include newline
+                }
+                let content_str: String = chars[start..offset].iter().collect();
+                chunks.push(ParsedChunk {
+                    chunk_type: ChunkType::Heading,
+                    heading_level: Some(level),
+                    list_depth: None,
+                    content: content_str.trim_end().to_string(),
+                    start_offset: start,
+                    end_offset: offset,
+                    start_byte: byte_at[start],
+                    end_byte: byte_at[offset],
+                });
+                continue;
+            } else {
+                // Not a valid heading, reset and treat as paragraph
+                offset = start;
+            }
+        }
+
+        // Check for horizontal rule (---, ***, ___)
+        if offset + 2 < len {
+            let c = chars[offset];
+            if (c == '-' || c == '*' || c == '_')
+                && chars[offset + 1] == c
+                && chars[offset + 2] == c
+            {
+                let start = offset;
+                while offset < len && chars[offset] == c {
+                    offset += 1;
+                }
+                // Skip to end of line
+                while offset < len && chars[offset] != '\n' {
+                    offset += 1;
+                }
+                if offset < len {
+                    offset += 1;
+                }
+                let content_str: String = chars[start..offset].iter().collect();
+                chunks.push(ParsedChunk {
+                    chunk_type: ChunkType::HorizontalRule,
+                    heading_level: None,
+                    list_depth: None,
+                    content: content_str.trim_end().to_string(),
+                    start_offset: start,
+                    end_offset: offset,
+                    start_byte: byte_at[start],
+                    end_byte: byte_at[offset],
+                });
+                continue;
+            }
+        }
+
+        // Check for list item
+        if is_list_item(&chars, offset, len) {
+            let start = offset;
+            // Indentation of each open nesting level, outermost first
+            let mut levels: Vec<usize> = Vec::new();
+            let mut depth = 0;
+            let mut indent = indent_before(&chars, offset);
+            let mut has_tasks = false;
+            // Consume all consecutive list items, nested ones included
+            loop {
+                while levels.last().is_some_and(|&l| l > indent) {
+                    levels.pop();
+                }
+                if levels.last() != Some(&indent) {
+                    levels.push(indent);
+                }
+                depth = depth.max(levels.len());
+
+                // Consume line
+                let line_start = offset;
+                while offset < len && chars[offset] != '\n' {
+                    offset += 1;
+                }
+                let line: String = chars[line_start..offset].iter().collect();
+                has_tasks |= task_mark(&line).is_some();
+                if offset < len {
+                    offset += 1;
+                }
+                // Skip a single empty line within list; double newline ends it
+                if offset < len
+                    && chars[offset] == '\n'
+                    && list_item_indent(&chars, offset + 1, len).is_some()
+                {
+                    offset += 1;
+                }
+                match list_item_indent(&chars, offset, len) {
+                    Some(next) => indent = next,
+                    None => break,
+                }
+            }
+            let content_str: String = chars[start..offset].iter().collect();
+            chunks.push(ParsedChunk {
+                chunk_type: if has_tasks {
+                    ChunkType::TaskList
+                } else {
+                    ChunkType::List
+                },
+                heading_level: None,
+                list_depth: Some(depth.min(u8::MAX as usize) as u8),
+                content: content_str.trim_end().to_string(),
+                start_offset: start,
+                end_offset: offset,
+                start_byte: byte_at[start],
+                end_byte: byte_at[offset],
+            });
+            continue;
+        }
+
+        // Check for setext heading: one line of text underlined with `=`
+        // (level 1) or `-` (level 2)
+        let line_end = (offset..len).find(|&i| chars[i] == '\n').unwrap_or(len);
+        if let Some((level, underline_end)) = setext_underline(&chars, line_end + 1, len) {
+            let start = offset;
+            offset = underline_end;
+            if offset < len {
+                offset += 1; // include newline
+            }
+            let content_str: String = chars[start..offset].iter().collect();
+            chunks.push(ParsedChunk {
+                chunk_type: ChunkType::Heading,
+                heading_level: Some(level),
+                list_depth: None,
+                content: content_str.trim_end().to_string(),
+                start_offset: start,
+                end_offset: offset,
+                start_byte: byte_at[start],
+                end_byte: byte_at[offset],
+            });
+            continue;
+        }
+
+        // Default: paragraph (until double newline or special marker)
+        let start = offset;
+        loop {
+            // Find end of line
+            while offset < len && chars[offset] != '\n' {
+                offset += 1;
+            }
+            if offset < len {
+                offset += 1; // consume newline
+            }
+
+            // Check if next line starts a new block
+            if offset >= len {
+                break;
+            }
+
+            // Double newline ends paragraph
+            if chars[offset] == '\n' {
+                break;
+            }
+
+            // Check if next line is a special block
+            if chars[offset] == '#'
+                || (offset + 2 < len
+                    && chars[offset] == '`'
+                    && chars[offset + 1] == '`'
+                    && chars[offset + 2] == '`')
+                || list_item_indent(&chars, offset, len).is_some()
+                || is_hr_start(&chars, offset, len)
+            {
+                break;
+            }
+        }
+
+        if start < offset {
+            let content_str: String = chars[start..offset].iter().collect();
+            let trimmed = content_str.trim();
+            if !trimmed.is_empty() {
+                chunks.push(ParsedChunk {
+                    chunk_type: ChunkType::Paragraph,
+                    heading_level: None,
+                    list_depth: None,
+                    content: trimmed.to_string(),
+                    start_offset: start,
+                    end_offset: offset,
+                    start_byte: byte_at[start],
+                    end_byte: byte_at[offset],
+                });
+            }
+        }
+    }
+
+    chunks
+}
+
+fn is_list_item(chars: &[char], offset: usize, len: usize) -> bool {
+    if offset >= len {
+        return false;
+    }
+
+    // Unordered list: -, *, +
+    if (chars[offset] == '-' || chars[offset] == '*' || chars[offset] == '+')
+        && offset + 1 < len
+        && chars[offset + 1] == ' '
+    {
+        return true;
+    }
+
+    // Ordered list: digit followed by . or )
+    if chars[offset].is_ascii_digit() {
+        let mut i = offset + 1;
+        while i < len && chars[i].is_ascii_digit() {
+            i += 1;
+        }
+        if i < len && (chars[i] == '.' || chars[i] == ')') && i + 1 < len && chars[i + 1] == ' ' {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Byte position of the checkbox mark in a task item line such as
+/// `- [ ] todo` or `  1. [x] done`, or `None` if the line isn't a task.
+fn task_mark(line: &str) -> Option<usize> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    let rest = line.trim_start_matches([' ', '\t']);
+    let indent = line.len() - rest.len();
+    let marker = if rest.starts_with(['-', '*', '+']) {
+        1
+    } else {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 || !rest[digits..].starts_with(['.', ')']) {
+            return None;
+        }
+        digits + 1
+    };
+    match rest.as_bytes()[marker..] {
+        [b' ', b'[', b' ' | b'x' | b'X', b']']
+        | [b' ', b'[', b' ' | b'x' | b'X', b']', b' ', ..] => Some(indent + marker + 2),
+        _ => None,
+    }
+}
+
+/// Flip the checkbox of the `index`-th task item (counting from 0) in a
+/// task list chunk. Returns `None` if there are fewer tasks than that.
+pub fn toggle_task(content: &str, index: usize) -> Option<String> {
+    let mut line_start = 0;
+    let mut seen = 0;
+    for line in content.split_inclusive('\n') {
+        if let Some(mark) = task_mark(line) {
+            if seen == index {
+                let pos = line_start + mark;
+                let flipped = if &content[pos..pos + 1] == " " {
+                    "x"
+                } else {
+                    " "
+                };
+                let mut toggled = content.to_string();
+                toggled.replace_range(pos..pos + 1, flipped);
+                return Some(toggled);
+            }
+            seen += 1;
+        }
+        line_start += line.len();
+    }
+    None
+}
+
+/// Width of the indentation before a list item starting at line start
+/// `offset`, or `None` if the line isn't a list item. Tabs count as 4.
+fn list_item_indent(chars: &[char], offset: usize, len: usize) -> Option<usize> {
+    let mut i = offset;
+    let mut width = 0;
+    while i < len && (chars[i] == ' ' || chars[i] == '\t') {
+        width += if chars[i] == '\t' { 4 } else { 1 };
+        i += 1;
+    }
+    is_list_item(chars, i, len).then_some(width)
+}
+
+/// Width of the whitespace between the start of the line and `offset`.
+fn indent_before(chars: &[char], offset: usize) -> usize {
+    chars[..offset]
+        .iter()
+        .rev()
+        .take_while(|&&c| c == ' ' || c == '\t')
+        .map(|&c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+fn is_hr_start(chars: &[char], offset: usize, len: usize) -> bool {
+    if offset + 2 >= len {
+        return false;
+    }
+    let c = chars[offset];
+    (c == '-' || c == '*' || c == '_') && chars[offset + 1] == c && chars[offset + 2] == c
+}
+
+/// Level of the setext underline starting at `offset`, a line of only `=`
+/// or only `-` indented at most three spaces, and where that line ends.
+fn setext_underline(chars: &[char], offset: usize, len: usize) -> Option<(u8, usize)> {
+    let mut i = offset;
+    while i < len && i < offset + 3 && chars[i] == ' ' {
+        i += 1;
+    }
+    let level = match chars.get(i)? {
+        '=' => 1,
+        '-' => 2,
+        _ => return None,
+    };
+    let marker = chars[i];
+    while i < len && chars[i] == marker {
+        i += 1;
+    }
+    while i < len && (chars[i] == ' ' || chars[i] == '\t') {
+        i += 1;
+    }
+    (i == len || chars[i] == '\n').then_some((level, i))
+}
+
+/// Byte offset in the UTF-8 text of each char offset, up to and including
+/// the end.
+fn byte_offsets(chars: &[char]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(chars.len() + 1);
+    let mut byte = 0;
+    offsets.push(byte);
+    for c in chars {
+        byte += c.len_utf8();
+        offsets.push(byte);
+    }
+    offsets
+}
+
+/// Convert a char offset into `content` to a byte offset, the unit Rust,
+/// Go and most other languages slice UTF-8 strings by.
+pub fn byte_offset(content: &str, char_offset: usize) -> usize {
+    content.chars().take(char_offset).map(char::len_utf8).sum()
+}
+
+/// Convert a char offset into `content` to UTF-16 code units, the unit
+/// JavaScript string indices use.
+pub fn utf16_offset(content: &str, char_offset: usize) -> usize {
+    content.chars().take(char_offset).map(char::len_utf16).sum()
+}
+
+/// Whether the last chunk is a code block missing its closing fence. The
+/// parser recovers by running such a block to the end of the note.
+pub fn has_unclosed_fence(chunks: &[ParsedChunk]) -> bool {
+    chunks.last().is_some_and(|chunk| {
+        let fence = chunk.content.chars().take_while(|&c| c == '`').count();
+        chunk.chunk_type == ChunkType::CodeBlock
+            && !chunk
+                .content
+                .lines()
+                .skip(1)
+                .any(|line| line.contains(&"`".repeat(fence)))
+    })
+}
+
+/// Length of the run of backticks starting at `offset`.
+fn backtick_run(chars: &[char], offset: usize) -> usize {
+    chars[offset..].iter().take_while(|&&c| c == '`').count()
+}
+
+/// `#tag` tokens in a chunk, lowercased, without the `#`, in order of first
+/// appearance. A tag starts after whitespace or `(` and runs over letters,
+/// digits, `_`, `-` and `/`; all-digit tokens like `#1` aren't tags. Code
+/// blocks and inline code have no tags, and neither does a heading's own
+/// `#` marker.
+pub fn extract_tags(chunk: &ParsedChunk) -> Vec<String> {
+    if chunk.chunk_type == ChunkType::CodeBlock {
+        return Vec::new();
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    let mut prev = ' ';
+    let mut in_code = false;
+    let mut chars = chunk.content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '`' {
+            in_code = !in_code;
+        } else if c == '#' && !in_code && (prev.is_whitespace() || prev == '(') {
+            let rest = &chunk.content[i + 1..];
+            let len: usize = rest
+                .chars()
+                .take_while(|&c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/'))
+                .map(char::len_utf8)
+                .sum();
+            let tag = rest[..len].trim_end_matches(['-', '/']).to_lowercase();
+            if tag.chars().any(|c| !c.is_ascii_digit()) && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+            while chars.peek().is_some_and(|&(j, _)| j <= i + len) {
+                chars.next();
+            }
+            prev = '#';
+            continue;
+        }
+        prev = c;
+    }
+    tags
+}
+
+/// A question and its answer written in a chunk, to review as a flashcard.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flashcard {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Tag that makes a chunk without `Q:` and `A:` lines a flashcard.
+pub const FLASHCARD_TAG: &str = "flashcard";
+
+/// The flashcards in a chunk. Each line starting `Q:` (in a list item too)
+/// and the `A:` line after it make one, both running on over the lines
+/// that follow. A chunk tagged `#flashcard` without such lines is a card of
+/// its own: the first line asks, the rest answers. Code blocks have none.
+pub fn extract_cards(chunk: &ParsedChunk) -> Vec<Flashcard> {
+    if chunk.chunk_type == ChunkType::CodeBlock {
+        return Vec::new();
+    }
+
+    let mut cards = Vec::new();
+    let mut question: Option<String> = None;
+    let mut answer: Option<String> = None;
+    let mut flush = |question: &mut Option<String>, answer: &mut Option<String>| {
+        if let (Some(q), Some(a)) = (question.take(), answer.take()) {
+            if !q.is_empty() && !a.is_empty() {
+                cards.push(Flashcard {
+                    question: q,
+                    answer: a,
+                });
+            }
+        }
+    };
+    for line in chunk.content.lines() {
+        let line = line.trim();
+        let line = ["- ", "* "]
+            .iter()
+            .find_map(|marker| line.strip_prefix(marker))
+            .unwrap_or(line);
+        if let Some(q) = line.strip_prefix("Q:") {
+            flush(&mut question, &mut answer);
+            question = Some(q.trim().to_string());
+        } else if let (Some(a), Some(_), None) = (line.strip_prefix("A:"), &question, &answer) {
+            answer = Some(a.trim().to_string());
+        } else if let Some(part) = answer.as_mut().or(question.as_mut()) {
+            if !line.is_empty() {
+                part.push('\n');
+                part.push_str(line);
+            }
+        }
+    }
+    flush(&mut question, &mut answer);
+    if !cards.is_empty() || !extract_tags(chunk).iter().any(|t| t == FLASHCARD_TAG) {
+        return cards;
+    }
+
+    let text: Vec<String> = chunk
+        .content
+        .lines()
+        .map(|line| {
+            line.split(' ')
+                .filter(|word| !word.eq_ignore_ascii_case("#flashcard"))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    match text.split_first() {
+        Some((question, answer)) if !answer.is_empty() => vec![Flashcard {
+            question: question.clone(),
+            answer: answer.join("\n"),
+        }],
+        _ => Vec::new(),
+    }
+}
+
+/// Every `[[...]]` link in `chunks`, resolved to the heading it names.
+/// Links in code blocks and inline code are ignored, and each chunk links to
+/// a given target once.
+pub fn resolve_links(chunks: &[ParsedChunk]) -> Vec<WikiLink> {
+    let heading_keys: Vec<Option<String>> = chunks
+        .iter()
+        .map(|chunk| (chunk.chunk_type == ChunkType::Heading).then(|| heading_key(chunk)))
+        .collect();
+
+    let mut links = Vec::new();
+    for (source, chunk) in chunks.iter().enumerate() {
+        for target in link_targets(chunk) {
+            let key = link_key(&target);
+            let heading = heading_keys.iter().position(|k| k.as_ref() == Some(&key));
+            links.push(WikiLink {
+                source,
+                target,
+                heading,
+            });
+        }
+    }
+    links
+}
+
+/// Rename the first heading that `[[from]]` links to, keeping its level,
+/// and if `rewrite_links`, point those links at `to` as well. Returns the new
+/// content and how many links changed, or `None` if no heading matches.
+pub fn rename_heading(
+    content: &str,
+    from: &str,
+    to: &str,
+    rewrite_links: bool,
+) -> Option<(String, usize)> {
+    let key = link_key(from);
+    let chunks = parse_chunks(content);
+    let heading = chunks
+        .iter()
+        .position(|chunk| chunk.chunk_type == ChunkType::Heading && heading_key(chunk) == key)?;
+
+    let chars: Vec<char> = content.chars().collect();
+    let mut renamed = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut rewritten = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let text = if i == heading {
+            let raw = String::from_iter(&chars[chunk.start_offset..chunk.end_offset]);
+            replace_heading_text(&raw, chunk, to)
+        } else if rewrite_links && chunk.chunk_type != ChunkType::CodeBlock {
+            let raw = String::from_iter(&chars[chunk.start_offset..chunk.end_offset]);
+            // Odd pieces are inside inline code
+            let pieces: Vec<String> = raw
+                .split('`')
+                .enumerate()
+                .map(|(n, piece)| {
+                    if n % 2 == 1 {
+                        return piece.to_string();
+                    }
+                    let (piece, count) = rewrite_link_targets(piece, &key, to);
+                    rewritten += count;
+                    piece
+                })
+                .collect();
+            pieces.join("`")
+        } else {
+            continue;
+        };
+        renamed.extend(&chars[copied..chunk.start_offset]);
+        renamed.push_str(&text);
+        copied = chunk.end_offset;
+    }
+    renamed.extend(&chars[copied..]);
+    Some((renamed, rewritten))
+}
+
+/// Each heading's place in the note's outline: its index in `chunks` and its
+/// number, the position among its siblings at every depth, so the third
+/// subsection of the second section is `[2, 3]`. A heading's parent is the
+/// nearest heading above it with a lower level.
+pub fn outline(chunks: &[ParsedChunk]) -> Vec<(usize, Vec<usize>)> {
+    let mut path: Vec<(u8, usize)> = Vec::new();
+    let mut entries = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let Some(level) = chunk.heading_level else {
+            continue;
+        };
+        let mut position = 1;
+        while let Some(&(parent_level, n)) = path.last() {
+            if parent_level < level {
+                break;
+            }
+            path.pop();
+            position = n + 1;
+        }
+        path.push((level, position));
+        entries.push((i, path.iter().map(|&(_, n)| n).collect()));
+    }
+    entries
+}
+
+/// Number the headings after the first, which is the note's title, by their
+/// place in the outline: `1.` for sections, `1.1`, `1.2` below them. Numbers
+/// written before are replaced, so moved sections are renumbered. `None` when
+/// every heading already has its number.
+pub fn number_headings(content: &str) -> Option<String> {
+    let chunks = parse_chunks(content);
+    let title = chunks
+        .iter()
+        .position(|chunk| chunk.chunk_type == ChunkType::Heading)?;
+    let numbers = outline(&chunks[title + 1..])
+        .into_iter()
+        .map(|(i, number)| (title + 1 + i, Some(number)))
+        .collect();
+    rewrite_headings(content, &chunks, numbers)
+}
+
+/// Take the outline numbers off every heading. `None` when none had one.
+pub fn unnumber_headings(content: &str) -> Option<String> {
+    let chunks = parse_chunks(content);
+    let headings = outline(&chunks)
+        .into_iter()
+        .map(|(i, _)| (i, None))
+        .collect();
+    rewrite_headings(content, &chunks, headings)
+}
+
+fn rewrite_headings(
+    content: &str,
+    chunks: &[ParsedChunk],
+    headings: Vec<(usize, Option<Vec<usize>>)>,
+) -> Option<String> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut rewritten = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut changed = false;
+    for (i, number) in headings {
+        let chunk = &chunks[i];
+        let raw = String::from_iter(&chars[chunk.start_offset..chunk.end_offset]);
+        let text = strip_outline_number(heading_text(chunk));
+        let line_end = &raw[raw.trim_end().len()..];
+        let heading = match number {
+            // Always with `#`s: underlined, a section number like `1.` would
+            // start a list
+            Some(number) => {
+                let number: Vec<String> = number.iter().map(usize::to_string).collect();
+                let dot = if number.len() == 1 { "." } else { "" };
+                format!(
+                    "{} {}{} {}{}",
+                    "#".repeat(chunk.heading_level.unwrap_or(1) as usize),
+                    number.join("."),
+                    dot,
+                    text,
+                    line_end
+                )
+            }
+            None => replace_heading_text(&raw, chunk, text),
+        };
+        changed |= heading != raw;
+        rewritten.extend(&chars[copied..chunk.start_offset]);
+        rewritten.push_str(&heading);
+        copied = chunk.end_offset;
+    }
+    rewritten.extend(&chars[copied..]);
+    changed.then_some(rewritten)
+}
+
+/// Heading text without a leading outline number such as `2.` or `1.3`.
+/// Plain numbers like a year are kept.
+fn strip_outline_number(text: &str) -> &str {
+    let Some((number, rest)) = text.split_once(char::is_whitespace) else {
+        return text;
+    };
+    let parts = number.strip_suffix('.').unwrap_or(number);
+    let is_outline = number.contains('.')
+        && parts
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    match rest.trim_start() {
+        rest if is_outline && !rest.is_empty() => rest,
+        _ => text,
+    }
+}
+
+/// A heading's text, without its `#`s or setext underline.
+pub fn heading_text(chunk: &ParsedChunk) -> &str {
+    match chunk.content.split_once('\n') {
+        Some((text, _)) => text.trim(),
+        None => chunk.content.trim_start_matches('#').trim(),
+    }
+}
+
+/// `chunk`'s heading as it was written, with its text replaced by `text`:
+/// the same number of `#`s, or the same underline.
+fn replace_heading_text(raw: &str, chunk: &ParsedChunk, text: &str) -> String {
+    let level = chunk.heading_level.unwrap_or(1) as usize;
+    let line_end = &raw[raw.trim_end().len()..];
+    match raw.split_once('\n') {
+        Some((_, underline)) if chunk.content.contains('\n') => format!("{}\n{}", text, underline),
+        _ => format!("{} {}{}", "#".repeat(level), text, line_end),
+    }
+}
+
+/// The key `[[links]]` match a heading by: its text without the `#`s or an
+/// outline number, so links still resolve once headings are numbered.
+fn heading_key(chunk: &ParsedChunk) -> String {
+    link_key(strip_outline_number(heading_text(chunk)))
+}
+
+/// `text` with the `[[...]]` links whose target matches `key` pointed at
+/// `to`, and how many there were. Links are found as in [`link_targets`].
+fn rewrite_link_targets(text: &str, key: &str, to: &str) -> (String, usize) {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut count = 0;
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        rewritten.push_str(&rest[..start + 2]);
+        rest = &rest[start + 2..];
+        let Some(end) = rest.find("]]") else {
+            break;
+        };
+        let inner = &rest[..end];
+        if inner.contains(['[', ']', '\n']) {
+            continue;
+        }
+        if link_key(inner) == key {
+            rewritten.push_str(to);
+            count += 1;
+        } else {
+            rewritten.push_str(inner);
+        }
+        rewritten.push_str("]]");
+        rest = &rest[end + 2..];
+    }
+    rewritten.push_str(rest);
+    (rewritten, count)
+}
+
+fn link_targets(chunk: &ParsedChunk) -> Vec<String> {
+    if chunk.chunk_type == ChunkType::CodeBlock {
+        return Vec::new();
+    }
+
+    let mut targets: Vec<String> = Vec::new();
+    // Odd pieces are inside inline code
+    for text in chunk.content.split('`').step_by(2) {
+        let mut rest = text;
+        while let Some(start) = rest.find("[[") {
+            rest = &rest[start + 2..];
+            let Some(end) = rest.find("]]") else {
+                break;
+            };
+            let inner = &rest[..end];
+            if inner.contains(['[', ']', '\n']) {
+                continue;
+            }
+            let target = inner.trim();
+            if !target.is_empty() && !targets.iter().any(|t| link_key(t) == link_key(target)) {
+                targets.push(target.to_string());
+            }
+            rest = &rest[end + 2..];
+        }
+    }
+    targets
+}
+
+fn link_key(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Parse and hash all chunks
+pub fn chunk_and_hash(content: &str) -> Vec<ChunkWithHash> {
+    parse_chunks(content)
+        .into_iter()
+        .map(|chunk| {
+            let hash = compute_hash(&chunk.content);
+            ChunkWithHash {
+                chunk,
+                content_hash: hash,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_links() {
+        let chunks = parse_chunks(
+            "# Plans\n\n## Next  Steps\n\nSee [[next steps]] and [[Plans]], [[Plans]] again, [[Missing]].\n\n`[[Plans]]` and [[ ]] and [[a [[Plans]]\n\n```\n[[Plans]]\n```",
+        );
+        let links: Vec<(usize, String, Option<usize>)> = resolve_links(&chunks)
+            .into_iter()
+            .map(|l| (l.source, l.target, l.heading))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (2, "next steps".to_string(), Some(1)),
+                (2, "Plans".to_string(), Some(0)),
+                (2, "Missing".to_string(), None),
+                (3, "Plans".to_string(), Some(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_cards() {
+        let chunks = parse_chunks(
+            "Q: Capital of France?\nA: Paris\nQ: Two\nlines?\nA: Yes\nthey do\nQ: Unanswered\n\n\
+             - Q: In a list?\n- A: Works\n\n\
+             What is SM-2? #flashcard\nA spaced repetition algorithm.\n\n\
+             Lonely #flashcard\n\n\
+             ```\nQ: code\nA: no\n```\n\n\
+             A: no question",
+        );
+        let cards: Vec<Vec<Flashcard>> = chunks.iter().map(extract_cards).collect();
+        let card = |q: &str, a: &str| Flashcard {
+            question: q.to_string(),
+            answer: a.to_string(),
+        };
+        assert_eq!(
+            cards[0],
+            [
+                card("Capital of France?", "Paris"),
+                card("Two\nlines?", "Yes\nthey do")
+            ]
+        );
+        assert_eq!(cards[1], [card("In a list?", "Works")]);
+        assert_eq!(
+            cards[2],
+            [card("What is SM-2?", "A spaced repetition algorithm.")]
+        );
+        assert!(cards[3..].iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn test_extract_tags() {
+        let chunks = parse_chunks(
+            "# Title #Work\n\nPlan for #work and #home/garden-, not #1 or a#b or `#code`.\n\n```\n#not-a-tag\n```\n\n- (#todo) #Été",
+        );
+        let tags: Vec<Vec<String>> = chunks.iter().map(extract_tags).collect();
+        assert_eq!(tags[0], vec!["work"]);
+        assert_eq!(tags[1], vec!["work", "home/garden"]);
+        assert!(tags[2].is_empty());
+        assert_eq!(tags[3], vec!["todo", "été"]);
+    }
+
+    #[test]
+    fn test_empty_content() {
+        let chunks = parse_chunks("");
+        assert!(chunks.is_empty());
+    }
+
+    #[test]
+    fn test_single_paragraph() {
+        let chunks = parse_chunks("Hello world");
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].chunk_type, ChunkType::Paragraph);
+        assert_eq!(chunks[0].content, "Hello world");
+    }
+
+    #[test]
+    fn test_heading() {
+        let chunks = parse_chunks("# Title\n\nSome text");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].chunk_type, ChunkType::Heading);
+        assert_eq!(chunks[0].heading_level, Some(1));
+        assert_eq!(chunks[0].content, "# Title");
+        assert_eq!(chunks[1].chunk_type, ChunkType::Paragraph);
+    }
+
+    #[test]
+    fn test_multiple_headings() {
+        let chunks = parse_chunks("# H1\n## H2\n### H3");
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].heading_level, Some(1));
+        assert_eq!(chunks[1].heading_level, Some(2));
+        assert_eq!(chunks[2].heading_level, Some(3));
+    }
+
+    #[test]
+    fn test_code_block() {
+        let content = "```rust\nfn main() {}\n```";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].chunk_type, ChunkType::CodeBlock);
+
+        // A longer fence holds shorter ones
+        let content = "````md\n```sh\nmake\n```\n````\n\nAfter";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].content, "````md\n```sh\nmake\n```\n````\n");
+        assert_eq!(chunks[1].content, "After");
+    }
+
+    #[test]
+    fn test_unclosed_fence() {
+        assert!(!has_unclosed_fence(&parse_chunks(
+            "```rust\nfn main() {}\n```"
+        )));
+        assert!(has_unclosed_fence(&parse_chunks(
+            "Intro\n\n```rust\nfn main() {}\n"
+        )));
+        assert!(has_unclosed_fence(&parse_chunks("```")));
+        assert!(has_unclosed_fence(&parse_chunks("````md\n```sh\nmake\n```\n")));
+        assert!(!has_unclosed_fence(&parse_chunks("Plain text")));
+    }
+
+    #[test]
+    fn test_list() {
+        let content = "- item 1\n- item 2\n- item 3";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].chunk_type, ChunkType::List);
+    }
+
+    #[test]
+    fn test_nested_list() {
+        let content = "- item 1\n  - nested\n    1. deeper\n  - nested 2\n- item 2\n\nAfter";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].chunk_type, ChunkType::List);
+        assert_eq!(chunks[0].list_depth, Some(3));
+        assert!(chunks[0].content.ends_with("- item 2"));
+        assert_eq!(chunks[1].content, "After");
+
+        assert_eq!(parse_chunks("- a\n- b")[0].list_depth, Some(1));
+    }
+
+    #[test]
+    fn test_indented_list_ends_paragraph() {
+        let chunks = parse_chunks("Intro\n  - item\n\n\t- tabbed");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].content, "Intro");
+        assert_eq!(chunks[1].chunk_type, ChunkType::List);
+        assert_eq!(chunks[1].content, "- item\n\n\t- tabbed");
+        assert_eq!(chunks[1].list_depth, Some(2));
+    }
+
+    #[test]
+    fn test_task_list() {
+        let chunks =
+            parse_chunks("- [ ] write\n  - [x] outline\n- plain\n\nText\n\n- not [ ] a task");
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].chunk_type, ChunkType::TaskList);
+        assert_eq!(chunks[2].chunk_type, ChunkType::List);
+        assert_eq!(task_mark("1. [X]"), Some(4));
+        assert_eq!(task_mark("- [ ]done"), None);
+    }
+
+    #[test]
+    fn test_rename_heading() {
+        let content = "# Plans\n\n## Next  Steps\n\nSee [[next steps]], [[ Next Steps ]] and [[Plans]].\n\n`[[next steps]]` stays, [[a [[next steps]] too\n\n```\n[[next steps]]\n```\n";
+        let (renamed, count) = rename_heading(content, "NEXT STEPS", "Then", true).unwrap();
+        assert_eq!(
+            renamed,
+            "# Plans\n\n## Then\n\nSee [[Then]], [[Then]] and [[Plans]].\n\n`[[next steps]]` stays, [[a [[Then]] too\n\n```\n[[next steps]]\n```\n"
+        );
+        assert_eq!(count, 3);
+
+        let (renamed, count) =
+            rename_heading("# Plans\n\n[[Plans]]", "plans", "Goals", false).unwrap();
+        assert_eq!(renamed, "# Goals\n\n[[Plans]]");
+        assert_eq!(count, 0);
+
+        assert_eq!(
+            rename_heading("Plans\n\n[[Plans]]", "Plans", "Goals", true),
+            None
+        );
+    }
+
+    #[test]
+    fn test_number_headings() {
+        let content = "# Title\n\n## Intro\n\n### 4.2 Why\n\n### How\n\n#### Detail\n\n## 2024 review\n\n### Wrap\n\n## 7. Next\n\n```\n## Not a heading\n```\n";
+        let numbered = number_headings(content).unwrap();
+        assert_eq!(
+            numbered,
+            "# Title\n\n## 1. Intro\n\n### 1.1 Why\n\n### 1.2 How\n\n#### 1.2.1 Detail\n\n## 2. 2024 review\n\n### 2.1 Wrap\n\n## 3. Next\n\n```\n## Not a heading\n```\n"
+        );
+        assert_eq!(number_headings(&numbered), None);
+
+        // Moving a section renumbers it and its subsections
+        let moved = numbered
+            .replace("## 3. Next\n\n", "")
+            .replace("## 1. Intro", "## 3. Next\n\n## 1. Intro");
+        assert!(number_headings(&moved)
+            .unwrap()
+            .contains("## 1. Next\n\n## 2. Intro\n\n### 2.1 Why"));
+
+        // Links resolve to headings whatever their number
+        let chunks = parse_chunks(&numbered);
+        let links = resolve_links(&parse_chunks("## 1.2 How\n\n[[how]]"));
+        assert_eq!(links[0].heading, Some(0));
+        assert_eq!(outline(&chunks)[4], (4, vec![1, 1, 2, 1]));
+
+        assert_eq!(
+            unnumber_headings(&numbered).unwrap(),
+            content.replace("4.2 ", "").replace("7. ", "")
+        );
+        assert_eq!(number_headings("No headings"), None);
+    }
+
+    #[test]
+    fn test_toggle_task() {
+        let content = "- [ ] write\n  - [x] outline\n- plain\n3. [ ] édit";
+        assert_eq!(
+            toggle_task(content, 1).unwrap(),
+            "- [ ] write\n  - [ ] outline\n- plain\n3. [ ] édit"
+        );
+        assert_eq!(
+            toggle_task(content, 2).unwrap(),
+            "- [ ] write\n  - [x] outline\n- plain\n3. [x] édit"
+        );
+        assert_eq!(toggle_task(content, 3), None);
+        assert_eq!(toggle_task("- [ ]\n- [ ] b", 0).unwrap(), "- [x]\n- [ ] b");
+    }
+
+    #[test]
+    fn test_horizontal_rule() {
+        let content = "text\n\n---\n\nmore text";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].chunk_type, ChunkType::HorizontalRule);
+    }
+
+    #[test]
+    fn test_setext_heading() {
+        let content =
+            "Title\n=====\n\nSubtitle  \n---\ntext\n\nnot a\nheading\n===\n\nlist\n- item";
+        let chunks = parse_chunks(content);
+        assert_eq!(chunks.len(), 6);
+        assert_eq!(chunks[0].chunk_type, ChunkType::Heading);
+        assert_eq!(chunks[0].heading_level, Some(1));
+        assert_eq!(chunks[0].content, "Title\n=====");
+        assert_eq!(heading_text(&chunks[0]), "Title");
+        assert_eq!(chunks[1].heading_level, Some(2));
+        assert_eq!(heading_text(&chunks[1]), "Subtitle");
+        assert_eq!(chunks[2].content, "text");
+        // Only a single line of text becomes a heading
+        assert_eq!(chunks[3].chunk_type, ChunkType::Paragraph);
+        assert_eq!(chunks[3].content, "not a\nheading\n===");
+        assert_eq!(chunks[4].content, "list");
+        assert_eq!(chunks[5].chunk_type, ChunkType::List);
+
+        // A stored heading parses back to itself
+        let reparsed = parse_chunks(&chunks[1].content);
+        assert_eq!(reparsed.len(), 1);
+        assert_eq!(reparsed[0].heading_level, Some(2));
+        assert_eq!(reparsed[0].content, chunks[1].content);
+
+        // Renaming keeps the underline; numbered headings take `#`s
+        let (renamed, _) =
+            rename_heading("Plans\n===\n\nSee [[plans]]", "Plans", "Goals", true).unwrap();
+        assert_eq!(renamed, "Goals\n===\n\nSee [[Goals]]");
+        let numbered = number_headings("Title\n=====\n\nIntro\n-----\n\n### Why").unwrap();
+        assert_eq!(numbered, "Title\n=====\n\n## 1. Intro\n\n### 1.1 Why");
+        assert_eq!(number_headings(&numbered), None);
+        assert_eq!(
+            unnumber_headings("Title\n=====\n\n1.1 Intro\n-----").unwrap(),
+            "Title\n=====\n\nIntro\n-----"
+        );
+    }
+
+    #[test]
+    fn test_hash_consistency() {
+        let hash1 = compute_hash("Hello world");
+        let hash2 = compute_hash("Hello world");
+        let hash3 = compute_hash("Hello world ");
+        assert_eq!(hash1, hash2);
+        assert_eq!(hash1, hash3); // trimmed
+    }
+
+    #[test]
+    fn test_hash_difference() {
+        let hash1 = compute_hash("Hello");
+        let hash2 = compute_hash("World");
+        assert_ne!(hash1, hash2);
+    }
+
+    #[test]
+    fn test_utf16_offset() {
+        let content = "a😀é b";
+        assert_eq!(utf16_offset(content, 0), 0);
+        assert_eq!(utf16_offset(content, 2), 3);
+        assert_eq!(utf16_offset(content, 5), 6);
+        assert_eq!(utf16_offset(content, 99), 6);
+    }
+
+    #[test]
+    fn test_byte_offsets() {
+        let content = "# 日本\n\nEmoji 😀 here\n\n- é";
+        assert_eq!(byte_offset(content, 3), 5);
+        assert_eq!(byte_offset(content, 99), content.len());
+        for chunk in parse_chunks(content) {
+            assert_eq!(
+                &content[chunk.start_byte..chunk.end_byte].trim_end(),
+                &chunk.content
+            );
+            assert_eq!(chunk.start_byte, byte_offset(content, chunk.start_offset));
+        }
+    }
+
+    #[test]
+    fn test_chunk_id() {
+        let hash = compute_hash("Hello");
+        assert_eq!(chunk_id("note1", &hash, 0), chunk_id("note1", &hash, 0));
+        assert_ne!(chunk_id("note1", &hash, 0), chunk_id("note1", &hash, 1));
+        assert_ne!(chunk_id("note1", &hash, 0), chunk_id("note2", &hash, 0));
+        assert_eq!(chunk_id("note1", &hash, 0).len(), 32);
+    }
+
+    #[test]
+    fn test_chunk_and_hash() {
+        let chunks = chunk_and_hash("# Title\n\nParagraph");
+        assert_eq!(chunks.len(), 2);
+        assert!(!chunks[0].content_hash.is_empty());
+        assert_eq!(chunks[0].content_hash.len(), 32);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_uses_type_names() {
+        let chunks = chunk_and_hash("---\n\n- [ ] task");
+        let value = serde_json::to_value(&chunks).unwrap();
+        assert_eq!(
+            value[0]["chunk"]["chunk_type"],
+            ChunkType::HorizontalRule.as_str()
+        );
+        assert_eq!(
+            value[1]["chunk"]["chunk_type"],
+            ChunkType::TaskList.as_str()
+        );
+        let back: Vec<ChunkWithHash> = serde_json::from_value(value).unwrap();
+        assert_eq!(back[1].chunk.chunk_type, ChunkType::TaskList);
+    }
+
+    #[test]
+    fn test_complex_document() {
+        let content = r#"# My Document
+
+This is the intro paragraph.
+
+## Section 1
+
+Some content here.
+
+- List item 1
+- List item 2
+
+```python
+print("hello")
+```
+
+---
+
+## Section 2
+
+Final thoughts."#;
+
+        let chunks = parse_chunks(content);
+        assert!(chunks.len() >= 7);
+        assert_eq!(chunks[0].chunk_type, ChunkType::Heading);
+        assert_eq!(chunks[0].heading_level, Some(1));
+    }
+}
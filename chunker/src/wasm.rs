@@ -0,0 +1,61 @@
+//! JavaScript bindings, built with
+//! `wasm-pack build chunker --target web -- --features wasm`.
+//!
+//! Offsets returned here are in UTF-16 code units, the unit JS strings are
+//! indexed by, rather than the char offsets used on the server.
+
+use wasm_bindgen::prelude::*;
+
+/// Chunk and hash `content`. Returns a JSON array of
+/// `{ chunk_type, heading_level, list_depth, content, content_hash,
+/// start_offset, end_offset }` objects.
+#[wasm_bindgen(js_name = chunkAndHash)]
+pub fn chunk_and_hash(content: &str) -> String {
+    let chunks: Vec<serde_json::Value> = crate::chunk_and_hash(content)
+        .into_iter()
+        .map(|c| {
+            serde_json::json!({
+                "chunk_type": c.chunk.chunk_type.as_str(),
+                "heading_level": c.chunk.heading_level,
+                "list_depth": c.chunk.list_depth,
+                "content": c.chunk.content,
+                "content_hash": c.content_hash,
+                "start_offset": crate::utf16_offset(content, c.chunk.start_offset),
+                "end_offset": crate::utf16_offset(content, c.chunk.end_offset),
+            })
+        })
+        .collect();
+    serde_json::Value::Array(chunks).to_string()
+}
+
+#[wasm_bindgen(js_name = computeHash)]
+pub fn compute_hash(content: &str) -> String {
+    crate::compute_hash(content)
+}
+
+/// Matches the server's ids, unless it encrypts notes under a master key.
+#[wasm_bindgen(js_name = chunkId)]
+pub fn chunk_id(note_id: &str, content_hash: &str, occurrence: u32) -> String {
+    crate::chunk_id(note_id, content_hash, occurrence)
+}
+
+/// Flip the `index`-th checkbox of a task list, or return `undefined` if
+/// there is no such task.
+#[wasm_bindgen(js_name = toggleTask)]
+pub fn toggle_task(content: &str, index: usize) -> Option<String> {
+    crate::toggle_task(content, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_are_utf16() {
+        let chunks: serde_json::Value =
+            serde_json::from_str(&chunk_and_hash("# 😀\n\nText")).unwrap();
+        assert_eq!(chunks[0]["end_offset"], 5);
+        assert_eq!(chunks[1]["start_offset"], 6);
+        assert_eq!(chunks[1]["content_hash"], crate::compute_hash("Text"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Records the commit the server is built from, for the startup report and
+//! `GET /api/admin/info`. `GIT_HASH` takes precedence, for builds without
+//! the repository such as the Docker image.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+
+    let hash = std::env::var("GIT_HASH")
+        .ok()
+        .filter(|h| !h.trim().is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+        });
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=TRAME_GIT_HASH={}", hash.trim());
+    }
+}
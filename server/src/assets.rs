@@ -0,0 +1,154 @@
+//! Frontend files served from `STATIC_DIR`, such as a bundler's `web/dist`
+//! output. Without it the server ships the single page embedded at build time.
+
+use std::path::{Path, PathBuf};
+
+pub struct Asset {
+    pub content_type: &'static str,
+    pub cache_control: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// The file under `root` for a request path. Extensionless paths that match
+/// no file get `index.html`, so client-side routes load the app.
+pub async fn load(root: &Path, request_path: &str) -> Option<Asset> {
+    let relative = resolve(request_path)?;
+    let mut file = root.join(&relative);
+    if !tokio::fs::metadata(&file).await.is_ok_and(|m| m.is_file()) {
+        if relative.extension().is_some() {
+            return None;
+        }
+        file = root.join("index.html");
+    }
+
+    let body = tokio::fs::read(&file).await.ok()?;
+    Some(Asset {
+        content_type: content_type(&file),
+        cache_control: cache_control(&file),
+        body,
+    })
+}
+
+/// The request path as a path relative to the static root, or `None` if it
+/// would leave the root or reach hidden files.
+fn resolve(request_path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in request_path.split('/').filter(|s| !s.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        relative.push(segment);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push("index.html");
+    }
+    Some(relative)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Bundlers put a content hash in asset names (`app-3f2a9c1b.js`), so those
+/// never change and can be cached for good. Pages are revalidated so a deploy
+/// shows up on the next load.
+fn cache_control(path: &Path) -> &'static str {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let hashed = stem.rsplit(['-', '.']).next().is_some_and(|h| {
+        h != stem
+            && h.len() >= 8
+            && h.chars().all(|c| c.is_ascii_alphanumeric())
+            && h.chars().any(|c| c.is_ascii_digit())
+    });
+    if path.extension().is_some_and(|e| e == "html") {
+        "no-cache"
+    } else if hashed {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("/"), Some(PathBuf::from("index.html")));
+        assert_eq!(
+            resolve("/assets//app.js"),
+            Some(PathBuf::from("assets/app.js"))
+        );
+        assert_eq!(resolve("/../secret"), None);
+        assert_eq!(resolve("/.env"), None);
+        assert_eq!(resolve("/a\\..\\b"), None);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        assert_eq!(cache_control(Path::new("index.html")), "no-cache");
+        assert_eq!(
+            cache_control(Path::new("assets/app-3f2a9c1b.js")),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            cache_control(Path::new("assets/index.BzX9kQ2a.css")),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            cache_control(Path::new("favicon.ico")),
+            "public, max-age=3600"
+        );
+        assert_eq!(
+            cache_control(Path::new("my-component.js")),
+            "public, max-age=3600"
+        );
+        assert_eq!(
+            cache_control(Path::new("service-worker.js")),
+            "public, max-age=3600"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_with_spa_fallback() {
+        let root = std::env::temp_dir().join(format!("trame-assets-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "<!doctype html>").unwrap();
+        std::fs::write(root.join("assets/app.js"), "run()").unwrap();
+
+        let js = load(&root, "/assets/app.js").await.unwrap();
+        assert_eq!(js.content_type, "text/javascript; charset=utf-8");
+        assert_eq!(js.body, b"run()");
+
+        let route = load(&root, "/notes/today").await.unwrap();
+        assert_eq!(route.content_type, "text/html; charset=utf-8");
+        assert_eq!(route.body, b"<!doctype html>");
+
+        assert!(load(&root, "/assets/missing.js").await.is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use crate::chunker::{byte_offset, utf16_offset};
+
+/// Request header in which clients list the optional features they support,
+/// comma-separated. The response echoes the ones the server honored.
+pub const HEADER: &str = "x-trame-capabilities";
+
+/// Report chunk and search offsets in UTF-16 code units instead of chars.
+pub const UTF16_OFFSETS: &str = "utf16-offsets";
+
+/// Report chunk and search offsets in bytes of the UTF-8 content instead of
+/// chars. Takes precedence over `utf16-offsets` if a client sends both.
+pub const BYTE_OFFSETS: &str = "byte-offsets";
+
+/// Capabilities the server understands. Anything else a client declares is
+/// ignored and left out of the echo, so clients can tell what took effect.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    pub utf16_offsets: bool,
+    pub byte_offsets: bool,
+}
+
+impl Capabilities {
+    pub fn parse(header: Option<&str>) -> Self {
+        let mut caps = Self::default();
+        for name in header.unwrap_or_default().split(',') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case(UTF16_OFFSETS) {
+                caps.utf16_offsets = true;
+            } else if name.eq_ignore_ascii_case(BYTE_OFFSETS) {
+                caps.byte_offsets = true;
+            }
+        }
+        // Offsets come in one unit
+        caps.utf16_offsets &= !caps.byte_offsets;
+        caps
+    }
+
+    /// Honored capabilities in header form, or `None` if there are none.
+    pub fn header_value(&self) -> Option<String> {
+        let mut honored = Vec::new();
+        if self.utf16_offsets {
+            honored.push(UTF16_OFFSETS);
+        }
+        if self.byte_offsets {
+            honored.push(BYTE_OFFSETS);
+        }
+        (!honored.is_empty()).then(|| honored.join(", "))
+    }
+
+    /// A char offset into `content` in the unit the client asked for.
+    pub fn offset(&self, content: &str, char_offset: i32) -> i32 {
+        let char_offset = char_offset as usize;
+        if self.byte_offsets {
+            byte_offset(content, char_offset) as i32
+        } else if self.utf16_offsets {
+            utf16_offset(content, char_offset) as i32
+        } else {
+            char_offset as i32
+        }
+    }
+
+    /// Like [`offset`](Self::offset) for a chunk boundary, using the byte
+    /// offset stored with the chunk when there is one.
+    pub fn chunk_offset(&self, content: &str, char_offset: i32, byte: Option<i32>) -> i32 {
+        match byte {
+            Some(byte) if self.byte_offsets => byte,
+            _ => self.offset(content, char_offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ignores_unknown_capabilities() {
+        let caps = Capabilities::parse(Some("chunk-patch, UTF16-Offsets ,crdt-v1"));
+        assert!(caps.utf16_offsets);
+        assert_eq!(caps.header_value().as_deref(), Some("utf16-offsets"));
+
+        let caps = Capabilities::parse(Some("crdt-v1"));
+        assert_eq!(caps, Capabilities::default());
+        assert_eq!(caps.header_value(), None);
+        assert_eq!(Capabilities::parse(None).header_value(), None);
+
+        let caps = Capabilities::parse(Some("utf16-offsets, byte-offsets"));
+        assert!(caps.byte_offsets && !caps.utf16_offsets);
+        assert_eq!(caps.header_value().as_deref(), Some("byte-offsets"));
+    }
+
+    #[test]
+    fn test_offsets() {
+        let content = "é😀 x";
+        let chars = Capabilities::default();
+        let utf16 = Capabilities::parse(Some(UTF16_OFFSETS));
+        let bytes = Capabilities::parse(Some(BYTE_OFFSETS));
+        assert_eq!(chars.offset(content, 3), 3);
+        assert_eq!(utf16.offset(content, 3), 4);
+        assert_eq!(bytes.offset(content, 3), 7);
+        assert_eq!(bytes.chunk_offset(content, 3, Some(7)), 7);
+        assert_eq!(bytes.chunk_offset(content, 3, None), 7);
+        assert_eq!(utf16.chunk_offset(content, 3, Some(7)), 4);
+    }
+}
@@ -0,0 +1,90 @@
+//! Flashcard scheduling with SM-2: each grade from 0 (forgotten) to 5
+//! (perfect) moves a card's next review further out or back to tomorrow, and
+//! nudges its easiness.
+
+use crate::db::Card;
+
+pub const MAX_GRADE: u8 = 5;
+/// Grades below this count as forgotten.
+const PASSING_GRADE: u8 = 3;
+const MIN_EASE: f64 = 1.3;
+
+/// Schedule `card` after a review graded `grade` at `now`. Grades above
+/// [`MAX_GRADE`] are taken as `MAX_GRADE`.
+pub fn grade(card: &mut Card, grade: u8, now: chrono::DateTime<chrono::Utc>) {
+    let grade = grade.min(MAX_GRADE);
+    if grade >= PASSING_GRADE {
+        card.interval_days = match card.repetitions {
+            0 => 1,
+            1 => 6,
+            _ => (card.interval_days as f64 * card.ease).round() as i64,
+        };
+        card.repetitions += 1;
+    } else {
+        card.repetitions = 0;
+        card.interval_days = 1;
+    }
+    let miss = f64::from(MAX_GRADE - grade);
+    card.ease = (card.ease + 0.1 - miss * (0.08 + miss * 0.02)).max(MIN_EASE);
+    card.due_at = (now + chrono::Duration::days(card.interval_days)).to_rfc3339();
+    card.last_reviewed_at = Some(now.to_rfc3339());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card() -> Card {
+        Card {
+            id: "k1".to_string(),
+            note_id: "n1".to_string(),
+            chunk_id: "c1".to_string(),
+            question: "Capital of France?".to_string(),
+            answer: "Paris".to_string(),
+            ease: 2.5,
+            interval_days: 0,
+            repetitions: 0,
+            due_at: "2026-03-01T00:00:00+00:00".to_string(),
+            last_reviewed_at: None,
+            created_at: "2026-03-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_grade() {
+        let now = chrono::DateTime::parse_from_rfc3339("2026-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut c = card();
+
+        grade(&mut c, 4, now);
+        assert_eq!((c.repetitions, c.interval_days), (1, 1));
+        assert_eq!(c.ease, 2.5);
+        assert_eq!(c.due_at, "2026-03-02T12:00:00+00:00");
+        assert_eq!(
+            c.last_reviewed_at.as_deref(),
+            Some("2026-03-01T12:00:00+00:00")
+        );
+
+        grade(&mut c, 5, now);
+        assert_eq!((c.repetitions, c.interval_days), (2, 6));
+        assert!((c.ease - 2.6).abs() < 1e-9);
+
+        grade(&mut c, 3, now);
+        assert_eq!((c.repetitions, c.interval_days), (3, 16));
+        assert!((c.ease - 2.46).abs() < 1e-9);
+
+        // Forgetting starts the card over but keeps its lowered ease
+        grade(&mut c, 1, now);
+        assert_eq!((c.repetitions, c.interval_days), (0, 1));
+        assert!((c.ease - 1.92).abs() < 1e-9);
+
+        for _ in 0..5 {
+            grade(&mut c, 0, now);
+        }
+        assert_eq!(c.ease, MIN_EASE);
+        // Out-of-range grades count as perfect
+        grade(&mut c, 9, now);
+        assert_eq!(c.repetitions, 1);
+    }
+}
@@ -0,0 +1,96 @@
+//! Fault injection for development: artificial latency and random 503s, so
+//! client authors can exercise retry and offline handling against a local
+//! server without a proxy in between. Release builds ignore it.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// Each response is delayed by up to this long, picked at random.
+    pub max_latency_ms: u64,
+    /// Share of requests, from 0.0 to 1.0, answered with a 503 instead.
+    pub error_rate: f64,
+}
+
+impl Chaos {
+    /// From the `CHAOS_LATENCY_MS` and `CHAOS_ERROR_RATE` settings, or `None`
+    /// when neither would do anything.
+    pub fn from_settings(latency_ms: Option<&str>, error_rate: Option<&str>) -> Option<Self> {
+        let max_latency_ms = latency_ms.and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+        let error_rate = error_rate
+            .and_then(|r| r.trim().parse::<f64>().ok())
+            .filter(|r| r.is_finite())
+            .map(|r| r.clamp(0.0, 1.0))
+            .unwrap_or(0.0);
+        (max_latency_ms > 0 || error_rate > 0.0).then_some(Self {
+            max_latency_ms,
+            error_rate,
+        })
+    }
+
+    /// Wait out this request's delay, then say whether it should fail.
+    pub async fn inject(&self) -> bool {
+        let (delay, fail) = {
+            let mut rng = rand::thread_rng();
+            (
+                rng.gen_range(0..=self.max_latency_ms),
+                rng.gen_bool(self.error_rate),
+            )
+        };
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        fail
+    }
+}
+
+/// Health checks are left alone so orchestrators don't restart the server.
+pub fn applies_to(path: &str) -> bool {
+    !path.starts_with("/api/health")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_settings() {
+        assert_eq!(Chaos::from_settings(None, None), None);
+        assert_eq!(Chaos::from_settings(Some("0"), Some("0")), None);
+        assert_eq!(
+            Chaos::from_settings(Some("250"), None),
+            Some(Chaos {
+                max_latency_ms: 250,
+                error_rate: 0.0
+            })
+        );
+        assert_eq!(
+            Chaos::from_settings(Some("soon"), Some("1.5")),
+            Some(Chaos {
+                max_latency_ms: 0,
+                error_rate: 1.0
+            })
+        );
+        assert_eq!(Chaos::from_settings(None, Some("NaN")), None);
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let always = Chaos {
+            max_latency_ms: 0,
+            error_rate: 1.0,
+        };
+        assert!(always.inject().await);
+
+        let slow = Chaos {
+            max_latency_ms: 5,
+            error_rate: 0.0,
+        };
+        assert!(!slow.inject().await);
+
+        assert!(applies_to("/api/note"));
+        assert!(!applies_to("/api/health/ready"));
+    }
+}
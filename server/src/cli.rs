@@ -0,0 +1,272 @@
+//! Operator commands. The server binary serves by default; given a command,
+//! it manages accounts and data through the same storage code and exits.
+//!
+//! ```text
+//! trame-server [serve]
+//! trame-server db migrate
+//! trame-server db encrypt
+//! trame-server user list
+//! trame-server user create <email>      (password read from stdin)
+//! trame-server user delete <email>
+//! trame-server export --user <email> [--format md|json|html]
+//! ```
+//!
+//! Flags after the command are settings, as for the server (`--database-url`).
+
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+use serde_json::json;
+
+use crate::config::Config;
+use crate::db::Storage;
+use crate::handlers;
+use crate::{ids, log, open_database, AppState};
+
+pub const USAGE: &str = "usage: trame-server [serve | db migrate | db encrypt | user list | user create <email> | user delete <email> | export --user <email> [--format md|json|html]] [--setting value ...]";
+
+type CommandResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    /// Apply pending migrations, including every shard, and finish the
+    /// backfills, so operators can migrate ahead of a deploy.
+    Migrate,
+    /// Encrypt note text written before `MASTER_KEY` was set.
+    Encrypt,
+    ListUsers,
+    CreateUser {
+        email: String,
+    },
+    DeleteUser {
+        email: String,
+    },
+    /// Print a user's note, as `GET /api/note/export` would.
+    Export {
+        email: String,
+        format: String,
+    },
+}
+
+impl Command {
+    /// The command at the start of `args`, and the setting flags after it.
+    /// `--migrate-only` still means `db migrate`.
+    pub fn parse(args: &[String]) -> Result<(Self, Vec<String>), String> {
+        let split = args
+            .iter()
+            .position(|arg| arg.starts_with("--"))
+            .unwrap_or(args.len());
+        let (words, flags) = args.split_at(split);
+        let mut flags: Vec<String> = flags.to_vec();
+        let legacy_migrate = flags.len();
+        flags.retain(|flag| flag != "--migrate-only");
+
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let command = match words.as_slice() {
+            [] if flags.len() < legacy_migrate => Command::Migrate,
+            [] | ["serve"] => Command::Serve,
+            ["db", "migrate"] => Command::Migrate,
+            ["db", "encrypt"] => Command::Encrypt,
+            ["user", "list"] => Command::ListUsers,
+            ["user", "create", email] => Command::CreateUser {
+                email: email.to_string(),
+            },
+            ["user", "delete", email] => Command::DeleteUser {
+                email: email.to_string(),
+            },
+            ["export"] => Command::Export {
+                email: take_flag(&mut flags, "--user").ok_or("export needs --user <email>")?,
+                format: take_flag(&mut flags, "--format").unwrap_or_else(|| "md".to_string()),
+            },
+            words => return Err(format!("unknown command: {}", words.join(" "))),
+        };
+        Ok((command, flags))
+    }
+}
+
+/// Remove `--name value` or `--name=value` from `flags` and return the value.
+fn take_flag(flags: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = flags
+        .iter()
+        .position(|f| f == name || f.strip_prefix(name).is_some_and(|v| v.starts_with('=')))?;
+    let flag = flags.remove(pos);
+    match flag.split_once('=') {
+        Some((_, value)) => Some(value.to_string()),
+        None if pos < flags.len() => Some(flags.remove(pos)),
+        None => None,
+    }
+}
+
+/// Run any command but `serve`, which the binary handles itself.
+pub async fn run(command: Command, config: Config) -> CommandResult {
+    match command {
+        Command::Migrate => return migrate(&config),
+        Command::Encrypt => return encrypt(&config),
+        _ => {}
+    }
+
+    // Stdout is the command's output
+    log::use_stderr();
+    let state = AppState::new(config)?;
+    let mut out = std::io::stdout().lock();
+    match command {
+        Command::Serve | Command::Migrate | Command::Encrypt => unreachable!("handled above"),
+        Command::ListUsers => {
+            for user in state.db.run(|db| db.list_users()).await? {
+                writeln!(out, "{}\t{}\t{}", user.id, user.email, user.created_at)?;
+            }
+        }
+        Command::CreateUser { email } => {
+            if !email.contains('@') {
+                return Err("Invalid email".into());
+            }
+            let password = read_password()?;
+            handlers::validate_password(&password).map_err(failure)?;
+            let password_hash = handlers::hash_password(&password).map_err(failure)?;
+            let user_id = ids::new_id();
+            let created = user_id.clone();
+            let exists = state
+                .db
+                .run(move |db| {
+                    if db.get_user_by_email(&email)?.is_some() {
+                        return Ok(true);
+                    }
+                    db.create_user(&created, &email, &password_hash)?;
+                    Ok(false)
+                })
+                .await?;
+            if exists {
+                return Err("Email already registered".into());
+            }
+            log::info("account created", json!({ "user_id": user_id }));
+            writeln!(out, "{}", user_id)?;
+        }
+        Command::DeleteUser { email } => {
+            let user = find_user(&state, &email).await?;
+            let user_id = user.id.clone();
+            state.db.run(move |db| db.delete_user(&user.id)).await?;
+            log::info("account deleted", json!({ "user_id": user_id }));
+        }
+        Command::Export { email, format } => {
+            let user = find_user(&state, &email).await?;
+            let download = handlers::export_note(&state, &user.id, Some(&format))
+                .await
+                .map_err(failure)?;
+            out.write_all(download.body.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn migrate(config: &Config) -> CommandResult {
+    log::info(
+        "database",
+        json!({ "path": config.database_url, "shard_dir": config.shard_dir }),
+    );
+    let db = open_database(config)?;
+    let shards = db.migrate_shards()?;
+    let batches = db.run_backfills(config.backfill_batch_size)?;
+    log::info(
+        "migrations complete",
+        json!({ "shards": shards, "backfill_batches": batches }),
+    );
+    Ok(())
+}
+
+fn encrypt(config: &Config) -> CommandResult {
+    if config.master_key.is_none() {
+        return Err("MASTER_KEY must be set to encrypt notes".into());
+    }
+    let db = open_database(config)?;
+    db.migrate_shards()?;
+    let sealed = db.encrypt_existing()?;
+    log::info("notes encrypted", json!({ "values": sealed }));
+    Ok(())
+}
+
+async fn find_user(
+    state: &AppState,
+    email: &str,
+) -> Result<crate::db::User, Box<dyn Error + Send + Sync>> {
+    let lookup = email.to_string();
+    state
+        .db
+        .run(move |db| db.get_user_by_email(&lookup))
+        .await?
+        .ok_or_else(|| format!("No account for {}", email).into())
+}
+
+/// The first line of stdin, so passwords stay out of shell history and `ps`.
+fn read_password() -> Result<String, std::io::Error> {
+    eprint!("Password: ");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The message of a handler's JSON error.
+fn failure((_, body): (u16, String)) -> Box<dyn Error + Send + Sync> {
+    serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v["error"].as_str().map(str::to_string))
+        .unwrap_or(body)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Command, Vec<String>), String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Command::parse(&args)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]).unwrap(), (Command::Serve, vec![]));
+        assert_eq!(
+            parse(&["serve", "--port", "9000"]).unwrap(),
+            (
+                Command::Serve,
+                vec!["--port".to_string(), "9000".to_string()]
+            )
+        );
+        assert_eq!(parse(&["db", "migrate"]).unwrap().0, Command::Migrate);
+        assert_eq!(parse(&["db", "encrypt"]).unwrap().0, Command::Encrypt);
+        assert_eq!(
+            parse(&["--database-url", "x.db", "--migrate-only"]).unwrap(),
+            (
+                Command::Migrate,
+                vec!["--database-url".to_string(), "x.db".to_string()]
+            )
+        );
+        assert_eq!(
+            parse(&["user", "create", "a@b.c"]).unwrap().0,
+            Command::CreateUser {
+                email: "a@b.c".to_string()
+            }
+        );
+        assert_eq!(
+            parse(&[
+                "export",
+                "--format=json",
+                "--user",
+                "a@b.c",
+                "--log-level",
+                "warn"
+            ])
+            .unwrap(),
+            (
+                Command::Export {
+                    email: "a@b.c".to_string(),
+                    format: "json".to_string()
+                },
+                vec!["--log-level".to_string(), "warn".to_string()]
+            )
+        );
+        assert!(parse(&["export"]).is_err());
+        assert!(parse(&["user", "rename"]).is_err());
+    }
+}
@@ -1,22 +1,604 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::path::Path;
+
+use crate::chaos::Chaos;
+use crate::cors::Cors;
+use crate::db::cipher::MasterKey;
+use crate::flags;
+use crate::ids::IdStrategy;
+use crate::log::Level;
 
 pub struct Config {
     pub port: u16,
     pub host: String,
     pub database_url: String,
-    pub allowed_origin: String,
+    pub database_pool_size: usize,
+    /// Encrypts note text at rest when set, from `MASTER_KEY`.
+    pub master_key: Option<MasterKey>,
+    /// Which browser origins may call the API, from `ALLOWED_ORIGIN`.
+    pub cors: Cors,
+    /// Take client addresses from `X-Forwarded-For`, set by a reverse proxy.
+    pub trust_proxy: bool,
+    /// Also accept sessions from an HttpOnly cookie, set at login, with
+    /// double-submit CSRF tokens on writes.
+    pub session_cookies: bool,
+    /// Directory of frontend files to serve instead of the embedded page.
+    pub static_dir: Option<String>,
+    pub shard_dir: Option<String>,
+    pub shard_cache_size: usize,
+    /// Users whose note and chunks are kept in memory; 0 turns the cache off.
+    pub note_cache_size: usize,
+    /// SQL statements slower than this many milliseconds are logged; 0 logs
+    /// none.
+    pub slow_query_ms: u64,
+    /// Rows per transaction for data migrations run in the background.
+    pub backfill_batch_size: usize,
+    pub max_meta_bytes: usize,
+    pub max_body_bytes: usize,
+    pub max_attachment_bytes: usize,
+    pub log_level: Level,
+    /// Scheme for the ids of new records.
+    pub id_strategy: IdStrategy,
+    pub shutdown_timeout_secs: u64,
+    /// How often expired sessions and old trash are purged, note history
+    /// thinned and key usage counts written down; 0 turns the purge off.
+    pub session_gc_interval_secs: u64,
+    /// Days a trashed note is kept before it's deleted for good; 0 keeps it.
+    pub trash_retention_days: u64,
+    /// Revisions saved within this many minutes are thinned to the latest by
+    /// the purge; 0 keeps every one.
+    pub revision_interval_mins: u64,
+    /// Most revisions, and most chunk versions, the purge leaves a note; 0
+    /// keeps them all.
+    pub history_limit: u32,
+    /// Days a chunk goes unedited before it's due in the review queue.
+    pub review_after_days: u64,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub email_hook: Option<String>,
+    /// Base URL of the GitHub API, for gist imports.
+    pub github_api_url: String,
+    /// Emails of accounts allowed to manage instance announcements.
+    pub admin_emails: Vec<String>,
+    /// Feature flags turned on for every account.
+    pub feature_flags: Vec<String>,
+    /// Current terms of service version; users must accept it before writing.
+    pub terms_version: Option<String>,
+    pub terms_url: Option<String>,
+    /// Directory to record anonymized API fixtures into.
+    pub record_fixtures: Option<String>,
+    /// Injected latency and errors for testing clients; development builds only.
+    pub chaos: Option<Chaos>,
+}
+
+/// Read when `TRAME_CONFIG` doesn't name another file.
+pub const DEFAULT_CONFIG_FILE: &str = "trame.toml";
+
+/// Every setting, by its environment variable name. The config file uses the
+/// same names in lowercase (`port`, `database_url`), and flags in kebab case
+/// (`--database-url`).
+const SETTINGS: &[&str] = &[
+    "PORT",
+    "HOST",
+    "DATABASE_URL",
+    "DATABASE_POOL_SIZE",
+    "MASTER_KEY",
+    "ALLOWED_ORIGIN",
+    "CORS_ALLOW_CREDENTIALS",
+    "CORS_MAX_AGE",
+    "CORS_EXPOSE_HEADERS",
+    "CORS_ALLOW_PRIVATE_NETWORK",
+    "TRUST_PROXY",
+    "SESSION_COOKIES",
+    "STATIC_DIR",
+    "SHARD_DIR",
+    "SHARD_CACHE_SIZE",
+    "NOTE_CACHE_SIZE",
+    "SLOW_QUERY_MS",
+    "BACKFILL_BATCH_SIZE",
+    "MAX_META_BYTES",
+    "MAX_BODY_BYTES",
+    "MAX_ATTACHMENT_BYTES",
+    "LOG_LEVEL",
+    "ID_STRATEGY",
+    "SHUTDOWN_TIMEOUT_SECS",
+    "SESSION_GC_INTERVAL_SECS",
+    "TRASH_RETENTION_DAYS",
+    "REVISION_INTERVAL_MINS",
+    "HISTORY_LIMIT",
+    "REVIEW_AFTER_DAYS",
+    "TLS_CERT_PATH",
+    "TLS_KEY_PATH",
+    "EMAIL_HOOK",
+    "GITHUB_API_URL",
+    "ADMIN_EMAILS",
+    "FEATURE_FLAGS",
+    "TERMS_VERSION",
+    "TERMS_URL",
+    "RECORD_FIXTURES",
+    "CHAOS_LATENCY_MS",
+    "CHAOS_ERROR_RATE",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// The config file can't be read or isn't valid TOML.
+    File { path: String, message: String },
+    /// A setting the server doesn't have, named as it was written.
+    Unknown { name: String },
+    /// A value that doesn't fit its setting.
+    Invalid {
+        name: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File { path, message } => {
+                write!(f, "can't load config file {}: {}", path, message)
+            }
+            ConfigError::Unknown { name } => write!(f, "unknown setting {}", name),
+            ConfigError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(f, "{} is {:?}, expected {}", name, value, expected),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// One setting's raw value, and how to name it in errors: `port in
+/// trame.toml`, `PORT` or `--port`, wherever it came from.
+struct Value {
+    value: String,
+    name: String,
+}
+
+impl Value {
+    fn invalid(&self, expected: &'static str) -> ConfigError {
+        ConfigError::Invalid {
+            name: self.name.clone(),
+            value: self.value.clone(),
+            expected,
+        }
+    }
+}
+
+/// Raw settings in layers: the config file, then the environment, then
+/// flags, each overriding the one before.
+#[derive(Default)]
+struct Settings {
+    values: HashMap<&'static str, Value>,
+}
+
+impl Settings {
+    fn set(&mut self, key: &'static str, value: String, name: String) {
+        self.values.insert(key, Value { value, name });
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|err| ConfigError::File {
+            path: path.to_string(),
+            message: err.to_string(),
+        })?;
+        self.read_toml(path, &text)
+    }
+
+    fn read_toml(&mut self, path: &str, text: &str) -> Result<(), ConfigError> {
+        let table: toml::Table = toml::from_str(text).map_err(|err| ConfigError::File {
+            path: path.to_string(),
+            message: err.message().to_string(),
+        })?;
+        self.read_table(path, "", &table)
+    }
+
+    /// Nested tables name settings by their path: `[tls] cert_path` is
+    /// `TLS_CERT_PATH`.
+    fn read_table(
+        &mut self,
+        path: &str,
+        prefix: &str,
+        table: &toml::Table,
+    ) -> Result<(), ConfigError> {
+        for (name, value) in table {
+            let name = match prefix {
+                "" => name.clone(),
+                prefix => format!("{}_{}", prefix, name),
+            };
+            if let toml::Value::Table(table) = value {
+                self.read_table(path, &name, table)?;
+                continue;
+            }
+            let written = format!("{} in {}", name, path);
+            let key = setting(&name).ok_or_else(|| ConfigError::Unknown {
+                name: written.clone(),
+            })?;
+            // Lists stand for the comma-separated values the environment takes
+            let text = match value {
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(toml_scalar)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| items.join(",")),
+                value => toml_scalar(value),
+            };
+            let text = text.ok_or_else(|| ConfigError::Invalid {
+                name: written.clone(),
+                value: value.to_string(),
+                expected: "a string, number, boolean or list of them",
+            })?;
+            self.set(key, text, written);
+        }
+        Ok(())
+    }
+
+    fn read_env(&mut self) {
+        for key in SETTINGS {
+            if let Ok(value) = env::var(key) {
+                self.set(key, value, key.to_string());
+            }
+        }
+        // RUST_LOG is still honored for existing deployments
+        if env::var("LOG_LEVEL").is_err() {
+            if let Ok(value) = env::var("RUST_LOG") {
+                self.set("LOG_LEVEL", value, "RUST_LOG".to_string());
+            }
+        }
+    }
+
+    /// `--name value` or `--name=value`; a flag with no value means `true`.
+    fn read_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let key =
+                flag.strip_prefix("--")
+                    .and_then(setting)
+                    .ok_or_else(|| ConfigError::Unknown {
+                        name: flag.to_string(),
+                    })?;
+            let value =
+                value.unwrap_or_else(|| match args.next_if(|next| !next.starts_with("--")) {
+                    Some(next) => next.clone(),
+                    None => "true".to_string(),
+                });
+            self.set(key, value, flag.to_string());
+        }
+        Ok(())
+    }
+
+    /// The value, unless unset or empty.
+    fn text(&self, key: &str) -> Option<String> {
+        self.values
+            .get(key)
+            .map(|v| v.value.clone())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn parse<T>(
+        &self,
+        key: &str,
+        default: T,
+        parse: impl Fn(&str) -> Option<T>,
+        expected: &'static str,
+    ) -> Result<T, ConfigError> {
+        match self.values.get(key) {
+            Some(v) if !v.value.trim().is_empty() => {
+                parse(v.value.trim()).ok_or_else(|| v.invalid(expected))
+            }
+            _ => Ok(default),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T, ConfigError> {
+        self.parse(key, default, |v| v.parse().ok(), "a whole number")
+    }
+
+    fn flag(&self, key: &str) -> Result<bool, ConfigError> {
+        self.parse(
+            key,
+            false,
+            |v| match v.to_ascii_lowercase().as_str() {
+                "true" | "1" => Some(true),
+                "false" | "0" => Some(false),
+                _ => None,
+            },
+            "true or false",
+        )
+    }
+
+    /// Like [`Settings::parse`], but errors don't repeat the value.
+    fn secret<T>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> Option<T>,
+        expected: &'static str,
+    ) -> Result<Option<T>, ConfigError> {
+        self.parse(key, None, |v| parse(v).map(Some), expected)
+            .map_err(|err| match err {
+                ConfigError::Invalid { name, expected, .. } => ConfigError::Invalid {
+                    name,
+                    value: "(hidden)".to_string(),
+                    expected,
+                },
+                err => err,
+            })
+    }
+
+    /// Comma-separated values, trimmed, without empty ones.
+    fn list(&self, key: &str) -> Vec<String> {
+        split_list(&self.text(key).unwrap_or_default())
+    }
+}
+
+fn split_list(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// The setting a file key or flag names, in any case, with `-` or `_`.
+fn setting(name: &str) -> Option<&'static str> {
+    let name = name.replace('-', "_");
+    SETTINGS
+        .iter()
+        .find(|key| key.eq_ignore_ascii_case(&name))
+        .copied()
+}
+
+fn toml_scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(d) => Some(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
 }
 
 impl Config {
-    pub fn from_env() -> Self {
-        Self {
-            port: env::var("PORT")
-                .ok()
-                .and_then(|p| p.parse().ok())
-                .unwrap_or(3000),
-            host: env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            database_url: env::var("DATABASE_URL").unwrap_or_else(|_| "trame.db".to_string()),
-            allowed_origin: env::var("ALLOWED_ORIGIN").unwrap_or_else(|_| "*".to_string()),
+    /// Settings from the config file (`TRAME_CONFIG`, or `trame.toml` if
+    /// present), overridden by the environment, overridden by `args`.
+    pub fn load(args: &[String]) -> Result<Self, ConfigError> {
+        let mut settings = Settings::default();
+        match env::var("TRAME_CONFIG").ok().filter(|p| !p.is_empty()) {
+            Some(path) => settings.read_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                settings.read_file(DEFAULT_CONFIG_FILE)?
+            }
+            None => {}
+        }
+        settings.read_env();
+        settings.read_args(args)?;
+        Self::from_settings(&settings)
+    }
+
+    /// Settings from the environment alone.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut settings = Settings::default();
+        settings.read_env();
+        Self::from_settings(&settings)
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let mut cors = Cors::from_settings(
+            &settings
+                .text("ALLOWED_ORIGIN")
+                .unwrap_or_else(|| "*".to_string()),
+            settings.text("CORS_ALLOW_CREDENTIALS").as_deref(),
+        )
+        .with_max_age(settings.number("CORS_MAX_AGE", 600)?)
+        .with_private_network(settings.flag("CORS_ALLOW_PRIVATE_NETWORK")?);
+        if settings.text("CORS_EXPOSE_HEADERS").is_some() {
+            cors = cors.with_extra_expose_headers(&settings.list("CORS_EXPOSE_HEADERS"));
         }
+
+        Ok(Self {
+            port: settings.parse("PORT", 3000, |p| p.parse().ok(), "a port number")?,
+            host: settings
+                .text("HOST")
+                .unwrap_or_else(|| "0.0.0.0".to_string()),
+            database_url: settings
+                .text("DATABASE_URL")
+                .unwrap_or_else(|| "trame.db".to_string()),
+            database_pool_size: settings
+                .number("DATABASE_POOL_SIZE", crate::db::DEFAULT_POOL_SIZE)?,
+            master_key: settings.secret(
+                "MASTER_KEY",
+                MasterKey::parse,
+                "32 bytes in base64 or hex",
+            )?,
+            cors,
+            trust_proxy: settings.flag("TRUST_PROXY")?,
+            session_cookies: settings.flag("SESSION_COOKIES")?,
+            static_dir: settings.text("STATIC_DIR"),
+            shard_dir: settings.text("SHARD_DIR"),
+            shard_cache_size: settings.number("SHARD_CACHE_SIZE", 64)?,
+            note_cache_size: settings.number("NOTE_CACHE_SIZE", 256)?,
+            slow_query_ms: settings.number("SLOW_QUERY_MS", 200)?,
+            backfill_batch_size: settings.number("BACKFILL_BATCH_SIZE", 500)?,
+            max_meta_bytes: settings.number("MAX_META_BYTES", 16 * 1024)?,
+            max_body_bytes: settings.number("MAX_BODY_BYTES", 10 * 1024 * 1024)?,
+            max_attachment_bytes: settings.number("MAX_ATTACHMENT_BYTES", 5 * 1024 * 1024)?,
+            log_level: settings.parse(
+                "LOG_LEVEL",
+                Level::Info,
+                Level::parse,
+                "off, error, warn, info, debug or trace",
+            )?,
+            id_strategy: settings.parse(
+                "ID_STRATEGY",
+                IdStrategy::Ulid,
+                IdStrategy::parse,
+                "ulid or uuid",
+            )?,
+            shutdown_timeout_secs: settings.number("SHUTDOWN_TIMEOUT_SECS", 30)?,
+            session_gc_interval_secs: settings.number("SESSION_GC_INTERVAL_SECS", 3600)?,
+            trash_retention_days: settings.number("TRASH_RETENTION_DAYS", 30)?,
+            revision_interval_mins: settings.number("REVISION_INTERVAL_MINS", 5)?,
+            history_limit: settings.number("HISTORY_LIMIT", 500)?,
+            review_after_days: settings.number("REVIEW_AFTER_DAYS", 30)?,
+            tls_cert_path: settings.text("TLS_CERT_PATH"),
+            tls_key_path: settings.text("TLS_KEY_PATH"),
+            email_hook: settings.text("EMAIL_HOOK"),
+            github_api_url: settings
+                .text("GITHUB_API_URL")
+                .map(|u| u.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| "https://api.github.com".to_string()),
+            admin_emails: settings
+                .list("ADMIN_EMAILS")
+                .into_iter()
+                .map(|e| e.to_lowercase())
+                .collect(),
+            feature_flags: settings.parse(
+                "FEATURE_FLAGS",
+                Vec::new(),
+                |v| {
+                    let names = split_list(v);
+                    names
+                        .iter()
+                        .all(|n| flags::is_valid_name(n))
+                        .then_some(names)
+                },
+                "flag names of lowercase letters, digits, - and _",
+            )?,
+            terms_version: settings.text("TERMS_VERSION"),
+            terms_url: settings.text("TERMS_URL"),
+            record_fixtures: settings.text("RECORD_FIXTURES"),
+            chaos: Chaos::from_settings(
+                settings.text("CHAOS_LATENCY_MS").as_deref(),
+                settings.text("CHAOS_ERROR_RATE").as_deref(),
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut settings = Settings::default();
+        settings
+            .read_toml(
+                "trame.toml",
+                r#"
+port = 8080
+host = "127.0.0.1"
+admin_emails = ["Ops@example.com", "me@example.com"]
+trust_proxy = true
+
+[tls]
+cert_path = "cert.pem"
+"#,
+            )
+            .unwrap();
+        settings.set("HOST", "0.0.0.0".to_string(), "HOST".to_string());
+        settings
+            .read_args(&args(&[
+                "--port",
+                "9000",
+                "--log-level=debug",
+                "--session-cookies",
+            ]))
+            .unwrap();
+
+        let config = Config::from_settings(&settings).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.admin_emails, ["ops@example.com", "me@example.com"]);
+        assert!(config.trust_proxy);
+        assert!(config.session_cookies);
+        assert_eq!(config.log_level, Level::Debug);
+        assert_eq!(config.tls_cert_path.as_deref(), Some("cert.pem"));
+        assert_eq!(config.id_strategy, IdStrategy::Ulid);
+    }
+
+    #[test]
+    fn test_errors_name_the_setting() {
+        let mut settings = Settings::default();
+        settings.read_toml("trame.toml", "port = 70000").unwrap();
+        assert_eq!(
+            Config::from_settings(&settings).err().unwrap().to_string(),
+            "port in trame.toml is \"70000\", expected a port number"
+        );
+
+        let mut settings = Settings::default();
+        settings
+            .read_args(&args(&["--trust-proxy", "yes"]))
+            .unwrap();
+        assert_eq!(
+            Config::from_settings(&settings).err(),
+            Some(ConfigError::Invalid {
+                name: "--trust-proxy".to_string(),
+                value: "yes".to_string(),
+                expected: "true or false",
+            })
+        );
+
+        let mut settings = Settings::default();
+        settings.set(
+            "ID_STRATEGY",
+            "serial".to_string(),
+            "ID_STRATEGY".to_string(),
+        );
+        assert!(Config::from_settings(&settings).is_err());
+
+        let mut settings = Settings::default();
+        settings.set(
+            "FEATURE_FLAGS",
+            "beta, Semantic".to_string(),
+            "FEATURE_FLAGS".to_string(),
+        );
+        assert_eq!(
+            Config::from_settings(&settings).err().unwrap().to_string(),
+            "FEATURE_FLAGS is \"beta, Semantic\", expected flag names of lowercase letters, digits, - and _"
+        );
+
+        // Keys stay out of the logs
+        let mut settings = Settings::default();
+        settings.set(
+            "MASTER_KEY",
+            "hunter2".to_string(),
+            "MASTER_KEY".to_string(),
+        );
+        assert_eq!(
+            Config::from_settings(&settings).err().unwrap().to_string(),
+            "MASTER_KEY is \"(hidden)\", expected 32 bytes in base64 or hex"
+        );
+
+        assert_eq!(
+            Settings::default().read_toml("trame.toml", "prot = 1"),
+            Err(ConfigError::Unknown {
+                name: "prot in trame.toml".to_string()
+            })
+        );
+        assert_eq!(
+            Settings::default().read_args(&args(&["--prot", "1"])),
+            Err(ConfigError::Unknown {
+                name: "--prot".to_string()
+            })
+        );
+        assert!(matches!(
+            Settings::default().read_toml("trame.toml", "port = "),
+            Err(ConfigError::File { .. })
+        ));
     }
 }
@@ -0,0 +1,488 @@
+//! Pasted text turned into Markdown the note can take as is: rows copied
+//! from a spreadsheet (TSV) or a CSV file become a pipe table, and an HTML
+//! fragment copied from a page becomes the Markdown for its text and
+//! structure. A table is one block without blank lines, so the chunker keeps
+//! it in a single chunk.
+
+/// What pasted text is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Tsv,
+    Html,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "tsv" => Some(Format::Tsv),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::Html => "html",
+        }
+    }
+
+    /// A guess from the text itself: markup is HTML, a tab in the first
+    /// line means TSV, and anything else is read as CSV.
+    pub fn detect(text: &str) -> Self {
+        let text = text.trim_start();
+        if text.starts_with('<') {
+            Format::Html
+        } else if text.lines().next().is_some_and(|line| line.contains('\t')) {
+            Format::Tsv
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+/// `text` as Markdown.
+pub fn to_markdown(text: &str, format: Format) -> String {
+    match format {
+        Format::Csv => table(&parse_delimited(text, ',')),
+        Format::Tsv => table(&parse_delimited(text, '\t')),
+        Format::Html => html_to_markdown(text),
+    }
+}
+
+/// Rows of delimited text. Fields may be quoted, with `""` for a quote, to
+/// hold delimiters and line breaks, as spreadsheets write them. Blank rows
+/// are dropped.
+fn parse_delimited(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows.retain(|row| row.iter().any(|field| !field.trim().is_empty()));
+    rows
+}
+
+/// A pipe table with the first row as its header. Short rows are padded.
+fn table(rows: &[Vec<String>]) -> String {
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if width == 0 {
+        return String::new();
+    }
+    let line = |cells: &[String]| {
+        let mut line = String::from("|");
+        for i in 0..width {
+            line.push(' ');
+            line.push_str(&table_cell(cells.get(i).map_or("", String::as_str)));
+            line.push_str(" |");
+        }
+        line
+    };
+    let mut lines = vec![line(&rows[0]), format!("|{}", " --- |".repeat(width))];
+    lines.extend(rows[1..].iter().map(|row| line(row)));
+    lines.join("\n")
+}
+
+/// Cell text that can't end the cell or the row early.
+fn table_cell(text: &str) -> String {
+    text.trim()
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+/// Elements whose content isn't text to keep.
+const SKIPPED: &[&str] = &["head", "script", "style", "template", "title"];
+const BLOCKS: &[&str] = &[
+    "address", "article", "aside", "div", "dl", "figure", "footer", "header", "main", "nav", "p",
+    "section",
+];
+
+/// An HTML fragment as Markdown: headings, paragraphs, emphasis, code,
+/// links, images, lists, quotes, rules and tables. Other elements keep only
+/// their text.
+fn html_to_markdown(html: &str) -> String {
+    let mut writer = HtmlWriter::default();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        writer.text(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        writer.tag(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+    writer.text(rest);
+    writer.finish()
+}
+
+#[derive(Default)]
+struct HtmlWriter {
+    /// The output, then one buffer per open quote or table cell.
+    buffers: Vec<String>,
+    /// Open lists: the next number for ordered ones.
+    lists: Vec<Option<u32>>,
+    links: Vec<String>,
+    /// Rows of the open table.
+    table: Option<Vec<Vec<String>>>,
+    skip: usize,
+    pre: usize,
+}
+
+impl HtmlWriter {
+    fn out(&mut self) -> &mut String {
+        if self.buffers.is_empty() {
+            self.buffers.push(String::new());
+        }
+        self.buffers.last_mut().unwrap()
+    }
+
+    fn text(&mut self, raw: &str) {
+        if self.skip > 0 || raw.is_empty() {
+            return;
+        }
+        let text = decode_entities(raw);
+        if self.pre > 0 {
+            self.out().push_str(&text);
+            return;
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let out = self.out();
+        let at_break = out.is_empty() || out.ends_with([' ', '\n']);
+        if text.starts_with(char::is_whitespace) && !at_break {
+            out.push(' ');
+        }
+        out.push_str(&words.join(" "));
+        if !words.is_empty() && text.ends_with(char::is_whitespace) {
+            out.push(' ');
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let attrs = &tag[name_end..];
+
+        if SKIPPED.contains(&name.as_str()) {
+            match closing {
+                true => self.skip = self.skip.saturating_sub(1),
+                false => self.skip += 1,
+            }
+            return;
+        }
+        if self.skip > 0 {
+            return;
+        }
+
+        match (name.as_str(), closing) {
+            ("br", _) => self.line_break(),
+            ("hr", _) => {
+                self.block();
+                self.out().push_str("---");
+                self.block();
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                self.block();
+                let level = name[1..].parse().unwrap_or(1);
+                let marker = format!("{} ", "#".repeat(level));
+                self.out().push_str(&marker);
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => self.block(),
+            (name, _) if BLOCKS.contains(&name) => self.block(),
+            ("strong" | "b", _) => self.out().push_str("**"),
+            ("em" | "i", _) => self.out().push('*'),
+            ("code", _) if self.pre == 0 => self.out().push('`'),
+            ("pre", false) => {
+                self.block();
+                self.out().push_str("```\n");
+                self.pre += 1;
+            }
+            ("pre", true) if self.pre > 0 => {
+                self.pre -= 1;
+                let out = self.out();
+                if !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str("```");
+                self.block();
+            }
+            ("a", false) => {
+                self.links.push(attr(attrs, "href").unwrap_or_default());
+                self.out().push('[');
+            }
+            ("a", true) => {
+                if let Some(href) = self.links.pop() {
+                    let link = format!("]({})", href);
+                    self.out().push_str(&link);
+                }
+            }
+            ("img", _) => {
+                let image = format!(
+                    "![{}]({})",
+                    attr(attrs, "alt").unwrap_or_default(),
+                    attr(attrs, "src").unwrap_or_default()
+                );
+                self.out().push_str(&image);
+            }
+            ("ul" | "ol", false) => {
+                if self.lists.is_empty() {
+                    self.block();
+                }
+                self.lists.push((name == "ol").then_some(1));
+            }
+            ("ul" | "ol", true) => {
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.block();
+                }
+            }
+            ("li", false) => {
+                self.line_break();
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                self.out().push_str(&format!("{}{}", indent, marker));
+            }
+            ("blockquote", false) => {
+                self.block();
+                self.buffers.push(String::new());
+            }
+            ("blockquote", true) if self.buffers.len() > 1 => {
+                let quoted = self.buffers.pop().unwrap_or_default();
+                let quoted: Vec<String> = quoted
+                    .trim()
+                    .lines()
+                    .map(|line| format!("> {}", line).trim_end().to_string())
+                    .collect();
+                self.block();
+                self.out().push_str(&quoted.join("\n"));
+                self.block();
+            }
+            ("table", false) => {
+                self.block();
+                self.table = Some(Vec::new());
+            }
+            ("table", true) => {
+                if let Some(rows) = self.table.take() {
+                    let table = table(&rows);
+                    self.out().push_str(&table);
+                    self.block();
+                }
+            }
+            ("tr", false) => {
+                if let Some(rows) = &mut self.table {
+                    rows.push(Vec::new());
+                }
+            }
+            ("td" | "th", false) if self.table.is_some() => self.buffers.push(String::new()),
+            ("td" | "th", true) if self.table.is_some() && self.buffers.len() > 1 => {
+                let cell = self.buffers.pop().unwrap_or_default();
+                if let Some(rows) = &mut self.table {
+                    match rows.last_mut() {
+                        Some(row) => row.push(cell),
+                        None => rows.push(vec![cell]),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// End the current block with a blank line.
+    fn block(&mut self) {
+        let out = self.out();
+        let end = out.trim_end().len();
+        out.truncate(end);
+        if !out.is_empty() {
+            out.push_str("\n\n");
+        }
+    }
+
+    fn line_break(&mut self) {
+        let out = self.out();
+        let end = out.trim_end_matches(' ').len();
+        out.truncate(end);
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> String {
+        // Unclosed quotes and cells still count
+        let mut out = String::new();
+        for buffer in self.buffers.drain(..) {
+            out.push_str(&buffer);
+        }
+        out.lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .split("\n\n")
+            .map(|block| block.trim_matches('\n'))
+            .filter(|block| !block.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// The value of attribute `name` in a tag's attribute text.
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    let lower = attrs.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower[from..].find(name) {
+        let start = from + pos;
+        from = start + name.len();
+        let preceded = lower[..start].ends_with(char::is_whitespace);
+        let rest = lower[from..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value = attrs[attrs.len() - rest.len() + 1..].trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value.split(char::is_whitespace).next().unwrap_or(""),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let c = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|n| n.parse().ok()))
+                .and_then(char::from_u32),
+        });
+        match (c, entity) {
+            (Some(c), Some(entity)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Format::detect("  <p>Hi</p>"), Format::Html);
+        assert_eq!(Format::detect("a\tb\n1\t2"), Format::Tsv);
+        assert_eq!(Format::detect("a,b\n1,2"), Format::Csv);
+    }
+
+    #[test]
+    fn test_delimited_tables() {
+        assert_eq!(
+            to_markdown("Name\tQty\r\nApples\t3\r\n\r\nPears\n", Format::Tsv),
+            "| Name | Qty |\n| --- | --- |\n| Apples | 3 |\n| Pears |  |"
+        );
+        assert_eq!(
+            to_markdown(
+                "item,note\n\"a, b\",\"says \"\"hi\"\"\"\nc,\"two\nlines | pipe\"",
+                Format::Csv
+            ),
+            "| item | note |\n| --- | --- |\n| a, b | says \"hi\" |\n| c | two<br>lines \\| pipe |"
+        );
+        assert_eq!(to_markdown("\n\n", Format::Csv), "");
+    }
+
+    #[test]
+    fn test_html() {
+        let html = "<html><head><title>x</title><style>p{}</style></head><body>
+            <h2>Plan &amp; notes</h2>
+            <p>Some <b>bold</b>,   <em>soft</em> and <code>code</code>.<br>Next <a href=\"https://example.com/?a=1&amp;b=2\">link</a></p>
+            <!-- <p>hidden</p> -->
+            <ul><li>One</li><li>Two<ol><li>Inner</li><li>More</li></ol></li></ul>
+            <blockquote><p>Quoted</p><p>twice</p></blockquote>
+            <pre><code>let x = 1;\n  indented &lt;ok&gt;</code></pre>
+            <table><tr><th>A</th><th>B</th></tr><tr><td>1</td><td>x | y</td></tr></table>
+            <hr><img alt=\"cat\" src='cat.png'>
+            </body></html>";
+        assert_eq!(
+            to_markdown(html, Format::Html),
+            "## Plan & notes\n\n\
+             Some **bold**, *soft* and `code`.\n\
+             Next [link](https://example.com/?a=1&b=2)\n\n\
+             - One\n- Two\n  1. Inner\n  2. More\n\n\
+             > Quoted\n>\n> twice\n\n\
+             ```\nlet x = 1;\n  indented <ok>\n```\n\n\
+             | A | B |\n| --- | --- |\n| 1 | x \\| y |\n\n\
+             ---\n\n\
+             ![cat](cat.png)"
+        );
+        assert_eq!(
+            to_markdown("plain &copy; &#169; &#xA9;", Format::Html),
+            "plain &copy; © ©"
+        );
+    }
+}
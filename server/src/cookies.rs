@@ -0,0 +1,86 @@
+//! Cookie sessions for browsers: the session token travels in an HttpOnly
+//! cookie, and state-changing requests prove they come from the app by
+//! echoing a second, readable cookie in a header (double submit).
+
+pub const SESSION_COOKIE: &str = "trame_session";
+pub const CSRF_COOKIE: &str = "trame_csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Value of cookie `name` in a `Cookie` header.
+pub fn get<'a>(cookie_header: Option<&'a str>, name: &str) -> Option<&'a str> {
+    cookie_header?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|value| !value.is_empty())
+}
+
+/// `Set-Cookie` values opening a browser session.
+pub fn issue(token: &str, csrf_token: &str, max_age_secs: i64) -> [String; 2] {
+    [
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+            SESSION_COOKIE, token, max_age_secs
+        ),
+        // Read by the app's script, so not HttpOnly
+        format!(
+            "{}={}; Path=/; Max-Age={}; Secure; SameSite=Strict",
+            CSRF_COOKIE, csrf_token, max_age_secs
+        ),
+    ]
+}
+
+/// `Set-Cookie` values ending a browser session.
+pub fn clear() -> [String; 2] {
+    [SESSION_COOKIE, CSRF_COOKIE].map(|name| format!("{}=; Path=/; Max-Age=0", name))
+}
+
+/// Whether the header repeats the CSRF cookie. Compared in constant time.
+pub fn csrf_matches(cookie_header: Option<&str>, csrf_header: Option<&str>) -> bool {
+    let (Some(cookie), Some(header)) = (get(cookie_header, CSRF_COOKIE), csrf_header) else {
+        return false;
+    };
+    cookie.len() == header.len()
+        && cookie
+            .bytes()
+            .zip(header.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let header = Some("theme=dark; trame_session=abc; trame_csrf=\"xyz\"; empty=");
+        assert_eq!(get(header, SESSION_COOKIE), Some("abc"));
+        assert_eq!(get(header, CSRF_COOKIE), Some("xyz"));
+        assert_eq!(get(header, "empty"), None);
+        assert_eq!(get(header, "trame"), None);
+        assert_eq!(get(None, SESSION_COOKIE), None);
+    }
+
+    #[test]
+    fn test_csrf_matches() {
+        let header = Some("trame_session=abc; trame_csrf=xyz");
+        assert!(csrf_matches(header, Some("xyz")));
+        assert!(!csrf_matches(header, Some("xy")));
+        assert!(!csrf_matches(header, Some("xyZ")));
+        assert!(!csrf_matches(header, None));
+        assert!(!csrf_matches(Some("trame_session=abc"), Some("")));
+    }
+
+    #[test]
+    fn test_session_cookie_is_http_only() {
+        let [session, csrf] = issue("abc", "xyz", 60);
+        assert_eq!(
+            session,
+            "trame_session=abc; Path=/; Max-Age=60; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert!(!csrf.contains("HttpOnly"));
+        assert!(clear().iter().all(|c| c.ends_with("Max-Age=0")));
+    }
+}
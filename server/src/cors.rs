@@ -0,0 +1,301 @@
+//! Cross-origin policy. `ALLOWED_ORIGIN` lists the origins browsers may call
+//! the API from, comma-separated: exact origins like `https://app.example.com`,
+//! subdomain wildcards like `https://*.example.com`, or `*` for anyone. Each
+//! request's `Origin` is checked against the list and echoed back only when
+//! it matches, so one server can serve several frontends.
+//!
+//! Preflights are cached for `CORS_MAX_AGE` seconds, and can grant Private
+//! Network Access so public pages may reach a server on the LAN.
+
+use hyper::http::response::Builder;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pattern {
+    Exact(String),
+    /// `https://*.example.com` keeps `https://` and `.example.com`; the bare
+    /// domain itself is not matched.
+    Subdomain {
+        scheme: String,
+        suffix: String,
+    },
+}
+
+impl Pattern {
+    fn parse(entry: &str) -> Self {
+        let entry = entry.trim_end_matches('/').to_lowercase();
+        match entry.split_once("://*.") {
+            Some((scheme, rest)) => Pattern::Subdomain {
+                scheme: format!("{}://", scheme),
+                suffix: format!(".{}", rest),
+            },
+            None => Pattern::Exact(entry),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Pattern::Exact(allowed) => allowed == origin,
+            Pattern::Subdomain { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|sub| {
+                    !sub.is_empty()
+                        && !sub.starts_with('.')
+                        && sub
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+                }),
+        }
+    }
+}
+
+/// Response headers of the server's own that scripts may read.
+const EXPOSE_HEADERS: &[&str] = &[
+    "ETag",
+    "Retry-After",
+    "Content-Disposition",
+    "X-Trame-Capabilities",
+    "X-Trame-Impersonated-By",
+];
+
+/// Exposed too unless `CORS_EXPOSE_HEADERS` says otherwise: the request id
+/// and rate-limit headers a proxy in front commonly adds.
+pub const DEFAULT_EXTRA_EXPOSE_HEADERS: &[&str] = &[
+    "X-Request-Id",
+    "RateLimit-Limit",
+    "RateLimit-Remaining",
+    "RateLimit-Reset",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cors {
+    any: bool,
+    patterns: Vec<Pattern>,
+    credentials: bool,
+    expose_headers: String,
+    max_age: u64,
+    private_network: bool,
+}
+
+impl Cors {
+    /// From the `ALLOWED_ORIGIN` and `CORS_ALLOW_CREDENTIALS` settings.
+    /// Credentials are only ever granted to listed origins: a wildcard that
+    /// let every site send cookies would defeat the point of the list.
+    pub fn from_settings(origins: &str, credentials: Option<&str>) -> Self {
+        let mut any = false;
+        let mut patterns = Vec::new();
+        for entry in origins.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if entry == "*" {
+                any = true;
+            } else {
+                patterns.push(Pattern::parse(entry));
+            }
+        }
+        Self {
+            any,
+            patterns,
+            credentials: matches!(credentials.map(str::trim), Some("true" | "1")),
+            expose_headers: EXPOSE_HEADERS
+                .iter()
+                .chain(DEFAULT_EXTRA_EXPOSE_HEADERS)
+                .copied()
+                .collect::<Vec<_>>()
+                .join(", "),
+            max_age: 0,
+            private_network: false,
+        }
+    }
+
+    /// Expose these headers, besides the server's own, in place of
+    /// [`DEFAULT_EXTRA_EXPOSE_HEADERS`].
+    pub fn with_extra_expose_headers(mut self, headers: &[String]) -> Self {
+        let headers = EXPOSE_HEADERS
+            .iter()
+            .copied()
+            .chain(headers.iter().map(String::as_str));
+        self.expose_headers = headers.collect::<Vec<_>>().join(", ");
+        self
+    }
+
+    /// Let browsers reuse a preflight for `secs` seconds; 0 leaves it to them.
+    pub fn with_max_age(mut self, secs: u64) -> Self {
+        self.max_age = secs;
+        self
+    }
+
+    /// Answer Private Network Access preflights from allowed origins.
+    pub fn with_private_network(mut self, allow: bool) -> Self {
+        self.private_network = allow;
+        self
+    }
+
+    /// Decide the CORS headers for a request carrying this `Origin`.
+    pub fn grant(&self, origin: Option<&str>) -> Grant {
+        let listed = origin
+            .map(|o| o.trim_end_matches('/').to_lowercase())
+            .filter(|o| self.patterns.iter().any(|p| p.matches(o)));
+        let (allow_origin, credentials) = match (listed, origin) {
+            (Some(_), Some(origin)) => (Some(origin.to_string()), self.credentials),
+            _ if self.any => (Some("*".to_string()), false),
+            _ => (None, false),
+        };
+        let allowed = allow_origin.is_some();
+        Grant {
+            allow_origin,
+            credentials,
+            // The answer depends on the request's origin unless it's always `*`
+            vary: !self.patterns.is_empty(),
+            expose_headers: Some(self.expose_headers.clone()).filter(|_| allowed),
+            max_age: Some(self.max_age).filter(|&secs| allowed && secs > 0),
+            private_network: allowed && self.private_network,
+        }
+    }
+}
+
+/// The CORS headers one response carries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    allow_origin: Option<String>,
+    credentials: bool,
+    vary: bool,
+    expose_headers: Option<String>,
+    max_age: Option<u64>,
+    private_network: bool,
+}
+
+impl Grant {
+    pub fn allow_origin(&self) -> Option<&str> {
+        self.allow_origin.as_deref()
+    }
+
+    pub fn apply(&self, mut builder: Builder) -> Builder {
+        if let Some(origin) = &self.allow_origin {
+            builder = builder.header("Access-Control-Allow-Origin", origin.as_str());
+        }
+        if self.credentials {
+            builder = builder.header("Access-Control-Allow-Credentials", "true");
+        }
+        if self.vary {
+            builder = builder.header("Vary", "Origin");
+        }
+        if let Some(headers) = &self.expose_headers {
+            builder = builder.header("Access-Control-Expose-Headers", headers.as_str());
+        }
+        builder
+    }
+
+    /// The headers only a preflight carries. `private_network` is whether it
+    /// asked for Private Network Access.
+    pub fn apply_preflight(&self, mut builder: Builder, private_network: bool) -> Builder {
+        if let Some(secs) = self.max_age {
+            builder = builder.header("Access-Control-Max-Age", secs);
+        }
+        if private_network && self.private_network {
+            builder = builder.header("Access-Control-Allow-Private-Network", "true");
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard() {
+        let cors = Cors::from_settings("*", Some("true"));
+        let grant = cors.grant(Some("https://anywhere.test"));
+        assert_eq!(grant.allow_origin(), Some("*"));
+        assert!(!grant.credentials);
+        assert!(!grant.vary);
+        assert_eq!(cors.grant(None).allow_origin(), Some("*"));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let cors = Cors::from_settings(
+            "https://app.example.com, https://*.trame.dev/, http://localhost:5173",
+            Some("1"),
+        );
+        for origin in [
+            "https://app.example.com",
+            "https://eu.trame.dev",
+            "https://a.b.trame.dev",
+            "http://localhost:5173",
+        ] {
+            let grant = cors.grant(Some(origin));
+            assert_eq!(grant.allow_origin(), Some(origin), "{}", origin);
+            assert!(grant.credentials);
+            assert!(grant.vary);
+        }
+        for origin in [
+            "https://trame.dev",
+            "http://eu.trame.dev",
+            "https://eviltrame.dev",
+            "https://app.example.com.evil.test",
+            "http://localhost:3000",
+            "null",
+        ] {
+            let grant = cors.grant(Some(origin));
+            assert_eq!(grant.allow_origin(), None, "{}", origin);
+            assert!(!grant.credentials);
+            assert!(grant.vary);
+        }
+        assert_eq!(cors.grant(None).allow_origin(), None);
+    }
+
+    #[test]
+    fn test_listed_origins_beside_wildcard() {
+        let cors = Cors::from_settings("*,https://app.example.com", Some("true"));
+        let grant = cors.grant(Some("https://app.example.com"));
+        assert_eq!(grant.allow_origin(), Some("https://app.example.com"));
+        assert!(grant.credentials);
+        let grant = cors.grant(Some("https://other.test"));
+        assert_eq!(grant.allow_origin(), Some("*"));
+        assert!(!grant.credentials);
+        assert!(grant.vary);
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let cors = Cors::from_settings("https://app.example.com", None)
+            .with_max_age(600)
+            .with_private_network(true)
+            .with_extra_expose_headers(&["X-Upstream".to_string()]);
+        let grant = cors.grant(Some("https://app.example.com"));
+        let exposed = grant.expose_headers.clone().unwrap();
+        assert!(exposed.starts_with("ETag, "));
+        assert!(exposed.ends_with(", X-Upstream"));
+        assert!(!exposed.contains("X-Request-Id"));
+        let preflight = grant
+            .apply_preflight(Builder::new(), true)
+            .body(())
+            .unwrap();
+        assert_eq!(preflight.headers()["Access-Control-Max-Age"], "600");
+        assert_eq!(
+            preflight.headers()["Access-Control-Allow-Private-Network"],
+            "true"
+        );
+        let preflight = grant
+            .apply_preflight(Builder::new(), false)
+            .body(())
+            .unwrap();
+        assert!(!preflight
+            .headers()
+            .contains_key("Access-Control-Allow-Private-Network"));
+
+        // Nothing for origins that aren't allowed
+        let grant = cors.grant(Some("https://other.test"));
+        assert_eq!(grant.expose_headers, None);
+        let preflight = grant
+            .apply_preflight(Builder::new(), true)
+            .body(())
+            .unwrap();
+        assert!(preflight.headers().is_empty());
+
+        let grant = Cors::from_settings("*", None).grant(Some("https://other.test"));
+        assert!(grant.expose_headers.unwrap().ends_with("RateLimit-Reset"));
+        assert_eq!(grant.max_age, None);
+        assert!(!grant.private_network);
+    }
+}
@@ -0,0 +1,170 @@
+//! Recently read notes and chunk lists, kept in memory for a bounded number
+//! of users and dropped whenever a write publishes a change for them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{Chunk, Note};
+
+#[derive(Default)]
+struct Entry {
+    note: Option<Note>,
+    /// With the id of the note they belong to.
+    chunks: Option<(String, Vec<Chunk>)>,
+}
+
+struct State {
+    // Most recently used last
+    entries: Vec<(String, Entry)>,
+    /// Bumped by every invalidation, so a read that raced a write doesn't
+    /// store what it read.
+    generation: u64,
+}
+
+pub struct NoteCache {
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NoteCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                entries: Vec::new(),
+                generation: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Take before reading from the database, and pass to `put_*` after.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    pub fn note(&self, user_id: &str) -> Option<Note> {
+        self.lookup(user_id, |entry| entry.note.clone())
+    }
+
+    pub fn chunks(&self, user_id: &str, note_id: &str) -> Option<Vec<Chunk>> {
+        self.lookup(user_id, |entry| match &entry.chunks {
+            Some((id, chunks)) if id == note_id => Some(chunks.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn put_note(&self, user_id: &str, note: &Note, generation: u64) {
+        self.update(user_id, generation, |entry| entry.note = Some(note.clone()));
+    }
+
+    pub fn put_chunks(&self, user_id: &str, note_id: &str, chunks: &[Chunk], generation: u64) {
+        self.update(user_id, generation, |entry| {
+            entry.chunks = Some((note_id.to_string(), chunks.to_vec()))
+        });
+    }
+
+    pub fn invalidate(&self, user_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.entries.retain(|(id, _)| id != user_id);
+    }
+
+    /// Lookups answered from memory and from the database, since startup.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn lookup<T>(&self, user_id: &str, get: impl Fn(&Entry) -> Option<T>) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let found = match state.entries.iter().position(|(id, _)| id == user_id) {
+            Some(pos) => {
+                let entry = state.entries.remove(pos);
+                let found = get(&entry.1);
+                state.entries.push(entry);
+                found
+            }
+            None => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    fn update(&self, user_id: &str, generation: u64, set: impl FnOnce(&mut Entry)) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        let mut entry = match state.entries.iter().position(|(id, _)| id == user_id) {
+            Some(pos) => state.entries.remove(pos),
+            None => (user_id.to_string(), Entry::default()),
+        };
+        set(&mut entry.1);
+        state.entries.push(entry);
+        if state.entries.len() > self.capacity {
+            state.entries.remove(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(user_id: &str, content: &str) -> Note {
+        Note {
+            id: format!("note-{}", user_id),
+            user_id: user_id.to_string(),
+            content: content.to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            deleted_at: None,
+            title: None,
+            journal: false,
+            numbered_headings: false,
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = NoteCache::new(2);
+        for user in ["u1", "u2"] {
+            cache.put_note(user, &note(user, "x"), cache.generation());
+        }
+        // Reading u1 makes u2 the oldest
+        assert!(cache.note("u1").is_some());
+        cache.put_note("u3", &note("u3", "x"), cache.generation());
+
+        assert!(cache.note("u2").is_none());
+        assert!(cache.note("u1").is_some());
+        assert!(cache.note("u3").is_some());
+        assert_eq!(cache.stats(), (3, 1));
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let cache = NoteCache::new(8);
+        cache.put_note("u1", &note("u1", "old"), cache.generation());
+        cache.put_chunks("u1", "note-u1", &[], cache.generation());
+        assert_eq!(cache.chunks("u1", "note-u1").map(|c| c.len()), Some(0));
+        assert!(cache.chunks("u1", "other-note").is_none());
+
+        // A read that started before a write doesn't store its result
+        let generation = cache.generation();
+        cache.invalidate("u1");
+        assert!(cache.note("u1").is_none());
+        cache.put_note("u1", &note("u1", "old"), generation);
+        assert!(cache.note("u1").is_none());
+    }
+}
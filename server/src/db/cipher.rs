@@ -0,0 +1,310 @@
+//! Encryption of note text at rest. With a `MASTER_KEY`, note content and
+//! titles, chunks, chunk versions, revisions, cards, link targets, metadata,
+//! attachments and imported text are sealed with AES-256-GCM before they
+//! reach SQLite, each note under its own key derived from the master key and
+//! the note id (HKDF-SHA256). Chunk content hashes are stored as an HMAC
+//! under a key derived the same way, and chunk and card ids are taken from
+//! those instead of from the text.
+//!
+//! Sealed values stay text: [`PREFIX`], then the nonce, ciphertext and tag in
+//! base64. The prefix opens with a control character nobody types, so text
+//! written before encryption was enabled reads as it is, and is sealed the
+//! next time it's written or by `trame-server db encrypt`. Sealed bytes are
+//! the prefix followed by the nonce, ciphertext and tag as they are.
+
+use std::fmt;
+
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::chunker;
+
+/// Marks a sealed value.
+pub const PREFIX: &str = "\u{1}enc1:";
+const KEY_LEN: usize = 32;
+/// Keeps note keys apart from anything else derived from the master key.
+const NOTE_KEY_SALT: &[u8] = b"trame note keys v1";
+/// Keeps a note's hash key apart from its encryption key.
+const HASH_KEY_INFO: &[u8] = b"content hash";
+
+/// The `MASTER_KEY` setting: 32 random bytes in base64 or hex, such as the
+/// output of `openssl rand -base64 32`.
+#[derive(Clone, PartialEq)]
+pub struct MasterKey([u8; KEY_LEN]);
+
+impl MasterKey {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let bytes = if value.len() == KEY_LEN * 2 {
+            hex::decode(value).ok()?
+        } else {
+            STANDARD.decode(value).ok()?
+        };
+        Some(Self(bytes.try_into().ok()?))
+    }
+}
+
+// Never print the key
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CipherError {
+    /// A sealed value was read, but no master key is configured.
+    NoKey,
+    /// The value is damaged, or was sealed under another master key.
+    Invalid,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::NoKey => f.write_str("note text is encrypted but MASTER_KEY isn't set"),
+            CipherError::Invalid => {
+                f.write_str("note text can't be decrypted with this MASTER_KEY")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+/// Seals and opens note text. Without a master key, text is stored as is.
+#[derive(Clone, Default)]
+pub struct Cipher {
+    master: Option<Prk>,
+}
+
+impl Cipher {
+    pub fn new(master: &MasterKey) -> Self {
+        Self {
+            master: Some(Salt::new(HKDF_SHA256, NOTE_KEY_SALT).extract(&master.0)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// `text` as it should be stored for `note_id`. Empty text stays empty,
+    /// so queries can still tell an empty note apart.
+    pub fn seal(&self, note_id: &str, text: &str) -> String {
+        let Some(master) = &self.master else {
+            return text.to_string();
+        };
+        if text.is_empty() {
+            return String::new();
+        }
+        let sealed = seal_bytes(master, note_id, text.as_bytes());
+        format!("{}{}", PREFIX, STANDARD_NO_PAD.encode(sealed))
+    }
+
+    /// The text behind a value stored for `note_id`, sealed or not.
+    pub fn open(&self, note_id: &str, value: String) -> Result<String, CipherError> {
+        let Some(encoded) = value.strip_prefix(PREFIX) else {
+            return Ok(value);
+        };
+        let master = self.master.as_ref().ok_or(CipherError::NoKey)?;
+        let bytes = STANDARD_NO_PAD
+            .decode(encoded)
+            .map_err(|_| CipherError::Invalid)?;
+        let text = open_bytes(master, note_id, bytes)?;
+        String::from_utf8(text).map_err(|_| CipherError::Invalid)
+    }
+
+    /// Like [`Cipher::seal`], for bytes such as an attachment's.
+    pub fn seal_data(&self, note_id: &str, data: &[u8]) -> Vec<u8> {
+        let Some(master) = &self.master else {
+            return data.to_vec();
+        };
+        let mut sealed = PREFIX.as_bytes().to_vec();
+        sealed.extend(seal_bytes(master, note_id, data));
+        sealed
+    }
+
+    /// Like [`Cipher::open`], for bytes stored with [`Cipher::seal_data`].
+    pub fn open_data(&self, note_id: &str, value: Vec<u8>) -> Result<Vec<u8>, CipherError> {
+        let Some(sealed) = value.strip_prefix(PREFIX.as_bytes()) else {
+            return Ok(value);
+        };
+        let master = self.master.as_ref().ok_or(CipherError::NoKey)?;
+        open_bytes(master, note_id, sealed.to_vec())
+    }
+
+    /// A chunk's content hash as it should be stored for `note_id`: keyed by
+    /// the note, so it can't be checked against guessed text. As it is
+    /// without a master key.
+    pub fn content_hash(&self, note_id: &str, hash: &str) -> String {
+        let Some(master) = &self.master else {
+            return hash.to_string();
+        };
+        let info = [note_id.as_bytes(), HASH_KEY_INFO];
+        let key: hmac::Key = master
+            .expand(&info, hmac::HMAC_SHA256)
+            .expect("an HMAC-SHA256 key is a valid HKDF output length")
+            .into();
+        hex::encode(&hmac::sign(&key, hash.as_bytes()).as_ref()[..16])
+    }
+
+    /// The id of the note's chunk with content hash `hash`, after
+    /// `occurrence` others with the same hash. Under a master key it's taken
+    /// from the keyed hash, so it can't be checked against guessed text
+    /// either, and clients can't compute it themselves.
+    pub fn chunk_id(&self, note_id: &str, hash: &str, occurrence: u32) -> String {
+        chunker::chunk_id(note_id, &self.content_hash(note_id, hash), occurrence)
+    }
+
+    /// The id of the note's flashcard asking `question`, keyed like
+    /// [`Cipher::chunk_id`].
+    pub fn card_id(&self, note_id: &str, question: &str, occurrence: u32) -> String {
+        chunker::card_id(note_id, &self.content_hash(note_id, question), occurrence)
+    }
+}
+
+/// Whether a stored value is sealed.
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// The nonce, then `data` sealed under the note's key, then the tag.
+fn seal_bytes(master: &Prk, note_id: &str, data: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("system randomness is available");
+    let mut sealed = data.to_vec();
+    note_key(master, note_id)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .expect("note text fits in one AES-GCM message");
+
+    let mut bytes = nonce.to_vec();
+    bytes.extend(sealed);
+    bytes
+}
+
+fn open_bytes(master: &Prk, note_id: &str, mut bytes: Vec<u8>) -> Result<Vec<u8>, CipherError> {
+    if bytes.len() < NONCE_LEN {
+        return Err(CipherError::Invalid);
+    }
+    let (nonce, sealed) = bytes.split_at_mut(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| CipherError::Invalid)?;
+    let data = note_key(master, note_id)
+        .open_in_place(nonce, Aad::empty(), sealed)
+        .map_err(|_| CipherError::Invalid)?;
+    Ok(data.to_vec())
+}
+
+fn note_key(master: &Prk, note_id: &str) -> LessSafeKey {
+    let info = [note_id.as_bytes()];
+    let okm = master
+        .expand(&info, &AES_256_GCM)
+        .expect("an AES-256 key is a valid HKDF output length");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey([byte; KEY_LEN])
+    }
+
+    #[test]
+    fn test_parse_master_key() {
+        let hex = "00".repeat(KEY_LEN);
+        assert_eq!(MasterKey::parse(&hex), Some(key(0)));
+        assert_eq!(
+            MasterKey::parse(&STANDARD.encode([7u8; KEY_LEN])),
+            Some(key(7))
+        );
+        assert_eq!(MasterKey::parse("c2hvcnQ="), None);
+        assert_eq!(MasterKey::parse("not a key"), None);
+        assert_eq!(format!("{:?}", key(7)), "MasterKey(..)");
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = Cipher::new(&key(1));
+        let sealed = cipher.seal("note1", "# Secret\n\nplans");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("Secret"));
+        assert_ne!(sealed, cipher.seal("note1", "# Secret\n\nplans"));
+        assert_eq!(
+            cipher.open("note1", sealed.clone()).unwrap(),
+            "# Secret\n\nplans"
+        );
+
+        // Each note has its own key
+        assert_eq!(
+            cipher.open("note2", sealed.clone()),
+            Err(CipherError::Invalid)
+        );
+        assert_eq!(
+            Cipher::new(&key(2)).open("note1", sealed.clone()),
+            Err(CipherError::Invalid)
+        );
+        assert_eq!(
+            Cipher::default().open("note1", sealed),
+            Err(CipherError::NoKey)
+        );
+
+        assert_eq!(cipher.seal("note1", ""), "");
+        assert_eq!(cipher.open("note1", "plain".to_string()).unwrap(), "plain");
+        assert_eq!(Cipher::default().seal("note1", "plain"), "plain");
+    }
+
+    #[test]
+    fn test_seal_data_and_hashes() {
+        let cipher = Cipher::new(&key(1));
+        let sealed = cipher.seal_data("note1", b"\x89PNG secret");
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            cipher.open_data("note1", sealed.clone()).unwrap(),
+            b"\x89PNG secret"
+        );
+        assert_eq!(cipher.open_data("note2", sealed), Err(CipherError::Invalid));
+        assert_eq!(
+            cipher.open_data("note1", b"plain".to_vec()).unwrap(),
+            b"plain"
+        );
+
+        // Hashes are stable per note, and differ between notes and keys
+        let hash = cipher.content_hash("note1", "abc");
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, cipher.content_hash("note1", "abc"));
+        assert_ne!(hash, cipher.content_hash("note2", "abc"));
+        assert_ne!(hash, Cipher::new(&key(2)).content_hash("note1", "abc"));
+        assert_eq!(Cipher::default().content_hash("note1", "abc"), "abc");
+
+        // So are ids, which the client computes the same without a key
+        assert_ne!(
+            cipher.chunk_id("note1", "abc", 0),
+            chunker::chunk_id("note1", "abc", 0)
+        );
+        assert_ne!(
+            cipher.card_id("note1", "Why?", 0),
+            chunker::card_id("note1", "Why?", 0)
+        );
+        let plain = Cipher::default();
+        assert_eq!(
+            plain.chunk_id("note1", "abc", 1),
+            chunker::chunk_id("note1", "abc", 1)
+        );
+        assert_eq!(
+            plain.card_id("note1", "Why?", 1),
+            chunker::card_id("note1", "Why?", 1)
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Per-method call counts and durations for the storage layer, and a log
+//! of slow SQL statements.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{
+    Announcement, Attachment, AuditEntry, Card, Chunk, ChunkVersion, DbHealth, ExportToken,
+    ExternalImport, FocusSession, FocusTotal, InboundHook, JournalEntry, KeyUsageDay, KeyUse, Note,
+    NoteGoal, NoteRevision, SearchHit, Session, Share, Storage, StorageResult, TagCount, User,
+};
+use crate::log;
+
+/// Statements slower than this many milliseconds are logged; 0 logs none.
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(0);
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The storage method running on this thread, to say which one a slow
+    /// statement came from.
+    static CURRENT_METHOD: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+pub fn set_slow_query_threshold(ms: u64) {
+    SLOW_QUERY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Statements logged as slow since startup.
+pub fn slow_queries_total() -> u64 {
+    SLOW_QUERIES.load(Ordering::Relaxed)
+}
+
+/// Profiling callback installed on every connection.
+pub(crate) fn statement_finished(sql: &str, took: Duration) {
+    let threshold = SLOW_QUERY_MS.load(Ordering::Relaxed);
+    if threshold == 0 || took < Duration::from_millis(threshold) {
+        return;
+    }
+    SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+    log::warn(
+        "slow query",
+        serde_json::json!({
+            "method": CURRENT_METHOD.with(Cell::get),
+            "sql": redact_sql(sql),
+            "duration_ms": took.as_secs_f64() * 1000.0,
+        }),
+    );
+}
+
+/// The statement on one line, with literal strings and numbers replaced by
+/// `?`. Bound parameters never appear in the text in the first place.
+fn redact_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut prev = ' ';
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            // '' inside a literal is an escaped quote
+            while let Some(c) = chars.next() {
+                if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                    break;
+                }
+            }
+            out.push('?');
+            prev = '?';
+        } else if c.is_ascii_digit() && !(prev.is_alphanumeric() || matches!(prev, '_' | '?')) {
+            while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+            out.push('?');
+            prev = '?';
+        } else if c.is_whitespace() {
+            if prev != ' ' {
+                out.push(' ');
+                prev = ' ';
+            }
+        } else {
+            out.push(c);
+            prev = c;
+        }
+    }
+    out.trim_end().to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MethodStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+#[derive(Default)]
+pub struct DbMetrics {
+    methods: Mutex<HashMap<&'static str, MethodStats>>,
+}
+
+impl DbMetrics {
+    fn record(&self, method: &'static str, took: Duration, failed: bool) {
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method).or_default();
+        stats.calls += 1;
+        stats.errors += failed as u64;
+        stats.total += took;
+        stats.max = stats.max.max(took);
+    }
+
+    /// Every method called so far, the most time spent first.
+    pub fn snapshot(&self) -> Vec<(&'static str, MethodStats)> {
+        let mut methods: Vec<_> = self
+            .methods
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (*name, *stats))
+            .collect();
+        methods.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        methods
+    }
+}
+
+/// Storage that times every call into the one it wraps, and retries calls
+/// the backend was too busy for.
+pub struct Metered<S> {
+    inner: S,
+    metrics: Arc<DbMetrics>,
+}
+
+impl<S: Storage> Metered<S> {
+    pub fn new(inner: S, metrics: Arc<DbMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    fn call<T>(
+        &self,
+        method: &'static str,
+        f: impl FnMut() -> StorageResult<T>,
+    ) -> StorageResult<T> {
+        let outer = CURRENT_METHOD.with(|current| current.replace(Some(method)));
+        let started = Instant::now();
+        let result = super::retry_busy(f);
+        self.metrics
+            .record(method, started.elapsed(), result.is_err());
+        CURRENT_METHOD.with(|current| current.set(outer));
+        result
+    }
+}
+
+macro_rules! metered {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl<S: Storage> Storage for Metered<S> {
+            $(fn $name(&self, $($arg: $ty),*) -> StorageResult<$ret> {
+                self.call(stringify!($name), || self.inner.$name($($arg),*))
+            })*
+        }
+    };
+}
+
+metered! {
+    fn migrate() -> ();
+    fn run_backfills(batch_size: usize) -> u64;
+    fn health() -> DbHealth;
+    fn checkpoint() -> ();
+    fn create_user(id: &str, email: &str, password_hash: &str) -> ();
+    fn get_user_by_email(email: &str) -> Option<User>;
+    fn get_user(id: &str) -> Option<User>;
+    fn list_users() -> Vec<User>;
+    fn delete_user(user_id: &str) -> ();
+    fn set_password_hash(user_id: &str, password_hash: &str) -> ();
+    fn accept_terms(user_id: &str, version: &str) -> ();
+    fn create_password_reset(token_hash: &str, user_id: &str, expires_at: &str) -> ();
+    fn consume_password_reset(token_hash: &str) -> Option<String>;
+    fn create_session(session: &Session) -> bool;
+    fn get_session(token: &str) -> Option<Session>;
+    fn list_sessions(user_id: &str, now: &str) -> Vec<Session>;
+    fn delete_session(token: &str) -> ();
+    fn purge_expired_tokens(now: &str) -> u64;
+    fn delete_user_sessions(user_id: &str, keep_token: Option<&str>) -> ();
+    fn get_or_create_note(user_id: &str) -> Note;
+    fn get_note(user_id: &str, note_id: &str) -> Option<Note>;
+    fn update_note(user_id: &str, content: &str) -> Note;
+    fn update_note_if(user_id: &str, content: &str, updated_at: &str) -> Option<Note>;
+    fn enable_journal(user_id: &str, note_id: &str) -> ();
+    fn set_numbered_headings(user_id: &str, numbered: bool) -> Note;
+    fn append_journal_entry(user_id: &str, text: &str) -> (Note, JournalEntry);
+    fn list_journal_entries(user_id: &str, note_id: &str) -> Vec<JournalEntry>;
+    fn trash_note(user_id: &str, note_id: &str, now: &str) -> bool;
+    fn list_trash(user_id: &str) -> Vec<Note>;
+    fn restore_note(user_id: &str, note_id: &str, now: &str) -> Option<Note>;
+    fn purge_trash(before: &str) -> u64;
+    fn prune_history(interval_secs: u64, max_kept: u32) -> u64;
+    fn get_chunks(user_id: &str, note_id: &str) -> Vec<Chunk>;
+    fn set_chunk_pinned(user_id: &str, note_id: &str, chunk_id: &str, pinned: bool) -> bool;
+    fn get_chunk_versions(user_id: &str, note_id: &str, before: Option<&str>, limit: u32) -> Vec<ChunkVersion>;
+    fn get_chunk_version(user_id: &str, note_id: &str, version_id: &str) -> Option<ChunkVersion>;
+    fn get_revisions(user_id: &str, note_id: &str) -> Vec<NoteRevision>;
+    fn get_revision(user_id: &str, note_id: &str, revision_id: &str) -> Option<NoteRevision>;
+    fn revision_at(user_id: &str, note_id: &str, at: &str) -> Option<NoteRevision>;
+    fn get_revision_before(user_id: &str, note_id: &str, before: &str) -> Option<NoteRevision>;
+    fn get_note_goal(user_id: &str, note_id: &str) -> NoteGoal;
+    fn set_note_goal(user_id: &str, note_id: &str, goal: &NoteGoal) -> ();
+    fn get_note_meta(user_id: &str, note_id: &str) -> Option<String>;
+    fn set_note_meta(user_id: &str, note_id: &str, data: &str) -> ();
+    fn get_active_focus(user_id: &str) -> Option<FocusSession>;
+    fn start_focus(user_id: &str, note_id: &str) -> Option<FocusSession>;
+    fn stop_focus(user_id: &str) -> Option<FocusSession>;
+    fn focus_totals(user_id: &str, since: &str) -> Vec<FocusTotal>;
+    fn search_chunks(user_id: &str, note_id: &str, query: &str, limit: u32) -> Vec<SearchHit>;
+    fn list_tags(user_id: &str, note_id: &str) -> Vec<TagCount>;
+    fn get_tagged_chunks(user_id: &str, note_id: &str, tag: &str) -> Vec<Chunk>;
+    fn get_backlinks(user_id: &str, note_id: &str, chunk_id: &str) -> Vec<Chunk>;
+    fn due_cards(user_id: &str, note_id: &str, now: &str, limit: u32) -> Vec<Card>;
+    fn get_card(user_id: &str, note_id: &str, card_id: &str) -> Option<Card>;
+    fn save_card_review(user_id: &str, card: &Card) -> ();
+    fn create_attachment(attachment: &Attachment) -> ();
+    fn get_attachment(user_id: &str, id: &str) -> Option<Attachment>;
+    fn get_external_import(user_id: &str, source: &str, external_id: &str)
+        -> Option<ExternalImport>;
+    fn save_external_import(import: &ExternalImport) -> ();
+    fn create_inbound_hook(hook: &InboundHook, token_hash: &str) -> ();
+    fn list_inbound_hooks(user_id: &str) -> Vec<InboundHook>;
+    fn delete_inbound_hook(user_id: &str, id: &str) -> bool;
+    fn use_inbound_hook(token_hash: &str) -> Option<InboundHook>;
+    fn create_export_token(token: &ExportToken, token_hash: &str) -> ();
+    fn list_export_tokens(user_id: &str) -> Vec<ExportToken>;
+    fn delete_export_token(user_id: &str, id: &str) -> bool;
+    fn use_export_token(token_hash: &str) -> Option<ExportToken>;
+    fn record_key_uses(uses: &[KeyUse]) -> ();
+    fn get_key_usage(key_id: &str, since_day: &str) -> Vec<KeyUsageDay>;
+    fn create_share(share: &Share, token_hash: &str) -> ();
+    fn list_shares(user_id: &str, now: &str) -> Vec<Share>;
+    fn delete_share(user_id: &str, id: &str) -> bool;
+    fn use_share(token_hash: &str, now: &str) -> Option<Share>;
+    fn enable_user_flag(user_id: &str, flag: &str) -> bool;
+    fn disable_user_flag(user_id: &str, flag: &str) -> bool;
+    fn list_user_flags(user_id: &str) -> Vec<String>;
+    fn record_audit(entry: &AuditEntry) -> ();
+    fn audit_entries(target_user_id: Option<&str>, limit: u32) -> Vec<AuditEntry>;
+    fn export_audit_entries(user_id: Option<&str>, since: Option<&str>, until: Option<&str>)
+        -> Vec<AuditEntry>;
+    fn create_announcement(announcement: &Announcement) -> ();
+    fn active_announcements(now: &str) -> Vec<Announcement>;
+    fn delete_announcement(id: &str) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    #[test]
+    fn test_calls_are_counted_per_method() {
+        let metrics = Arc::new(DbMetrics::default());
+        let db = Metered::new(Database::open(":memory:").unwrap(), metrics.clone());
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.get_user("user1").unwrap();
+        db.get_user("user2").unwrap();
+        assert!(db.create_user("user1", "test@example.com", "hash").is_err());
+
+        let methods: HashMap<_, _> = metrics.snapshot().into_iter().collect();
+        assert_eq!(methods["get_user"].calls, 2);
+        assert_eq!(methods["create_user"].calls, 2);
+        assert_eq!(methods["create_user"].errors, 1);
+        assert!(methods["migrate"].max <= methods["migrate"].total);
+        assert!(!methods.contains_key("get_or_create_note"));
+    }
+
+    #[test]
+    fn test_redact_sql() {
+        assert_eq!(
+            redact_sql(
+                "SELECT id FROM notes\n   WHERE user_id = ?1 AND kind = 'it''s' AND n > 42 LIMIT 1"
+            ),
+            "SELECT id FROM notes WHERE user_id = ?1 AND kind = ? AND n > ? LIMIT ?"
+        );
+        assert_eq!(
+            redact_sql("SELECT t1.x FROM idx_2 WHERE y = -1.5"),
+            "SELECT t1.x FROM idx_2 WHERE y = -?"
+        );
+    }
+}
@@ -0,0 +1,802 @@
+//! Storage for accounts and notes. Handlers talk to a [`Storage`] trait
+//! object, so the backend can be swapped; [`Database`] is the SQLite one.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+mod cache;
+pub mod cipher;
+pub mod metrics;
+mod sqlite;
+
+pub use sqlite::Database;
+
+pub const DEFAULT_POOL_SIZE: usize = 8;
+
+/// Days of request counts kept for each key.
+pub const KEY_USAGE_DAYS: i64 = 30;
+
+/// The key a session's requests are counted under: a hash of its token, so
+/// usage counts don't keep a second copy of live credentials.
+pub fn session_key_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Extra attempts made at a storage call when the backend reports it is busy,
+/// on top of whatever waiting the backend does itself.
+const BUSY_RETRIES: u32 = 3;
+const BUSY_BACKOFF_MS: u64 = 50;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// Another writer held a lock for too long; retrying may succeed.
+    Busy(String),
+    Other(String),
+}
+
+impl StorageError {
+    pub fn is_busy(&self) -> bool {
+        matches!(self, StorageError::Busy(_))
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Busy(msg) => write!(f, "storage busy: {}", msg),
+            StorageError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: String,
+    pub email: String,
+    pub password_hash: String,
+    pub created_at: String,
+    /// Version of the terms of service the user last accepted.
+    pub terms_version: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub id: String,
+    pub user_id: String,
+    pub content: String,
+    pub created_at: String,
+    pub updated_at: String,
+    /// When the note was moved to the trash.
+    pub deleted_at: Option<String>,
+    /// First heading of the content. Unset for notes without one, and for
+    /// old notes until the background backfill reaches them.
+    pub title: Option<String>,
+    /// Only ever appended to, one timestamped entry at a time.
+    pub journal: bool,
+    /// Headings are numbered by their place in the outline on every save.
+    pub numbered_headings: bool,
+}
+
+/// Text appended to a journal note, as a range of its content.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub id: String,
+    pub note_id: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub token: String,
+    pub user_id: String,
+    pub expires_at: String,
+    /// Admin acting as the user, for read-only support sessions.
+    pub impersonator_id: Option<String>,
+    /// Unset for sessions opened before these were recorded.
+    pub created_at: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub id: String,
+    pub note_id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub heading_level: Option<i32>,
+    pub content: String,
+    pub content_hash: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub created_at: String,
+    pub updated_at: String,
+    /// Kept at the top of the note, above everything else.
+    pub pinned: bool,
+    /// The chunk's range in bytes of the UTF-8 content. Unset for chunks
+    /// saved before bytes were tracked, until the backfill or the next save
+    /// reaches them.
+    pub start_byte: Option<i32>,
+    pub end_byte: Option<i32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkVersion {
+    pub id: String,
+    pub note_id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub heading_level: Option<i32>,
+    pub content: String,
+    pub content_hash: String,
+    pub created_at: String,
+    pub replaced_at: String,
+}
+
+/// A flashcard written in the note, with its SM-2 review schedule.
+#[derive(Debug, Clone)]
+pub struct Card {
+    pub id: String,
+    pub note_id: String,
+    /// The chunk it's written in.
+    pub chunk_id: String,
+    pub question: String,
+    pub answer: String,
+    /// SM-2 easiness factor, never below 1.3.
+    pub ease: f64,
+    pub interval_days: i64,
+    /// Passing grades in a row.
+    pub repetitions: i64,
+    pub due_at: String,
+    pub last_reviewed_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct NoteRevision {
+    pub id: String,
+    pub note_id: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NoteGoal {
+    pub word_goal: Option<u32>,
+    pub daily_word_goal: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FocusSession {
+    pub id: String,
+    pub note_id: String,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub duration_seconds: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FocusTotal {
+    pub note_id: String,
+    pub sessions: i64,
+    pub total_seconds: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub chunk_id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub snippet: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub start_byte: Option<i32>,
+    pub end_byte: Option<i32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TagCount {
+    pub tag: String,
+    /// Number of chunks carrying the tag.
+    pub count: i64,
+}
+
+/// Record of a sensitive action. Kept when the accounts involved are deleted.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub id: String,
+    /// User who acted.
+    pub actor_id: String,
+    /// e.g. `impersonation.start`.
+    pub action: String,
+    pub target_user_id: Option<String>,
+    /// JSON object with action-specific details.
+    pub details: Option<String>,
+    pub created_at: String,
+}
+
+/// What the readiness check reports about the database.
+#[derive(Debug, Clone)]
+pub struct DbHealth {
+    /// Size of the main database's write-ahead log, `None` for in-memory databases.
+    pub wal_bytes: Option<u64>,
+    /// Sessions that haven't expired yet.
+    pub active_sessions: i64,
+    /// Note cache lookups served from memory and from the database since
+    /// startup, `None` when the cache is off.
+    pub note_cache: Option<(u64, u64)>,
+}
+
+/// A document imported from another system, by its id there. Re-imports
+/// find the text written last time and replace it.
+#[derive(Debug, Clone)]
+pub struct ExternalImport {
+    pub id: String,
+    pub user_id: String,
+    pub note_id: String,
+    /// System the document came from, e.g. `notion` or `github-gist`.
+    pub source: String,
+    pub external_id: String,
+    /// Text as last written into the note.
+    pub content: String,
+    pub imported_at: String,
+}
+
+/// A file uploaded to embed in the note.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub id: String,
+    pub user_id: String,
+    pub note_id: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub data: Vec<u8>,
+    pub created_at: String,
+}
+
+/// A secret URL through which an external service writes into the note.
+#[derive(Debug, Clone)]
+pub struct InboundHook {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    /// `append` or `prepend`.
+    pub action: String,
+    pub template: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// A token that can only export its owner's note, for backup tools that
+/// shouldn't hold a session. Only stored hashed.
+#[derive(Debug, Clone)]
+pub struct ExportToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// Requests made with one key, a session or an export token, on one UTC day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyUsageDay {
+    /// `YYYY-MM-DD`
+    pub day: String,
+    pub requests: i64,
+    pub last_used_at: String,
+}
+
+/// Requests made with one key on one UTC day, counted in memory between
+/// writes to the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyUse {
+    pub key_id: String,
+    pub user_id: String,
+    /// `YYYY-MM-DD`
+    pub day: String,
+    pub requests: i64,
+    pub last_used_at: String,
+}
+
+/// Public read-only link to a note. The token in its URL is only stored
+/// hashed.
+#[derive(Debug, Clone)]
+pub struct Share {
+    pub id: String,
+    pub user_id: String,
+    pub note_id: String,
+    pub created_at: String,
+    /// Never expires when unset.
+    pub expires_at: Option<String>,
+    pub last_viewed_at: Option<String>,
+}
+
+/// Operator message shown to every user, optionally only within a window.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    pub id: String,
+    pub message: String,
+    /// `info` or `warning`.
+    pub level: String,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub created_at: String,
+}
+
+/// Everything the server stores. Methods are blocking; async callers go
+/// through [`run`](#method.run). New methods are also listed in
+/// [`metrics::Metered`], which times each one.
+pub trait Storage: Send + Sync {
+    /// Create or upgrade the schema.
+    fn migrate(&self) -> StorageResult<()>;
+
+    /// Run the data migrations left to do in the background, `batch_size`
+    /// rows per transaction. Returns how many batches ran.
+    fn run_backfills(&self, batch_size: usize) -> StorageResult<u64>;
+
+    /// Cheap queries showing the database answers, for readiness checks.
+    fn health(&self) -> StorageResult<DbHealth>;
+
+    /// Flush anything buffered to durable storage. Called on shutdown.
+    fn checkpoint(&self) -> StorageResult<()>;
+
+    // Users
+    fn create_user(&self, id: &str, email: &str, password_hash: &str) -> StorageResult<()>;
+
+    fn get_user_by_email(&self, email: &str) -> StorageResult<Option<User>>;
+
+    fn get_user(&self, id: &str) -> StorageResult<Option<User>>;
+
+    /// Every account, oldest first.
+    fn list_users(&self) -> StorageResult<Vec<User>>;
+
+    /// Delete a user along with their notes, sessions and password resets.
+    fn delete_user(&self, user_id: &str) -> StorageResult<()>;
+
+    fn set_password_hash(&self, user_id: &str, password_hash: &str) -> StorageResult<()>;
+
+    /// Record that the user accepted terms `version` just now.
+    fn accept_terms(&self, user_id: &str, version: &str) -> StorageResult<()>;
+
+    // Password resets
+    fn create_password_reset(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        expires_at: &str,
+    ) -> StorageResult<()>;
+
+    /// Mark an unused, unexpired reset as used and return its user. Each
+    /// reset can be consumed only once.
+    fn consume_password_reset(&self, token_hash: &str) -> StorageResult<Option<String>>;
+
+    // Sessions
+    /// Returns false, storing nothing, if the token is already in use.
+    fn create_session(&self, session: &Session) -> StorageResult<bool>;
+
+    fn get_session(&self, token: &str) -> StorageResult<Option<Session>>;
+
+    /// The user's sessions that expire after `now`, newest first.
+    fn list_sessions(&self, user_id: &str, now: &str) -> StorageResult<Vec<Session>>;
+
+    fn delete_session(&self, token: &str) -> StorageResult<()>;
+
+    /// Delete sessions and password reset tokens that expired by `now`, and
+    /// return how many went. New token tables should be purged here too.
+    /// Usage counts of keys that are gone, or older than [`KEY_USAGE_DAYS`],
+    /// go as well but aren't counted.
+    fn purge_expired_tokens(&self, now: &str) -> StorageResult<u64>;
+
+    /// Sign the user out everywhere, except for `keep_token` if given.
+    fn delete_user_sessions(&self, user_id: &str, keep_token: Option<&str>) -> StorageResult<()>;
+
+    // Notes
+    /// The user's note, created empty if they have none outside the trash.
+    fn get_or_create_note(&self, user_id: &str) -> StorageResult<Note>;
+
+    /// One of the user's notes by id, `None` if missing or in the trash.
+    fn get_note(&self, user_id: &str, note_id: &str) -> StorageResult<Option<Note>>;
+
+    /// Save the note's content, recording a revision and re-chunking it.
+    fn update_note(&self, user_id: &str, content: &str) -> StorageResult<Note>;
+
+    /// Save the note's content like [`Storage::update_note`], but only over
+    /// the version saved at `updated_at`. `None`, with nothing saved, when
+    /// another save came in since.
+    fn update_note_if(
+        &self,
+        user_id: &str,
+        content: &str,
+        updated_at: &str,
+    ) -> StorageResult<Option<Note>>;
+
+    /// Turn the note into a journal, for good. Its chunks are unpinned and
+    /// heading numbering is turned off, since nothing may move once written.
+    fn enable_journal(&self, user_id: &str, note_id: &str) -> StorageResult<()>;
+
+    /// Turn heading numbering on or off, renumbering the headings or taking
+    /// their numbers off in a save of its own if that changes the content.
+    fn set_numbered_headings(&self, user_id: &str, numbered: bool) -> StorageResult<Note>;
+
+    /// Add `text` as a new block at the end of the user's note and record it
+    /// as a journal entry, timestamped with the save.
+    fn append_journal_entry(
+        &self,
+        user_id: &str,
+        text: &str,
+    ) -> StorageResult<(Note, JournalEntry)>;
+
+    /// The note's journal entries, oldest first.
+    fn list_journal_entries(
+        &self,
+        user_id: &str,
+        note_id: &str,
+    ) -> StorageResult<Vec<JournalEntry>>;
+
+    /// Move the note to the trash. False if the user has no such note
+    /// outside the trash.
+    fn trash_note(&self, user_id: &str, note_id: &str, now: &str) -> StorageResult<bool>;
+
+    /// The user's trashed notes, most recently deleted first.
+    fn list_trash(&self, user_id: &str) -> StorageResult<Vec<Note>>;
+
+    /// Bring a note back from the trash. The note it replaces goes to the
+    /// trash in its place, or is dropped if empty. `None` if the note isn't
+    /// in the trash.
+    fn restore_note(&self, user_id: &str, note_id: &str, now: &str) -> StorageResult<Option<Note>>;
+
+    /// Delete notes trashed before `before` for good, across all shards, and
+    /// return how many went.
+    fn purge_trash(&self, before: &str) -> StorageResult<u64>;
+
+    /// Thin out every note's history, across all shards: of the revisions
+    /// saved within each `interval_secs` only the latest is kept (0 keeps
+    /// them all), then at most `max_kept` revisions and `max_kept` chunk
+    /// versions per note, the latest (0 keeps them all). Returns how many
+    /// went.
+    fn prune_history(&self, interval_secs: u64, max_kept: u32) -> StorageResult<u64>;
+
+    fn get_chunks(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<Chunk>>;
+
+    /// Pin or unpin a chunk of the note. Pins take effect at the next save,
+    /// which moves pinned chunks to the top. False if there's no such chunk.
+    fn set_chunk_pinned(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        chunk_id: &str,
+        pinned: bool,
+    ) -> StorageResult<bool>;
+
+    /// Versions of the note's chunks, the latest replaced first, at most
+    /// `limit` of them and, if given, only those listed after the version
+    /// `before`.
+    fn get_chunk_versions(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        before: Option<&str>,
+        limit: u32,
+    ) -> StorageResult<Vec<ChunkVersion>>;
+
+    fn get_chunk_version(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        version_id: &str,
+    ) -> StorageResult<Option<ChunkVersion>>;
+
+    // Revisions
+    fn get_revisions(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<NoteRevision>>;
+
+    fn get_revision(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        revision_id: &str,
+    ) -> StorageResult<Option<NoteRevision>>;
+
+    /// The latest revision saved at or before `at`: the note as it was then.
+    fn revision_at(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        at: &str,
+    ) -> StorageResult<Option<NoteRevision>>;
+
+    /// Latest revision saved strictly before `before` (RFC 3339).
+    fn get_revision_before(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        before: &str,
+    ) -> StorageResult<Option<NoteRevision>>;
+
+    // Goals
+    fn get_note_goal(&self, user_id: &str, note_id: &str) -> StorageResult<NoteGoal>;
+
+    fn set_note_goal(&self, user_id: &str, note_id: &str, goal: &NoteGoal) -> StorageResult<()>;
+
+    // Metadata
+    /// The note's metadata as a JSON object string, if any was set.
+    fn get_note_meta(&self, user_id: &str, note_id: &str) -> StorageResult<Option<String>>;
+
+    fn set_note_meta(&self, user_id: &str, note_id: &str, data: &str) -> StorageResult<()>;
+
+    // Focus sessions
+    fn get_active_focus(&self, user_id: &str) -> StorageResult<Option<FocusSession>>;
+
+    /// Start a session on the note, or `None` if one is already running.
+    fn start_focus(&self, user_id: &str, note_id: &str) -> StorageResult<Option<FocusSession>>;
+
+    /// End the running session, if any, and return it with its duration.
+    fn stop_focus(&self, user_id: &str) -> StorageResult<Option<FocusSession>>;
+
+    /// Completed focus time per note, counting sessions started at or after `since`.
+    fn focus_totals(&self, user_id: &str, since: &str) -> StorageResult<Vec<FocusTotal>>;
+
+    // Search
+    /// Chunks of the note matching `query`, best first, with highlighted snippets.
+    fn search_chunks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        query: &str,
+        limit: u32,
+    ) -> StorageResult<Vec<SearchHit>>;
+
+    // Tags
+    /// Tags used in the note, alphabetically.
+    fn list_tags(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<TagCount>>;
+
+    /// Chunks of the note tagged `tag`, in note order.
+    fn get_tagged_chunks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        tag: &str,
+    ) -> StorageResult<Vec<Chunk>>;
+
+    // Links
+    /// Chunks linking to the heading chunk `chunk_id` with `[[...]]`, in note order.
+    fn get_backlinks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        chunk_id: &str,
+    ) -> StorageResult<Vec<Chunk>>;
+
+    // Cards
+    /// Cards due at or before `now`, the longest overdue first.
+    fn due_cards(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        now: &str,
+        limit: u32,
+    ) -> StorageResult<Vec<Card>>;
+
+    fn get_card(&self, user_id: &str, note_id: &str, card_id: &str) -> StorageResult<Option<Card>>;
+
+    /// Save a card's schedule after a review.
+    fn save_card_review(&self, user_id: &str, card: &Card) -> StorageResult<()>;
+
+    // Attachments
+    fn create_attachment(&self, attachment: &Attachment) -> StorageResult<()>;
+
+    /// The user's attachment `id`, or `None` if it belongs to someone else.
+    fn get_attachment(&self, user_id: &str, id: &str) -> StorageResult<Option<Attachment>>;
+
+    // External ids
+    fn get_external_import(
+        &self,
+        user_id: &str,
+        source: &str,
+        external_id: &str,
+    ) -> StorageResult<Option<ExternalImport>>;
+
+    /// Record an import, replacing the content and time of an earlier one
+    /// with the same source and external id.
+    fn save_external_import(&self, import: &ExternalImport) -> StorageResult<()>;
+
+    // Inbound hooks
+    fn create_inbound_hook(&self, hook: &InboundHook, token_hash: &str) -> StorageResult<()>;
+
+    fn list_inbound_hooks(&self, user_id: &str) -> StorageResult<Vec<InboundHook>>;
+
+    /// Returns whether the user had such a hook.
+    fn delete_inbound_hook(&self, user_id: &str, id: &str) -> StorageResult<bool>;
+
+    /// Look up the hook for a token, recording that it was used now.
+    fn use_inbound_hook(&self, token_hash: &str) -> StorageResult<Option<InboundHook>>;
+
+    // Export tokens
+    fn create_export_token(&self, token: &ExportToken, token_hash: &str) -> StorageResult<()>;
+    /// Oldest first.
+    fn list_export_tokens(&self, user_id: &str) -> StorageResult<Vec<ExportToken>>;
+    /// Returns whether the user had such a token.
+    fn delete_export_token(&self, user_id: &str, id: &str) -> StorageResult<bool>;
+    /// Look up the export token for a hash, recording that it was used now.
+    fn use_export_token(&self, token_hash: &str) -> StorageResult<Option<ExportToken>>;
+
+    // Key usage
+    /// Add counted requests to their keys' daily totals, all in one
+    /// transaction. Keys are a session's [`session_key_id`], or an export
+    /// token's id.
+    fn record_key_uses(&self, uses: &[KeyUse]) -> StorageResult<()>;
+    /// Requests made with `key_id` per day since `since_day`, oldest first.
+    fn get_key_usage(&self, key_id: &str, since_day: &str) -> StorageResult<Vec<KeyUsageDay>>;
+
+    // Share links
+    fn create_share(&self, share: &Share, token_hash: &str) -> StorageResult<()>;
+
+    /// Shares that haven't expired by `now`, oldest first.
+    fn list_shares(&self, user_id: &str, now: &str) -> StorageResult<Vec<Share>>;
+
+    /// Revoke a share. Returns whether the user had such a share.
+    fn delete_share(&self, user_id: &str, id: &str) -> StorageResult<bool>;
+
+    /// Look up the unexpired share for a token, recording that it was
+    /// viewed at `now`.
+    fn use_share(&self, token_hash: &str, now: &str) -> StorageResult<Option<Share>>;
+
+    // Feature flags
+    /// Returns false if the flag was already on.
+    fn enable_user_flag(&self, user_id: &str, flag: &str) -> StorageResult<bool>;
+    /// Returns false if the flag was not on.
+    fn disable_user_flag(&self, user_id: &str, flag: &str) -> StorageResult<bool>;
+    /// Flags turned on for the user, by name.
+    fn list_user_flags(&self, user_id: &str) -> StorageResult<Vec<String>>;
+
+    // Audit log
+    fn record_audit(&self, entry: &AuditEntry) -> StorageResult<()>;
+
+    /// Latest entries first, optionally only those about `target_user_id`.
+    fn audit_entries(
+        &self,
+        target_user_id: Option<&str>,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditEntry>>;
+
+    /// Every entry by or about `user_id`, if given, created from `since` up
+    /// to but not including `until`, oldest first.
+    fn export_audit_entries(
+        &self,
+        user_id: Option<&str>,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> StorageResult<Vec<AuditEntry>>;
+
+    // Announcements
+    fn create_announcement(&self, announcement: &Announcement) -> StorageResult<()>;
+
+    /// Announcements whose window includes `now` (RFC 3339), newest first.
+    fn active_announcements(&self, now: &str) -> StorageResult<Vec<Announcement>>;
+
+    /// Returns whether the announcement existed.
+    fn delete_announcement(&self, id: &str) -> StorageResult<bool>;
+}
+
+impl dyn Storage {
+    /// Run blocking storage work on tokio's blocking thread pool, so async
+    /// callers don't stall the runtime while the backend does I/O or waits
+    /// on locks.
+    ///
+    /// `f` runs once. Busy errors are retried call by call, by
+    /// [`metrics::Metered`], never by running `f` again: the calls before
+    /// the busy one may already have been committed.
+    pub async fn run<T, F>(self: &Arc<Self>, f: F) -> StorageResult<T>
+    where
+        F: FnOnce(&dyn Storage) -> StorageResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        match tokio::task::spawn_blocking(move || f(&*db)).await {
+            Ok(result) => result,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+/// Make one storage call, trying it again a few times with jittered backoff
+/// while the backend reports it is busy. Each call is one statement or
+/// transaction, so a busy failure leaves nothing half done to repeat.
+fn retry_busy<T>(mut call: impl FnMut() -> StorageResult<T>) -> StorageResult<T> {
+    let mut attempt = 0;
+    loop {
+        match call() {
+            Err(err) if err.is_busy() && attempt < BUSY_RETRIES => {
+                attempt += 1;
+                let base = BUSY_BACKOFF_MS << attempt;
+                let jitter = rand::random::<u64>() % base;
+                std::thread::sleep(Duration::from_millis(base + jitter));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn storage() -> Arc<dyn Storage> {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        Arc::new(db)
+    }
+
+    #[tokio::test]
+    async fn test_run_on_blocking_pool() {
+        let db = storage();
+
+        db.run(|db| db.create_user("user1", "test@example.com", "hash"))
+            .await
+            .unwrap();
+        let user = db
+            .run(|db| db.get_user_by_email("test@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(user.unwrap().id, "user1");
+    }
+
+    #[test]
+    fn test_retry_busy_errors() {
+        let busy = || StorageError::Busy("database is locked".to_string());
+
+        let attempts = AtomicU32::new(0);
+        let value = retry_busy(|| match attempts.fetch_add(1, Ordering::SeqCst) {
+            0 => Err(busy()),
+            n => Ok(n),
+        })
+        .unwrap();
+        assert_eq!(value, 1);
+
+        // Gives up after a bounded number of attempts
+        let attempts = AtomicU32::new(0);
+        let err = retry_busy(|| -> StorageResult<()> {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(busy())
+        })
+        .unwrap_err();
+        assert!(err.is_busy());
+        assert_eq!(attempts.load(Ordering::SeqCst), BUSY_RETRIES + 1);
+
+        // Other errors aren't retried
+        let attempts = AtomicU32::new(0);
+        retry_busy(|| -> StorageResult<()> {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(StorageError::Other("boom".to_string()))
+        })
+        .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_never_reruns_work() {
+        let db = storage();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let seen = attempts.clone();
+        let err = db
+            .run(move |_| -> StorageResult<()> {
+                seen.fetch_add(1, Ordering::SeqCst);
+                Err(StorageError::Busy("database is locked".to_string()))
+            })
+            .await
+            .unwrap_err();
+        assert!(err.is_busy());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
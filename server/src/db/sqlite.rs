@@ -0,0 +1,4373 @@
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::cache::NoteCache;
+use super::cipher::{self, Cipher, CipherError, MasterKey};
+use super::{
+    session_key_id, Announcement, Attachment, AuditEntry, Card, Chunk, ChunkVersion, DbHealth,
+    ExportToken, ExternalImport, FocusSession, FocusTotal, InboundHook, JournalEntry, KeyUsageDay,
+    KeyUse, Note, NoteGoal, NoteRevision, SearchHit, Session, Share, Storage, StorageError,
+    StorageResult, TagCount, User, DEFAULT_POOL_SIZE, KEY_USAGE_DAYS,
+};
+use crate::chunker::{
+    card_id, chunk_and_hash, chunk_id, compute_hash, extract_cards, extract_tags, number_headings,
+    parse_chunks, resolve_links, unnumber_headings, ParsedChunk,
+};
+use crate::events::{Event, EventBus};
+use crate::ids;
+use crate::pool::{Pool, PooledConnection};
+
+mod migrations;
+
+/// The users table lives in the main file, so shards drop that foreign key.
+fn shard_sql(sql: &str) -> String {
+    sql.replace(" REFERENCES users(id)", "")
+}
+
+/// Note text columns sealed under a master key: table, the column holding
+/// the note id, and the text column. Chunk tags aren't stored at all under
+/// a key, and content hashes and attachment data are sealed on their own.
+const SEALED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("notes", "id", "content"),
+    ("notes", "id", "title"),
+    ("chunks", "note_id", "content"),
+    ("chunk_versions", "note_id", "content"),
+    ("note_revisions", "note_id", "content"),
+    ("external_ids", "note_id", "content"),
+    ("cards", "note_id", "question"),
+    ("cards", "note_id", "answer"),
+    ("links", "note_id", "target"),
+    ("note_meta", "note_id", "data"),
+    ("attachments", "note_id", "filename"),
+];
+
+/// Tables whose `content_hash` is keyed under a master key.
+const HASHED_TABLES: &[&str] = &["chunks", "chunk_versions"];
+
+/// Handle to the database. Cloning is cheap and shares the same connections.
+#[derive(Clone)]
+pub struct Database {
+    pool: Arc<Pool>,
+    shards: Option<Arc<Shards>>,
+    events: Arc<EventBus>,
+    note_cache: Option<Arc<NoteCache>>,
+    cipher: Cipher,
+}
+
+/// Per-user database files, opened lazily and kept in a small LRU.
+struct Shards {
+    dir: PathBuf,
+    capacity: usize,
+    pool_size: usize,
+    // Most recently used last
+    open: Mutex<Vec<(String, Arc<Pool>)>>,
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Self, rusqlite::Error> {
+        Self::open_pooled(path, DEFAULT_POOL_SIZE)
+    }
+
+    /// Open `path` with up to `pool_size` concurrent connections.
+    pub fn open_pooled(path: &str, pool_size: usize) -> Result<Self, rusqlite::Error> {
+        let pool = Pool::new(path, pool_size);
+        // Fail early on an unusable path
+        drop(pool.get()?);
+        Ok(Self {
+            pool,
+            shards: None,
+            events: Arc::new(EventBus::default()),
+            note_cache: None,
+            cipher: Cipher::default(),
+        })
+    }
+
+    /// Keep each user's notes in `<shard_dir>/<user_id>.db`, with at most
+    /// `capacity` shards open at once, or more while they're all in use.
+    pub fn with_shards(
+        mut self,
+        shard_dir: &str,
+        capacity: usize,
+    ) -> Result<Self, rusqlite::Error> {
+        std::fs::create_dir_all(shard_dir)
+            .map_err(|_| rusqlite::Error::InvalidPath(PathBuf::from(shard_dir)))?;
+        self.shards = Some(Arc::new(Shards {
+            dir: PathBuf::from(shard_dir),
+            capacity: capacity.max(1),
+            pool_size: self.pool.size(),
+            open: Mutex::new(Vec::new()),
+        }));
+        Ok(self)
+    }
+
+    /// Keep the notes and chunk lists of the `capacity` most recently read
+    /// users in memory. Writes drop a user's entry through [`Event::NoteChanged`].
+    pub fn with_note_cache(mut self, capacity: usize) -> Self {
+        let cache = Arc::new(NoteCache::new(capacity));
+        let subscriber = cache.clone();
+        self.events.subscribe(move |event| match event {
+            Event::NoteChanged { user_id } => subscriber.invalidate(user_id),
+        });
+        self.note_cache = Some(cache);
+        self
+    }
+
+    /// Encrypt note text under `master`; see [`super::cipher`].
+    pub fn with_master_key(mut self, master: &MasterKey) -> Self {
+        self.cipher = Cipher::new(master);
+        self
+    }
+
+    /// Bus on which writes announce what they changed.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    fn note_changed(&self, user_id: &str) {
+        self.events.publish(&Event::NoteChanged {
+            user_id: user_id.to_string(),
+        });
+    }
+
+    /// Connection holding the notes of `user_id`.
+    fn note_conn(&self, user_id: &str) -> Result<PooledConnection, rusqlite::Error> {
+        match &self.shards {
+            None => self.pool.get(),
+            Some(shards) => shards.get(user_id)?.get(),
+        }
+    }
+
+    /// Path of the shard file for `user_id`, if sharding is enabled.
+    pub fn shard_path(&self, user_id: &str) -> Option<PathBuf> {
+        self.shards.as_ref().map(|s| s.path(user_id))
+    }
+
+    /// Migrate every existing shard now instead of on first use. Returns the
+    /// number of shards visited.
+    pub fn migrate_shards(&self) -> Result<usize, rusqlite::Error> {
+        let Some(shards) = &self.shards else {
+            return Ok(0);
+        };
+        let entries = std::fs::read_dir(&shards.dir)
+            .map_err(|_| rusqlite::Error::InvalidPath(shards.dir.clone()))?;
+
+        let mut count = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("db") {
+                continue;
+            }
+            if let Some(user_id) = path.file_stem().and_then(|s| s.to_str()) {
+                // Opening a shard migrates it
+                shards.get(user_id)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Seal the note text stored before a master key was set, in the notes
+    /// database or every shard, then vacuum so no plaintext lingers in free
+    /// pages. Returns the number of values sealed; none without a master key.
+    pub fn encrypt_existing(&self) -> Result<u64, rusqlite::Error> {
+        if !self.cipher.is_enabled() {
+            return Ok(0);
+        }
+        self.each_note_db(|conn| self.seal_stored(conn))
+    }
+
+    /// Run `f` on the database holding notes, or on each shard, and add up
+    /// what it returns.
+    fn each_note_db(
+        &self,
+        mut f: impl FnMut(PooledConnection) -> Result<u64, rusqlite::Error>,
+    ) -> Result<u64, rusqlite::Error> {
+        let Some(shards) = &self.shards else {
+            return f(self.pool.get()?);
+        };
+
+        let entries = std::fs::read_dir(&shards.dir)
+            .map_err(|_| rusqlite::Error::InvalidPath(shards.dir.clone()))?;
+        let mut total = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("db") {
+                continue;
+            }
+            if let Some(user_id) = path.file_stem().and_then(|s| s.to_str()) {
+                total += f(self.note_conn(user_id)?)?;
+            }
+        }
+        Ok(total)
+    }
+
+    fn seal_stored(&self, mut conn: PooledConnection) -> Result<u64, rusqlite::Error> {
+        let tx = conn.transaction()?;
+        let mut sealed = 0;
+
+        // Tags go under a key, and ids move to keyed ones
+        sealed += tx.execute("DELETE FROM chunk_tags", [])? as u64;
+        sealed += self.rekey_ids(&tx)?;
+
+        // Hashes first, while the text they were taken from is still in the clear
+        for table in HASHED_TABLES {
+            let rows = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT rowid, note_id, content FROM {table} WHERE instr(content, ?1) != 1"
+                ))?;
+                let rows = stmt.query_map(params![cipher::PREFIX], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            for (rowid, note_id, text) in rows {
+                let hash = self.cipher.content_hash(&note_id, &compute_hash(&text));
+                tx.execute(
+                    &format!("UPDATE {table} SET content_hash = ?1 WHERE rowid = ?2"),
+                    params![hash, rowid],
+                )?;
+            }
+        }
+        let rows = {
+            let mut stmt = tx.prepare(
+                "SELECT rowid, note_id, data FROM attachments WHERE instr(data, ?1) != 1",
+            )?;
+            let rows = stmt.query_map(params![cipher::PREFIX.as_bytes()], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for (rowid, note_id, data) in rows {
+            tx.execute(
+                "UPDATE attachments SET data = ?1 WHERE rowid = ?2",
+                params![self.cipher.seal_data(&note_id, &data), rowid],
+            )?;
+            sealed += 1;
+        }
+
+        for (table, note_column, column) in SEALED_COLUMNS {
+            let rows = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT rowid, {note_column}, {column} FROM {table}
+                     WHERE {column} != '' AND instr({column}, ?1) != 1"
+                ))?;
+                let rows = stmt.query_map(params![cipher::PREFIX], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()?
+            };
+            for (rowid, note_id, text) in rows {
+                tx.execute(
+                    &format!("UPDATE {table} SET {column} = ?1 WHERE rowid = ?2"),
+                    params![self.cipher.seal(&note_id, &text), rowid],
+                )?;
+                sealed += 1;
+            }
+        }
+        if sealed > 0 {
+            // The search index still holds the words of the old text
+            tx.execute("INSERT INTO chunks_fts(chunks_fts) VALUES ('rebuild')", [])?;
+        }
+        tx.commit()?;
+        if sealed > 0 {
+            conn.execute_batch("VACUUM")?;
+        }
+        Ok(sealed)
+    }
+
+    /// Move chunks and cards stored under ids computed from their text to
+    /// keyed ones, along with what refers to them, and return how many
+    /// moved. Ids already keyed are left alone.
+    fn rekey_ids(&self, tx: &rusqlite::Transaction) -> Result<u64, rusqlite::Error> {
+        // Rows point at the old ids until all have moved
+        tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+        let notes = {
+            let mut stmt = tx.prepare("SELECT id, content FROM notes")?;
+            let rows = stmt.query_map([], |row| {
+                let note_id: String = row.get(0)?;
+                let content = sealed_text(&self.cipher, row, 1, &note_id)?;
+                Ok((note_id, content))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut moved = 0;
+        for (note_id, content) in notes {
+            let mut chunk_occurrences: std::collections::HashMap<String, u32> =
+                std::collections::HashMap::new();
+            let mut card_occurrences: std::collections::HashMap<String, u32> =
+                std::collections::HashMap::new();
+            for c in chunk_and_hash(&content) {
+                let occurrence = chunk_occurrences.entry(c.content_hash.clone()).or_insert(0);
+                let old = chunk_id(&note_id, &c.content_hash, *occurrence);
+                let new = self.cipher.chunk_id(&note_id, &c.content_hash, *occurrence);
+                *occurrence += 1;
+                let renamed = tx.execute(
+                    "UPDATE chunks SET id = ?2 WHERE id = ?1 AND note_id = ?3",
+                    params![old, new, note_id],
+                )?;
+                if renamed > 0 {
+                    tx.execute(
+                        "UPDATE links SET source_chunk_id = ?2 WHERE source_chunk_id = ?1",
+                        params![old, new],
+                    )?;
+                    tx.execute(
+                        "UPDATE links SET target_chunk_id = ?2 WHERE target_chunk_id = ?1",
+                        params![old, new],
+                    )?;
+                    tx.execute(
+                        "UPDATE cards SET chunk_id = ?2 WHERE chunk_id = ?1",
+                        params![old, new],
+                    )?;
+                    moved += renamed as u64;
+                }
+
+                for card in extract_cards(&c.chunk) {
+                    let occurrence = card_occurrences.entry(card.question.clone()).or_insert(0);
+                    let old = card_id(&note_id, &card.question, *occurrence);
+                    let new = self.cipher.card_id(&note_id, &card.question, *occurrence);
+                    *occurrence += 1;
+                    moved += tx.execute(
+                        "UPDATE cards SET id = ?2 WHERE id = ?1 AND note_id = ?3",
+                        params![old, new, note_id],
+                    )? as u64;
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// The note's chunks with their tags read from its text, for when the
+    /// text is sealed and tags aren't stored.
+    fn tagged_sealed(
+        &self,
+        user_id: &str,
+        note_id: &str,
+    ) -> StorageResult<Vec<(Chunk, std::collections::BTreeSet<String>)>> {
+        let Some(note) = self.get_note(user_id, note_id)? else {
+            return Ok(Vec::new());
+        };
+        // Chunks are saved with the content, so they line up
+        let parsed = parse_chunks(&note.content);
+        let chunks = self.get_chunks(user_id, note_id)?.into_iter().zip(parsed);
+        Ok(chunks
+            .map(|(chunk, parsed)| (chunk, extract_tags(&parsed).into_iter().collect()))
+            .collect())
+    }
+
+    /// Search by reading the note's chunks, for when their text is sealed
+    /// and the full-text index can't see it: chunks holding every word of
+    /// `query`, in note order.
+    fn search_sealed(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        query: &str,
+        limit: u32,
+    ) -> StorageResult<Vec<SearchHit>> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        let hits = self
+            .get_chunks(user_id, note_id)?
+            .into_iter()
+            .filter(|chunk| {
+                let content = chunk.content.to_lowercase();
+                terms.iter().all(|term| content.contains(term.as_str()))
+            })
+            .take(limit as usize)
+            .map(|chunk| SearchHit {
+                snippet: snippet(&chunk.content, &terms),
+                chunk_id: chunk.id,
+                sequence: chunk.sequence,
+                chunk_type: chunk.chunk_type,
+                start_offset: chunk.start_offset,
+                end_offset: chunk.end_offset,
+                start_byte: chunk.start_byte,
+                end_byte: chunk.end_byte,
+            })
+            .collect();
+        Ok(hits)
+    }
+
+    /// Save `content` as the note's, with a revision and its chunks, on the
+    /// caller's transaction. Pinned chunks move to the top and headings are
+    /// numbered first if the note asks for it.
+    fn write_note(
+        &self,
+        tx: &rusqlite::Transaction,
+        note: &Note,
+        content: &str,
+    ) -> Result<(), rusqlite::Error> {
+        let now = chrono::Utc::now().to_rfc3339();
+
+        let pinned = {
+            let mut stmt = tx.prepare("SELECT id FROM chunks WHERE note_id = ?1 AND pinned = 1")?;
+            let ids = stmt.query_map(params![note.id], |row| row.get::<_, String>(0))?;
+            ids.collect::<Result<std::collections::HashSet<_>, _>>()?
+        };
+        let (reordered, pinned) = pinned_first(&self.cipher, content, &note.id, &pinned);
+        let content = reordered.as_deref().unwrap_or(content);
+        let numbered = match note.numbered_headings {
+            true => number_headings(content),
+            false => None,
+        };
+        let content = numbered.as_deref().unwrap_or(content);
+
+        let sealed = self.cipher.seal(&note.id, content);
+        let title = migrations::note_title(content).map(|title| self.cipher.seal(&note.id, &title));
+        tx.execute(
+            "UPDATE notes SET content = ?1, updated_at = ?2, title = ?3 WHERE id = ?4",
+            params![sealed, now, title, note.id],
+        )?;
+
+        // Record a revision unless the content didn't change since the last one
+        let last: Option<String> = tx
+            .query_row(
+                "SELECT content FROM note_revisions WHERE note_id = ?1 ORDER BY created_at DESC, id DESC LIMIT 1",
+                params![note.id],
+                |row| sealed_text(&self.cipher, row, 0, &note.id),
+            )
+            .optional()?;
+        if last.as_deref() != Some(content) {
+            tx.execute(
+                "INSERT INTO note_revisions (id, note_id, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![ids::new_id(), note.id, sealed, now],
+            )?;
+        }
+
+        // The content, revision and chunks are saved together or not at all
+        self.replace_chunks(tx, &note.id, content, pinned)?;
+        Ok(())
+    }
+
+    /// The note as `write_note` left it, read on the same transaction: once
+    /// it commits, nothing is left to fail that a retry would repeat.
+    fn saved_note(
+        &self,
+        tx: &rusqlite::Transaction,
+        note_id: &str,
+    ) -> Result<Note, rusqlite::Error> {
+        tx.query_row(
+            "SELECT id, user_id, content, created_at, updated_at, deleted_at, title, journal, numbered_headings
+             FROM notes WHERE id = ?1",
+            params![note_id],
+            |row| note_from_row(&self.cipher, row),
+        )
+    }
+
+    // Chunks
+    /// Rechunk the note. Chunk ids come from their content, so a chunk the
+    /// edit didn't touch keeps its row, and only rows that changed are
+    /// written. Runs on the caller's transaction, so the chunks change
+    /// together with the content. The first `pinned` chunks are pinned ones.
+    fn replace_chunks(
+        &self,
+        conn: &rusqlite::Connection,
+        note_id: &str,
+        content: &str,
+        pinned: usize,
+    ) -> Result<Vec<Chunk>, rusqlite::Error> {
+        let new_chunks = chunk_and_hash(content);
+        let now = chrono::Utc::now().to_rfc3339();
+
+        // Get existing chunks by id
+        let mut existing: std::collections::HashMap<String, Chunk> =
+            std::collections::HashMap::new();
+        {
+            let mut stmt = conn.prepare(
+                "SELECT id, note_id, sequence, chunk_type, heading_level, content, content_hash, start_offset, end_offset, created_at, updated_at, pinned, start_byte, end_byte
+                 FROM chunks WHERE note_id = ?1"
+            )?;
+            let mut rows = stmt.query(params![note_id])?;
+            while let Some(row) = rows.next()? {
+                let chunk = Chunk {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    sequence: row.get(2)?,
+                    chunk_type: row.get(3)?,
+                    heading_level: row.get(4)?,
+                    // As stored: it's only copied into chunk_versions
+                    content: row.get(5)?,
+                    content_hash: row.get(6)?,
+                    start_offset: row.get(7)?,
+                    end_offset: row.get(8)?,
+                    created_at: row.get(9)?,
+                    updated_at: row.get(10)?,
+                    pinned: row.get(11)?,
+                    start_byte: row.get(12)?,
+                    end_byte: row.get(13)?,
+                };
+                existing.insert(chunk.id.clone(), chunk);
+            }
+        }
+        let existing_hashes: std::collections::HashMap<&str, &Chunk> = existing
+            .values()
+            .map(|c| (c.content_hash.as_str(), c))
+            .collect();
+
+        // Saves keep pinned chunks at the top, so this many lead the note
+        let pinned_before = existing.values().filter(|c| c.pinned).count();
+
+        // Hashes as stored, to compare with the existing ones
+        let stored_hashes: Vec<String> = new_chunks
+            .iter()
+            .map(|c| self.cipher.content_hash(note_id, &c.content_hash))
+            .collect();
+
+        // Keep a version of every chunk whose content disappears from the note
+        let new_hashes: std::collections::HashSet<&str> =
+            stored_hashes.iter().map(String::as_str).collect();
+        let mut insert_version = conn.prepare_cached(
+            "INSERT INTO chunk_versions (id, note_id, sequence, chunk_type, heading_level, content, content_hash, created_at, replaced_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for old in existing_hashes.values() {
+            if new_hashes.contains(old.content_hash.as_str()) {
+                continue;
+            }
+            insert_version.execute(params![
+                ids::new_id(),
+                note_id,
+                old.sequence,
+                old.chunk_type,
+                old.heading_level,
+                old.content,
+                old.content_hash,
+                old.updated_at,
+                now,
+            ])?;
+        }
+
+        // Insert new chunks and move kept ones, reusing timestamps for
+        // unchanged content
+        let mut insert_chunk = conn.prepare_cached(
+            "INSERT INTO chunks (id, note_id, sequence, chunk_type, heading_level, content, content_hash, start_offset, end_offset, created_at, updated_at, pinned, start_byte, end_byte)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        )?;
+        let mut update_chunk = conn.prepare_cached(
+            "UPDATE chunks SET sequence = ?2, chunk_type = ?3, heading_level = ?4, start_offset = ?5, end_offset = ?6, pinned = ?7, start_byte = ?8, end_byte = ?9
+             WHERE id = ?1",
+        )?;
+        let mut insert_tag = conn.prepare_cached(
+            "INSERT OR IGNORE INTO chunk_tags (chunk_id, note_id, tag) VALUES (?1, ?2, ?3)",
+        )?;
+        let mut result: Vec<Chunk> = Vec::new();
+        let mut occurrences: std::collections::HashMap<&str, u32> =
+            std::collections::HashMap::new();
+        for (seq, chunk_with_hash) in new_chunks.iter().enumerate() {
+            let occurrence = occurrences
+                .entry(chunk_with_hash.content_hash.as_str())
+                .or_insert(0);
+            let id = self
+                .cipher
+                .chunk_id(note_id, &chunk_with_hash.content_hash, *occurrence);
+            *occurrence += 1;
+            let chunk = &chunk_with_hash.chunk;
+
+            // Check if content existed before (the same row, else by hash)
+            let stored_hash = &stored_hashes[seq];
+            let before = existing
+                .get(&id)
+                .or_else(|| existing_hashes.get(stored_hash.as_str()).copied());
+            let (created_at, updated_at, pinned) = match before {
+                // Content unchanged - preserve original timestamps
+                Some(old) => (old.created_at.clone(), old.updated_at.clone(), seq < pinned),
+                // New or modified content; edits inside the pinned block stay pinned
+                None => (now.clone(), now.clone(), seq < pinned.max(pinned_before)),
+            };
+            let new = Chunk {
+                id,
+                note_id: note_id.to_string(),
+                sequence: seq as i32,
+                chunk_type: chunk.chunk_type.as_str().to_string(),
+                heading_level: chunk.heading_level.map(|l| l as i32),
+                content: chunk.content.clone(),
+                content_hash: chunk_with_hash.content_hash.clone(),
+                start_offset: chunk.start_offset as i32,
+                end_offset: chunk.end_offset as i32,
+                created_at,
+                updated_at,
+                pinned,
+                start_byte: Some(chunk.start_byte as i32),
+                end_byte: Some(chunk.end_byte as i32),
+            };
+
+            match existing.get(&new.id) {
+                Some(old) if same_placement(old, &new) => {}
+                Some(_) => {
+                    update_chunk.execute(params![
+                        new.id,
+                        new.sequence,
+                        new.chunk_type,
+                        new.heading_level,
+                        new.start_offset,
+                        new.end_offset,
+                        new.pinned,
+                        new.start_byte,
+                        new.end_byte,
+                    ])?;
+                }
+                None => {
+                    insert_chunk.execute(params![
+                        new.id,
+                        note_id,
+                        new.sequence,
+                        new.chunk_type,
+                        new.heading_level,
+                        self.cipher.seal(note_id, &new.content),
+                        stored_hash,
+                        new.start_offset,
+                        new.end_offset,
+                        new.created_at,
+                        new.updated_at,
+                        new.pinned,
+                        new.start_byte,
+                        new.end_byte,
+                    ])?;
+                    // Under a key, tags are read from the chunks instead
+                    if !self.cipher.is_enabled() {
+                        for tag in extract_tags(chunk) {
+                            insert_tag.execute(params![new.id, note_id, tag])?;
+                        }
+                    }
+                }
+            }
+            result.push(new);
+        }
+
+        // Their tags and the links from them go too
+        let kept: std::collections::HashSet<&str> = result.iter().map(|c| c.id.as_str()).collect();
+        let mut delete_chunk = conn.prepare_cached("DELETE FROM chunks WHERE id = ?1")?;
+        for id in existing.keys().filter(|id| !kept.contains(id.as_str())) {
+            delete_chunk.execute(params![id])?;
+        }
+
+        // A new or renamed heading can change where any link points, so links
+        // are recorded afresh, once every chunk exists
+        conn.execute("DELETE FROM links WHERE note_id = ?1", params![note_id])?;
+        let parsed: Vec<ParsedChunk> = new_chunks.iter().map(|c| c.chunk.clone()).collect();
+        let mut insert_link = conn.prepare_cached(
+            "INSERT INTO links (source_chunk_id, note_id, target, target_chunk_id) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for link in resolve_links(&parsed) {
+            insert_link.execute(params![
+                result[link.source].id,
+                note_id,
+                self.cipher.seal(note_id, &link.target),
+                link.heading.map(|h| result[h].id.as_str()),
+            ])?;
+        }
+
+        self.replace_cards(conn, note_id, &result, &parsed, &now)?;
+        Ok(result)
+    }
+
+    /// Bring the note's flashcards in line with its chunks. A card's id comes
+    /// from its question, so a card keeps its schedule through edits to its
+    /// answer and moves; a changed question starts a new card.
+    fn replace_cards(
+        &self,
+        conn: &rusqlite::Connection,
+        note_id: &str,
+        chunks: &[Chunk],
+        parsed: &[ParsedChunk],
+        now: &str,
+    ) -> Result<(), rusqlite::Error> {
+        let mut existing: std::collections::HashMap<String, (String, String)> =
+            std::collections::HashMap::new();
+        {
+            let mut stmt =
+                conn.prepare_cached("SELECT id, chunk_id, answer FROM cards WHERE note_id = ?1")?;
+            let mut rows = stmt.query(params![note_id])?;
+            while let Some(row) = rows.next()? {
+                let answer = sealed_text(&self.cipher, row, 2, note_id)?;
+                existing.insert(row.get(0)?, (row.get(1)?, answer));
+            }
+        }
+
+        let mut insert_card = conn.prepare_cached(
+            "INSERT INTO cards (id, note_id, chunk_id, question, answer, due_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+        )?;
+        let mut update_card =
+            conn.prepare_cached("UPDATE cards SET chunk_id = ?2, answer = ?3 WHERE id = ?1")?;
+        let mut kept = std::collections::HashSet::new();
+        let mut occurrences: std::collections::HashMap<String, u32> =
+            std::collections::HashMap::new();
+        for (chunk, parsed) in chunks.iter().zip(parsed) {
+            for card in extract_cards(parsed) {
+                let occurrence = occurrences.entry(card.question.clone()).or_insert(0);
+                let id = self.cipher.card_id(note_id, &card.question, *occurrence);
+                *occurrence += 1;
+                match existing.get(&id) {
+                    None => {
+                        insert_card.execute(params![
+                            id,
+                            note_id,
+                            chunk.id,
+                            self.cipher.seal(note_id, &card.question),
+                            self.cipher.seal(note_id, &card.answer),
+                            now,
+                        ])?;
+                    }
+                    Some((chunk_id, answer)) if *chunk_id != chunk.id || *answer != card.answer => {
+                        update_card.execute(params![
+                            id,
+                            chunk.id,
+                            self.cipher.seal(note_id, &card.answer),
+                        ])?;
+                    }
+                    Some(_) => {}
+                }
+                kept.insert(id);
+            }
+        }
+
+        let mut delete_card = conn.prepare_cached("DELETE FROM cards WHERE id = ?1")?;
+        for id in existing.keys().filter(|id| !kept.contains(*id)) {
+            delete_card.execute(params![id])?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a kept chunk's row already says where it is in the note.
+fn same_placement(old: &Chunk, new: &Chunk) -> bool {
+    old.sequence == new.sequence
+        && old.chunk_type == new.chunk_type
+        && old.heading_level == new.heading_level
+        && old.start_offset == new.start_offset
+        && old.end_offset == new.end_offset
+        && old.start_byte == new.start_byte
+        && old.end_byte == new.end_byte
+        && old.pinned == new.pinned
+}
+
+impl Storage for Database {
+    fn migrate(&self) -> StorageResult<()> {
+        let mut conn = self.pool.get()?;
+        let same = |sql: &str| sql.to_string();
+
+        migrations::apply(
+            &mut conn,
+            migrations::ACCOUNT,
+            migrations::ACCOUNT_MIGRATIONS,
+            &same,
+        )?;
+        if self.shards.is_none() {
+            migrations::apply(
+                &mut conn,
+                migrations::NOTES,
+                migrations::NOTE_MIGRATIONS,
+                &same,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn run_backfills(&self, batch_size: usize) -> StorageResult<u64> {
+        let mut conn = self.pool.get()?;
+        let mut batches = migrations::run_backfills(
+            &mut conn,
+            migrations::ACCOUNT,
+            migrations::ACCOUNT_MIGRATIONS,
+            batch_size,
+        )?;
+        drop(conn);
+        let run = |mut conn: PooledConnection| {
+            migrations::run_backfills(
+                &mut conn,
+                migrations::NOTES,
+                migrations::NOTE_MIGRATIONS,
+                batch_size,
+            )
+        };
+        let Some(shards) = &self.shards else {
+            return Ok(batches + run(self.pool.get()?)?);
+        };
+
+        let entries = std::fs::read_dir(&shards.dir)
+            .map_err(|_| rusqlite::Error::InvalidPath(shards.dir.clone()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("db") {
+                continue;
+            }
+            if let Some(user_id) = path.file_stem().and_then(|s| s.to_str()) {
+                batches += run(self.note_conn(user_id)?)?;
+            }
+        }
+        Ok(batches)
+    }
+
+    fn health(&self) -> StorageResult<DbHealth> {
+        let conn = self.pool.get()?;
+        let active_sessions = conn.query_row(
+            "SELECT COUNT(*) FROM sessions WHERE expires_at > ?1",
+            params![chrono::Utc::now().to_rfc3339()],
+            |row| row.get(0),
+        )?;
+        // Empty for in-memory databases
+        let file: String = conn.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            [],
+            |row| row.get(0),
+        )?;
+        let wal_bytes = (!file.is_empty())
+            .then(|| std::fs::metadata(format!("{}-wal", file)).map_or(0, |m| m.len()));
+        Ok(DbHealth {
+            wal_bytes,
+            active_sessions,
+            note_cache: self.note_cache.as_ref().map(|cache| cache.stats()),
+        })
+    }
+
+    /// Fold the WAL back into the main file(s) and truncate it. Called on shutdown.
+    fn checkpoint(&self) -> StorageResult<()> {
+        let mut pools = vec![self.pool.clone()];
+        if let Some(shards) = &self.shards {
+            pools.extend(shards.open.lock().unwrap().iter().map(|(_, p)| p.clone()));
+        }
+        for pool in pools {
+            let conn = pool.get()?;
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        }
+        Ok(())
+    }
+
+    // Users
+    fn create_user(&self, id: &str, email: &str, password_hash: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        let now = chrono::Utc::now().to_rfc3339();
+
+        conn.execute(
+            "INSERT INTO users (id, email, password_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, email, password_hash, now],
+        )?;
+
+        Ok(())
+    }
+
+    fn get_user_by_email(&self, email: &str) -> StorageResult<Option<User>> {
+        let conn = self.pool.get()?;
+
+        let mut stmt = conn
+            .prepare("SELECT id, email, password_hash, created_at, terms_version FROM users WHERE email = ?1")?;
+        let mut rows = stmt.query(params![email])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(User {
+                id: row.get(0)?,
+                email: row.get(1)?,
+                password_hash: row.get(2)?,
+                created_at: row.get(3)?,
+                terms_version: row.get(4)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_user(&self, id: &str) -> StorageResult<Option<User>> {
+        let conn = self.pool.get()?;
+        conn.query_row(
+            "SELECT id, email, password_hash, created_at, terms_version FROM users WHERE id = ?1",
+            params![id],
+            |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    email: row.get(1)?,
+                    password_hash: row.get(2)?,
+                    created_at: row.get(3)?,
+                    terms_version: row.get(4)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn list_users(&self) -> StorageResult<Vec<User>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, email, password_hash, created_at, terms_version FROM users ORDER BY created_at, id",
+        )?;
+        let users = stmt
+            .query_map([], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    email: row.get(1)?,
+                    password_hash: row.get(2)?,
+                    created_at: row.get(3)?,
+                    terms_version: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    /// Delete a user along with their notes and sessions. Without sharding
+    /// this is a single transaction. With sharding the user's note rows are
+    /// deleted from their shard first, so a failure leaves the account in
+    /// place and the deletion can simply be retried.
+    fn delete_user(&self, user_id: &str) -> StorageResult<()> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        // Chunks, revisions and the rest cascade from the note
+        match &self.shards {
+            None => {
+                tx.execute("DELETE FROM notes WHERE user_id = ?1", params![user_id])?;
+            }
+            Some(_) => {
+                let mut shard = self.note_conn(user_id)?;
+                let shard_tx = shard.transaction()?;
+                shard_tx.execute("DELETE FROM notes WHERE user_id = ?1", params![user_id])?;
+                shard_tx.commit()?;
+            }
+        }
+        tx.execute("DELETE FROM sessions WHERE user_id = ?1", params![user_id])?;
+        tx.execute("DELETE FROM users WHERE id = ?1", params![user_id])?;
+        tx.commit()?;
+        self.note_changed(user_id);
+        Ok(())
+    }
+
+    fn set_password_hash(&self, user_id: &str, password_hash: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+            params![password_hash, user_id],
+        )?;
+        Ok(())
+    }
+
+    fn accept_terms(&self, user_id: &str, version: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE users SET terms_version = ?1, terms_accepted_at = ?2 WHERE id = ?3",
+            params![version, chrono::Utc::now().to_rfc3339(), user_id],
+        )?;
+        Ok(())
+    }
+
+    // Password resets
+    fn create_password_reset(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        expires_at: &str,
+    ) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO password_resets (token_hash, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![token_hash, user_id, chrono::Utc::now().to_rfc3339(), expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn consume_password_reset(&self, token_hash: &str) -> StorageResult<Option<String>> {
+        let conn = self.pool.get()?;
+        let now = chrono::Utc::now().to_rfc3339();
+        conn.query_row(
+            "UPDATE password_resets SET used_at = ?1
+             WHERE token_hash = ?2 AND used_at IS NULL AND expires_at > ?1
+             RETURNING user_id",
+            params![now, token_hash],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Sessions
+    fn create_session(&self, session: &Session) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+
+        let inserted = conn.execute(
+            "INSERT INTO sessions (token, user_id, expires_at, impersonator_id, created_at, user_agent, ip)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (token) DO NOTHING",
+            params![
+                session.token,
+                session.user_id,
+                session.expires_at,
+                session.impersonator_id,
+                session.created_at,
+                session.user_agent,
+                session.ip,
+            ],
+        )?;
+
+        Ok(inserted > 0)
+    }
+
+    fn get_session(&self, token: &str) -> StorageResult<Option<Session>> {
+        let conn = self.pool.get()?;
+        let session = conn
+            .query_row(
+                "SELECT token, user_id, expires_at, impersonator_id, created_at, user_agent, ip
+                 FROM sessions WHERE token = ?1",
+                params![token],
+                session_from_row,
+            )
+            .optional()?;
+        Ok(session)
+    }
+
+    fn list_sessions(&self, user_id: &str, now: &str) -> StorageResult<Vec<Session>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT token, user_id, expires_at, impersonator_id, created_at, user_agent, ip
+             FROM sessions WHERE user_id = ?1 AND expires_at > ?2
+             ORDER BY created_at DESC, expires_at DESC",
+        )?;
+        let sessions = stmt
+            .query_map(params![user_id, now], session_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sessions)
+    }
+
+    fn delete_session(&self, token: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute("DELETE FROM sessions WHERE token = ?1", params![token])?;
+        Ok(())
+    }
+
+    fn purge_expired_tokens(&self, now: &str) -> StorageResult<u64> {
+        let conn = self.pool.get()?;
+        let sessions = conn.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+        let resets = conn.execute(
+            "DELETE FROM password_resets WHERE expires_at <= ?1",
+            params![now],
+        )?;
+        let shares = conn.execute("DELETE FROM shares WHERE expires_at <= ?1", params![now])?;
+        conn.execute(
+            "DELETE FROM key_usage WHERE day <= date(substr(?1, 1, 10), ?2)",
+            params![now, format!("-{} days", KEY_USAGE_DAYS)],
+        )?;
+
+        // Sessions are counted by a hash of their token, which SQL can't take
+        let mut live = std::collections::HashSet::new();
+        let mut stmt = conn.prepare("SELECT token FROM sessions")?;
+        for token in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            live.insert(session_key_id(&token?));
+        }
+        let mut stmt = conn.prepare("SELECT id FROM export_tokens")?;
+        for id in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            live.insert(id?);
+        }
+        let mut stmt = conn.prepare("SELECT DISTINCT key_id FROM key_usage")?;
+        let keys = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in keys.iter().filter(|key| !live.contains(*key)) {
+            conn.execute("DELETE FROM key_usage WHERE key_id = ?1", params![key])?;
+        }
+        Ok((sessions + resets + shares) as u64)
+    }
+
+    fn delete_user_sessions(&self, user_id: &str, keep_token: Option<&str>) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "DELETE FROM sessions WHERE user_id = ?1 AND token IS NOT ?2",
+            params![user_id, keep_token],
+        )?;
+        Ok(())
+    }
+
+    // Notes
+    fn get_or_create_note(&self, user_id: &str) -> StorageResult<Note> {
+        let generation = match &self.note_cache {
+            Some(cache) => match cache.note(user_id) {
+                Some(note) => return Ok(note),
+                None => cache.generation(),
+            },
+            None => 0,
+        };
+        let conn = self.note_conn(user_id)?;
+
+        // Try to get existing note
+        let existing = conn
+            .query_row(
+                "SELECT id, user_id, content, created_at, updated_at, deleted_at, title, journal, numbered_headings
+                 FROM notes WHERE user_id = ?1 AND deleted_at IS NULL LIMIT 1",
+                params![user_id],
+                |row| note_from_row(&self.cipher, row),
+            )
+            .optional()?;
+        if let Some(note) = existing {
+            if let Some(cache) = &self.note_cache {
+                cache.put_note(user_id, &note, generation);
+            }
+            return Ok(note);
+        }
+
+        // Create new note
+        let id = ids::new_id();
+        let now = chrono::Utc::now().to_rfc3339();
+
+        conn.execute(
+            "INSERT INTO notes (id, user_id, content, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, user_id, "", now, now],
+        )?;
+
+        Ok(Note {
+            id,
+            user_id: user_id.to_string(),
+            content: String::new(),
+            created_at: now.clone(),
+            updated_at: now,
+            deleted_at: None,
+            title: None,
+            journal: false,
+            numbered_headings: false,
+        })
+    }
+
+    fn get_note(&self, user_id: &str, note_id: &str) -> StorageResult<Option<Note>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, user_id, content, created_at, updated_at, deleted_at, title, journal, numbered_headings
+             FROM notes WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL",
+            params![note_id, user_id],
+            |row| note_from_row(&self.cipher, row),
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn update_note(&self, user_id: &str, content: &str) -> StorageResult<Note> {
+        // Ensure note exists
+        let note = self.get_or_create_note(user_id)?;
+
+        let mut conn = self.note_conn(user_id)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        self.write_note(&tx, &note, content)?;
+        let saved = self.saved_note(&tx, &note.id)?;
+        tx.commit()?;
+        drop(conn);
+        self.note_changed(user_id);
+        Ok(saved)
+    }
+
+    fn update_note_if(
+        &self,
+        user_id: &str,
+        content: &str,
+        updated_at: &str,
+    ) -> StorageResult<Option<Note>> {
+        let note = self.get_or_create_note(user_id)?;
+
+        // Immediate, so no other save can come in between the check and the write
+        let mut conn = self.note_conn(user_id)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current: String = tx.query_row(
+            "SELECT updated_at FROM notes WHERE id = ?1",
+            params![note.id],
+            |row| row.get(0),
+        )?;
+        if current != updated_at {
+            return Ok(None);
+        }
+        self.write_note(&tx, &note, content)?;
+        let saved = self.saved_note(&tx, &note.id)?;
+        tx.commit()?;
+        drop(conn);
+        self.note_changed(user_id);
+        Ok(Some(saved))
+    }
+
+    fn enable_journal(&self, user_id: &str, note_id: &str) -> StorageResult<()> {
+        let mut conn = self.note_conn(user_id)?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE notes SET journal = 1, numbered_headings = 0 WHERE id = ?1 AND user_id = ?2",
+            params![note_id, user_id],
+        )?;
+        tx.execute(
+            "UPDATE chunks SET pinned = 0 WHERE note_id = ?1",
+            params![note_id],
+        )?;
+        tx.commit()?;
+        drop(conn);
+        self.note_changed(user_id);
+        Ok(())
+    }
+
+    fn set_numbered_headings(&self, user_id: &str, numbered: bool) -> StorageResult<Note> {
+        let note = self.get_or_create_note(user_id)?;
+        {
+            let conn = self.note_conn(user_id)?;
+            conn.execute(
+                "UPDATE notes SET numbered_headings = ?1 WHERE id = ?2",
+                params![numbered, note.id],
+            )?;
+        }
+        self.note_changed(user_id);
+
+        let rewritten = match numbered {
+            true => number_headings(&note.content),
+            false => unnumber_headings(&note.content),
+        };
+        match rewritten {
+            Some(content) => self.update_note(user_id, &content),
+            None => self.get_or_create_note(user_id),
+        }
+    }
+
+    fn append_journal_entry(
+        &self,
+        user_id: &str,
+        text: &str,
+    ) -> StorageResult<(Note, JournalEntry)> {
+        let note = self.get_or_create_note(user_id)?;
+
+        // Immediate, so no other save can come in between the read and the
+        // write, and the entry is recorded with the text or not at all
+        let mut conn = self.note_conn(user_id)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let note = self.saved_note(&tx, &note.id)?;
+        let text = text.trim();
+        let content = match note.content.trim_end() {
+            "" => text.to_string(),
+            current => format!("{}\n\n{}", current, text),
+        };
+        let end_offset = content.chars().count();
+        self.write_note(&tx, &note, &content)?;
+        let note = self.saved_note(&tx, &note.id)?;
+
+        let entry = JournalEntry {
+            id: ids::new_id(),
+            note_id: note.id.clone(),
+            start_offset: (end_offset - text.chars().count()) as i32,
+            end_offset: end_offset as i32,
+            created_at: note.updated_at.clone(),
+        };
+        tx.execute(
+            "INSERT INTO journal_entries (id, note_id, start_offset, end_offset, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                entry.id,
+                entry.note_id,
+                entry.start_offset,
+                entry.end_offset,
+                entry.created_at
+            ],
+        )?;
+        tx.commit()?;
+        drop(conn);
+        self.note_changed(user_id);
+        Ok((note, entry))
+    }
+
+    fn list_journal_entries(
+        &self,
+        user_id: &str,
+        note_id: &str,
+    ) -> StorageResult<Vec<JournalEntry>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT id, note_id, start_offset, end_offset, created_at
+             FROM journal_entries WHERE note_id = ?1 ORDER BY start_offset",
+        )?;
+        let entries = stmt
+            .query_map(params![note_id], |row| {
+                Ok(JournalEntry {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    start_offset: row.get(2)?,
+                    end_offset: row.get(3)?,
+                    created_at: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    fn trash_note(&self, user_id: &str, note_id: &str, now: &str) -> StorageResult<bool> {
+        let conn = self.note_conn(user_id)?;
+        let trashed = conn.execute(
+            "UPDATE notes SET deleted_at = ?1 WHERE id = ?2 AND user_id = ?3 AND deleted_at IS NULL",
+            params![now, note_id, user_id],
+        )?;
+        if trashed > 0 {
+            self.note_changed(user_id);
+        }
+        Ok(trashed > 0)
+    }
+
+    fn list_trash(&self, user_id: &str) -> StorageResult<Vec<Note>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, content, created_at, updated_at, deleted_at, title, journal, numbered_headings
+             FROM notes WHERE user_id = ?1 AND deleted_at IS NOT NULL
+             ORDER BY deleted_at DESC, id DESC",
+        )?;
+        let notes = stmt
+            .query_map(params![user_id], |row| note_from_row(&self.cipher, row))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(notes)
+    }
+
+    fn restore_note(&self, user_id: &str, note_id: &str, now: &str) -> StorageResult<Option<Note>> {
+        let mut conn = self.note_conn(user_id)?;
+        let tx = conn.transaction()?;
+
+        let trashed: Option<String> = tx
+            .query_row(
+                "SELECT id FROM notes WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NOT NULL",
+                params![note_id, user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if trashed.is_none() {
+            return Ok(None);
+        }
+
+        // The user keeps one note: the current one makes way
+        tx.execute(
+            "DELETE FROM notes WHERE user_id = ?1 AND deleted_at IS NULL AND content = ''",
+            params![user_id],
+        )?;
+        tx.execute(
+            "UPDATE notes SET deleted_at = ?1 WHERE user_id = ?2 AND deleted_at IS NULL",
+            params![now, user_id],
+        )?;
+        let restored = tx.query_row(
+            "UPDATE notes SET deleted_at = NULL WHERE id = ?1
+             RETURNING id, user_id, content, created_at, updated_at, deleted_at, title, journal, numbered_headings",
+            params![note_id],
+            |row| note_from_row(&self.cipher, row),
+        )?;
+        tx.commit()?;
+        self.note_changed(user_id);
+        Ok(Some(restored))
+    }
+
+    fn purge_trash(&self, before: &str) -> StorageResult<u64> {
+        // Chunks, revisions and the rest cascade from the note
+        Ok(self.each_note_db(|conn| {
+            let purged = conn.execute(
+                "DELETE FROM notes WHERE deleted_at IS NOT NULL AND deleted_at <= ?1",
+                params![before],
+            )?;
+            Ok(purged as u64)
+        })?)
+    }
+
+    fn prune_history(&self, interval_secs: u64, max_kept: u32) -> StorageResult<u64> {
+        Ok(self.each_note_db(|mut conn| {
+            let tx = conn.transaction()?;
+            let mut pruned = 0;
+
+            if interval_secs > 0 {
+                // Latest first, so the first revision seen in a window is kept
+                let mut stmt = tx.prepare(
+                    "SELECT id, note_id, created_at FROM note_revisions
+                     ORDER BY note_id, created_at DESC, id DESC",
+                )?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?;
+                let mut windows = std::collections::HashSet::new();
+                let mut coalesced = Vec::new();
+                for row in rows {
+                    let (id, note_id, created_at) = row?;
+                    let Ok(at) = chrono::DateTime::parse_from_rfc3339(&created_at) else {
+                        continue;
+                    };
+                    let window = at.timestamp().div_euclid(interval_secs as i64);
+                    if !windows.insert((note_id, window)) {
+                        coalesced.push(id);
+                    }
+                }
+                drop(stmt);
+                for id in coalesced {
+                    pruned +=
+                        tx.execute("DELETE FROM note_revisions WHERE id = ?1", params![id])?;
+                }
+            }
+
+            if max_kept > 0 {
+                pruned += tx.execute(
+                    "DELETE FROM note_revisions WHERE id IN (
+                         SELECT id FROM (
+                             SELECT id, ROW_NUMBER() OVER (
+                                 PARTITION BY note_id ORDER BY created_at DESC, id DESC
+                             ) AS n FROM note_revisions
+                         ) WHERE n > ?1
+                     )",
+                    params![max_kept],
+                )?;
+                pruned += tx.execute(
+                    "DELETE FROM chunk_versions WHERE id IN (
+                         SELECT id FROM (
+                             SELECT id, ROW_NUMBER() OVER (
+                                 PARTITION BY note_id ORDER BY replaced_at DESC, sequence, id
+                             ) AS n FROM chunk_versions
+                         ) WHERE n > ?1
+                     )",
+                    params![max_kept],
+                )?;
+            }
+
+            tx.commit()?;
+            Ok(pruned as u64)
+        })?)
+    }
+
+    fn get_chunks(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<Chunk>> {
+        let generation = match &self.note_cache {
+            Some(cache) => match cache.chunks(user_id, note_id) {
+                Some(chunks) => return Ok(chunks),
+                None => cache.generation(),
+            },
+            None => 0,
+        };
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT id, note_id, sequence, chunk_type, heading_level, content, content_hash, start_offset, end_offset, created_at, updated_at, pinned, start_byte, end_byte
+             FROM chunks WHERE note_id = ?1 ORDER BY sequence"
+        )?;
+        let mut rows = stmt.query(params![note_id])?;
+        let mut chunks = Vec::new();
+        while let Some(row) = rows.next()? {
+            chunks.push(chunk_from_row(&self.cipher, row, note_id)?);
+        }
+        if let Some(cache) = &self.note_cache {
+            cache.put_chunks(user_id, note_id, &chunks, generation);
+        }
+        Ok(chunks)
+    }
+
+    fn set_chunk_pinned(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        chunk_id: &str,
+        pinned: bool,
+    ) -> StorageResult<bool> {
+        let conn = self.note_conn(user_id)?;
+        let updated = conn.execute(
+            "UPDATE chunks SET pinned = ?1 WHERE id = ?2 AND note_id = ?3",
+            params![pinned, chunk_id, note_id],
+        )?;
+        drop(conn);
+        if updated > 0 {
+            self.note_changed(user_id);
+        }
+        Ok(updated > 0)
+    }
+
+    fn get_chunk_versions(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        before: Option<&str>,
+        limit: u32,
+    ) -> StorageResult<Vec<ChunkVersion>> {
+        let conn = self.note_conn(user_id)?;
+        // Listed after `before`: replaced earlier, or in the same save but
+        // further down the note
+        let mut stmt = conn.prepare(
+            "SELECT v.id, v.note_id, v.sequence, v.chunk_type, v.heading_level, v.content, v.content_hash, v.created_at, v.replaced_at
+             FROM chunk_versions v LEFT JOIN chunk_versions b ON b.id = ?2 AND b.note_id = v.note_id
+             WHERE v.note_id = ?1
+               AND (?2 IS NULL OR v.replaced_at < b.replaced_at
+                    OR (v.replaced_at = b.replaced_at AND (v.sequence, v.id) > (b.sequence, b.id)))
+             ORDER BY v.replaced_at DESC, v.sequence, v.id
+             LIMIT ?3"
+        )?;
+        let mut rows = stmt.query(params![note_id, before, limit])?;
+        let mut versions = Vec::new();
+        while let Some(row) = rows.next()? {
+            versions.push(chunk_version_from_row(&self.cipher, row, note_id)?);
+        }
+        Ok(versions)
+    }
+
+    fn get_chunk_version(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        version_id: &str,
+    ) -> StorageResult<Option<ChunkVersion>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, note_id, sequence, chunk_type, heading_level, content, content_hash, created_at, replaced_at
+             FROM chunk_versions WHERE id = ?1 AND note_id = ?2",
+            params![version_id, note_id],
+            |row| chunk_version_from_row(&self.cipher, row, note_id),
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Revisions
+    fn get_revisions(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<NoteRevision>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT id, note_id, content, created_at FROM note_revisions
+             WHERE note_id = ?1 ORDER BY created_at DESC, id DESC",
+        )?;
+        let mut rows = stmt.query(params![note_id])?;
+        let mut revisions = Vec::new();
+        while let Some(row) = rows.next()? {
+            revisions.push(NoteRevision {
+                id: row.get(0)?,
+                note_id: row.get(1)?,
+                content: sealed_text(&self.cipher, row, 2, note_id)?,
+                created_at: row.get(3)?,
+            });
+        }
+        Ok(revisions)
+    }
+
+    fn get_revision(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        revision_id: &str,
+    ) -> StorageResult<Option<NoteRevision>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, note_id, content, created_at FROM note_revisions WHERE id = ?1 AND note_id = ?2",
+            params![revision_id, note_id],
+            |row| {
+                Ok(NoteRevision {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    content: sealed_text(&self.cipher, row, 2, note_id)?,
+                    created_at: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn revision_at(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        at: &str,
+    ) -> StorageResult<Option<NoteRevision>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, note_id, content, created_at FROM note_revisions
+             WHERE note_id = ?1 AND created_at <= ?2
+             ORDER BY created_at DESC, id DESC LIMIT 1",
+            params![note_id, at],
+            |row| {
+                Ok(NoteRevision {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    content: sealed_text(&self.cipher, row, 2, note_id)?,
+                    created_at: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn get_revision_before(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        before: &str,
+    ) -> StorageResult<Option<NoteRevision>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, note_id, content, created_at FROM note_revisions
+             WHERE note_id = ?1 AND created_at < ?2 ORDER BY created_at DESC, id DESC LIMIT 1",
+            params![note_id, before],
+            |row| {
+                Ok(NoteRevision {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    content: sealed_text(&self.cipher, row, 2, note_id)?,
+                    created_at: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Goals
+    fn get_note_goal(&self, user_id: &str, note_id: &str) -> StorageResult<NoteGoal> {
+        let conn = self.note_conn(user_id)?;
+        let goal = conn
+            .query_row(
+                "SELECT word_goal, daily_word_goal FROM note_goals WHERE note_id = ?1",
+                params![note_id],
+                |row| {
+                    Ok(NoteGoal {
+                        word_goal: row.get(0)?,
+                        daily_word_goal: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(goal.unwrap_or_default())
+    }
+
+    fn set_note_goal(&self, user_id: &str, note_id: &str, goal: &NoteGoal) -> StorageResult<()> {
+        let conn = self.note_conn(user_id)?;
+        let now = chrono::Utc::now().to_rfc3339();
+        conn.execute(
+            "INSERT INTO note_goals (note_id, word_goal, daily_word_goal, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(note_id) DO UPDATE SET word_goal = ?2, daily_word_goal = ?3, updated_at = ?4",
+            params![note_id, goal.word_goal, goal.daily_word_goal, now],
+        )?;
+        Ok(())
+    }
+
+    // Metadata
+    fn get_note_meta(&self, user_id: &str, note_id: &str) -> StorageResult<Option<String>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT data FROM note_meta WHERE note_id = ?1",
+            params![note_id],
+            |row| sealed_text(&self.cipher, row, 0, note_id),
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn set_note_meta(&self, user_id: &str, note_id: &str, data: &str) -> StorageResult<()> {
+        let conn = self.note_conn(user_id)?;
+        let now = chrono::Utc::now().to_rfc3339();
+        conn.execute(
+            "INSERT INTO note_meta (note_id, data, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(note_id) DO UPDATE SET data = ?2, updated_at = ?3",
+            params![note_id, self.cipher.seal(note_id, data), now],
+        )?;
+        Ok(())
+    }
+
+    // Focus sessions
+    fn get_active_focus(&self, user_id: &str) -> StorageResult<Option<FocusSession>> {
+        let conn = self.note_conn(user_id)?;
+        conn.query_row(
+            "SELECT id, note_id, started_at, ended_at, duration_seconds FROM focus_sessions
+             WHERE user_id = ?1 AND ended_at IS NULL",
+            params![user_id],
+            |row| {
+                Ok(FocusSession {
+                    id: row.get(0)?,
+                    note_id: row.get(1)?,
+                    started_at: row.get(2)?,
+                    ended_at: row.get(3)?,
+                    duration_seconds: row.get(4)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    fn start_focus(&self, user_id: &str, note_id: &str) -> StorageResult<Option<FocusSession>> {
+        let conn = self.note_conn(user_id)?;
+        let id = ids::new_id();
+        let now = chrono::Utc::now().to_rfc3339();
+
+        // Ignored if the user has a session running: there's a unique index
+        // on those
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO focus_sessions (id, user_id, note_id, started_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, user_id, note_id, now],
+        )?;
+        if inserted == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(FocusSession {
+            id,
+            note_id: note_id.to_string(),
+            started_at: now,
+            ended_at: None,
+            duration_seconds: None,
+        }))
+    }
+
+    fn stop_focus(&self, user_id: &str) -> StorageResult<Option<FocusSession>> {
+        let Some(mut session) = self.get_active_focus(user_id)? else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now();
+        let duration = chrono::DateTime::parse_from_rfc3339(&session.started_at)
+            .map(|start| {
+                (now - start.with_timezone(&chrono::Utc))
+                    .num_seconds()
+                    .max(0)
+            })
+            .unwrap_or(0);
+
+        let conn = self.note_conn(user_id)?;
+        conn.execute(
+            "UPDATE focus_sessions SET ended_at = ?1, duration_seconds = ?2 WHERE id = ?3",
+            params![now.to_rfc3339(), duration, session.id],
+        )?;
+
+        session.ended_at = Some(now.to_rfc3339());
+        session.duration_seconds = Some(duration);
+        Ok(Some(session))
+    }
+
+    fn focus_totals(&self, user_id: &str, since: &str) -> StorageResult<Vec<FocusTotal>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT note_id, COUNT(*), COALESCE(SUM(duration_seconds), 0) FROM focus_sessions
+             WHERE user_id = ?1 AND ended_at IS NOT NULL AND started_at >= ?2
+             GROUP BY note_id ORDER BY note_id",
+        )?;
+        let mut rows = stmt.query(params![user_id, since])?;
+        let mut totals = Vec::new();
+        while let Some(row) = rows.next()? {
+            totals.push(FocusTotal {
+                note_id: row.get(0)?,
+                sessions: row.get(1)?,
+                total_seconds: row.get(2)?,
+            });
+        }
+        Ok(totals)
+    }
+
+    fn search_chunks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        query: &str,
+        limit: u32,
+    ) -> StorageResult<Vec<SearchHit>> {
+        let match_expr = fts_query(query);
+        if match_expr.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.cipher.is_enabled() {
+            return self.search_sealed(user_id, note_id, query, limit);
+        }
+
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.sequence, c.chunk_type, snippet(chunks_fts, 0, '**', '**', '…', 12), c.start_offset, c.end_offset, c.start_byte, c.end_byte
+             FROM chunks_fts JOIN chunks c ON c.rowid = chunks_fts.rowid
+             WHERE chunks_fts MATCH ?1 AND c.note_id = ?2
+             ORDER BY rank LIMIT ?3"
+        )?;
+        let mut rows = stmt.query(params![match_expr, note_id, limit])?;
+        let mut hits = Vec::new();
+        while let Some(row) = rows.next()? {
+            hits.push(SearchHit {
+                chunk_id: row.get(0)?,
+                sequence: row.get(1)?,
+                chunk_type: row.get(2)?,
+                snippet: row.get(3)?,
+                start_offset: row.get(4)?,
+                end_offset: row.get(5)?,
+                start_byte: row.get(6)?,
+                end_byte: row.get(7)?,
+            });
+        }
+        Ok(hits)
+    }
+
+    // Tags
+    fn list_tags(&self, user_id: &str, note_id: &str) -> StorageResult<Vec<TagCount>> {
+        if self.cipher.is_enabled() {
+            let mut counts: std::collections::BTreeMap<String, i64> = Default::default();
+            for (_, tags) in self.tagged_sealed(user_id, note_id)? {
+                for tag in tags {
+                    *counts.entry(tag).or_default() += 1;
+                }
+            }
+            let tags = counts
+                .into_iter()
+                .map(|(tag, count)| TagCount { tag, count });
+            return Ok(tags.collect());
+        }
+
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT tag, COUNT(*) FROM chunk_tags WHERE note_id = ?1 GROUP BY tag ORDER BY tag",
+        )?;
+        let mut rows = stmt.query(params![note_id])?;
+        let mut tags = Vec::new();
+        while let Some(row) = rows.next()? {
+            tags.push(TagCount {
+                tag: row.get(0)?,
+                count: row.get(1)?,
+            });
+        }
+        Ok(tags)
+    }
+
+    fn get_tagged_chunks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        tag: &str,
+    ) -> StorageResult<Vec<Chunk>> {
+        if self.cipher.is_enabled() {
+            let tagged = self.tagged_sealed(user_id, note_id)?.into_iter();
+            return Ok(tagged
+                .filter(|(_, tags)| tags.contains(tag))
+                .map(|(chunk, _)| chunk)
+                .collect());
+        }
+
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.note_id, c.sequence, c.chunk_type, c.heading_level, c.content, c.content_hash, c.start_offset, c.end_offset, c.created_at, c.updated_at, c.pinned, c.start_byte, c.end_byte
+             FROM chunk_tags t JOIN chunks c ON c.id = t.chunk_id
+             WHERE t.note_id = ?1 AND t.tag = ?2 ORDER BY c.sequence"
+        )?;
+        let mut rows = stmt.query(params![note_id, tag])?;
+        let mut chunks = Vec::new();
+        while let Some(row) = rows.next()? {
+            chunks.push(chunk_from_row(&self.cipher, row, note_id)?);
+        }
+        Ok(chunks)
+    }
+
+    // Links
+    fn get_backlinks(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        chunk_id: &str,
+    ) -> StorageResult<Vec<Chunk>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.note_id, c.sequence, c.chunk_type, c.heading_level, c.content, c.content_hash, c.start_offset, c.end_offset, c.created_at, c.updated_at, c.pinned, c.start_byte, c.end_byte
+             FROM links l JOIN chunks c ON c.id = l.source_chunk_id
+             WHERE l.note_id = ?1 AND l.target_chunk_id = ?2 ORDER BY c.sequence"
+        )?;
+        let mut rows = stmt.query(params![note_id, chunk_id])?;
+        let mut chunks = Vec::new();
+        while let Some(row) = rows.next()? {
+            chunks.push(chunk_from_row(&self.cipher, row, note_id)?);
+        }
+        Ok(chunks)
+    }
+
+    // Cards
+    fn due_cards(
+        &self,
+        user_id: &str,
+        note_id: &str,
+        now: &str,
+        limit: u32,
+    ) -> StorageResult<Vec<Card>> {
+        let conn = self.note_conn(user_id)?;
+        let mut stmt = conn.prepare(
+            "SELECT id, note_id, chunk_id, question, answer, ease, interval_days, repetitions, due_at, last_reviewed_at, created_at
+             FROM cards WHERE note_id = ?1 AND due_at <= ?2 ORDER BY due_at, id LIMIT ?3",
+        )?;
+        let cards = stmt
+            .query_map(params![note_id, now, limit], |row| {
+                card_from_row(&self.cipher, row)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(cards)
+    }
+
+    fn get_card(&self, user_id: &str, note_id: &str, card_id: &str) -> StorageResult<Option<Card>> {
+        let conn = self.note_conn(user_id)?;
+        let card = conn
+            .query_row(
+                "SELECT id, note_id, chunk_id, question, answer, ease, interval_days, repetitions, due_at, last_reviewed_at, created_at
+                 FROM cards WHERE id = ?1 AND note_id = ?2",
+                params![card_id, note_id],
+                |row| card_from_row(&self.cipher, row),
+            )
+            .optional()?;
+        Ok(card)
+    }
+
+    fn save_card_review(&self, user_id: &str, card: &Card) -> StorageResult<()> {
+        let conn = self.note_conn(user_id)?;
+        conn.execute(
+            "UPDATE cards SET ease = ?2, interval_days = ?3, repetitions = ?4, due_at = ?5, last_reviewed_at = ?6
+             WHERE id = ?1",
+            params![
+                card.id,
+                card.ease,
+                card.interval_days,
+                card.repetitions,
+                card.due_at,
+                card.last_reviewed_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Attachments
+    fn create_attachment(&self, attachment: &Attachment) -> StorageResult<()> {
+        let conn = self.note_conn(&attachment.user_id)?;
+        conn.execute(
+            "INSERT INTO attachments (id, user_id, note_id, filename, content_type, size, data, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                attachment.id,
+                attachment.user_id,
+                attachment.note_id,
+                attachment
+                    .filename
+                    .as_ref()
+                    .map(|name| self.cipher.seal(&attachment.note_id, name)),
+                attachment.content_type,
+                attachment.size,
+                self.cipher.seal_data(&attachment.note_id, &attachment.data),
+                attachment.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_attachment(&self, user_id: &str, id: &str) -> StorageResult<Option<Attachment>> {
+        let conn = self.note_conn(user_id)?;
+        let attachment = conn
+            .query_row(
+                "SELECT id, user_id, note_id, filename, content_type, size, data, created_at
+                 FROM attachments WHERE id = ?1 AND user_id = ?2",
+                params![id, user_id],
+                |row| {
+                    let note_id: String = row.get(2)?;
+                    let filename: Option<String> = row.get(3)?;
+                    Ok(Attachment {
+                        id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        filename: filename
+                            .map(|name| self.cipher.open(&note_id, name))
+                            .transpose()
+                            .map_err(|err| unreadable(3, err))?,
+                        content_type: row.get(4)?,
+                        size: row.get(5)?,
+                        data: self
+                            .cipher
+                            .open_data(&note_id, row.get(6)?)
+                            .map_err(|err| unreadable(6, err))?,
+                        created_at: row.get(7)?,
+                        note_id,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(attachment)
+    }
+
+    // External ids
+    fn get_external_import(
+        &self,
+        user_id: &str,
+        source: &str,
+        external_id: &str,
+    ) -> StorageResult<Option<ExternalImport>> {
+        let conn = self.note_conn(user_id)?;
+        let import = conn
+            .query_row(
+                "SELECT id, user_id, note_id, source, external_id, content, imported_at
+                 FROM external_ids WHERE user_id = ?1 AND source = ?2 AND external_id = ?3",
+                params![user_id, source, external_id],
+                |row| {
+                    Ok(ExternalImport {
+                        id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        note_id: row.get(2)?,
+                        source: row.get(3)?,
+                        external_id: row.get(4)?,
+                        content: sealed_text(&self.cipher, row, 5, &row.get::<_, String>(2)?)?,
+                        imported_at: row.get(6)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(import)
+    }
+
+    fn save_external_import(&self, import: &ExternalImport) -> StorageResult<()> {
+        let conn = self.note_conn(&import.user_id)?;
+        conn.execute(
+            "INSERT INTO external_ids (id, user_id, note_id, source, external_id, content, imported_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (user_id, source, external_id)
+             DO UPDATE SET note_id = excluded.note_id, content = excluded.content,
+                           imported_at = excluded.imported_at",
+            params![
+                import.id,
+                import.user_id,
+                import.note_id,
+                import.source,
+                import.external_id,
+                self.cipher.seal(&import.note_id, &import.content),
+                import.imported_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Inbound hooks
+    fn create_inbound_hook(&self, hook: &InboundHook, token_hash: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO inbound_hooks (id, user_id, token_hash, name, action, template, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                hook.id,
+                hook.user_id,
+                token_hash,
+                hook.name,
+                hook.action,
+                hook.template,
+                hook.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_inbound_hooks(&self, user_id: &str) -> StorageResult<Vec<InboundHook>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, name, action, template, created_at, last_used_at
+             FROM inbound_hooks WHERE user_id = ?1 ORDER BY created_at",
+        )?;
+        let hooks = stmt
+            .query_map(params![user_id], inbound_hook_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hooks)
+    }
+
+    fn delete_inbound_hook(&self, user_id: &str, id: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute(
+            "DELETE FROM inbound_hooks WHERE id = ?1 AND user_id = ?2",
+            params![id, user_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn use_inbound_hook(&self, token_hash: &str) -> StorageResult<Option<InboundHook>> {
+        let conn = self.pool.get()?;
+        conn.query_row(
+            "UPDATE inbound_hooks SET last_used_at = ?1 WHERE token_hash = ?2
+             RETURNING id, user_id, name, action, template, created_at, last_used_at",
+            params![chrono::Utc::now().to_rfc3339(), token_hash],
+            inbound_hook_from_row,
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Export tokens
+    fn create_export_token(&self, token: &ExportToken, token_hash: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO export_tokens (id, user_id, token_hash, name, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                token.id,
+                token.user_id,
+                token_hash,
+                token.name,
+                token.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_export_tokens(&self, user_id: &str) -> StorageResult<Vec<ExportToken>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, name, created_at, last_used_at
+             FROM export_tokens WHERE user_id = ?1 ORDER BY created_at",
+        )?;
+        let tokens = stmt
+            .query_map(params![user_id], export_token_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tokens)
+    }
+
+    fn delete_export_token(&self, user_id: &str, id: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute(
+            "DELETE FROM export_tokens WHERE id = ?1 AND user_id = ?2",
+            params![id, user_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn use_export_token(&self, token_hash: &str) -> StorageResult<Option<ExportToken>> {
+        let conn = self.pool.get()?;
+        conn.query_row(
+            "UPDATE export_tokens SET last_used_at = ?1 WHERE token_hash = ?2
+             RETURNING id, user_id, name, created_at, last_used_at",
+            params![chrono::Utc::now().to_rfc3339(), token_hash],
+            export_token_from_row,
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Key usage
+    fn record_key_uses(&self, uses: &[KeyUse]) -> StorageResult<()> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO key_usage (key_id, user_id, day, requests, last_used_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (key_id, day)
+                 DO UPDATE SET requests = requests + excluded.requests,
+                    last_used_at = max(last_used_at, excluded.last_used_at)",
+            )?;
+            for key_use in uses {
+                stmt.execute(params![
+                    key_use.key_id,
+                    key_use.user_id,
+                    key_use.day,
+                    key_use.requests,
+                    key_use.last_used_at,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_key_usage(&self, key_id: &str, since_day: &str) -> StorageResult<Vec<KeyUsageDay>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT day, requests, last_used_at FROM key_usage
+             WHERE key_id = ?1 AND day >= ?2 ORDER BY day",
+        )?;
+        let days = stmt
+            .query_map(params![key_id, since_day], |row| {
+                Ok(KeyUsageDay {
+                    day: row.get(0)?,
+                    requests: row.get(1)?,
+                    last_used_at: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(days)
+    }
+
+    // Share links
+    fn create_share(&self, share: &Share, token_hash: &str) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO shares (id, user_id, note_id, token_hash, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                share.id,
+                share.user_id,
+                share.note_id,
+                token_hash,
+                share.created_at,
+                share.expires_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_shares(&self, user_id: &str, now: &str) -> StorageResult<Vec<Share>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, note_id, created_at, expires_at, last_viewed_at
+             FROM shares WHERE user_id = ?1 AND (expires_at IS NULL OR expires_at > ?2)
+             ORDER BY created_at",
+        )?;
+        let shares = stmt
+            .query_map(params![user_id, now], share_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(shares)
+    }
+
+    fn delete_share(&self, user_id: &str, id: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute(
+            "DELETE FROM shares WHERE id = ?1 AND user_id = ?2",
+            params![id, user_id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn use_share(&self, token_hash: &str, now: &str) -> StorageResult<Option<Share>> {
+        let conn = self.pool.get()?;
+        conn.query_row(
+            "UPDATE shares SET last_viewed_at = ?1
+             WHERE token_hash = ?2 AND (expires_at IS NULL OR expires_at > ?1)
+             RETURNING id, user_id, note_id, created_at, expires_at, last_viewed_at",
+            params![now, token_hash],
+            share_from_row,
+        )
+        .optional()
+        .map_err(StorageError::from)
+    }
+
+    // Feature flags
+    fn enable_user_flag(&self, user_id: &str, flag: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO user_flags (user_id, flag, enabled_at) VALUES (?1, ?2, ?3)",
+            params![user_id, flag, chrono::Utc::now().to_rfc3339()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn disable_user_flag(&self, user_id: &str, flag: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute(
+            "DELETE FROM user_flags WHERE user_id = ?1 AND flag = ?2",
+            params![user_id, flag],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn list_user_flags(&self, user_id: &str) -> StorageResult<Vec<String>> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare("SELECT flag FROM user_flags WHERE user_id = ?1 ORDER BY flag")?;
+        let flags = stmt
+            .query_map(params![user_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(flags)
+    }
+
+    // Audit log
+    fn record_audit(&self, entry: &AuditEntry) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO audit_log (id, actor_id, action, target_user_id, details, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.id,
+                entry.actor_id,
+                entry.action,
+                entry.target_user_id,
+                entry.details,
+                entry.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn audit_entries(
+        &self,
+        target_user_id: Option<&str>,
+        limit: u32,
+    ) -> StorageResult<Vec<AuditEntry>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, actor_id, action, target_user_id, details, created_at FROM audit_log
+             WHERE ?1 IS NULL OR target_user_id = ?1
+             ORDER BY created_at DESC, id DESC LIMIT ?2",
+        )?;
+        let entries = stmt
+            .query_map(params![target_user_id, limit], audit_entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    fn export_audit_entries(
+        &self,
+        user_id: Option<&str>,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> StorageResult<Vec<AuditEntry>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, actor_id, action, target_user_id, details, created_at FROM audit_log
+             WHERE (?1 IS NULL OR actor_id = ?1 OR target_user_id = ?1)
+               AND (?2 IS NULL OR created_at >= ?2)
+               AND (?3 IS NULL OR created_at < ?3)
+             ORDER BY created_at, id",
+        )?;
+        let entries = stmt
+            .query_map(params![user_id, since, until], audit_entry_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    // Announcements
+    fn create_announcement(&self, announcement: &Announcement) -> StorageResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO announcements (id, message, level, starts_at, ends_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                announcement.id,
+                announcement.message,
+                announcement.level,
+                announcement.starts_at,
+                announcement.ends_at,
+                announcement.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn active_announcements(&self, now: &str) -> StorageResult<Vec<Announcement>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, message, level, starts_at, ends_at, created_at FROM announcements
+             WHERE (starts_at IS NULL OR starts_at <= ?1) AND (ends_at IS NULL OR ends_at > ?1)
+             ORDER BY created_at DESC, id DESC",
+        )?;
+        let mut rows = stmt.query(params![now])?;
+        let mut announcements = Vec::new();
+        while let Some(row) = rows.next()? {
+            announcements.push(Announcement {
+                id: row.get(0)?,
+                message: row.get(1)?,
+                level: row.get(2)?,
+                starts_at: row.get(3)?,
+                ends_at: row.get(4)?,
+                created_at: row.get(5)?,
+            });
+        }
+        Ok(announcements)
+    }
+
+    fn delete_announcement(&self, id: &str) -> StorageResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute("DELETE FROM announcements WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+}
+
+fn note_from_row(cipher: &Cipher, row: &rusqlite::Row) -> Result<Note, rusqlite::Error> {
+    let id: String = row.get(0)?;
+    let title: Option<String> = row.get(6)?;
+    Ok(Note {
+        user_id: row.get(1)?,
+        content: sealed_text(cipher, row, 2, &id)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+        deleted_at: row.get(5)?,
+        title: title
+            .map(|title| cipher.open(&id, title))
+            .transpose()
+            .map_err(|err| unreadable(6, err))?,
+        journal: row.get(7)?,
+        numbered_headings: row.get(8)?,
+        id,
+    })
+}
+
+/// Text column `idx` of a row belonging to `note_id`, opened if sealed.
+fn card_from_row(cipher: &Cipher, row: &rusqlite::Row) -> Result<Card, rusqlite::Error> {
+    let note_id: String = row.get(1)?;
+    Ok(Card {
+        id: row.get(0)?,
+        question: sealed_text(cipher, row, 3, &note_id)?,
+        answer: sealed_text(cipher, row, 4, &note_id)?,
+        note_id,
+        chunk_id: row.get(2)?,
+        ease: row.get(5)?,
+        interval_days: row.get(6)?,
+        repetitions: row.get(7)?,
+        due_at: row.get(8)?,
+        last_reviewed_at: row.get(9)?,
+        created_at: row.get(10)?,
+    })
+}
+
+/// A chunk from its columns in table order, from `id` to `end_byte`.
+fn chunk_version_from_row(
+    cipher: &Cipher,
+    row: &rusqlite::Row,
+    note_id: &str,
+) -> Result<ChunkVersion, rusqlite::Error> {
+    let content = sealed_text(cipher, row, 5, note_id)?;
+    Ok(ChunkVersion {
+        id: row.get(0)?,
+        note_id: row.get(1)?,
+        sequence: row.get(2)?,
+        chunk_type: row.get(3)?,
+        heading_level: row.get(4)?,
+        // The stored one is keyed when the text is sealed
+        content_hash: compute_hash(&content),
+        content,
+        created_at: row.get(7)?,
+        replaced_at: row.get(8)?,
+    })
+}
+
+fn chunk_from_row(
+    cipher: &Cipher,
+    row: &rusqlite::Row,
+    note_id: &str,
+) -> Result<Chunk, rusqlite::Error> {
+    let content = sealed_text(cipher, row, 5, note_id)?;
+    Ok(Chunk {
+        id: row.get(0)?,
+        note_id: row.get(1)?,
+        sequence: row.get(2)?,
+        chunk_type: row.get(3)?,
+        heading_level: row.get(4)?,
+        // The stored one is keyed when the text is sealed
+        content_hash: compute_hash(&content),
+        content,
+        start_offset: row.get(7)?,
+        end_offset: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+        pinned: row.get(11)?,
+        start_byte: row.get(12)?,
+        end_byte: row.get(13)?,
+    })
+}
+
+fn sealed_text(
+    cipher: &Cipher,
+    row: &rusqlite::Row,
+    idx: usize,
+    note_id: &str,
+) -> Result<String, rusqlite::Error> {
+    cipher
+        .open(note_id, row.get(idx)?)
+        .map_err(|err| unreadable(idx, err))
+}
+
+fn unreadable(idx: usize, err: CipherError) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(err))
+}
+
+fn session_from_row(row: &rusqlite::Row) -> Result<Session, rusqlite::Error> {
+    Ok(Session {
+        token: row.get(0)?,
+        user_id: row.get(1)?,
+        expires_at: row.get(2)?,
+        impersonator_id: row.get(3)?,
+        created_at: row.get(4)?,
+        user_agent: row.get(5)?,
+        ip: row.get(6)?,
+    })
+}
+
+fn inbound_hook_from_row(row: &rusqlite::Row) -> Result<InboundHook, rusqlite::Error> {
+    Ok(InboundHook {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        action: row.get(3)?,
+        template: row.get(4)?,
+        created_at: row.get(5)?,
+        last_used_at: row.get(6)?,
+    })
+}
+
+fn audit_entry_from_row(row: &rusqlite::Row) -> Result<AuditEntry, rusqlite::Error> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        actor_id: row.get(1)?,
+        action: row.get(2)?,
+        target_user_id: row.get(3)?,
+        details: row.get(4)?,
+        created_at: row.get(5)?,
+    })
+}
+
+fn export_token_from_row(row: &rusqlite::Row) -> Result<ExportToken, rusqlite::Error> {
+    Ok(ExportToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        created_at: row.get(3)?,
+        last_used_at: row.get(4)?,
+    })
+}
+
+fn share_from_row(row: &rusqlite::Row) -> Result<Share, rusqlite::Error> {
+    Ok(Share {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        note_id: row.get(2)?,
+        created_at: row.get(3)?,
+        expires_at: row.get(4)?,
+        last_viewed_at: row.get(5)?,
+    })
+}
+
+/// Up to a dozen words of `content` around the first match of `terms`, with
+/// matches in `**`, as FTS5's `snippet()` would give.
+fn snippet(content: &str, terms: &[String]) -> String {
+    const WORDS: usize = 12;
+    let words: Vec<&str> = content.split_whitespace().collect();
+    let matches = |word: &str| {
+        let word = word.to_lowercase();
+        terms.iter().any(|term| word.contains(term.as_str()))
+    };
+    let first = words.iter().position(|w| matches(w)).unwrap_or(0);
+    let start = first
+        .saturating_sub(WORDS / 2)
+        .min(words.len().saturating_sub(WORDS));
+    let end = (start + WORDS).min(words.len());
+    let shown: Vec<String> = words[start..end]
+        .iter()
+        .map(|w| {
+            if matches(w) {
+                format!("**{}**", w)
+            } else {
+                w.to_string()
+            }
+        })
+        .collect();
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        shown.join(" "),
+        if end < words.len() { "…" } else { "" }
+    )
+}
+
+/// Turn free text into an FTS5 query: every word must match, the last one as a prefix.
+fn fts_query(query: &str) -> String {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+        .collect();
+    match terms.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} {}*", rest.join(" "), last),
+        Some((last, _)) => format!("{}*", last),
+        None => String::new(),
+    }
+}
+
+/// The content with the chunks whose ids are in `pinned` moved to the top,
+/// in their note order, and everything else left as written: `None` when
+/// they already lead the note. Also how many chunks were pinned.
+fn pinned_first(
+    cipher: &Cipher,
+    content: &str,
+    note_id: &str,
+    pinned: &std::collections::HashSet<String>,
+) -> (Option<String>, usize) {
+    let chunks = chunk_and_hash(content);
+    // Ids as the chunks will get them: a repeated chunk is told apart from
+    // its copies by how many came before it
+    let mut occurrences: std::collections::HashMap<&str, u32> = std::collections::HashMap::new();
+    let is_pinned: Vec<bool> = chunks
+        .iter()
+        .map(|c| {
+            let occurrence = occurrences.entry(c.content_hash.as_str()).or_insert(0);
+            let id = cipher.chunk_id(note_id, &c.content_hash, *occurrence);
+            *occurrence += 1;
+            pinned.contains(&id)
+        })
+        .collect();
+    let count = is_pinned.iter().filter(|p| **p).count();
+    let leading = is_pinned.iter().take_while(|p| **p).count();
+    if leading == count {
+        return (None, count);
+    }
+
+    let chars: Vec<char> = content.chars().collect();
+    let mut top = Vec::new();
+    let mut rest = String::new();
+    let mut cursor = 0;
+    for (i, chunk) in chunks.iter().enumerate().filter(|(i, _)| is_pinned[*i]) {
+        let (start, end) = (chunk.chunk.start_offset, chunk.chunk.end_offset);
+        rest.extend(&chars[cursor..start]);
+        top.push(String::from_iter(&chars[start..end]).trim_end().to_string());
+        // The blank lines after a moved chunk go with it
+        cursor = chunks
+            .get(i + 1)
+            .map_or(chars.len(), |next| next.chunk.start_offset);
+    }
+    rest.extend(&chars[cursor..]);
+    let ending = &content[content.trim_end().len()..];
+    (
+        Some(format!("{}\n\n{}{}", top.join("\n\n"), rest.trim(), ending)),
+        count,
+    )
+}
+
+impl Shards {
+    fn path(&self, user_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.db", user_id))
+    }
+
+    fn get(&self, user_id: &str) -> Result<Arc<Pool>, rusqlite::Error> {
+        if user_id.is_empty()
+            || !user_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(rusqlite::Error::InvalidPath(PathBuf::from(user_id)));
+        }
+
+        let mut open = self.open.lock().unwrap();
+        let pool = match open.iter().position(|(id, _)| id == user_id) {
+            Some(pos) => {
+                let entry = open.remove(pos);
+                let pool = entry.1.clone();
+                open.push(entry);
+                pool
+            }
+            None => {
+                let path = self.path(user_id);
+                let pool = Pool::new(&path.to_string_lossy(), self.pool_size);
+                let mut conn = pool.get()?;
+                migrations::apply(
+                    &mut conn,
+                    migrations::NOTES,
+                    migrations::NOTE_MIGRATIONS,
+                    &shard_sql,
+                )?;
+                drop(conn);
+                open.push((user_id.to_string(), pool.clone()));
+                pool
+            }
+        };
+
+        // Only idle shards are closed: a pool still in use stays listed, so
+        // the next caller gets it instead of opening a second one on the same
+        // file. Until enough are idle the cache stays over capacity.
+        while open.len() > self.capacity {
+            match open
+                .iter()
+                .position(|(_, pool)| Arc::strong_count(pool) == 1)
+            {
+                Some(pos) => drop(open.remove(pos)),
+                None => break,
+            }
+        }
+
+        Ok(pool)
+    }
+}
+
+/// Whether `err` means another connection held a lock for longer than the
+/// busy timeout, as opposed to a query or data error.
+fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        if is_busy(&err) {
+            StorageError::Busy(err.to_string())
+        } else {
+            StorageError::Other(format!("{:?}", err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(token: &str, user_id: &str, expires_at: &str) -> Session {
+        Session {
+            token: token.to_string(),
+            user_id: user_id.to_string(),
+            expires_at: expires_at.to_string(),
+            impersonator_id: None,
+            created_at: None,
+            user_agent: None,
+            ip: None,
+        }
+    }
+
+    #[test]
+    fn test_busy_errors_map_to_storage_busy() {
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert!(StorageError::from(busy).is_busy());
+        assert!(!StorageError::from(rusqlite::Error::QueryReturnedNoRows).is_busy());
+    }
+
+    #[test]
+    fn test_user_crud() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        // Create user
+        db.create_user("user1", "test@example.com", "hash123")
+            .unwrap();
+
+        // Get user
+        let user = db.get_user_by_email("test@example.com").unwrap().unwrap();
+        assert_eq!(user.id, "user1");
+        assert_eq!(user.email, "test@example.com");
+
+        // User not found
+        let not_found = db.get_user_by_email("other@example.com").unwrap();
+        assert!(not_found.is_none());
+    }
+
+    #[test]
+    fn test_delete_user() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_user("user2", "other@example.com", "hash")
+            .unwrap();
+        db.create_session(&session("token1", "user1", "2030-01-01T00:00:00Z"))
+            .unwrap();
+        let note = db.update_note("user1", "# Title\n\nsecret words").unwrap();
+        db.update_note("user1", "# Title").unwrap();
+        db.set_note_meta("user1", &note.id, "{}").unwrap();
+        db.update_note("user2", "other words").unwrap();
+
+        db.delete_user("user1").unwrap();
+        assert!(db.get_user("user1").unwrap().is_none());
+        assert!(db.get_session("token1").unwrap().is_none());
+        assert!(db.get_chunks("user1", &note.id).unwrap().is_empty());
+        assert!(db.get_revisions("user1", &note.id).unwrap().is_empty());
+        assert!(db
+            .get_chunk_versions("user1", &note.id, None, 100)
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .search_chunks("user1", &note.id, "secret", 10)
+            .unwrap()
+            .is_empty());
+
+        // Other users are untouched
+        assert!(db.get_user("user2").unwrap().is_some());
+        assert_eq!(
+            db.get_or_create_note("user2").unwrap().content,
+            "other words"
+        );
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_password_reset("fresh", "user1", "2999-01-01T00:00:00+00:00")
+            .unwrap();
+        db.create_password_reset("stale", "user1", "2000-01-01T00:00:00+00:00")
+            .unwrap();
+
+        assert_eq!(
+            db.consume_password_reset("fresh").unwrap().as_deref(),
+            Some("user1")
+        );
+        // Single use
+        assert!(db.consume_password_reset("fresh").unwrap().is_none());
+        assert!(db.consume_password_reset("stale").unwrap().is_none());
+        assert!(db.consume_password_reset("unknown").unwrap().is_none());
+
+        db.set_password_hash("user1", "new-hash").unwrap();
+        assert_eq!(
+            db.get_user("user1").unwrap().unwrap().password_hash,
+            "new-hash"
+        );
+
+        // Resets go with the account
+        db.create_password_reset("again", "user1", "2999-01-01T00:00:00+00:00")
+            .unwrap();
+        db.delete_user("user1").unwrap();
+    }
+
+    #[test]
+    fn test_accept_terms() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        assert_eq!(db.get_user("user1").unwrap().unwrap().terms_version, None);
+
+        db.accept_terms("user1", "2026-05").unwrap();
+        let user = db.get_user_by_email("test@example.com").unwrap().unwrap();
+        assert_eq!(user.terms_version.as_deref(), Some("2026-05"));
+    }
+
+    #[test]
+    fn test_announcements() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        let announcement =
+            |id: &str, starts_at: Option<&str>, ends_at: Option<&str>| Announcement {
+                id: id.to_string(),
+                message: format!("message {}", id),
+                level: "info".to_string(),
+                starts_at: starts_at.map(str::to_string),
+                ends_at: ends_at.map(str::to_string),
+                created_at: format!("2026-01-01T00:00:0{}+00:00", &id[1..]),
+            };
+        db.create_announcement(&announcement("a1", None, None))
+            .unwrap();
+        db.create_announcement(&announcement("a2", Some("2026-02-01T00:00:00+00:00"), None))
+            .unwrap();
+        db.create_announcement(&announcement("a3", None, Some("2026-02-01T00:00:00+00:00")))
+            .unwrap();
+
+        let ids = |now: &str| -> Vec<String> {
+            db.active_announcements(now)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.id)
+                .collect()
+        };
+        assert_eq!(ids("2026-01-15T00:00:00+00:00"), vec!["a3", "a1"]);
+        assert_eq!(ids("2026-03-01T00:00:00+00:00"), vec!["a2", "a1"]);
+
+        assert!(db.delete_announcement("a1").unwrap());
+        assert!(!db.delete_announcement("a1").unwrap());
+        assert_eq!(ids("2026-03-01T00:00:00+00:00"), vec!["a2"]);
+    }
+
+    #[test]
+    fn test_delete_user_sessions() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        for token in ["a", "b", "c"] {
+            db.create_session(&session(token, "user1", "2030-01-01T00:00:00Z"))
+                .unwrap();
+        }
+        db.delete_user_sessions("user1", Some("b")).unwrap();
+        assert!(db.get_session("a").unwrap().is_none());
+        assert!(db.get_session("b").unwrap().is_some());
+        db.delete_user_sessions("user1", None).unwrap();
+        assert!(db.get_session("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_chunk_ids_are_stable() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "Same\n\nOther\n\nSame").unwrap();
+        let before = db.get_chunks("user1", &note.id).unwrap();
+        assert_ne!(before[0].id, before[2].id);
+
+        db.update_note("user1", "New\n\nSame\n\nOther\n\nSame")
+            .unwrap();
+        let after = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(after[1].id, before[0].id);
+        assert_eq!(after[2].id, before[1].id);
+        assert_eq!(after[3].id, before[2].id);
+    }
+
+    #[test]
+    fn test_chunk_byte_offsets() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db.update_note("user1", "cafe\n\nrest").unwrap();
+        let bytes = |db: &Database| -> Vec<(Option<i32>, Option<i32>)> {
+            db.get_chunks("user1", &note.id)
+                .unwrap()
+                .iter()
+                .map(|c| (c.start_byte, c.end_byte))
+                .collect()
+        };
+        assert_eq!(bytes(&db), [(Some(0), Some(5)), (Some(6), Some(10))]);
+
+        // Same chars, more bytes: the kept chunk moves in bytes only
+        let note = db.update_note("user1", "café\n\nrest").unwrap();
+        assert_eq!(bytes(&db), [(Some(0), Some(6)), (Some(7), Some(11))]);
+        let rest = &db.get_chunks("user1", &note.id).unwrap()[1];
+        let (start, end) = (rest.start_byte.unwrap(), rest.end_byte.unwrap());
+        assert_eq!(&note.content[start as usize..end as usize], "rest");
+    }
+
+    #[test]
+    fn test_only_changed_chunks_are_written() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db
+            .update_note("user1", "# Title #plans\n\nOne [[Title #plans]]\n\nTwo")
+            .unwrap();
+        db.pool
+            .get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE chunk_writes (kind TEXT NOT NULL);
+                 CREATE TRIGGER log_insert AFTER INSERT ON chunks
+                 BEGIN INSERT INTO chunk_writes VALUES ('insert'); END;
+                 CREATE TRIGGER log_update AFTER UPDATE ON chunks
+                 BEGIN INSERT INTO chunk_writes VALUES ('update'); END;
+                 CREATE TRIGGER log_delete AFTER DELETE ON chunks
+                 BEGIN INSERT INTO chunk_writes VALUES ('delete'); END;",
+            )
+            .unwrap();
+        let writes = || -> Vec<String> {
+            let conn = db.pool.get().unwrap();
+            let kinds = conn
+                .prepare("SELECT kind FROM chunk_writes ORDER BY kind")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            conn.execute("DELETE FROM chunk_writes", []).unwrap();
+            kinds
+        };
+        let before = db.get_chunks("user1", &note.id).unwrap();
+
+        db.update_note("user1", "# Title #plans\n\nOne [[Title #plans]]\n\nTwo!")
+            .unwrap();
+        assert_eq!(writes(), vec!["delete", "insert"]);
+
+        // Chunks after an insertion move, but keep their rows
+        db.update_note(
+            "user1",
+            "# Title #plans\n\nZero\n\nOne [[Title #plans]]\n\nTwo!",
+        )
+        .unwrap();
+        assert_eq!(writes(), vec!["insert", "update", "update"]);
+        let after = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(after[0].id, before[0].id);
+        assert_eq!(after[2].id, before[1].id);
+        assert_eq!(after[2].created_at, before[1].created_at);
+
+        // Tags and links of kept chunks are still there
+        let tagged = db.get_tagged_chunks("user1", &note.id, "plans").unwrap();
+        assert_eq!(tagged.len(), 2);
+        let backlinks = db.get_backlinks("user1", &note.id, &after[0].id).unwrap();
+        assert_eq!(backlinks[0].id, after[2].id);
+
+        db.update_note(
+            "user1",
+            "# Title #plans\n\nZero\n\nOne [[Title #plans]]\n\nTwo!",
+        )
+        .unwrap();
+        assert!(writes().is_empty());
+    }
+
+    #[test]
+    fn test_session_crud() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_session(&Session {
+            created_at: Some("2026-01-01T00:00:00Z".to_string()),
+            user_agent: Some("curl/8.0".to_string()),
+            ip: Some("203.0.113.7".to_string()),
+            ..session("token123", "user1", "2030-01-01T00:00:00Z")
+        })
+        .unwrap();
+
+        let stored = db.get_session("token123").unwrap().unwrap();
+        assert_eq!(stored.user_id, "user1");
+        assert_eq!(stored.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(stored.ip.as_deref(), Some("203.0.113.7"));
+
+        // A token in use is refused, not overwritten
+        db.create_user("user2", "two@example.com", "hash").unwrap();
+        assert!(!db
+            .create_session(&session("token123", "user2", "2030-01-01T00:00:00Z"))
+            .unwrap());
+        assert_eq!(
+            db.get_session("token123").unwrap().unwrap().user_id,
+            "user1"
+        );
+
+        db.delete_session("token123").unwrap();
+        assert!(db.get_session("token123").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_purge_expired_tokens() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_session(&session("old", "user1", "2026-01-01T00:00:00Z"))
+            .unwrap();
+        db.create_session(&session("live", "user1", "2026-03-01T00:00:00Z"))
+            .unwrap();
+        db.create_password_reset("reset-old", "user1", "2026-01-15T00:00:00Z")
+            .unwrap();
+        db.create_password_reset("reset-live", "user1", "2026-03-01T00:00:00Z")
+            .unwrap();
+
+        assert_eq!(db.purge_expired_tokens("2026-02-01T00:00:00Z").unwrap(), 2);
+        assert!(db.get_session("old").unwrap().is_none());
+        assert!(db.get_session("live").unwrap().is_some());
+        assert_eq!(db.purge_expired_tokens("2026-02-01T00:00:00Z").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_shares() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "Shared").unwrap();
+        for (id, expires_at) in [("forever", None), ("brief", Some("2026-02-01T00:00:00Z"))] {
+            let share = Share {
+                id: id.to_string(),
+                user_id: "user1".to_string(),
+                note_id: note.id.clone(),
+                created_at: "2026-01-01T00:00:00Z".to_string(),
+                expires_at: expires_at.map(str::to_string),
+                last_viewed_at: None,
+            };
+            db.create_share(&share, &format!("hash-{}", id)).unwrap();
+        }
+
+        let viewed = db
+            .use_share("hash-brief", "2026-01-15T00:00:00Z")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            viewed.last_viewed_at.as_deref(),
+            Some("2026-01-15T00:00:00Z")
+        );
+        assert_eq!(
+            db.get_note("user1", &viewed.note_id)
+                .unwrap()
+                .unwrap()
+                .content,
+            "Shared"
+        );
+        assert!(db.get_note("user2", &viewed.note_id).unwrap().is_none());
+
+        // Expired shares stop working and are purged with the other tokens
+        assert!(db
+            .use_share("hash-brief", "2026-02-01T00:00:00Z")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            db.list_shares("user1", "2026-02-01T00:00:00Z")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(db.purge_expired_tokens("2026-02-01T00:00:00Z").unwrap(), 1);
+
+        assert!(!db.delete_share("user2", "forever").unwrap());
+        assert!(db.delete_share("user1", "forever").unwrap());
+        assert!(db
+            .use_share("hash-forever", "2026-01-15T00:00:00Z")
+            .unwrap()
+            .is_none());
+
+        // A trashed note can't be viewed
+        db.trash_note("user1", &note.id, "2026-01-01T00:00:00Z")
+            .unwrap();
+        assert!(db.get_note("user1", &note.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_note_crud() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        // Get or create
+        let note = db.get_or_create_note("user1").unwrap();
+        assert_eq!(note.user_id, "user1");
+        assert_eq!(note.content, "");
+
+        // Update note
+        let updated = db.update_note("user1", "Hello world").unwrap();
+        assert_eq!(updated.content, "Hello world");
+
+        // Get again returns same note
+        let same = db.get_or_create_note("user1").unwrap();
+        assert_eq!(same.id, note.id);
+        assert_eq!(same.content, "Hello world");
+    }
+
+    #[test]
+    fn test_trash() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let first = db.update_note("user1", "First").unwrap();
+        assert!(db
+            .trash_note("user1", &first.id, "2026-01-01T00:00:00Z")
+            .unwrap());
+        assert!(!db
+            .trash_note("user1", &first.id, "2026-01-01T00:00:00Z")
+            .unwrap());
+        assert!(!db
+            .trash_note("user2", &first.id, "2026-01-01T00:00:00Z")
+            .unwrap());
+
+        // A fresh note takes its place, and saving it leaves the trash alone
+        let second = db.update_note("user1", "Second").unwrap();
+        assert_ne!(second.id, first.id);
+        let trash = db.list_trash("user1").unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].content, "First");
+        assert_eq!(trash[0].deleted_at.as_deref(), Some("2026-01-01T00:00:00Z"));
+
+        // Restoring swaps the notes
+        let restored = db
+            .restore_note("user1", &first.id, "2026-01-02T00:00:00Z")
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.content, "First");
+        assert_eq!(restored.deleted_at, None);
+        assert_eq!(db.get_or_create_note("user1").unwrap().id, first.id);
+        assert_eq!(db.list_trash("user1").unwrap()[0].id, second.id);
+        assert!(db
+            .restore_note("user1", &first.id, "2026-01-02T00:00:00Z")
+            .unwrap()
+            .is_none());
+
+        // An empty current note is dropped instead
+        db.trash_note("user1", &first.id, "2026-01-03T00:00:00Z")
+            .unwrap();
+        let empty = db.get_or_create_note("user1").unwrap();
+        db.restore_note("user1", &first.id, "2026-01-04T00:00:00Z")
+            .unwrap()
+            .unwrap();
+        let trash = db.list_trash("user1").unwrap();
+        assert!(trash.iter().all(|note| note.id != empty.id));
+        assert_eq!(trash.len(), 1);
+
+        assert_eq!(db.purge_trash("2026-01-01T12:00:00Z").unwrap(), 0);
+        assert_eq!(db.purge_trash("2026-01-02T12:00:00Z").unwrap(), 1);
+        assert!(db.list_trash("user1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_note_cache_follows_writes() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db.update_note("user1", "# One").unwrap();
+        assert_eq!(
+            db.get_chunks("user1", &note.id).unwrap()[0].content,
+            "# One"
+        );
+        assert_eq!(db.get_chunks("user1", &note.id).unwrap().len(), 1);
+
+        db.update_note("user1", "# Two\n\nMore").unwrap();
+        assert_eq!(
+            db.get_or_create_note("user1").unwrap().content,
+            "# Two\n\nMore"
+        );
+        assert_eq!(db.get_chunks("user1", &note.id).unwrap().len(), 2);
+
+        db.trash_note("user1", &note.id, "2026-01-01T00:00:00Z")
+            .unwrap();
+        assert_ne!(db.get_or_create_note("user1").unwrap().id, note.id);
+        db.restore_note("user1", &note.id, "2026-01-02T00:00:00Z")
+            .unwrap();
+        assert_eq!(db.get_or_create_note("user1").unwrap().id, note.id);
+
+        db.delete_user("user1").unwrap();
+        assert!(db.get_chunks("user1", &note.id).unwrap().is_empty());
+
+        let (hits, misses) = db.health().unwrap().note_cache.unwrap();
+        assert!(hits > 0 && misses > 0);
+    }
+
+    #[test]
+    fn test_journal_entries() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db.update_note("user1", "- pinned").unwrap();
+        let chunk = db.get_chunks("user1", &note.id).unwrap()[0].clone();
+        db.set_chunk_pinned("user1", &note.id, &chunk.id, true)
+            .unwrap();
+        db.enable_journal("user1", &note.id).unwrap();
+        assert!(db.get_or_create_note("user1").unwrap().journal);
+        assert!(!db.get_chunks("user1", &note.id).unwrap()[0].pinned);
+
+        let (_, first) = db.append_journal_entry("user1", "  Started é\n").unwrap();
+        let (note, second) = db.append_journal_entry("user1", "Done").unwrap();
+        assert_eq!(note.content, "- pinned\n\nStarted é\n\nDone");
+        assert_eq!((first.start_offset, first.end_offset), (10, 19));
+        assert_eq!((second.start_offset, second.end_offset), (21, 25));
+        assert_eq!(second.created_at, note.updated_at);
+
+        let entries = db.list_journal_entries("user1", &note.id).unwrap();
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, [first.id.as_str(), second.id.as_str()]);
+    }
+
+    #[test]
+    fn test_concurrent_journal_appends_all_land() {
+        let path = std::env::temp_dir().join(format!("trame-journal-{}.db", ulid::Ulid::new()));
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        db.enable_journal("user1", &note.id).unwrap();
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let db = &db;
+                scope.spawn(move || db.append_journal_entry("user1", &format!("Entry {}", i)));
+            }
+        });
+
+        // Every entry points at its own text
+        let note = db.get_or_create_note("user1").unwrap();
+        let chars: Vec<char> = note.content.chars().collect();
+        let entries = db.list_journal_entries("user1", &note.id).unwrap();
+        assert_eq!(entries.len(), 8);
+        let mut texts: Vec<String> = entries
+            .iter()
+            .map(|e| {
+                chars[e.start_offset as usize..e.end_offset as usize]
+                    .iter()
+                    .collect()
+            })
+            .collect();
+        texts.sort();
+        assert_eq!(
+            texts,
+            (0..8).map(|i| format!("Entry {}", i)).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_numbered_headings() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        db.update_note("user1", "# Title\n\n## Intro\n\n### Why")
+            .unwrap();
+        let note = db.set_numbered_headings("user1", true).unwrap();
+        assert!(note.numbered_headings);
+        assert_eq!(note.content, "# Title\n\n## 1. Intro\n\n### 1.1 Why");
+
+        // Saves keep the outline numbered
+        let note = db
+            .update_note("user1", "# Title\n\n## Plan\n\n## 1. Intro\n\n### 1.1 Why")
+            .unwrap();
+        assert_eq!(
+            note.content,
+            "# Title\n\n## 1. Plan\n\n## 2. Intro\n\n### 2.1 Why"
+        );
+        let revisions = db.get_revisions("user1", &note.id).unwrap();
+        assert!(revisions.iter().any(|r| r.content == note.content));
+
+        let note = db.set_numbered_headings("user1", false).unwrap();
+        assert!(!note.numbered_headings);
+        assert_eq!(note.content, "# Title\n\n## Plan\n\n## Intro\n\n### Why");
+        let note = db.update_note("user1", "# Title\n\n## New").unwrap();
+        assert_eq!(note.content, "# Title\n\n## New");
+
+        // A journal keeps its headings as written
+        db.set_numbered_headings("user1", true).unwrap();
+        db.enable_journal("user1", &note.id).unwrap();
+        let (note, _) = db.append_journal_entry("user1", "## Later").unwrap();
+        assert!(!note.numbered_headings);
+        assert_eq!(note.content, "# Title\n\n## 1. New\n\n## Later");
+    }
+
+    #[test]
+    fn test_cards() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db
+            .update_note(
+                "user1",
+                "Q: 2+2?\nA: 4\n\nNo card here\n\nCapital of France #flashcard\nParis",
+            )
+            .unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+        let cards = db.due_cards("user1", &note.id, &now, 10).unwrap();
+        let mut questions: Vec<&str> = cards.iter().map(|c| c.question.as_str()).collect();
+        questions.sort();
+        assert_eq!(questions, ["2+2?", "Capital of France"]);
+
+        // A reviewed card keeps its schedule through edits to its answer
+        let mut card = cards.into_iter().find(|c| c.question == "2+2?").unwrap();
+        card.due_at = "2999-01-01T00:00:00+00:00".to_string();
+        card.repetitions = 1;
+        db.save_card_review("user1", &card).unwrap();
+        db.update_note("user1", "Intro\n\nQ: 2+2?\nA: four")
+            .unwrap();
+        let kept = db.get_card("user1", &note.id, &card.id).unwrap().unwrap();
+        assert_eq!(kept.answer, "four");
+        assert_eq!(kept.repetitions, 1);
+        assert!(db
+            .due_cards("user1", &note.id, &now, 10)
+            .unwrap()
+            .is_empty());
+
+        // Cards go with their question
+        db.update_note("user1", "Q: 3+3?\nA: 6").unwrap();
+        assert!(db.get_card("user1", &note.id, &card.id).unwrap().is_none());
+        let cards = db
+            .due_cards("user1", &note.id, &chrono::Utc::now().to_rfc3339(), 10)
+            .unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].question, "3+3?");
+    }
+
+    #[test]
+    fn test_pinned_chunks_stay_on_top() {
+        let db = Database::open(":memory:").unwrap().with_note_cache(8);
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db
+            .update_note("user1", "- log 1\n\nSummary: quiet")
+            .unwrap();
+        let summary = db.get_chunks("user1", &note.id).unwrap()[1].clone();
+        assert!(!summary.pinned);
+        assert!(db
+            .set_chunk_pinned("user1", &note.id, &summary.id, true)
+            .unwrap());
+        assert!(!db
+            .set_chunk_pinned("user1", &note.id, "missing", true)
+            .unwrap());
+
+        let note = db.update_note("user1", &note.content).unwrap();
+        assert_eq!(note.content, "Summary: quiet\n\n- log 1");
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert!(chunks[0].pinned && !chunks[1].pinned);
+
+        // Appending leaves the summary first, and editing it keeps the pin
+        let note = db
+            .update_note("user1", "Summary: busy\n\n- log 1\n\n- log 2")
+            .unwrap();
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(chunks[0].content, "Summary: busy");
+        assert!(chunks[0].pinned);
+        assert!(chunks[1..].iter().all(|c| !c.pinned));
+
+        // Text written above the pinned block moves below it
+        let note = db
+            .update_note("user1", "Intro\n\nSummary: busy\n\n- log 1\n\n- log 2")
+            .unwrap();
+        assert_eq!(note.content, "Summary: busy\n\nIntro\n\n- log 1\n\n- log 2");
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert!(chunks[0].pinned && !chunks[1].pinned);
+
+        db.set_chunk_pinned("user1", &note.id, &chunks[0].id, false)
+            .unwrap();
+        let note = db.update_note("user1", "Intro\n\nSummary: busy").unwrap();
+        assert_eq!(note.content, "Intro\n\nSummary: busy");
+    }
+
+    #[test]
+    fn test_pinning_a_repeated_chunk_pins_one_copy() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+
+        let note = db.update_note("user1", "Todo\n\nMiddle\n\nTodo").unwrap();
+        let copy = db.get_chunks("user1", &note.id).unwrap()[2].clone();
+        db.set_chunk_pinned("user1", &note.id, &copy.id, true)
+            .unwrap();
+
+        let note = db.update_note("user1", &note.content).unwrap();
+        assert_eq!(note.content, "Todo\n\nTodo\n\nMiddle");
+        let pinned: Vec<bool> = db
+            .get_chunks("user1", &note.id)
+            .unwrap()
+            .iter()
+            .map(|c| c.pinned)
+            .collect();
+        assert_eq!(pinned, [true, false, false]);
+    }
+
+    #[test]
+    fn test_update_note_is_atomic() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "# One\n\nFirst").unwrap();
+        db.pool
+            .get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_chunk BEFORE INSERT ON chunks WHEN NEW.content = 'boom'
+                 BEGIN SELECT RAISE(ABORT, 'chunk insert failed'); END;",
+            )
+            .unwrap();
+
+        // A failure partway through the chunks leaves the note as it was
+        assert!(db.update_note("user1", "# Two\n\nboom").is_err());
+        assert_eq!(
+            db.get_or_create_note("user1").unwrap().content,
+            "# One\n\nFirst"
+        );
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<_>>(),
+            vec!["# One", "First"]
+        );
+        assert_eq!(db.get_revisions("user1", &note.id).unwrap().len(), 1);
+        assert!(db
+            .get_chunk_versions("user1", &note.id, None, 100)
+            .unwrap()
+            .is_empty());
+
+        db.update_note("user1", "# Two\n\nSecond").unwrap();
+        assert_eq!(
+            db.get_chunks("user1", &note.id).unwrap()[1].content,
+            "Second"
+        );
+    }
+
+    #[test]
+    fn test_update_note_if() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let first = db.update_note("user1", "First").unwrap();
+        let second = db
+            .update_note_if("user1", "Second", &first.updated_at)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.content, "Second");
+
+        // Saved since: nothing is written
+        assert!(db
+            .update_note_if("user1", "Third", &first.updated_at)
+            .unwrap()
+            .is_none());
+        let note = db.get_or_create_note("user1").unwrap();
+        assert_eq!(note.content, "Second");
+        assert_eq!(db.get_revisions("user1", &note.id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_chunk_versions() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "# Title\n\nFirst draft").unwrap();
+        assert!(db
+            .get_chunk_versions("user1", &note.id, None, 100)
+            .unwrap()
+            .is_empty());
+
+        // Editing the paragraph keeps the old text, the heading is unchanged
+        db.update_note("user1", "# Title\n\nSecond draft").unwrap();
+        let versions = db.get_chunk_versions("user1", &note.id, None, 100).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].content, "First draft");
+        assert_eq!(versions[0].chunk_type, "paragraph");
+        assert_eq!(versions[0].sequence, 1);
+
+        db.update_note("user1", "# Title\n\nThird draft").unwrap();
+        let versions = db.get_chunk_versions("user1", &note.id, None, 100).unwrap();
+        assert_eq!(versions.len(), 2);
+
+        // A page at a time, the latest first
+        let page = db.get_chunk_versions("user1", &note.id, None, 1).unwrap();
+        assert_eq!(page[0].content, "Second draft");
+        let page = db
+            .get_chunk_versions("user1", &note.id, Some(&page[0].id), 1)
+            .unwrap();
+        assert_eq!(page[0].content, "First draft");
+        let page = db
+            .get_chunk_versions("user1", &note.id, Some(&page[0].id), 1)
+            .unwrap();
+        assert!(page.is_empty());
+        let version = db
+            .get_chunk_version("user1", &note.id, &versions[1].id)
+            .unwrap();
+        assert_eq!(version.unwrap().content, "First draft");
+    }
+
+    #[test]
+    fn test_prune_history() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "Start").unwrap();
+        {
+            let conn = db.pool.get().unwrap();
+            conn.execute("DELETE FROM note_revisions", []).unwrap();
+            for (id, at) in [
+                ("r1", "2026-01-01T10:00:00+00:00"),
+                ("r2", "2026-01-01T10:01:00+00:00"),
+                ("r3", "2026-01-01T10:04:59+00:00"),
+                ("r4", "2026-01-01T10:05:00+00:00"),
+                ("r5", "2026-01-01T11:00:00+00:00"),
+            ] {
+                conn.execute(
+                    "INSERT INTO note_revisions (id, note_id, content, created_at) VALUES (?1, ?2, ?1, ?3)",
+                    params![id, note.id, at],
+                )
+                .unwrap();
+            }
+        }
+        for i in 0..4 {
+            db.update_note("user1", &format!("Draft {}", i)).unwrap();
+        }
+        let ids = |db: &Database| -> Vec<String> {
+            let revisions = db.get_revisions("user1", &note.id).unwrap();
+            revisions.into_iter().map(|r| r.id).collect()
+        };
+
+        // One revision per five minutes: r1 and r2 give way to r3
+        assert!(db.prune_history(300, 0).unwrap() >= 2);
+        let kept = ids(&db);
+        assert!(!kept.contains(&"r1".to_string()) && !kept.contains(&"r2".to_string()));
+        assert_eq!(kept[kept.len() - 3..], ["r5", "r4", "r3"]);
+
+        // Then only the latest few
+        db.prune_history(0, 2).unwrap();
+        assert_eq!(ids(&db).len(), 2);
+        let versions = db.get_chunk_versions("user1", &note.id, None, 100).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| v.content.as_str())
+                .collect::<Vec<_>>(),
+            ["Draft 2", "Draft 1"]
+        );
+        assert_eq!(db.prune_history(0, 2).unwrap(), 0);
+    }
+    #[test]
+    fn test_sharded_notes() {
+        let dir = std::env::temp_dir().join(format!("trame-shards-{}", ulid::Ulid::new()));
+        let dir_str = dir.to_str().unwrap();
+        let db = Database::open(":memory:")
+            .unwrap()
+            .with_shards(dir_str, 1)
+            .unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "a@example.com", "hash").unwrap();
+        db.create_user("user2", "b@example.com", "hash").unwrap();
+
+        db.update_note("user1", "Note one").unwrap();
+        // Opening a second shard evicts the first from the cache
+        db.update_note("user2", "Note two").unwrap();
+
+        assert!(db.shard_path("user1").unwrap().exists());
+        assert!(db.shard_path("user2").unwrap().exists());
+        assert_eq!(db.get_or_create_note("user1").unwrap().content, "Note one");
+        assert_eq!(db.get_or_create_note("user2").unwrap().content, "Note two");
+
+        // Ids that would escape the shard directory are rejected
+        assert!(db.get_or_create_note("../user1").is_err());
+
+        // A shard in use isn't evicted, so it never has two pools
+        let shards = db.shards.as_ref().unwrap();
+        let held = shards.get("user1").unwrap();
+        let other = shards.get("user2").unwrap();
+        assert!(Arc::ptr_eq(&held, &shards.get("user1").unwrap()));
+        drop((held, other));
+        shards.get("user2").unwrap();
+        assert_eq!(shards.open.lock().unwrap().len(), 1);
+
+        // Purging the trash visits every shard
+        let note = db.get_or_create_note("user2").unwrap();
+        db.trash_note("user2", &note.id, "2026-01-01T00:00:00Z")
+            .unwrap();
+        assert_eq!(db.purge_trash("2026-02-01T00:00:00Z").unwrap(), 1);
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+    #[test]
+    fn test_chunk_tags() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db
+            .update_note(
+                "user1",
+                "# Trip #travel\n\nBook #Travel and #work\n\n```\n#notatag\n```",
+            )
+            .unwrap();
+
+        let tags: Vec<(String, i64)> = db
+            .list_tags("user1", &note.id)
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.tag, t.count))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![("travel".to_string(), 2), ("work".to_string(), 1)]
+        );
+
+        let chunks = db.get_tagged_chunks("user1", &note.id, "travel").unwrap();
+        assert_eq!(
+            chunks.iter().map(|c| c.sequence).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        // Tags follow the content on the next save
+        db.update_note("user1", "# Trip\n\nBook #work").unwrap();
+        assert!(db
+            .get_tagged_chunks("user1", &note.id, "travel")
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.list_tags("user1", &note.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_backlinks() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db
+            .update_note(
+                "user1",
+                "See [[Plans]]\n\n# Plans\n\nBack to [[plans]] and [[Nowhere]]",
+            )
+            .unwrap();
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+
+        let backlinks = db.get_backlinks("user1", &note.id, &chunks[1].id).unwrap();
+        assert_eq!(
+            backlinks.iter().map(|c| c.sequence).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        // Renaming the heading breaks the links
+        db.update_note("user1", "See [[Plans]]\n\n# Later\n\nBack to [[plans]]")
+            .unwrap();
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert!(db
+            .get_backlinks("user1", &note.id, &chunks[1].id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_attachments() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        db.create_user("user2", "two@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        db.create_attachment(&Attachment {
+            id: "att1".to_string(),
+            user_id: "user1".to_string(),
+            note_id: note.id,
+            filename: Some("dot.png".to_string()),
+            content_type: "image/png".to_string(),
+            size: 3,
+            data: vec![1, 2, 3],
+            created_at: "2026-01-01T00:00:00+00:00".to_string(),
+        })
+        .unwrap();
+
+        let attachment = db.get_attachment("user1", "att1").unwrap().unwrap();
+        assert_eq!(attachment.data, vec![1, 2, 3]);
+        assert_eq!(attachment.content_type, "image/png");
+        assert!(db.get_attachment("user2", "att1").unwrap().is_none());
+
+        // Removed along with the account's notes
+        db.delete_user("user1").unwrap();
+        assert!(db.get_attachment("user1", "att1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_external_imports() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        let import = |id: &str, content: &str| ExternalImport {
+            id: id.to_string(),
+            user_id: "user1".to_string(),
+            note_id: note.id.clone(),
+            source: "notion".to_string(),
+            external_id: "page-1".to_string(),
+            content: content.to_string(),
+            imported_at: "2026-01-01T00:00:00+00:00".to_string(),
+        };
+        assert!(db
+            .get_external_import("user1", "notion", "page-1")
+            .unwrap()
+            .is_none());
+
+        db.save_external_import(&import("ext1", "first")).unwrap();
+        db.save_external_import(&import("ext2", "second")).unwrap();
+        let saved = db
+            .get_external_import("user1", "notion", "page-1")
+            .unwrap()
+            .unwrap();
+        // Updated in place, keeping the original id
+        assert_eq!(saved.id, "ext1");
+        assert_eq!(saved.content, "second");
+        assert!(db
+            .get_external_import("user1", "evernote", "page-1")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_inbound_hooks() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        let hook = InboundHook {
+            id: "hook1".to_string(),
+            user_id: "user1".to_string(),
+            name: "CI".to_string(),
+            action: "append".to_string(),
+            template: "{{title}}".to_string(),
+            created_at: "2026-01-01T00:00:00+00:00".to_string(),
+            last_used_at: None,
+        };
+        db.create_inbound_hook(&hook, "tokenhash").unwrap();
+
+        assert!(db.use_inbound_hook("other").unwrap().is_none());
+        let used = db.use_inbound_hook("tokenhash").unwrap().unwrap();
+        assert_eq!(used.user_id, "user1");
+        assert!(used.last_used_at.is_some());
+        assert_eq!(db.list_inbound_hooks("user1").unwrap().len(), 1);
+
+        assert!(!db.delete_inbound_hook("user2", "hook1").unwrap());
+        assert!(db.delete_inbound_hook("user1", "hook1").unwrap());
+        assert!(db.use_inbound_hook("tokenhash").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_export_tokens() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        let token = ExportToken {
+            id: "export1".to_string(),
+            user_id: "user1".to_string(),
+            name: "Nightly backup".to_string(),
+            created_at: "2026-01-01T00:00:00+00:00".to_string(),
+            last_used_at: None,
+        };
+        db.create_export_token(&token, "tokenhash").unwrap();
+        assert!(db.list_export_tokens("user1").unwrap()[0]
+            .last_used_at
+            .is_none());
+
+        assert!(db.use_export_token("other").unwrap().is_none());
+        let used = db.use_export_token("tokenhash").unwrap().unwrap();
+        assert_eq!(used.user_id, "user1");
+        assert!(used.last_used_at.is_some());
+        assert!(db.list_export_tokens("user1").unwrap()[0]
+            .last_used_at
+            .is_some());
+
+        assert!(!db.delete_export_token("user2", "export1").unwrap());
+        assert!(db.delete_export_token("user1", "export1").unwrap());
+        assert!(db.use_export_token("tokenhash").unwrap().is_none());
+        assert!(db.list_export_tokens("user1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_key_usage() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        db.create_session(&session("live", "user1", "2026-03-01T00:00:00Z"))
+            .unwrap();
+        let live = session_key_id("live");
+        let key_use = |key_id: &str, requests: i64, last_used_at: &str| KeyUse {
+            key_id: key_id.to_string(),
+            user_id: "user1".to_string(),
+            day: last_used_at[..10].to_string(),
+            requests,
+            last_used_at: last_used_at.to_string(),
+        };
+        db.record_key_uses(&[
+            key_use(&live, 1, "2026-01-01T09:00:00+00:00"),
+            key_use(&live, 1, "2026-01-02T17:30:00+00:00"),
+            key_use("gone", 1, "2026-01-02T09:00:00+00:00"),
+        ])
+        .unwrap();
+        // Later flushes add to the day, keeping its latest use
+        db.record_key_uses(&[key_use(&live, 1, "2026-01-02T09:00:00+00:00")])
+            .unwrap();
+
+        let day = |day: &str, requests: i64, last_used_at: &str| KeyUsageDay {
+            day: day.to_string(),
+            requests,
+            last_used_at: last_used_at.to_string(),
+        };
+        assert_eq!(
+            db.get_key_usage(&live, "2026-01-01").unwrap(),
+            vec![
+                day("2026-01-01", 1, "2026-01-01T09:00:00+00:00"),
+                day("2026-01-02", 2, "2026-01-02T17:30:00+00:00"),
+            ]
+        );
+        assert_eq!(db.get_key_usage(&live, "2026-01-02").unwrap().len(), 1);
+
+        // Keys that no longer exist, and days past retention, are purged
+        db.purge_expired_tokens("2026-01-31T12:00:00+00:00")
+            .unwrap();
+        assert!(db.get_key_usage("gone", "2026-01-01").unwrap().is_empty());
+        assert_eq!(
+            db.get_key_usage(&live, "2026-01-01").unwrap(),
+            vec![day("2026-01-02", 2, "2026-01-02T17:30:00+00:00")]
+        );
+    }
+
+    #[test]
+    fn test_list_sessions() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        db.create_user("user2", "two@example.com", "hash").unwrap();
+        let opened = |token: &str, user_id: &str, created_at: &str, expires_at: &str| Session {
+            created_at: Some(created_at.to_string()),
+            ..session(token, user_id, expires_at)
+        };
+        for s in [
+            opened(
+                "first",
+                "user1",
+                "2026-01-01T00:00:00Z",
+                "2030-01-01T00:00:00Z",
+            ),
+            opened(
+                "second",
+                "user1",
+                "2026-02-01T00:00:00Z",
+                "2030-01-01T00:00:00Z",
+            ),
+            opened(
+                "expired",
+                "user1",
+                "2026-03-01T00:00:00Z",
+                "2026-03-02T00:00:00Z",
+            ),
+            opened(
+                "other",
+                "user2",
+                "2026-01-01T00:00:00Z",
+                "2030-01-01T00:00:00Z",
+            ),
+        ] {
+            db.create_session(&s).unwrap();
+        }
+
+        let tokens: Vec<String> = db
+            .list_sessions("user1", "2026-06-01T00:00:00Z")
+            .unwrap()
+            .into_iter()
+            .map(|s| s.token)
+            .collect();
+        assert_eq!(tokens, vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_health() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_session(&session("live", "user1", "2999-01-01T00:00:00+00:00"))
+            .unwrap();
+        db.create_session(&session("old", "user1", "2000-01-01T00:00:00+00:00"))
+            .unwrap();
+
+        let health = db.health().unwrap();
+        assert_eq!(health.active_sessions, 1);
+        assert_eq!(health.wal_bytes, None);
+    }
+
+    #[test]
+    fn test_impersonation_audit() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("admin", "admin@example.com", "hash")
+            .unwrap();
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        db.create_session(&Session {
+            impersonator_id: Some("admin".to_string()),
+            ..session("tok", "user1", "2999-01-01T00:00:00+00:00")
+        })
+        .unwrap();
+        let stored = db.get_session("tok").unwrap().unwrap();
+        assert_eq!(stored.user_id, "user1");
+        assert_eq!(stored.impersonator_id.as_deref(), Some("admin"));
+
+        let entry = |id: &str, target: &str| AuditEntry {
+            id: id.to_string(),
+            actor_id: "admin".to_string(),
+            action: "impersonation.start".to_string(),
+            target_user_id: Some(target.to_string()),
+            details: None,
+            created_at: format!("2026-01-01T00:00:0{}+00:00", &id[1..]),
+        };
+        db.record_audit(&entry("e1", "user1")).unwrap();
+        db.record_audit(&entry("e2", "user2")).unwrap();
+        db.record_audit(&entry("e3", "user1")).unwrap();
+
+        let ids = |target: Option<&str>| -> Vec<String> {
+            db.audit_entries(target, 10)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.id)
+                .collect()
+        };
+        assert_eq!(ids(Some("user1")), vec!["e3", "e1"]);
+        assert_eq!(ids(None), vec!["e3", "e2", "e1"]);
+
+        let exported = |user_id: Option<&str>, since: Option<&str>, until: Option<&str>| {
+            db.export_audit_entries(user_id, since, until)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(exported(None, None, None), vec!["e1", "e2", "e3"]);
+        assert_eq!(exported(Some("user2"), None, None), vec!["e2"]);
+        assert_eq!(exported(Some("admin"), None, None).len(), 3);
+        assert_eq!(
+            exported(
+                None,
+                Some("2026-01-01T00:00:02+00:00"),
+                Some("2026-01-01T00:00:03+00:00")
+            ),
+            vec!["e2"]
+        );
+
+        // The trail outlives the account
+        db.delete_user("user1").unwrap();
+        assert_eq!(ids(Some("user1")).len(), 2);
+    }
+
+    #[test]
+    fn test_user_flags() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "one@example.com", "hash").unwrap();
+        assert!(db.enable_user_flag("user1", "semantic-search").unwrap());
+        assert!(!db.enable_user_flag("user1", "semantic-search").unwrap());
+        assert!(db.enable_user_flag("user1", "crdt-sync").unwrap());
+        assert_eq!(
+            db.list_user_flags("user1").unwrap(),
+            vec!["crdt-sync", "semantic-search"]
+        );
+
+        assert!(db.disable_user_flag("user1", "crdt-sync").unwrap());
+        assert!(!db.disable_user_flag("user1", "crdt-sync").unwrap());
+        assert_eq!(
+            db.list_user_flags("user1").unwrap(),
+            vec!["semantic-search"]
+        );
+
+        db.delete_user("user1").unwrap();
+        assert!(db.list_user_flags("user1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search_chunks() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db
+            .update_note(
+                "user1",
+                "# Groceries\n\nBuy apples and pears\n\nCall the bank",
+            )
+            .unwrap();
+
+        let hits = db.search_chunks("user1", &note.id, "apples", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].sequence, 1);
+        assert!(hits[0].snippet.contains("**apples**"));
+
+        // Prefix match on the last word
+        let hits = db.search_chunks("user1", &note.id, "groc", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].chunk_type, "heading");
+
+        // Replaced chunks drop out of the index
+        db.update_note("user1", "Call the bank").unwrap();
+        assert!(db
+            .search_chunks("user1", &note.id, "apples", 10)
+            .unwrap()
+            .is_empty());
+
+        // FTS syntax in user input is treated as plain text
+        assert!(db
+            .search_chunks("user1", &note.id, "\"bank AND (", 10)
+            .is_ok());
+        assert!(db
+            .search_chunks("user1", &note.id, "   ", 10)
+            .unwrap()
+            .is_empty());
+    }
+    #[test]
+    fn test_encrypted_notes() {
+        let path = std::env::temp_dir().join(format!("trame-enc-{}.db", ulid::Ulid::new()));
+        let path = path.to_str().unwrap();
+        let key = MasterKey::parse(&"ab".repeat(32)).unwrap();
+
+        // Written before encryption was enabled
+        let plain = Database::open(path).unwrap();
+        plain.migrate().unwrap();
+        plain
+            .create_user("user1", "test@example.com", "hash")
+            .unwrap();
+        let old = plain
+            .update_note("user1", "# Old\n\nwritten in the clear")
+            .unwrap();
+        plain
+            .set_note_meta("user1", &old.id, r#"{"mood":"hopeful"}"#)
+            .unwrap();
+        plain
+            .create_attachment(&Attachment {
+                id: "att1".to_string(),
+                user_id: "user1".to_string(),
+                note_id: old.id.clone(),
+                filename: Some("receipt.txt".to_string()),
+                content_type: "text/plain".to_string(),
+                size: 13,
+                data: b"receipt bytes".to_vec(),
+                created_at: "2026-01-01T00:00:00+00:00".to_string(),
+            })
+            .unwrap();
+
+        let db = Database::open(path).unwrap().with_master_key(&key);
+        assert_eq!(
+            db.get_or_create_note("user1").unwrap().content,
+            "# Old\n\nwritten in the clear"
+        );
+        let text = "# Secret\n\nBuy apples and pears\n\nFruit #shopping\n\nSee [[Secret]]\n\nQ: Capital of Mars?\nA: Olympus";
+        let note = db.update_note("user1", text).unwrap();
+        assert_eq!(note.content, text);
+        assert_eq!(note.title.as_deref(), Some("Secret"));
+        assert_eq!(
+            db.get_chunks("user1", &note.id).unwrap()[0].content,
+            "# Secret"
+        );
+        assert_eq!(db.get_revisions("user1", &note.id).unwrap().len(), 2);
+
+        let hits = db.search_chunks("user1", &note.id, "APPL", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "Buy **apples** and pears");
+
+        let stored = |sql: &str| -> Vec<String> {
+            let conn = db.pool.get().unwrap();
+            let mut stmt = conn.prepare(sql).unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+        let sealed = |values: Vec<String>| values.iter().all(|v| cipher::is_sealed(v));
+        assert!(sealed(stored("SELECT content FROM notes")));
+        assert!(sealed(stored("SELECT title FROM notes")));
+        assert!(sealed(stored("SELECT content FROM chunks")));
+        assert!(!sealed(stored("SELECT content FROM note_revisions")));
+
+        // What was written in the clear is sealed when catching up: the
+        // first revision, its chunk versions, the metadata and the attachment
+        assert_eq!(db.encrypt_existing().unwrap(), 6);
+        assert!(sealed(stored("SELECT content FROM note_revisions")));
+        assert!(sealed(stored("SELECT content FROM chunk_versions")));
+        assert_eq!(db.encrypt_existing().unwrap(), 0);
+        assert_eq!(
+            db.get_revisions("user1", &note.id).unwrap()[1].content,
+            "# Old\n\nwritten in the clear"
+        );
+
+        // No table holds the text or an unkeyed hash of it
+        let secrets: Vec<Vec<u8>> = [
+            "Secret", "apples", "clear", "shopping", "Mars", "Olympus", "hopeful", "receipt",
+        ]
+        .iter()
+        .map(|s| s.as_bytes().to_vec())
+        .chain(
+            parse_chunks(text)
+                .iter()
+                .chain(&parse_chunks("# Old\n\nwritten in the clear"))
+                .map(|c| compute_hash(&c.content).into_bytes()),
+        )
+        .collect();
+        for table in stored("SELECT name FROM sqlite_master WHERE type = 'table'") {
+            let conn = db.pool.get().unwrap();
+            let mut stmt = conn.prepare(&format!("SELECT * FROM {table}")).unwrap();
+            let columns = stmt.column_count();
+            let mut rows = stmt.query([]).unwrap();
+            while let Some(row) = rows.next().unwrap() {
+                for i in 0..columns {
+                    let bytes = match row.get::<_, rusqlite::types::Value>(i).unwrap() {
+                        rusqlite::types::Value::Text(text) => text.into_bytes(),
+                        rusqlite::types::Value::Blob(blob) => blob,
+                        _ => continue,
+                    };
+                    for secret in &secrets {
+                        assert!(
+                            !bytes.windows(secret.len()).any(|w| w == secret.as_slice()),
+                            "{table} holds {}",
+                            String::from_utf8_lossy(secret)
+                        );
+                    }
+                }
+            }
+        }
+
+        // And everything still reads back under the key
+        let tags = db.list_tags("user1", &note.id).unwrap();
+        assert_eq!(
+            tags.iter().map(|t| t.tag.as_str()).collect::<Vec<_>>(),
+            ["shopping"]
+        );
+        let tagged = db.get_tagged_chunks("user1", &note.id, "shopping").unwrap();
+        assert_eq!(tagged[0].content, "Fruit #shopping");
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(chunks[2].content_hash, compute_hash("Fruit #shopping"));
+        let backlinks = db.get_backlinks("user1", &note.id, &chunks[0].id).unwrap();
+        assert_eq!(backlinks[0].content, "See [[Secret]]");
+        let now = chrono::Utc::now().to_rfc3339();
+        let cards = db.due_cards("user1", &note.id, &now, 10).unwrap();
+        assert_eq!(cards[0].question, "Capital of Mars?");
+        assert_eq!(
+            db.get_note_meta("user1", &old.id).unwrap().as_deref(),
+            Some(r#"{"mood":"hopeful"}"#)
+        );
+        let attachment = db.get_attachment("user1", "att1").unwrap().unwrap();
+        assert_eq!(attachment.filename.as_deref(), Some("receipt.txt"));
+        assert_eq!(attachment.data, b"receipt bytes");
+
+        // Without the key the text can't be read
+        assert!(Database::open(path)
+            .unwrap()
+            .get_or_create_note("user1")
+            .is_err());
+
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path, suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn test_encrypted_ids() {
+        let path = std::env::temp_dir().join(format!("trame-ids-{}.db", ulid::Ulid::new()));
+        let path = path.to_str().unwrap();
+        let key = MasterKey::parse(&"cd".repeat(32)).unwrap();
+        let text = "# Plan\n\nSee [[Plan]]\n\nQ: Why?\nA: Because";
+
+        // Ids computed in the clear, as any client would
+        let plain = Database::open(path).unwrap();
+        plain.migrate().unwrap();
+        plain
+            .create_user("user1", "test@example.com", "hash")
+            .unwrap();
+        let note = plain.update_note("user1", text).unwrap();
+        let plain_chunks = plain.get_chunks("user1", &note.id).unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+        let plain_card = plain.due_cards("user1", &note.id, &now, 10).unwrap()[0].clone();
+
+        // Under a key they're taken from the keyed hashes instead
+        let db = Database::open(path).unwrap().with_master_key(&key);
+        assert!(db.encrypt_existing().unwrap() > 0);
+        let chunks = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(chunks.len(), 3);
+        for (chunk, before) in chunks.iter().zip(&plain_chunks) {
+            assert_eq!(chunk.content, before.content);
+            assert_ne!(chunk.id, before.id);
+            assert_ne!(
+                chunk.id,
+                chunk_id(&note.id, &compute_hash(&chunk.content), 0)
+            );
+        }
+        let backlinks = db.get_backlinks("user1", &note.id, &chunks[0].id).unwrap();
+        assert_eq!(backlinks[0].id, chunks[1].id);
+        let card = db.due_cards("user1", &note.id, &now, 10).unwrap()[0].clone();
+        assert_ne!(card.id, plain_card.id);
+        assert_eq!(card.chunk_id, chunks[2].id);
+        assert_eq!(db.encrypt_existing().unwrap(), 0);
+
+        // Saves find the chunks under their keyed ids, pins included
+        assert!(db
+            .set_chunk_pinned("user1", &note.id, &chunks[2].id, true)
+            .unwrap());
+        let saved = db
+            .update_note("user1", &format!("{}\n\nMore", text))
+            .unwrap();
+        assert!(saved.content.starts_with("Q: Why?"));
+        let after = db.get_chunks("user1", &note.id).unwrap();
+        assert_eq!(after[0].id, chunks[2].id);
+        assert_eq!(after[1].id, chunks[0].id);
+        assert_eq!(
+            db.get_card("user1", &note.id, &card.id)
+                .unwrap()
+                .unwrap()
+                .id,
+            card.id
+        );
+
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path, suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn test_note_revisions() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "First").unwrap();
+        db.update_note("user1", "Second").unwrap();
+        // Saving identical content doesn't add a revision
+        db.update_note("user1", "Second").unwrap();
+
+        let revisions = db.get_revisions("user1", &note.id).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(revisions[0].content, "Second");
+        assert_eq!(revisions[1].content, "First");
+
+        let first = db
+            .get_revision("user1", &note.id, &revisions[1].id)
+            .unwrap();
+        assert_eq!(first.unwrap().content, "First");
+        assert!(db
+            .get_revision("user1", &note.id, "missing")
+            .unwrap()
+            .is_none());
+
+        let at = |time: &str| {
+            db.revision_at("user1", &note.id, time)
+                .unwrap()
+                .map(|r| r.content)
+        };
+        assert_eq!(at(&revisions[1].created_at).as_deref(), Some("First"));
+        assert_eq!(at(&revisions[0].created_at).as_deref(), Some("Second"));
+        assert_eq!(at("2000-01-01T00:00:00Z"), None);
+    }
+    #[test]
+    fn test_note_goals() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        assert_eq!(db.get_note_goal("user1", &note.id).unwrap().word_goal, None);
+
+        let goal = NoteGoal {
+            word_goal: Some(1000),
+            daily_word_goal: None,
+        };
+        db.set_note_goal("user1", &note.id, &goal).unwrap();
+        let goal = NoteGoal {
+            word_goal: Some(1500),
+            daily_word_goal: Some(200),
+        };
+        db.set_note_goal("user1", &note.id, &goal).unwrap();
+
+        let stored = db.get_note_goal("user1", &note.id).unwrap();
+        assert_eq!(stored.word_goal, Some(1500));
+        assert_eq!(stored.daily_word_goal, Some(200));
+    }
+
+    #[test]
+    fn test_revision_before() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.update_note("user1", "First").unwrap();
+        db.update_note("user1", "Second").unwrap();
+
+        let before = db
+            .get_revision_before("user1", &note.id, "2000-01-01T00:00:00+00:00")
+            .unwrap();
+        assert!(before.is_none());
+        let latest = db
+            .get_revision_before("user1", &note.id, "2999-01-01T00:00:00+00:00")
+            .unwrap();
+        assert_eq!(latest.unwrap().content, "Second");
+    }
+    #[test]
+    fn test_focus_sessions() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        assert!(db.stop_focus("user1").unwrap().is_none());
+
+        let started = db.start_focus("user1", &note.id).unwrap().unwrap();
+        let active = db.get_active_focus("user1").unwrap().unwrap();
+        assert_eq!(active.id, started.id);
+        // Only one runs at a time
+        assert!(db.start_focus("user1", &note.id).unwrap().is_none());
+        // Running sessions don't count yet
+        assert!(db.focus_totals("user1", "").unwrap().is_empty());
+
+        let stopped = db.stop_focus("user1").unwrap().unwrap();
+        assert_eq!(stopped.id, started.id);
+        assert!(stopped.duration_seconds.is_some());
+        assert!(db.get_active_focus("user1").unwrap().is_none());
+
+        let totals = db.focus_totals("user1", "").unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].note_id, note.id);
+        assert_eq!(totals[0].sessions, 1);
+        assert!(db.focus_totals("user1", "2999-01-01").unwrap().is_empty());
+    }
+    #[test]
+    fn test_note_meta() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let note = db.get_or_create_note("user1").unwrap();
+        assert!(db.get_note_meta("user1", &note.id).unwrap().is_none());
+
+        db.set_note_meta("user1", &note.id, r#"{"color":"red"}"#)
+            .unwrap();
+        db.set_note_meta("user1", &note.id, r#"{"color":"blue"}"#)
+            .unwrap();
+        assert_eq!(
+            db.get_note_meta("user1", &note.id).unwrap().as_deref(),
+            Some(r#"{"color":"blue"}"#)
+        );
+    }
+}
@@ -0,0 +1,1083 @@
+//! Versioned schema migrations for the SQLite backend.
+//!
+//! Each database records how far it has been migrated in `schema_version`,
+//! one row per component. Steps are applied in order, each in its own
+//! transaction, so a failed step leaves the database at the previous version.
+//! Never edit a released step; append a new one instead.
+//!
+//! Data changes over big tables use a [`Backfill::Batched`] step instead: the
+//! schema change is applied and versioned as usual, and [`run_backfills`]
+//! then works through the rows in short transactions while the server runs,
+//! recording a cursor after each batch in `backfill_progress`. Code reading
+//! the new columns must expect them unset until the backfill finishes.
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::chunker::{
+    card_id, chunk_and_hash, chunk_id, extract_cards, extract_tags, heading_text, parse_chunks,
+    resolve_links, ChunkType, ParsedChunk,
+};
+use crate::db::{cipher, session_key_id};
+use crate::log;
+
+/// Data step of a migration.
+pub enum Backfill {
+    /// Runs after the step's SQL, on its transaction.
+    Once(fn(&Connection) -> Result<(), rusqlite::Error>),
+    /// Runs later, batch by batch; see [`run_backfills`].
+    Batched(BatchStep),
+}
+
+/// Process up to `limit` rows whose key sorts after the given cursor (from
+/// the first row when `None`), and return the last key processed, or `None`
+/// once no rows are left.
+pub type BatchStep =
+    fn(&Connection, Option<&str>, usize) -> Result<Option<String>, rusqlite::Error>;
+
+/// Rest between batches, so writers waiting on the lock get their turn.
+const BATCH_PAUSE: Duration = Duration::from_millis(5);
+
+/// Progress is logged every this many batches.
+const BATCH_LOG_INTERVAL: u64 = 100;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+    /// Data changes SQL alone can't make
+    pub backfill: Option<Backfill>,
+}
+
+/// Component holding account data. Always stored in the main database file.
+pub const ACCOUNT: &str = "account";
+
+/// Component holding a user's notes. Stored in the main database file, or in
+/// the user's own shard when sharding is enabled.
+pub const NOTES: &str = "notes";
+
+// Version 1 is the schema as it was before migrations were tracked. It uses
+// IF NOT EXISTS so databases created back then adopt it without changes.
+pub const ACCOUNT_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: "
+    CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        email TEXT UNIQUE NOT NULL,
+        password_hash TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        token TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id),
+        expires_at TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_sessions_user ON sessions(user_id);
+
+    CREATE TABLE IF NOT EXISTS password_resets (
+        token_hash TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        used_at TEXT
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_password_resets_user ON password_resets(user_id);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        name: "announcements",
+        sql: "
+    CREATE TABLE announcements (
+        id TEXT PRIMARY KEY,
+        message TEXT NOT NULL,
+        level TEXT NOT NULL,
+        starts_at TEXT,
+        ends_at TEXT,
+        created_at TEXT NOT NULL
+    );
+",
+        backfill: None,
+    },
+    Migration {
+        version: 3,
+        name: "terms_acceptance",
+        sql: "
+    ALTER TABLE users ADD COLUMN terms_version TEXT;
+    ALTER TABLE users ADD COLUMN terms_accepted_at TEXT;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 4,
+        name: "inbound_hooks",
+        sql: "
+    CREATE TABLE inbound_hooks (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        token_hash TEXT UNIQUE NOT NULL,
+        name TEXT NOT NULL,
+        action TEXT NOT NULL,
+        template TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        last_used_at TEXT
+    );
+
+    CREATE INDEX idx_inbound_hooks_user ON inbound_hooks(user_id);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        name: "impersonation_audit",
+        sql: "
+    ALTER TABLE sessions ADD COLUMN impersonator_id TEXT;
+
+    CREATE TABLE audit_log (
+        id TEXT PRIMARY KEY,
+        actor_id TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target_user_id TEXT,
+        details TEXT,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE INDEX idx_audit_log_target ON audit_log(target_user_id, created_at);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 6,
+        name: "user_flags",
+        sql: "
+    CREATE TABLE user_flags (
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        flag TEXT NOT NULL,
+        enabled_at TEXT NOT NULL,
+        PRIMARY KEY (user_id, flag)
+    );
+",
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        name: "session_details",
+        sql: "
+    ALTER TABLE sessions ADD COLUMN created_at TEXT;
+    ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+    ALTER TABLE sessions ADD COLUMN ip TEXT;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 8,
+        name: "shares",
+        // The note may live in a shard, so note_id has no foreign key
+        sql: "
+    CREATE TABLE shares (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        note_id TEXT NOT NULL,
+        token_hash TEXT UNIQUE NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT,
+        last_viewed_at TEXT
+    );
+
+    CREATE INDEX idx_shares_user ON shares(user_id);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 9,
+        name: "export_tokens",
+        sql: "
+    CREATE TABLE export_tokens (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        token_hash TEXT UNIQUE NOT NULL,
+        name TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        last_used_at TEXT
+    );
+
+    CREATE INDEX idx_export_tokens_user ON export_tokens(user_id);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 10,
+        name: "key_usage",
+        sql: "
+    CREATE TABLE key_usage (
+        key_id TEXT NOT NULL,
+        user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        day TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        last_used_at TEXT NOT NULL,
+        PRIMARY KEY (key_id, day)
+    );
+",
+        backfill: None,
+    },
+    Migration {
+        version: 11,
+        name: "hashed_session_keys",
+        sql: "",
+        backfill: Some(Backfill::Once(backfill_session_key_ids)),
+    },
+];
+
+// The trailing rebuild indexes chunks written before the search table existed.
+pub const NOTE_MIGRATIONS: &[Migration] = &[
+    Migration {
+    version: 1,
+    name: "initial",
+    sql: "
+    CREATE TABLE IF NOT EXISTS notes (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users(id),
+        content TEXT NOT NULL DEFAULT '',
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_notes_user ON notes(user_id);
+
+    CREATE TABLE IF NOT EXISTS chunks (
+        id TEXT PRIMARY KEY,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        sequence INTEGER NOT NULL,
+        chunk_type TEXT NOT NULL,
+        heading_level INTEGER,
+        content TEXT NOT NULL,
+        content_hash TEXT NOT NULL,
+        start_offset INTEGER NOT NULL,
+        end_offset INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_chunks_note ON chunks(note_id);
+
+    CREATE TABLE IF NOT EXISTS chunk_versions (
+        id TEXT PRIMARY KEY,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        sequence INTEGER NOT NULL,
+        chunk_type TEXT NOT NULL,
+        heading_level INTEGER,
+        content TEXT NOT NULL,
+        content_hash TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        replaced_at TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_chunk_versions_note ON chunk_versions(note_id, replaced_at);
+
+    CREATE TABLE IF NOT EXISTS note_revisions (
+        id TEXT PRIMARY KEY,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        content TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_note_revisions_note ON note_revisions(note_id, created_at);
+
+    CREATE TABLE IF NOT EXISTS note_goals (
+        note_id TEXT PRIMARY KEY REFERENCES notes(id) ON DELETE CASCADE,
+        word_goal INTEGER,
+        daily_word_goal INTEGER,
+        updated_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS focus_sessions (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        started_at TEXT NOT NULL,
+        ended_at TEXT,
+        duration_seconds INTEGER
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_focus_sessions_user ON focus_sessions(user_id, started_at);
+
+    CREATE TABLE IF NOT EXISTS note_meta (
+        note_id TEXT PRIMARY KEY REFERENCES notes(id) ON DELETE CASCADE,
+        data TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+
+    CREATE VIRTUAL TABLE IF NOT EXISTS chunks_fts USING fts5(
+        content,
+        content='chunks',
+        content_rowid='rowid'
+    );
+
+    CREATE TRIGGER IF NOT EXISTS chunks_fts_insert AFTER INSERT ON chunks BEGIN
+        INSERT INTO chunks_fts(rowid, content) VALUES (new.rowid, new.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS chunks_fts_delete AFTER DELETE ON chunks BEGIN
+        INSERT INTO chunks_fts(chunks_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS chunks_fts_update AFTER UPDATE ON chunks BEGIN
+        INSERT INTO chunks_fts(chunks_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+        INSERT INTO chunks_fts(rowid, content) VALUES (new.rowid, new.content);
+    END;
+
+    INSERT INTO chunks_fts(chunks_fts) VALUES ('rebuild');
+",
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        name: "chunk_tags",
+        sql: "
+    CREATE TABLE chunk_tags (
+        chunk_id TEXT NOT NULL REFERENCES chunks(id) ON DELETE CASCADE,
+        note_id TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (chunk_id, tag)
+    );
+    CREATE INDEX idx_chunk_tags_note ON chunk_tags(note_id, tag);
+",
+        backfill: Some(Backfill::Once(backfill_chunk_tags)),
+    },
+    Migration {
+        version: 3,
+        name: "links",
+        sql: "
+    CREATE TABLE links (
+        source_chunk_id TEXT NOT NULL REFERENCES chunks(id) ON DELETE CASCADE,
+        note_id TEXT NOT NULL,
+        target TEXT NOT NULL,
+        target_chunk_id TEXT REFERENCES chunks(id) ON DELETE SET NULL,
+        PRIMARY KEY (source_chunk_id, target)
+    );
+    CREATE INDEX idx_links_target ON links(note_id, target_chunk_id);
+",
+        backfill: Some(Backfill::Once(backfill_links)),
+    },
+    Migration {
+        version: 4,
+        name: "attachments",
+        sql: "
+    CREATE TABLE attachments (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        filename TEXT,
+        content_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX idx_attachments_user ON attachments(user_id);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        name: "external_ids",
+        sql: "
+    CREATE TABLE external_ids (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        external_id TEXT NOT NULL,
+        content TEXT NOT NULL,
+        imported_at TEXT NOT NULL,
+        UNIQUE (user_id, source, external_id)
+    );
+",
+        backfill: None,
+    },
+    Migration {
+        version: 6,
+        name: "trash",
+        sql: "
+    ALTER TABLE notes ADD COLUMN deleted_at TEXT;
+    CREATE INDEX idx_notes_deleted ON notes(deleted_at) WHERE deleted_at IS NOT NULL;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        name: "note_titles",
+        sql: "
+    ALTER TABLE notes ADD COLUMN title TEXT;
+",
+        backfill: Some(Backfill::Batched(backfill_note_titles)),
+    },
+    Migration {
+        version: 8,
+        name: "chunk_pins",
+        sql: "
+    ALTER TABLE chunks ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 9,
+        name: "journal",
+        sql: "
+    ALTER TABLE notes ADD COLUMN journal INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE journal_entries (
+        id TEXT PRIMARY KEY,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        start_offset INTEGER NOT NULL,
+        end_offset INTEGER NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX idx_journal_entries_note ON journal_entries(note_id, start_offset);
+",
+        backfill: None,
+    },
+    Migration {
+        version: 10,
+        name: "chunks_fts_content_updates",
+        // Moving a chunk within the note doesn't change what search sees
+        sql: "
+    DROP TRIGGER chunks_fts_update;
+    CREATE TRIGGER chunks_fts_update AFTER UPDATE OF content ON chunks BEGIN
+        INSERT INTO chunks_fts(chunks_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+        INSERT INTO chunks_fts(rowid, content) VALUES (new.rowid, new.content);
+    END;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 11,
+        name: "numbered_headings",
+        sql: "
+    ALTER TABLE notes ADD COLUMN numbered_headings INTEGER NOT NULL DEFAULT 0;
+",
+        backfill: None,
+    },
+    Migration {
+        version: 12,
+        name: "chunk_byte_offsets",
+        sql: "
+    ALTER TABLE chunks ADD COLUMN start_byte INTEGER;
+    ALTER TABLE chunks ADD COLUMN end_byte INTEGER;
+",
+        backfill: Some(Backfill::Batched(backfill_chunk_bytes)),
+    },
+    Migration {
+        version: 13,
+        name: "cards",
+        sql: "
+    CREATE TABLE cards (
+        id TEXT PRIMARY KEY,
+        note_id TEXT NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+        chunk_id TEXT NOT NULL,
+        question TEXT NOT NULL,
+        answer TEXT NOT NULL,
+        ease REAL NOT NULL DEFAULT 2.5,
+        interval_days INTEGER NOT NULL DEFAULT 0,
+        repetitions INTEGER NOT NULL DEFAULT 0,
+        due_at TEXT NOT NULL,
+        last_reviewed_at TEXT,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX idx_cards_due ON cards(note_id, due_at);
+",
+        backfill: Some(Backfill::Once(backfill_cards)),
+    },
+    Migration {
+        version: 14,
+        name: "one_running_focus_session",
+        // Of sessions left running side by side, all but the latest end
+        // empty, so the index can be built
+        sql: "
+    UPDATE focus_sessions SET ended_at = started_at, duration_seconds = 0
+    WHERE ended_at IS NULL AND EXISTS (
+        SELECT 1 FROM focus_sessions later
+        WHERE later.user_id = focus_sessions.user_id AND later.ended_at IS NULL
+          AND (later.started_at, later.id) > (focus_sessions.started_at, focus_sessions.id)
+    );
+    CREATE UNIQUE INDEX idx_focus_sessions_running ON focus_sessions(user_id) WHERE ended_at IS NULL;
+",
+        backfill: None,
+    },
+];
+
+/// First heading of a note's content, without its markers.
+pub fn note_title(content: &str) -> Option<String> {
+    parse_chunks(content)
+        .into_iter()
+        .find(|c| c.chunk_type == ChunkType::Heading)
+        .map(|c| heading_text(&c).to_string())
+}
+
+/// Title the notes saved before titles were stored, in id order.
+fn backfill_note_titles(
+    conn: &Connection,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Option<String>, rusqlite::Error> {
+    let mut stmt =
+        conn.prepare("SELECT id, content FROM notes WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+    let notes = stmt
+        .query_map(params![after.unwrap_or(""), limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    for (id, content) in &notes {
+        // Sealed notes were given their title when written
+        if cipher::is_sealed(content) {
+            continue;
+        }
+        conn.execute(
+            "UPDATE notes SET title = ?1 WHERE id = ?2",
+            params![note_title(content), id],
+        )?;
+    }
+    Ok(notes.last().map(|(id, _)| id.clone()))
+}
+
+/// Give the chunks saved before bytes were tracked their byte range, note
+/// by note in id order.
+fn backfill_chunk_bytes(
+    conn: &Connection,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Option<String>, rusqlite::Error> {
+    let mut stmt =
+        conn.prepare("SELECT id, content FROM notes WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+    let notes = stmt
+        .query_map(params![after.unwrap_or(""), limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    let mut update = conn.prepare(
+        "UPDATE chunks SET start_byte = ?1, end_byte = ?2
+         WHERE id = ?3 AND start_offset = ?4 AND start_byte IS NULL",
+    )?;
+    for (note_id, content) in &notes {
+        // Sealed notes get theirs on the next save
+        if cipher::is_sealed(content) {
+            continue;
+        }
+        let mut occurrences: HashMap<String, u32> = HashMap::new();
+        for c in chunk_and_hash(content) {
+            let occurrence = occurrences.entry(c.content_hash.clone()).or_insert(0);
+            update.execute(params![
+                c.chunk.start_byte as i64,
+                c.chunk.end_byte as i64,
+                chunk_id(note_id, &c.content_hash, *occurrence),
+                c.chunk.start_offset as i64,
+            ])?;
+            *occurrence += 1;
+        }
+    }
+    Ok(notes.last().map(|(id, _)| id.clone()))
+}
+
+/// Count live sessions' usage under a hash of their token instead of the
+/// token itself. Usage of sessions already gone is left to the purge.
+fn backfill_session_key_ids(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT key_id FROM key_usage WHERE key_id IN (SELECT token FROM sessions)",
+    )?;
+    let tokens = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for token in tokens {
+        conn.execute(
+            "UPDATE key_usage SET key_id = ?1 WHERE key_id = ?2",
+            params![session_key_id(&token), token],
+        )?;
+    }
+    Ok(())
+}
+
+/// Find the flashcards in notes saved before cards were tracked, all due now.
+fn backfill_cards(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT id, content FROM notes")?;
+    let notes = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    let now = chrono::Utc::now().to_rfc3339();
+    for (note_id, content) in &notes {
+        // Sealed notes get theirs on the next save
+        if cipher::is_sealed(content) {
+            continue;
+        }
+        let mut chunk_occurrences: HashMap<String, u32> = HashMap::new();
+        let mut card_occurrences: HashMap<String, u32> = HashMap::new();
+        for c in chunk_and_hash(content) {
+            let occurrence = chunk_occurrences.entry(c.content_hash.clone()).or_insert(0);
+            let source = chunk_id(note_id, &c.content_hash, *occurrence);
+            *occurrence += 1;
+            for card in extract_cards(&c.chunk) {
+                let occurrence = card_occurrences.entry(card.question.clone()).or_insert(0);
+                conn.execute(
+                    "INSERT OR IGNORE INTO cards (id, note_id, chunk_id, question, answer, due_at, created_at)
+                     SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?6
+                     WHERE EXISTS (SELECT 1 FROM chunks WHERE id = ?3)",
+                    params![
+                        card_id(note_id, &card.question, *occurrence),
+                        note_id,
+                        source,
+                        card.question,
+                        card.answer,
+                        now,
+                    ],
+                )?;
+                *occurrence += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tag the chunks saved before tags were tracked.
+fn backfill_chunk_tags(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT id, note_id, content FROM chunks")?;
+    let chunks = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, String, String)>, _>>()?;
+    for (chunk_id, note_id, content) in chunks {
+        // A stored chunk parses back to itself
+        for chunk in parse_chunks(&content) {
+            for tag in extract_tags(&chunk) {
+                conn.execute(
+                    "INSERT OR IGNORE INTO chunk_tags (chunk_id, note_id, tag) VALUES (?1, ?2, ?3)",
+                    params![chunk_id, note_id, tag],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record the links of notes saved before links were tracked.
+fn backfill_links(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT id, content FROM notes")?;
+    let notes = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    for (note_id, content) in notes {
+        let chunks = chunk_and_hash(&content);
+        let mut occurrences: HashMap<&str, u32> = HashMap::new();
+        let ids: Vec<String> = chunks
+            .iter()
+            .map(|c| {
+                let occurrence = occurrences.entry(c.content_hash.as_str()).or_insert(0);
+                *occurrence += 1;
+                chunk_id(&note_id, &c.content_hash, *occurrence - 1)
+            })
+            .collect();
+        let parsed: Vec<ParsedChunk> = chunks.into_iter().map(|c| c.chunk).collect();
+
+        // Chunks stored under other ids are skipped; the next save links them
+        for link in resolve_links(&parsed) {
+            conn.execute(
+                "INSERT OR IGNORE INTO links (source_chunk_id, note_id, target, target_chunk_id)
+                 SELECT ?1, ?2, ?3, (SELECT id FROM chunks WHERE id = ?4)
+                 WHERE EXISTS (SELECT 1 FROM chunks WHERE id = ?1)",
+                params![
+                    ids[link.source],
+                    note_id,
+                    link.target,
+                    link.heading.map(|h| ids[h].as_str()),
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+const VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_version (
+        component TEXT PRIMARY KEY,
+        version INTEGER NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS backfill_progress (
+        component TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        cursor TEXT,
+        batches INTEGER NOT NULL DEFAULT 0,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (component, version)
+    );
+";
+
+/// Version `component` is at on `conn`, or 0 if it has never been migrated.
+pub fn current_version(conn: &Connection, component: &str) -> Result<u32, rusqlite::Error> {
+    conn.execute_batch(VERSION_TABLE)?;
+    let version = conn
+        .query_row(
+            "SELECT version FROM schema_version WHERE component = ?1",
+            params![component],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Bring `component` up to the last of `migrations`, passing each step's SQL
+/// through `adapt` first. Returns how many steps were applied.
+pub fn apply(
+    conn: &mut Connection,
+    component: &str,
+    migrations: &[Migration],
+    adapt: &dyn Fn(&str) -> String,
+) -> Result<usize, rusqlite::Error> {
+    conn.execute_batch(VERSION_TABLE)?;
+
+    let mut applied = 0;
+    for migration in migrations {
+        // Immediate so two processes migrating at once take turns, and the
+        // second sees the step as done
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if current_version(&tx, component)? >= migration.version {
+            continue;
+        }
+
+        tx.execute_batch(&adapt(migration.sql))?;
+        match migration.backfill {
+            Some(Backfill::Once(backfill)) => backfill(&tx)?,
+            Some(Backfill::Batched(_)) => {
+                tx.execute(
+                    "INSERT OR IGNORE INTO backfill_progress (component, version, updated_at)
+                     VALUES (?1, ?2, ?3)",
+                    params![
+                        component,
+                        migration.version,
+                        chrono::Utc::now().to_rfc3339()
+                    ],
+                )?;
+            }
+            None => {}
+        }
+        tx.execute(
+            "INSERT INTO schema_version (component, version, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(component) DO UPDATE SET version = excluded.version, updated_at = excluded.updated_at",
+            params![component, migration.version, chrono::Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+
+        log::info(
+            "migration applied",
+            json!({ "component": component, "version": migration.version, "name": migration.name }),
+        );
+        applied += 1;
+    }
+
+    Ok(applied)
+}
+
+/// Run the batched backfills `apply` left pending for `component`, each
+/// batch of `batch_size` rows in its own transaction. A run that stops
+/// halfway resumes from the last batch committed. Returns how many batches
+/// were run.
+pub fn run_backfills(
+    conn: &mut Connection,
+    component: &str,
+    migrations: &[Migration],
+    batch_size: usize,
+) -> Result<u64, rusqlite::Error> {
+    conn.execute_batch(VERSION_TABLE)?;
+
+    let mut batches = 0;
+    for migration in migrations {
+        let Some(Backfill::Batched(step)) = migration.backfill else {
+            continue;
+        };
+        loop {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            // Gone once finished, possibly by another process
+            let progress: Option<(Option<String>, u64)> = tx
+                .query_row(
+                    "SELECT cursor, batches FROM backfill_progress WHERE component = ?1 AND version = ?2",
+                    params![component, migration.version],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let Some((cursor, done)) = progress else {
+                break;
+            };
+
+            let Some(last) = step(&tx, cursor.as_deref(), batch_size.max(1))? else {
+                tx.execute(
+                    "DELETE FROM backfill_progress WHERE component = ?1 AND version = ?2",
+                    params![component, migration.version],
+                )?;
+                tx.commit()?;
+                log::info(
+                    "backfill finished",
+                    json!({ "component": component, "version": migration.version, "name": migration.name, "batches": done }),
+                );
+                break;
+            };
+            tx.execute(
+                "UPDATE backfill_progress SET cursor = ?1, batches = batches + 1, updated_at = ?2
+                 WHERE component = ?3 AND version = ?4",
+                params![
+                    last,
+                    chrono::Utc::now().to_rfc3339(),
+                    component,
+                    migration.version
+                ],
+            )?;
+            tx.commit()?;
+            batches += 1;
+
+            if (done + 1) % BATCH_LOG_INTERVAL == 0 {
+                log::info(
+                    "backfill progress",
+                    json!({ "component": component, "version": migration.version, "name": migration.name, "batches": done + 1, "cursor": last }),
+                );
+            }
+            std::thread::sleep(BATCH_PAUSE);
+        }
+    }
+
+    Ok(batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_strictly_increase() {
+        for migrations in [ACCOUNT_MIGRATIONS, NOTE_MIGRATIONS] {
+            let versions: Vec<u32> = migrations.iter().map(|m| m.version).collect();
+            assert_eq!(versions[0], 1);
+            assert!(versions.windows(2).all(|w| w[0] < w[1]), "{:?}", versions);
+        }
+    }
+
+    #[test]
+    fn test_apply_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let same = |sql: &str| sql.to_string();
+
+        assert_eq!(current_version(&conn, ACCOUNT).unwrap(), 0);
+        assert_eq!(
+            apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap(),
+            ACCOUNT_MIGRATIONS.len()
+        );
+        assert_eq!(
+            apply(&mut conn, NOTES, NOTE_MIGRATIONS, &same).unwrap(),
+            NOTE_MIGRATIONS.len()
+        );
+        assert_eq!(
+            apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap(),
+            0
+        );
+        assert_eq!(
+            current_version(&conn, ACCOUNT).unwrap(),
+            ACCOUNT_MIGRATIONS.last().unwrap().version
+        );
+    }
+
+    #[test]
+    fn test_failed_step_rolls_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let steps = [
+            Migration {
+                version: 1,
+                name: "one",
+                sql: "CREATE TABLE a (x INTEGER);",
+                backfill: None,
+            },
+            Migration {
+                version: 2,
+                name: "two",
+                sql: "CREATE TABLE b (x INTEGER); SELECT nope FROM a;",
+                backfill: None,
+            },
+        ];
+
+        assert!(apply(&mut conn, "test", &steps, &|sql| sql.to_string()).is_err());
+        assert_eq!(current_version(&conn, "test").unwrap(), 1);
+        let has_b: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'b')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!has_b);
+    }
+
+    #[test]
+    fn test_batched_backfill_resumes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let same = |sql: &str| sql.to_string();
+        apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap();
+        apply(&mut conn, NOTES, &NOTE_MIGRATIONS[..6], &same).unwrap();
+        // Up to the titles, the step under test
+        let migrations = &NOTE_MIGRATIONS[..7];
+        conn.execute_batch(
+            "INSERT INTO users (id, email, password_hash, created_at) VALUES ('u1', 'a@b.c', '', '');
+             INSERT INTO notes (id, user_id, content, created_at, updated_at) VALUES
+                 ('n1', 'u1', '# One', '', ''),
+                 ('n2', 'u1', 'text\n\n## Two', '', ''),
+                 ('n3', 'u1', 'no heading', '', '');",
+        )
+        .unwrap();
+
+        // The schema step lands at once and leaves the data for later
+        apply(&mut conn, NOTES, migrations, &same).unwrap();
+        let title = |conn: &Connection, id: &str| -> Option<String> {
+            conn.query_row(
+                "SELECT title FROM notes WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(title(&conn, "n1"), None);
+
+        // A run stopped after the first batch picks up at its cursor
+        conn.execute_batch(
+            "UPDATE notes SET title = 'One' WHERE id = 'n1';
+             UPDATE backfill_progress SET cursor = 'n1', batches = 1;",
+        )
+        .unwrap();
+        assert_eq!(run_backfills(&mut conn, NOTES, migrations, 1).unwrap(), 2);
+        assert_eq!(title(&conn, "n1").as_deref(), Some("One"));
+        assert_eq!(title(&conn, "n2").as_deref(), Some("Two"));
+        assert_eq!(title(&conn, "n3"), None);
+
+        let pending: i64 = conn
+            .query_row("SELECT COUNT(*) FROM backfill_progress", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(pending, 0);
+        assert_eq!(run_backfills(&mut conn, NOTES, migrations, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_chunk_tags_backfill() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let same = |sql: &str| sql.to_string();
+        apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap();
+        apply(&mut conn, NOTES, &NOTE_MIGRATIONS[..1], &same).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, email, password_hash, created_at) VALUES ('u1', 'a@b.c', '', '');
+             INSERT INTO notes (id, user_id, created_at, updated_at) VALUES ('n1', 'u1', '', '');
+             INSERT INTO chunks (id, note_id, sequence, chunk_type, content, content_hash, start_offset, end_offset, created_at, updated_at)
+             VALUES ('c1', 'n1', 0, 'paragraph', 'see #later', 'h1', 0, 10, '', ''),
+                    ('c2', 'n1', 1, 'code_block', '```\n#later\n```', 'h2', 12, 28, '', '');",
+        )
+        .unwrap();
+
+        apply(&mut conn, NOTES, NOTE_MIGRATIONS, &same).unwrap();
+        let tagged: Vec<String> = conn
+            .prepare("SELECT chunk_id FROM chunk_tags WHERE tag = 'later'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tagged, vec!["c1"]);
+    }
+
+    #[test]
+    fn test_links_backfill() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let same = |sql: &str| sql.to_string();
+        apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap();
+        apply(&mut conn, NOTES, &NOTE_MIGRATIONS[..2], &same).unwrap();
+
+        let content = "See [[Plans]]\n\n# Plans";
+        conn.execute_batch(
+            "INSERT INTO users (id, email, password_hash, created_at) VALUES ('u1', 'a@b.c', '', '');",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO notes (id, user_id, content, created_at, updated_at) VALUES ('n1', 'u1', ?1, '', '')",
+            params![content],
+        )
+        .unwrap();
+        let ids: Vec<String> = chunk_and_hash(content)
+            .iter()
+            .enumerate()
+            .map(|(seq, c)| {
+                let id = chunk_id("n1", &c.content_hash, 0);
+                conn.execute(
+                    "INSERT INTO chunks (id, note_id, sequence, chunk_type, content, content_hash, start_offset, end_offset, created_at, updated_at)
+                     VALUES (?1, 'n1', ?2, ?3, ?4, ?5, 0, 0, '', '')",
+                    params![id, seq, c.chunk.chunk_type.as_str(), c.chunk.content, c.content_hash],
+                )
+                .unwrap();
+                id
+            })
+            .collect();
+
+        apply(&mut conn, NOTES, NOTE_MIGRATIONS, &same).unwrap();
+        let link: (String, String) = conn
+            .query_row(
+                "SELECT source_chunk_id, target_chunk_id FROM links WHERE target = 'Plans'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(link, (ids[0].clone(), ids[1].clone()));
+    }
+
+    #[test]
+    fn test_chunk_bytes_backfill() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let same = |sql: &str| sql.to_string();
+        apply(&mut conn, ACCOUNT, ACCOUNT_MIGRATIONS, &same).unwrap();
+        apply(&mut conn, NOTES, &NOTE_MIGRATIONS[..11], &same).unwrap();
+
+        let content = "# 日本語\n\nnaïve 😀";
+        conn.execute_batch(
+            "INSERT INTO users (id, email, password_hash, created_at) VALUES ('u1', 'a@b.c', '', '');",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO notes (id, user_id, content, created_at, updated_at) VALUES ('n1', 'u1', ?1, '', '')",
+            params![content],
+        )
+        .unwrap();
+        for (seq, c) in chunk_and_hash(content).iter().enumerate() {
+            conn.execute(
+                "INSERT INTO chunks (id, note_id, sequence, chunk_type, content, content_hash, start_offset, end_offset, created_at, updated_at)
+                 VALUES (?1, 'n1', ?2, ?3, ?4, ?5, ?6, ?7, '', '')",
+                params![
+                    chunk_id("n1", &c.content_hash, 0),
+                    seq,
+                    c.chunk.chunk_type.as_str(),
+                    c.chunk.content,
+                    c.content_hash,
+                    c.chunk.start_offset,
+                    c.chunk.end_offset,
+                ],
+            )
+            .unwrap();
+        }
+
+        apply(&mut conn, NOTES, NOTE_MIGRATIONS, &same).unwrap();
+        run_backfills(&mut conn, NOTES, NOTE_MIGRATIONS, 10).unwrap();
+        let bytes: Vec<(i64, i64)> = conn
+            .prepare("SELECT start_byte, end_byte FROM chunks ORDER BY sequence")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bytes, vec![(0, 12), (13, content.len() as i64)]);
+    }
+}
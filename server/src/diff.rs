@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::Serialize;
+
+use crate::chunker::{chunk_and_hash, ChunkWithHash};
+
+/// Above this many token comparisons a modified chunk is reported as a whole
+/// delete + insert instead of a word diff.
+const MAX_TEXT_DIFF_CELLS: usize = 4_000_000;
+
+/// Above this many chunk comparisons, what lies between the unchanged start
+/// and end of two notes is reported as removed and added as a whole.
+const MAX_NOTE_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Unchanged,
+    Added,
+    Removed,
+    Modified,
+    /// Same content at a different position; `old_index` is where it was.
+    Moved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextSpan {
+    pub op: SpanOp,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkChange {
+    pub kind: ChangeKind,
+    pub chunk_type: String,
+    pub old_index: Option<usize>,
+    pub new_index: Option<usize>,
+    pub old_content: Option<String>,
+    pub new_content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub text: Vec<TextSpan>,
+}
+
+/// Diff two note contents at chunk granularity.
+///
+/// Chunks with identical hashes are aligned first. Unaligned chunks whose
+/// hash appears on both sides are reported as moved; the blocks left over
+/// between two aligned chunks are paired by type as modifications, and the
+/// rest are reported as added or removed.
+pub fn diff_notes(old: &str, new: &str) -> Vec<ChunkChange> {
+    let old_chunks = chunk_and_hash(old);
+    let new_chunks = chunk_and_hash(new);
+
+    let old_hashes: Vec<&str> = old_chunks.iter().map(|c| c.content_hash.as_str()).collect();
+    let new_hashes: Vec<&str> = new_chunks.iter().map(|c| c.content_hash.as_str()).collect();
+
+    let Some(pairs) = lcs_pairs(&old_hashes, &new_hashes, MAX_NOTE_DIFF_CELLS) else {
+        return replaced(&old_chunks, &new_chunks, &old_hashes, &new_hashes);
+    };
+
+    // Content that left one place and reappears in another, unchanged
+    let unaligned = |hashes: &[&str], aligned: Vec<usize>| -> HashSet<String> {
+        let aligned: HashSet<usize> = aligned.into_iter().collect();
+        (0..hashes.len())
+            .filter(|i| !aligned.contains(i))
+            .map(|i| hashes[i].to_string())
+            .collect()
+    };
+    let moved: HashSet<String> = unaligned(&old_hashes, pairs.iter().map(|p| p.0).collect())
+        .intersection(&unaligned(&new_hashes, pairs.iter().map(|p| p.1).collect()))
+        .cloned()
+        .collect();
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let anchors = pairs
+        .into_iter()
+        .chain(std::iter::once((old_chunks.len(), new_chunks.len())));
+
+    for (ai, bj) in anchors {
+        diff_gap(&old_chunks, i..ai, &new_chunks, j..bj, &moved, &mut changes);
+        if ai < old_chunks.len() {
+            let chunk = &new_chunks[bj].chunk;
+            changes.push(ChunkChange {
+                kind: ChangeKind::Unchanged,
+                chunk_type: chunk.chunk_type.as_str().to_string(),
+                old_index: Some(ai),
+                new_index: Some(bj),
+                old_content: Some(old_chunks[ai].chunk.content.clone()),
+                new_content: Some(chunk.content.clone()),
+                text: Vec::new(),
+            });
+        }
+        i = ai + 1;
+        j = bj + 1;
+    }
+
+    pair_moves(changes)
+}
+
+/// The diff of two notes too far apart to align: the chunks they start and
+/// end with are unchanged, and everything in between is removed and added.
+fn replaced(
+    old: &[ChunkWithHash],
+    new: &[ChunkWithHash],
+    old_hashes: &[&str],
+    new_hashes: &[&str],
+) -> Vec<ChunkChange> {
+    let (prefix, suffix) = common_ends(old_hashes, new_hashes);
+    let unchanged = |oi: usize, nj: usize| ChunkChange {
+        kind: ChangeKind::Unchanged,
+        chunk_type: new[nj].chunk.chunk_type.as_str().to_string(),
+        old_index: Some(oi),
+        new_index: Some(nj),
+        old_content: Some(old[oi].chunk.content.clone()),
+        new_content: Some(new[nj].chunk.content.clone()),
+        text: Vec::new(),
+    };
+
+    let mut changes: Vec<ChunkChange> = (0..prefix).map(|i| unchanged(i, i)).collect();
+    changes.extend((prefix..old.len() - suffix).map(|oi| removed_at(old, oi)));
+    changes.extend((prefix..new.len() - suffix).map(|nj| added(new, nj)));
+    changes.extend((0..suffix).map(|k| unchanged(old.len() - suffix + k, new.len() - suffix + k)));
+    changes
+}
+
+/// Merge each added chunk with a removed chunk of the same content into one
+/// move, reported at the chunk's new position.
+fn pair_moves(changes: Vec<ChunkChange>) -> Vec<ChunkChange> {
+    // Removed chunks by content, first removed first
+    let mut removed: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (r, change) in changes.iter().enumerate() {
+        if let (ChangeKind::Removed, Some(content)) = (change.kind, &change.old_content) {
+            removed.entry(content).or_default().push_back(r);
+        }
+    }
+
+    let mut taken = vec![false; changes.len()];
+    let mut moved_from = vec![None; changes.len()];
+    for (a, added) in changes.iter().enumerate() {
+        if added.kind != ChangeKind::Added {
+            continue;
+        }
+        let r = added
+            .new_content
+            .as_deref()
+            .and_then(|content| removed.get_mut(content))
+            .and_then(|queue| queue.pop_front());
+        if let Some(r) = r {
+            taken[r] = true;
+            moved_from[a] = changes[r].old_index;
+        }
+    }
+
+    changes
+        .into_iter()
+        .zip(taken.into_iter().zip(moved_from))
+        .filter(|(_, (taken, _))| !taken)
+        .map(|(change, (_, from))| match from {
+            Some(old_index) => ChunkChange {
+                kind: ChangeKind::Moved,
+                old_index: Some(old_index),
+                old_content: change.new_content.clone(),
+                ..change
+            },
+            None => change,
+        })
+        .collect()
+}
+
+fn diff_gap(
+    old: &[ChunkWithHash],
+    old_range: std::ops::Range<usize>,
+    new: &[ChunkWithHash],
+    new_range: std::ops::Range<usize>,
+    moved: &HashSet<String>,
+    changes: &mut Vec<ChunkChange>,
+) {
+    let mut next_new = new_range.start;
+
+    for oi in old_range {
+        let removed = &old[oi].chunk;
+        // Moved chunks are left unpaired so they can be matched up later
+        let paired = (next_new..new_range.end)
+            .filter(|_| !moved.contains(&old[oi].content_hash))
+            .find(|&nj| {
+                new[nj].chunk.chunk_type == removed.chunk_type
+                    && !moved.contains(&new[nj].content_hash)
+            });
+
+        match paired {
+            Some(nj) => {
+                for skipped in next_new..nj {
+                    changes.push(added(new, skipped));
+                }
+                let inserted = &new[nj].chunk;
+                changes.push(ChunkChange {
+                    kind: ChangeKind::Modified,
+                    chunk_type: inserted.chunk_type.as_str().to_string(),
+                    old_index: Some(oi),
+                    new_index: Some(nj),
+                    old_content: Some(removed.content.clone()),
+                    new_content: Some(inserted.content.clone()),
+                    text: diff_text(&removed.content, &inserted.content),
+                });
+                next_new = nj + 1;
+            }
+            None => changes.push(removed_at(old, oi)),
+        }
+    }
+
+    for nj in next_new..new_range.end {
+        changes.push(added(new, nj));
+    }
+}
+
+fn removed_at(old: &[ChunkWithHash], index: usize) -> ChunkChange {
+    let chunk = &old[index].chunk;
+    ChunkChange {
+        kind: ChangeKind::Removed,
+        chunk_type: chunk.chunk_type.as_str().to_string(),
+        old_index: Some(index),
+        new_index: None,
+        old_content: Some(chunk.content.clone()),
+        new_content: None,
+        text: Vec::new(),
+    }
+}
+
+fn added(new: &[ChunkWithHash], index: usize) -> ChunkChange {
+    let chunk = &new[index].chunk;
+    ChunkChange {
+        kind: ChangeKind::Added,
+        chunk_type: chunk.chunk_type.as_str().to_string(),
+        old_index: None,
+        new_index: Some(index),
+        old_content: None,
+        new_content: Some(chunk.content.clone()),
+        text: Vec::new(),
+    }
+}
+
+/// Word-level diff of two strings. Whitespace runs are kept as their own
+/// tokens so the spans concatenate back to the original texts.
+pub fn diff_text(old: &str, new: &str) -> Vec<TextSpan> {
+    let a = tokenize(old);
+    let b = tokenize(new);
+
+    let mut spans: Vec<TextSpan> = Vec::new();
+    let mut push = |op: SpanOp, text: &str| match spans.last_mut() {
+        Some(last) if last.op == op => last.text.push_str(text),
+        _ => spans.push(TextSpan {
+            op,
+            text: text.to_string(),
+        }),
+    };
+
+    let Some(pairs) = lcs_pairs(&a, &b, MAX_TEXT_DIFF_CELLS) else {
+        if !old.is_empty() {
+            push(SpanOp::Delete, old);
+        }
+        if !new.is_empty() {
+            push(SpanOp::Insert, new);
+        }
+        return spans;
+    };
+
+    let (mut i, mut j) = (0, 0);
+    let anchors = pairs.into_iter().chain(std::iter::once((a.len(), b.len())));
+    for (ai, bj) in anchors {
+        for token in &a[i..ai] {
+            push(SpanOp::Delete, token);
+        }
+        for token in &b[j..bj] {
+            push(SpanOp::Insert, token);
+        }
+        if ai < a.len() {
+            push(SpanOp::Equal, a[ai]);
+        }
+        i = ai + 1;
+        j = bj + 1;
+    }
+
+    spans
+}
+
+fn tokenize(s: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut prev_ws = None;
+    for (idx, c) in s.char_indices() {
+        let ws = c.is_whitespace();
+        if prev_ws.is_some_and(|p| p != ws) {
+            tokens.push(&s[start..idx]);
+            start = idx;
+        }
+        prev_ws = Some(ws);
+    }
+    if start < s.len() {
+        tokens.push(&s[start..]);
+    }
+    tokens
+}
+
+/// Index pairs of a longest common subsequence of `a` and `b`, in order.
+///
+/// The common start and end are matched as they are; the rest takes a table
+/// of one cell per pair of items, so `None` when that would need more than
+/// `max_cells` of them.
+pub(crate) fn lcs_pairs<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    max_cells: usize,
+) -> Option<Vec<(usize, usize)>> {
+    let (prefix, suffix) = common_ends(a, b);
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if a_mid.len().saturating_mul(b_mid.len()) > max_cells {
+        return None;
+    }
+
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    pairs.extend(
+        table_pairs(a_mid, b_mid)
+            .into_iter()
+            .map(|(i, j)| (prefix + i, prefix + j)),
+    );
+    pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+    Some(pairs)
+}
+
+/// Lengths of the longest common prefix and suffix of `a` and `b`, which
+/// don't overlap.
+fn common_ends<T: PartialEq>(a: &[T], b: &[T]) -> (usize, usize) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    (prefix, suffix)
+}
+
+fn table_pairs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len(), b.len());
+    let mut table = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[at(i, j)] = if a[i] == b[j] {
+                table[at(i + 1, j + 1)] + 1
+            } else {
+                table[at(i + 1, j)].max(table[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[at(i + 1, j)] >= table[at(i, j + 1)] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(changes: &[ChunkChange]) -> Vec<ChangeKind> {
+        changes.iter().map(|c| c.kind).collect()
+    }
+
+    #[test]
+    fn test_identical_notes() {
+        let changes = diff_notes("# Title\n\nBody", "# Title\n\nBody");
+        assert_eq!(
+            kinds(&changes),
+            vec![ChangeKind::Unchanged, ChangeKind::Unchanged]
+        );
+    }
+
+    #[test]
+    fn test_added_and_removed_chunks() {
+        let changes = diff_notes("# Title\n\n---", "# Title\n\n- item");
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::Unchanged,
+                ChangeKind::Removed,
+                ChangeKind::Added
+            ]
+        );
+        assert_eq!(changes[2].new_index, Some(1));
+    }
+
+    #[test]
+    fn test_modified_chunk_has_word_diff() {
+        let changes = diff_notes("# Title\n\nThe quick fox", "# Title\n\nThe slow fox");
+        assert_eq!(
+            kinds(&changes),
+            vec![ChangeKind::Unchanged, ChangeKind::Modified]
+        );
+        assert_eq!(
+            changes[1].text,
+            vec![
+                TextSpan {
+                    op: SpanOp::Equal,
+                    text: "The ".into()
+                },
+                TextSpan {
+                    op: SpanOp::Delete,
+                    text: "quick".into()
+                },
+                TextSpan {
+                    op: SpanOp::Insert,
+                    text: "slow".into()
+                },
+                TextSpan {
+                    op: SpanOp::Equal,
+                    text: " fox".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inserted_chunk_before_modified() {
+        let changes = diff_notes("Intro\n\n# End", "Intro v2\n\n- new\n\n# End");
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::Modified,
+                ChangeKind::Added,
+                ChangeKind::Unchanged
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moved_chunks() {
+        let changes = diff_notes("# A\n\nFirst\n\nSecond", "# A\n\nSecond\n\nFirst");
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::Unchanged,
+                ChangeKind::Unchanged,
+                ChangeKind::Moved
+            ]
+        );
+        assert_eq!(changes[2].old_index, Some(1));
+        assert_eq!(changes[2].new_index, Some(2));
+        assert_eq!(changes[2].new_content.as_deref(), Some("First"));
+
+        // A moved paragraph isn't paired up as an edit of another one
+        let changes = diff_notes("Moved\n\n# H\n\nOld", "# H\n\nNew\n\nMoved");
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::Unchanged,
+                ChangeKind::Modified,
+                ChangeKind::Moved
+            ]
+        );
+        assert_eq!(changes[1].old_content.as_deref(), Some("Old"));
+        assert_eq!(changes[2].old_index, Some(0));
+
+        // Swapped around an anchor: two moves rather than two edits
+        let changes = diff_notes(
+            "Apple\n\n# H\n\n# I\n\nBanana",
+            "Banana\n\n# H\n\n# I\n\nApple",
+        );
+        assert_eq!(
+            kinds(&changes),
+            vec![
+                ChangeKind::Moved,
+                ChangeKind::Unchanged,
+                ChangeKind::Unchanged,
+                ChangeKind::Moved
+            ]
+        );
+        assert_eq!(changes[0].old_index, Some(3));
+    }
+
+    #[test]
+    fn test_text_spans_rebuild_both_sides() {
+        let old = "one two  three\nfour";
+        let new = "one 2 three\nfour five";
+        let spans = diff_text(old, new);
+        let rebuilt_old: String = spans
+            .iter()
+            .filter(|s| s.op != SpanOp::Insert)
+            .map(|s| s.text.as_str())
+            .collect();
+        let rebuilt_new: String = spans
+            .iter()
+            .filter(|s| s.op != SpanOp::Delete)
+            .map(|s| s.text.as_str())
+            .collect();
+        assert_eq!(rebuilt_old, old);
+        assert_eq!(rebuilt_new, new);
+    }
+
+    #[test]
+    fn test_large_notes_diff_without_alignment() {
+        let note = |word: &str| {
+            let body: Vec<String> = (0..3000).map(|i| format!("{} {}", word, i)).collect();
+            format!("# Title\n\n{}\n\n# End", body.join("\n\n"))
+        };
+        let changes = diff_notes(&note("old"), &note("new"));
+        assert_eq!(changes.len(), 6002);
+        assert_eq!(changes[0].kind, ChangeKind::Unchanged);
+        assert!(changes[1..3001]
+            .iter()
+            .all(|c| c.kind == ChangeKind::Removed));
+        assert!(changes[3001..6001]
+            .iter()
+            .all(|c| c.kind == ChangeKind::Added));
+        assert_eq!(changes[6001].kind, ChangeKind::Unchanged);
+        assert_eq!(changes[6001].old_index, Some(3001));
+    }
+}
@@ -0,0 +1,79 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::log;
+
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hand `message` to the configured email hook: a shell command that gets
+/// the message as JSON on stdin and is expected to deliver it. Without a
+/// hook the message is only logged at debug level, which is enough for
+/// local development.
+pub async fn send(hook: Option<&str>, message: Value) {
+    let Some(hook) = hook else {
+        log::warn(
+            "email hook not configured",
+            json!({ "kind": message["kind"] }),
+        );
+        log::debug("email not sent", message);
+        return;
+    };
+
+    if let Err(err) = run_hook(hook, &message).await {
+        log::error(
+            "email hook failed",
+            json!({ "kind": message["kind"], "error": err }),
+        );
+    }
+}
+
+async fn run_hook(hook: &str, message: &Value) -> Result<(), String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    stdin
+        .write_all(message.to_string().as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    drop(stdin);
+
+    let status = tokio::time::timeout(HOOK_TIMEOUT, child.wait())
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hook_receives_message_on_stdin() {
+        let path = std::env::temp_dir().join(format!("trame-email-{}.json", ulid::Ulid::new()));
+        let hook = format!("cat > {}", path.display());
+        let message = json!({ "kind": "test", "to": "a@example.com" });
+
+        run_hook(&hook, &message).await.unwrap();
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, message);
+        std::fs::remove_file(&path).ok();
+
+        assert!(run_hook("exit 3", &message).await.is_err());
+    }
+}
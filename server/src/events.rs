@@ -0,0 +1,54 @@
+//! In-process event bus. Listeners run on the publishing thread before
+//! `publish` returns, so they see changes before anyone can read them back.
+
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A user's note or its chunks changed, or the note went away.
+    NoteChanged { user_id: String },
+}
+
+type Listener = Box<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+pub struct EventBus {
+    listeners: RwLock<Vec<Listener>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self, listener: impl Fn(&Event) + Send + Sync + 'static) {
+        self.listeners.write().unwrap().push(Box::new(listener));
+    }
+
+    pub fn publish(&self, event: &Event) {
+        for listener in self.listeners.read().unwrap().iter() {
+            listener(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_publish_reaches_every_listener() {
+        let bus = EventBus::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        for name in ["cache", "audit"] {
+            let seen = seen.clone();
+            bus.subscribe(move |event| seen.lock().unwrap().push((name, event.clone())));
+        }
+
+        let event = Event::NoteChanged {
+            user_id: "user1".to_string(),
+        };
+        bus.publish(&event);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![("cache", event.clone()), ("audit", event)]
+        );
+    }
+}
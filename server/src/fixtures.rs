@@ -0,0 +1,568 @@
+//! API fixtures: with `RECORD_FIXTURES` set, every request and its response
+//! are written to that directory as a numbered JSON file, and [`replay`]
+//! sends a directory of them back through the router as a regression suite.
+//!
+//! Recordings are anonymized. Passwords, tokens, emails and ids are replaced
+//! by placeholders such as `<token-1>`, the same value always by the same
+//! placeholder. On replay a placeholder stands for whatever the server
+//! returned in its place, so a token from a signup response is the one sent
+//! by later requests, and timestamps match any other timestamp.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::router::{find_route, Router};
+use crate::AppState;
+
+/// Request headers kept in recordings; the rest are transport details.
+const RECORDED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "if-none-match",
+    "user-agent",
+    "x-trame-capabilities",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fixture {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: BTreeMap<String, String>,
+    /// JSON bodies as JSON, others as text, and `null` when empty.
+    pub body: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+/// One request and its response, as seen by the router.
+pub struct Exchange<'a> {
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub headers: &'a HeaderMap,
+    pub request_body: &'a [u8],
+    pub status: u16,
+    pub response_body: &'a [u8],
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    state: Mutex<(usize, Anonymizer)>,
+}
+
+impl Recorder {
+    /// Record into `dir`, which must be empty or missing: placeholders are
+    /// only consistent within one recording.
+    pub fn new(dir: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        if std::fs::read_dir(dir)?.next().is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} is not empty", dir),
+            ));
+        }
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            state: Mutex::new((0, Anonymizer::default())),
+        })
+    }
+
+    pub fn record(&self, exchange: &Exchange) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let (seq, anonymizer) = &mut *state;
+        *seq += 1;
+
+        let mut headers = BTreeMap::new();
+        for name in RECORDED_HEADERS {
+            if let Some(value) = exchange.headers.get(*name).and_then(|v| v.to_str().ok()) {
+                headers.insert(name.to_string(), anonymizer.header(name, value));
+            }
+        }
+        let uri = anonymizer.path(exchange.uri);
+        let request_body = anonymizer.value(None, body_value(exchange.request_body));
+        let fixture = Fixture {
+            request: RecordedRequest {
+                method: exchange.method.to_string(),
+                uri: uri.clone(),
+                headers,
+                body: request_body,
+            },
+            response: RecordedResponse {
+                status: exchange.status,
+                body: anonymizer.value(None, body_value(exchange.response_body)),
+            },
+        };
+
+        let slug: String = uri
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let name = format!(
+            "{:05}-{}-{}.json",
+            seq,
+            exchange.method.to_lowercase(),
+            slug.trim_matches('-')
+        );
+        let json = serde_json::to_string_pretty(&fixture).unwrap();
+        std::fs::write(self.dir.join(name), json + "\n")
+    }
+}
+
+/// A fixture whose replayed response differs from the recorded one.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub fixture: String,
+    pub reason: String,
+}
+
+/// Send the fixtures in `dir` through the router in file name order, and
+/// report each response that doesn't match its recording.
+pub async fn replay(state: Arc<AppState>, dir: &Path) -> std::io::Result<Vec<Mismatch>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    files.sort();
+
+    let mut bindings = Bindings::default();
+    let mut mismatches = Vec::new();
+    for file in files {
+        let name = file.file_name().unwrap().to_string_lossy().into_owned();
+        let fixture: Fixture = serde_json::from_str(&std::fs::read_to_string(&file)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        let request = &fixture.request;
+        let mut builder = Request::builder()
+            .method(request.method.as_str())
+            .uri(bindings.fill(&request.uri));
+        for (name, value) in &request.headers {
+            builder = builder.header(name, bindings.fill(value));
+        }
+        let body = match &request.body {
+            Value::Null => String::new(),
+            Value::String(text) => bindings.fill(text),
+            json => bindings.fill(&json.to_string()),
+        };
+        let mut req = builder.body(Full::new(Bytes::from(body))).unwrap();
+        // Stands in for the connection's peer address
+        req.extensions_mut()
+            .insert(SocketAddr::from(([127, 0, 0, 1], 0)));
+
+        let res = match Router::handle(req, state.clone()).await {
+            Ok(res) => res,
+            Err(err) => {
+                mismatches.push(Mismatch {
+                    fixture: name,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let status = res.status().as_u16();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+
+        let reason = if status != fixture.response.status {
+            Some(format!(
+                "status {}, expected {}",
+                status, fixture.response.status
+            ))
+        } else {
+            bindings
+                .compare("body", &fixture.response.body, &body_value(&body))
+                .err()
+        };
+        if let Some(reason) = reason {
+            mismatches.push(Mismatch {
+                fixture: name,
+                reason,
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+fn body_value(body: &[u8]) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+/// The placeholder kind for values under `key`, if they are sensitive or
+/// differ from run to run.
+fn sensitive_kind(key: &str) -> Option<&'static str> {
+    match key {
+        "password" | "current_password" | "new_password" => Some("password"),
+        "token" => Some("token"),
+        "token_prefix" => Some("token_prefix"),
+        "email" => Some("email"),
+        "secret" => Some("secret"),
+        "ip" => Some("ip"),
+        key if key == "id" || key.ends_with("_id") => Some("id"),
+        _ => None,
+    }
+}
+
+/// Replaces sensitive values with placeholders, consistently for a whole
+/// recording.
+#[derive(Default)]
+struct Anonymizer {
+    placeholders: HashMap<String, String>,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl Anonymizer {
+    fn placeholder(&mut self, kind: &'static str, value: &str) -> String {
+        if let Some(placeholder) = self.placeholders.get(value) {
+            return placeholder.clone();
+        }
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        let placeholder = format!("<{}-{}>", kind, count);
+        self.placeholders
+            .insert(value.to_string(), placeholder.clone());
+        placeholder
+    }
+
+    /// `text` with every value seen so far replaced, longest first so a
+    /// token is replaced before its prefix.
+    fn text(&self, text: &str) -> String {
+        let mut known: Vec<_> = self.placeholders.iter().collect();
+        known.sort_by_key(|(value, _)| std::cmp::Reverse(value.len()));
+        known
+            .into_iter()
+            .fold(text.to_string(), |text, (value, placeholder)| {
+                text.replace(value.as_str(), placeholder)
+            })
+    }
+
+    /// A path, with route parameters such as a hook's `:token` anonymized.
+    fn path(&mut self, uri: &str) -> String {
+        let path = uri.split('?').next().unwrap_or_default();
+        if let Some(route) = find_route(path) {
+            for (name, value) in &route.params {
+                if let Some(kind) = sensitive_kind(name) {
+                    self.placeholder(kind, value);
+                }
+            }
+        }
+        self.text(uri)
+    }
+
+    fn header(&mut self, name: &str, value: &str) -> String {
+        if name == "authorization" {
+            if let Some(token) = value.strip_prefix("Bearer ") {
+                return format!("Bearer {}", self.placeholder("token", token.trim()));
+            }
+        }
+        self.text(value)
+    }
+
+    fn value(&mut self, key: Option<&str>, value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(k, v)| {
+                        let v = self.value(Some(&k), v);
+                        (k, v)
+                    })
+                    .collect(),
+            ),
+            Value::Array(items) => {
+                Value::Array(items.into_iter().map(|v| self.value(key, v)).collect())
+            }
+            Value::String(s) => match key.and_then(sensitive_kind) {
+                Some(kind) if !s.is_empty() => Value::String(self.placeholder(kind, &s)),
+                _ if key == Some("url") => Value::String(self.path(&s)),
+                _ => Value::String(self.text(&s)),
+            },
+            other => other,
+        }
+    }
+}
+
+/// What each placeholder stood for in this replay.
+#[derive(Default)]
+struct Bindings {
+    values: HashMap<String, String>,
+}
+
+impl Bindings {
+    /// `template` with its placeholders replaced. Ones not seen yet, such as
+    /// the email a signup sends, get a made-up value of their kind.
+    fn fill(&mut self, template: &str) -> String {
+        split(template)
+            .into_iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.to_string(),
+                Part::Placeholder(placeholder) => self
+                    .values
+                    .entry(placeholder.to_string())
+                    .or_insert_with(|| {
+                        let name = placeholder.trim_matches(['<', '>']);
+                        match name.starts_with("email-") {
+                            true => format!("{}@example.com", name),
+                            false => name.to_string(),
+                        }
+                    })
+                    .clone(),
+            })
+            .collect()
+    }
+
+    /// Whether `actual` fits the `recorded` template, binding placeholders
+    /// seen for the first time.
+    fn matches(&mut self, recorded: &str, actual: &str) -> bool {
+        let parts = split(recorded);
+        let mut rest = actual;
+        let mut bound = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            match part {
+                Part::Literal(text) => match rest.strip_prefix(text) {
+                    Some(after) => rest = after,
+                    None => return false,
+                },
+                Part::Placeholder(placeholder) => {
+                    let value = match self.values.get(*placeholder) {
+                        Some(value) => value.clone(),
+                        // Runs up to the next literal, or to the end
+                        None => {
+                            let end = match parts.get(i + 1) {
+                                Some(Part::Literal(next)) => match rest.find(next) {
+                                    Some(end) => end,
+                                    None => return false,
+                                },
+                                _ => rest.len(),
+                            };
+                            let value = rest[..end].to_string();
+                            bound.push((placeholder.to_string(), value.clone()));
+                            value
+                        }
+                    };
+                    match rest.strip_prefix(value.as_str()) {
+                        Some(after) if !value.is_empty() => rest = after,
+                        _ => return false,
+                    }
+                }
+            }
+        }
+        if !rest.is_empty() {
+            return false;
+        }
+        self.values.extend(bound);
+        true
+    }
+
+    fn compare(&mut self, at: &str, recorded: &Value, actual: &Value) -> Result<(), String> {
+        match (recorded, actual) {
+            (Value::String(r), Value::String(a)) if is_timestamp(r) && is_timestamp(a) => Ok(()),
+            (Value::String(r), Value::String(a)) if self.matches(r, a) => Ok(()),
+            (Value::Object(r), Value::Object(a)) => {
+                if !r.keys().eq(a.keys()) {
+                    return Err(format!(
+                        "{}: keys {:?}, expected {:?}",
+                        at,
+                        a.keys().collect::<Vec<_>>(),
+                        r.keys().collect::<Vec<_>>()
+                    ));
+                }
+                r.iter().try_for_each(|(key, value)| {
+                    self.compare(&format!("{}.{}", at, key), value, &a[key])
+                })
+            }
+            (Value::Array(r), Value::Array(a)) if r.len() == a.len() => r
+                .iter()
+                .zip(a)
+                .enumerate()
+                .try_for_each(|(i, (r, a))| self.compare(&format!("{}[{}]", at, i), r, a)),
+            (r, a) if r == a => Ok(()),
+            (r, a) => Err(format!("{}: {}, expected {}", at, a, r)),
+        }
+    }
+}
+
+fn is_timestamp(s: &str) -> bool {
+    chrono::DateTime::parse_from_rfc3339(s).is_ok()
+}
+
+enum Part<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+/// `text` split into literal runs and `<kind-n>` placeholders.
+fn split(text: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..].find('>').map(|end| start + end + 1);
+        let Some(end) = end.filter(|&end| is_placeholder(&rest[start..end])) else {
+            parts.push(Part::Literal(&rest[..start + 1]));
+            rest = &rest[start + 1..];
+            continue;
+        };
+        if start > 0 {
+            parts.push(Part::Literal(&rest[..start]));
+        }
+        parts.push(Part::Placeholder(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest));
+    }
+    parts
+}
+
+fn is_placeholder(s: &str) -> bool {
+    let Some((kind, n)) = s
+        .strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .and_then(|s| s.rsplit_once('-'))
+    else {
+        return false;
+    };
+    !kind.is_empty()
+        && kind.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+        && !n.is_empty()
+        && n.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn state(record_fixtures: Option<&Path>) -> Arc<AppState> {
+        let mut config = Config::from_env().unwrap();
+        config.database_url = ":memory:".to_string();
+        config.shard_dir = None;
+        config.chaos = None;
+        config.terms_version = None;
+        config.record_fixtures = record_fixtures.map(|dir| dir.to_string_lossy().into_owned());
+        AppState::new(config).unwrap()
+    }
+
+    async fn send(
+        state: &Arc<AppState>,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> Value {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("user-agent", "trame-tests");
+        if let Some(token) = token {
+            req = req.header("authorization", format!("Bearer {}", token));
+        }
+        let mut req = req.body(Full::new(Bytes::from(body.to_string()))).unwrap();
+        req.extensions_mut()
+            .insert(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        let res = Router::handle(req, state.clone()).await.unwrap();
+        body_value(&res.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut bindings = Bindings::default();
+        assert!(bindings.matches("/hooks/<token-1>", "/hooks/abc123"));
+        assert_eq!(bindings.fill("Bearer <token-1>"), "Bearer abc123");
+        assert!(!bindings.matches("<token-1>", "other"));
+        assert_eq!(bindings.fill("<email-1>"), "email-1@example.com");
+        assert!(bindings.matches("a < b", "a < b"));
+
+        let recorded = serde_json::json!({ "id": "<id-1>", "at": "2026-01-01T00:00:00Z" });
+        let actual = serde_json::json!({ "id": "01J0", "at": "2026-10-16T01:02:03+00:00" });
+        assert_eq!(bindings.compare("body", &recorded, &actual), Ok(()));
+        assert_eq!(bindings.fill("/api/notes/<id-1>"), "/api/notes/01J0");
+        assert!(bindings
+            .compare("body", &recorded, &serde_json::json!({ "id": "01J0" }))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("trame-fixtures-{}", ulid::Ulid::new()));
+
+        let recording = state(Some(&dir));
+        let signup = send(
+            &recording,
+            "POST",
+            "/api/signup",
+            None,
+            r#"{"email":"alice@example.org","password":"correct horse"}"#,
+        )
+        .await;
+        let token = signup["token"].as_str().unwrap().to_string();
+        send(
+            &recording,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r##"{"content":"# Hello"}"##,
+        )
+        .await;
+        send(&recording, "GET", "/api/note", Some(&token), "").await;
+        let sessions = send(&recording, "GET", "/api/sessions", Some(&token), "").await;
+        assert_eq!(sessions["sessions"][0]["ip"], "127.0.0.1");
+        send(
+            &recording,
+            "POST",
+            "/api/login",
+            None,
+            r#"{"email":"alice@example.org","password":"wrong password"}"#,
+        )
+        .await;
+
+        let recorded: String = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        assert!(!recorded.contains("alice@example.org"));
+        assert!(!recorded.contains("correct horse"));
+        assert!(!recorded.contains(&token));
+        assert!(!recorded.contains("127.0.0.1"));
+        assert!(recorded.contains("Bearer <token-1>"));
+
+        let mismatches = replay(state(None), &dir).await.unwrap();
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+        // A changed response is reported
+        let first = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().contains("00003-get"))
+            .unwrap();
+        let edited = std::fs::read_to_string(&first)
+            .unwrap()
+            .replace("# Hello", "# Bye");
+        std::fs::write(&first, edited).unwrap();
+        let mismatches = replay(state(None), &dir).await.unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].fixture.starts_with("00003-get"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+//! Feature flags for rolling out experimental subsystems. A flag is on for
+//! everyone when listed in `FEATURE_FLAGS`, or for single accounts when an
+//! admin enables it for them.
+
+const MAX_FLAG_CHARS: usize = 64;
+
+/// Flag names are short lowercase slugs like `semantic-search`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_FLAG_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Flags in effect for a user: the instance-wide ones and their own, sorted
+/// and without duplicates.
+pub fn effective(global: &[String], user: &[String]) -> Vec<String> {
+    let mut flags: Vec<String> = global.iter().chain(user).cloned().collect();
+    flags.sort();
+    flags.dedup();
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("semantic-search"));
+        assert!(is_valid_name("crdt_sync2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("Semantic"));
+        assert!(!is_valid_name("a b"));
+        assert!(!is_valid_name(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn test_effective() {
+        let global = vec!["crdt-sync".to_string()];
+        let user = vec!["semantic-search".to_string(), "crdt-sync".to_string()];
+        assert_eq!(
+            effective(&global, &user),
+            vec!["crdt-sync", "semantic-search"]
+        );
+        assert!(effective(&[], &[]).is_empty());
+    }
+}
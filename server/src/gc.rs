@@ -0,0 +1,124 @@
+//! Background purge of expired sessions and other tokens, which would
+//! otherwise only be deleted when someone presents them, and of notes left
+//! in the trash past their retention, and thinning of note history. Key usage
+//! counted in memory is written down on the same schedule.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::time::MissedTickBehavior;
+
+use crate::db::Storage;
+use crate::log;
+use crate::usage::KeyUsage;
+
+static PURGED: AtomicU64 = AtomicU64::new(0);
+
+/// Expired tokens deleted since the process started.
+pub fn purged_total() -> u64 {
+    PURGED.load(Ordering::Relaxed)
+}
+
+/// How long what the purge deletes is kept.
+pub struct Retention {
+    /// Days trashed notes are kept; 0 keeps them.
+    pub trash_days: u64,
+    /// Of the revisions saved within this long, only the latest is kept;
+    /// zero keeps them all.
+    pub revision_interval: Duration,
+    /// Most revisions and chunk versions kept per note; 0 keeps them all.
+    pub history_limit: u32,
+}
+
+/// Purge now and then every `interval`, for as long as the process runs.
+pub async fn run(
+    db: Arc<dyn Storage>,
+    usage: Arc<KeyUsage>,
+    interval: Duration,
+    retention: Retention,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = usage.flush(&db).await {
+            log::warn(
+                "key usage not recorded",
+                json!({ "error": err.to_string() }),
+            );
+        }
+        purge(&db).await;
+        if retention.trash_days > 0 {
+            empty_trash(&db, retention.trash_days).await;
+        }
+        prune_history(&db, &retention).await;
+    }
+}
+
+async fn purge(db: &Arc<dyn Storage>) {
+    let now = chrono::Utc::now().to_rfc3339();
+    match db.run(move |db| db.purge_expired_tokens(&now)).await {
+        Ok(0) => {}
+        Ok(purged) => {
+            PURGED.fetch_add(purged, Ordering::Relaxed);
+            log::info("expired tokens purged", json!({ "count": purged }));
+        }
+        Err(err) => log::warn("token purge failed", json!({ "error": err.to_string() })),
+    }
+}
+
+async fn empty_trash(db: &Arc<dyn Storage>, retention_days: u64) {
+    let before = (chrono::Utc::now() - chrono::Duration::days(retention_days as i64)).to_rfc3339();
+    match db.run(move |db| db.purge_trash(&before)).await {
+        Ok(0) => {}
+        Ok(purged) => log::info("trashed notes purged", json!({ "count": purged })),
+        Err(err) => log::warn("trash purge failed", json!({ "error": err.to_string() })),
+    }
+}
+
+async fn prune_history(db: &Arc<dyn Storage>, retention: &Retention) {
+    let interval_secs = retention.revision_interval.as_secs();
+    let max_kept = retention.history_limit;
+    if interval_secs == 0 && max_kept == 0 {
+        return;
+    }
+    match db
+        .run(move |db| db.prune_history(interval_secs, max_kept))
+        .await
+    {
+        Ok(0) => {}
+        Ok(pruned) => log::info("note history pruned", json!({ "count": pruned })),
+        Err(err) => log::warn("history prune failed", json!({ "error": err.to_string() })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, Session};
+
+    #[tokio::test]
+    async fn test_purge_counts() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        db.create_session(&Session {
+            token: "old".to_string(),
+            user_id: "user1".to_string(),
+            expires_at: "2020-01-01T00:00:00Z".to_string(),
+            impersonator_id: None,
+            created_at: None,
+            user_agent: None,
+            ip: None,
+        })
+        .unwrap();
+
+        let db: Arc<dyn Storage> = Arc::new(db);
+        let before = purged_total();
+        purge(&db).await;
+        assert!(purged_total() > before);
+        assert!(db.get_session("old").unwrap().is_none());
+    }
+}
@@ -0,0 +1,170 @@
+//! GitHub Gist import: find the gist id in a URL, and turn the gist's files
+//! into Markdown with fenced, language-tagged code blocks.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// A gist as returned by `GET /gists/:id`, keeping only what the import uses.
+#[derive(Debug, Deserialize)]
+pub struct Gist {
+    pub description: Option<String>,
+    /// By filename, so files come out in a stable order.
+    pub files: BTreeMap<String, GistFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GistFile {
+    pub filename: String,
+    /// GitHub's name for the language, e.g. `Rust` or `C++`.
+    pub language: Option<String>,
+    pub content: Option<String>,
+    /// Set for files too large to be returned inline.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// The gist id in `https://gist.github.com/<user>/<id>`, the short form
+/// without the user, or a bare id.
+pub fn gist_id(url: &str) -> Option<String> {
+    let url = url.trim();
+    let path = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .map(|rest| rest.strip_prefix("gist.github.com/"))
+        .unwrap_or(Some(url))?;
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let id = path
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    (!id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())).then(|| id.to_lowercase())
+}
+
+/// The gist as Markdown: a heading with its description, then each file
+/// under its own heading in a code block tagged with its language.
+pub fn to_markdown(id: &str, gist: &Gist) -> String {
+    let title = gist
+        .description
+        .as_deref()
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("Gist {}", id));
+
+    let mut out = format!("# {}", title);
+    for file in gist.files.values() {
+        let content = file.content.as_deref().unwrap_or_default();
+        let fence = fence(content);
+        out.push_str(&format!(
+            "\n\n## {}\n\n{}{}\n{}\n{}",
+            file.filename,
+            fence,
+            fence_language(file),
+            content.trim_end_matches('\n'),
+            fence
+        ));
+    }
+    out
+}
+
+/// A code fence longer than any run of backticks in `content`, so fences
+/// inside it (a Markdown file, say) don't close the block early.
+fn fence(content: &str) -> String {
+    let longest = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or_default();
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Info string for a file's code fence, from GitHub's language name or else
+/// the file extension.
+fn fence_language(file: &GistFile) -> String {
+    match file.language.as_deref() {
+        Some("C++") => "cpp".to_string(),
+        Some("C#") => "csharp".to_string(),
+        Some("F#") => "fsharp".to_string(),
+        Some(language) => language.to_lowercase().replace(' ', "-"),
+        None => file
+            .filename
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gist_id() {
+        let id = Some("aa5a315d61ae9438b18d".to_string());
+        assert_eq!(
+            gist_id("https://gist.github.com/octocat/aa5a315d61ae9438b18d"),
+            id
+        );
+        assert_eq!(gist_id("https://gist.github.com/aa5a315d61ae9438b18d/"), id);
+        assert_eq!(
+            gist_id("https://gist.github.com/octocat/AA5A315D61AE9438B18D#file-hello-rs"),
+            id
+        );
+        assert_eq!(gist_id("aa5a315d61ae9438b18d"), id);
+        assert_eq!(
+            gist_id("https://github.com/octocat/aa5a315d61ae9438b18d"),
+            None
+        );
+        assert_eq!(gist_id("https://gist.github.com/octocat"), None);
+        assert_eq!(gist_id(""), None);
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        let gist: Gist = serde_json::from_str(
+            r#"{
+                "description": "Hello world",
+                "files": {
+                    "main.rs": {"filename": "main.rs", "language": "Rust", "content": "fn main() {}\n"},
+                    "Makefile": {"filename": "Makefile", "language": null, "content": "all:\n"},
+                    "a.cpp": {"filename": "a.cpp", "language": "C++", "content": "int x;"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            to_markdown("abc", &gist),
+            "# Hello world\n\n## Makefile\n\n```\nall:\n```\n\n## a.cpp\n\n```cpp\nint x;\n```\n\n## main.rs\n\n```rust\nfn main() {}\n```"
+        );
+
+        let untitled = Gist {
+            description: None,
+            files: BTreeMap::new(),
+        };
+        assert_eq!(to_markdown("abc", &untitled), "# Gist abc");
+    }
+
+    #[test]
+    fn test_to_markdown_fenced_content() {
+        let gist: Gist = serde_json::from_str(
+            r#"{
+                "description": "Notes",
+                "files": {
+                    "README.md": {"filename": "README.md", "language": "Markdown", "content": "Run:\n\n```sh\nmake\n```\n"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            to_markdown("abc", &gist),
+            "# Notes\n\n## README.md\n\n````markdown\nRun:\n\n```sh\nmake\n```\n````"
+        );
+        assert_eq!(fence("a `b` ``c``"), "```");
+        assert_eq!(fence("`````"), "``````");
+    }
+}
@@ -5,176 +5,3901 @@ use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use base64::Engine;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+use crate::capabilities::Capabilities;
+use crate::cards;
+use crate::chunker::{self, chunk_and_hash, has_unclosed_fence, parse_chunks, ChunkType};
+use crate::convert::{self, Format};
+use crate::db::metrics;
+use crate::db::{
+    session_key_id, Announcement, Attachment, AuditEntry, Card, Chunk, ExportToken, ExternalImport,
+    FocusSession, InboundHook, JournalEntry, Note, NoteGoal, Session, Share, Storage, StorageError,
+    KEY_USAGE_DAYS,
+};
+use crate::diff::{diff_notes, ChunkChange};
+use crate::email;
+use crate::flags;
+use crate::gc;
+use crate::gist::{self, Gist};
+use crate::hooks::{self, HookAction};
+use crate::ids;
+use crate::imports;
+use crate::info::RuntimeInfo;
+use crate::log;
+use crate::merge;
+use crate::render::{self, LangHint};
+use crate::review;
+use crate::stats;
 use crate::AppState;
 
+pub const SESSION_TTL_DAYS: i64 = 30;
+const PASSWORD_RESET_TTL_MINUTES: i64 = 60;
+const MAX_ANNOUNCEMENT_CHARS: usize = 2000;
+const MAX_HOOK_NAME_CHARS: usize = 100;
+const MAX_HOOK_TEMPLATE_CHARS: usize = 4000;
+const IMPERSONATION_TTL_MINUTES: i64 = 15;
+/// Session tokens are `trame_v1_` and 43 characters of URL-safe base64. The
+/// version lets a later token scheme be told apart while both are in use.
+const SESSION_TOKEN_PREFIX: &str = "trame_v1_";
+const TOKEN_VERSION_MARKER: &str = "trame_v";
+/// Export tokens are `trame_export_` and the same random part. They only
+/// open the export endpoint, never a session.
+const EXPORT_TOKEN_PREFIX: &str = "trame_export_";
+const MAX_EXPORT_TOKEN_NAME_CHARS: usize = 100;
+const SESSION_TOKEN_ATTEMPTS: usize = 3;
+/// Enough of a token to tell a user's sessions apart, too little to use it.
+const TOKEN_PREFIX_CHARS: usize = 8;
+const MAX_USER_AGENT_CHARS: usize = 512;
+const MAX_SOURCE_CHARS: usize = 50;
+const MAX_EXTERNAL_ID_CHARS: usize = 255;
+const MAX_AUDIT_ENTRIES: u32 = 100;
+const DEFAULT_REVIEW_CHUNKS: usize = 20;
+const MAX_REVIEW_CHUNKS: usize = 100;
+const DEFAULT_DUE_CARDS: u32 = 20;
+const MAX_DUE_CARDS: u32 = 100;
+const DEFAULT_HISTORY_VERSIONS: u32 = 50;
+const MAX_HISTORY_VERSIONS: u32 = 200;
+const TRASH_PREVIEW_CHARS: usize = 200;
+const MAX_SHARE_DAYS: u32 = 365;
+
 // Request/Response types
 #[derive(Deserialize)]
 pub struct SignupRequest {
     pub email: String,
     pub password: String,
+    /// Terms version the user agreed to; required when `TERMS_VERSION` is set.
+    pub accepted_terms: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct AuthResponse {
+    pub token: String,
+}
+
+/// Who is on the other end of a request, recorded with the sessions it opens.
+#[derive(Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub token_prefix: String,
+    /// The session making this request.
+    pub current: bool,
+    /// An admin's read-only support session.
+    pub impersonated: bool,
+    pub created_at: Option<String>,
+    pub expires_at: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionsResponse {
+    pub sessions: Vec<SessionResponse>,
+}
+
+#[derive(Serialize)]
+pub struct NoteResponse {
+    pub id: String,
+    pub content: String,
+    pub updated_at: String,
+    pub meta: serde_json::Value,
+    /// `journal` for notes that only take appends, else `standard`.
+    pub mode: &'static str,
+    pub numbered_headings: bool,
+}
+
+#[derive(Serialize)]
+pub struct RenameResponse {
+    #[serde(flatten)]
+    pub note: NoteResponse,
+    pub links_updated: usize,
+}
+
+#[derive(Serialize)]
+pub struct AppendResponse {
+    #[serde(flatten)]
+    pub note: NoteResponse,
+    /// The entry recorded, for journal notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<JournalEntryResponse>,
+}
+
+#[derive(Serialize)]
+pub struct JournalEntryResponse {
+    pub id: String,
+    pub created_at: String,
+    pub content: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    /// Chunks that start inside the entry.
+    pub chunk_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct JournalEntriesResponse {
+    pub entries: Vec<JournalEntryResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TrashedNoteResponse {
+    pub id: String,
+    /// First heading, if the note has one.
+    pub title: Option<String>,
+    /// The start of the note's content.
+    pub preview: String,
+    pub updated_at: String,
+    pub deleted_at: String,
+    /// When the note will be deleted for good, if the trash is emptied.
+    pub purge_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TrashResponse {
+    pub notes: Vec<TrashedNoteResponse>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkResponse {
+    pub id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub content: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct ChunkPreview {
+    pub chunk_type: String,
+    pub heading_level: Option<u8>,
+    pub list_depth: Option<u8>,
+    pub content: String,
+    pub content_hash: String,
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+#[derive(Serialize)]
+pub struct PreviewResponse {
+    pub chunks: Vec<ChunkPreview>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkVersionResponse {
+    pub id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub heading_level: Option<i32>,
+    pub content: String,
+    pub content_hash: String,
+    pub created_at: String,
+    pub replaced_at: String,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    pub note_id: String,
+    pub versions: Vec<ChunkVersionResponse>,
+}
+
+#[derive(Serialize)]
+pub struct SearchHitResponse {
+    pub chunk_id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub snippet: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+}
+
+#[derive(Serialize)]
+pub struct ReviewChunkResponse {
+    pub id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub content: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub updated_at: String,
+    pub days_stale: i64,
+    pub tags: Vec<String>,
+    pub score: f64,
+}
+
+#[derive(Serialize)]
+pub struct ReviewResponse {
+    pub after_days: u64,
+    pub chunks: Vec<ReviewChunkResponse>,
+}
+
+#[derive(Serialize)]
+pub struct CardResponse {
+    pub id: String,
+    pub chunk_id: String,
+    pub question: String,
+    pub answer: String,
+    pub ease: f64,
+    pub interval_days: i64,
+    pub repetitions: i64,
+    pub due_at: String,
+    pub last_reviewed_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DueCardsResponse {
+    pub cards: Vec<CardResponse>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchHitResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TagResponse {
+    pub tag: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct TagsResponse {
+    pub tags: Vec<TagResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TaggedChunksResponse {
+    pub tag: String,
+    pub chunks: Vec<ChunkResponse>,
+}
+
+#[derive(Serialize)]
+pub struct BacklinksResponse {
+    pub chunk_id: String,
+    pub backlinks: Vec<ChunkResponse>,
+}
+
+#[derive(Serialize)]
+pub struct RevisionSummary {
+    pub id: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct RevisionsResponse {
+    pub note_id: String,
+    pub revisions: Vec<RevisionSummary>,
+}
+
+#[derive(Serialize)]
+pub struct DiffResponse {
+    pub from: String,
+    pub to: String,
+    pub changes: Vec<ChunkChange>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetConfirmRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct NoteExport {
+    pub id: String,
+    pub content: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub meta: serde_json::Value,
+    pub word_goal: Option<u32>,
+    pub daily_word_goal: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct RevisionExport {
+    pub id: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct AccountExport {
+    pub email: String,
+    pub created_at: String,
+    pub exported_at: String,
+    pub note: NoteExport,
+    pub revisions: Vec<RevisionExport>,
+}
+
+#[derive(Serialize)]
+pub struct LimitUsage {
+    pub name: &'static str,
+    /// `None` when the instance doesn't cap this resource.
+    pub limit: Option<u64>,
+    pub used: u64,
+}
+
+#[derive(Serialize)]
+pub struct LimitsResponse {
+    pub limits: Vec<LimitUsage>,
+}
+
+#[derive(Serialize)]
+pub struct ExportedChunk {
+    pub id: String,
+    pub sequence: i32,
+    pub chunk_type: String,
+    pub heading_level: Option<i32>,
+    pub content: String,
+    pub content_hash: String,
+    pub start_offset: i32,
+    pub end_offset: i32,
+    pub created_at: String,
+    pub updated_at: String,
+    pub pinned: bool,
+}
+
+/// The JSON note export, also accepted back by the import endpoint.
+#[derive(Serialize)]
+pub struct NoteDocument {
+    pub format: &'static str,
+    pub version: u32,
+    pub id: String,
+    pub content: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub exported_at: String,
+    pub chunks: Vec<ExportedChunk>,
+}
+
+/// The part of a JSON note export that import reads back.
+#[derive(Deserialize)]
+pub struct ImportedDocument {
+    pub format: String,
+    pub content: String,
+}
+
+/// A response body meant to be saved as a file rather than read by the app.
+pub struct Download {
+    pub content_type: &'static str,
+    pub filename: String,
+    pub body: String,
+}
+
+#[derive(Serialize)]
+pub struct AttachmentResponse {
+    pub id: String,
+    /// Where to fetch the file, for embedding in the note.
+    pub url: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    pub created_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementRequest {
+    pub message: String,
+    pub level: Option<String>,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AnnouncementResponse {
+    pub id: String,
+    pub message: String,
+    pub level: String,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct AnnouncementsResponse {
+    pub announcements: Vec<AnnouncementResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateHookRequest {
+    pub name: String,
+    pub action: String,
+    pub template: String,
+}
+
+#[derive(Serialize)]
+pub struct HookResponse {
+    pub id: String,
+    pub name: String,
+    pub action: String,
+    pub template: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    /// Where to post, only returned when the hook is created. The token in
+    /// it is stored hashed and can't be shown again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HooksResponse {
+    pub hooks: Vec<HookResponse>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateExportTokenRequest {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct ExportTokenResponse {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    /// Only returned when the token is created; it is stored hashed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ExportTokensResponse {
+    pub tokens: Vec<ExportTokenResponse>,
+}
+
+#[derive(Serialize)]
+pub struct DailyUsageResponse {
+    pub day: String,
+    pub requests: i64,
+}
+
+#[derive(Serialize)]
+pub struct KeyUsageResponse {
+    pub id: String,
+    /// `session` or `export_token`.
+    pub kind: &'static str,
+    /// Requests over the days listed.
+    pub requests: i64,
+    pub last_used_at: Option<String>,
+    /// UTC days with requests, oldest first.
+    pub days: Vec<DailyUsageResponse>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct CreateShareRequest {
+    /// Days until the link stops working; never if unset.
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct ShareResponse {
+    pub id: String,
+    pub note_id: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_viewed_at: Option<String>,
+    /// The public link, only returned when the share is created. The token
+    /// in it is stored hashed and can't be shown again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SharesResponse {
+    pub shares: Vec<ShareResponse>,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: &'static str,
+    pub wal_bytes: Option<u64>,
+    pub active_sessions: i64,
+    /// Expired tokens the background purge has deleted since startup.
+    pub expired_tokens_purged: u64,
+    /// Note cache lookups since startup, absent when `NOTE_CACHE_SIZE=0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note_cache_hits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note_cache_misses: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ImpersonateRequest {
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct ImpersonateResponse {
+    pub token: String,
+    pub user_id: String,
+    pub expires_at: String,
+    pub read_only: bool,
+}
+
+#[derive(Serialize)]
+pub struct AuditEntryResponse {
+    pub id: String,
+    pub actor_id: String,
+    pub action: String,
+    pub target_user_id: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct AuditLogResponse {
+    pub entries: Vec<AuditEntryResponse>,
+}
+
+#[derive(Serialize)]
+pub struct AdminInfoResponse {
+    #[serde(flatten)]
+    pub info: RuntimeInfo,
+    pub started_at: String,
+    pub uptime_secs: i64,
+}
+
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    pub database: DbMetricsResponse,
+}
+
+#[derive(Serialize)]
+pub struct DbMetricsResponse {
+    pub slow_query_ms: u64,
+    /// Statements logged as slow since startup.
+    pub slow_queries: u64,
+    /// Most time spent first.
+    pub methods: Vec<MethodMetrics>,
+}
+
+#[derive(Serialize)]
+pub struct MethodMetrics {
+    pub method: &'static str,
+    pub calls: u64,
+    pub errors: u64,
+    pub total_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct FlagsResponse {
+    pub flags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserFlagsResponse {
+    pub user_id: String,
+    /// Enabled for this account.
+    pub flags: Vec<String>,
+    /// Enabled for every account by `FEATURE_FLAGS`.
+    pub global: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AcceptTermsRequest {
+    pub version: String,
+}
+
+#[derive(Serialize)]
+pub struct TermsResponse {
+    pub version: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct AuthInfo {
+    pub user_id: String,
+    /// Set when an admin is acting as this user.
+    pub impersonator_id: Option<String>,
+    /// What the request is counted under: the session's [`session_key_id`],
+    /// or the export token's id.
+    pub key_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateNoteRequest {
+    pub content: String,
+    /// `updated_at` of the note the client edited. When the note has been
+    /// saved since, the changes are merged instead of overwriting it.
+    pub base_updated_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameHeadingRequest {
+    pub from: String,
+    pub to: String,
+    /// Also point `[[from]]` links at the new name. Defaults to true.
+    pub rewrite_links: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ConvertRequest {
+    pub text: String,
+    /// `csv`, `tsv` or `html`; guessed from the text when absent.
+    pub from: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ConvertResponse {
+    pub from: &'static str,
+    pub markdown: String,
+}
+
+#[derive(Deserialize)]
+pub struct ToggleTaskRequest {
+    pub index: usize,
+}
+
+#[derive(Deserialize)]
+pub struct PinChunkRequest {
+    pub pinned: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NoteModeRequest {
+    pub mode: String,
+}
+
+#[derive(Deserialize)]
+pub struct NumberingRequest {
+    pub numbered: bool,
+}
+
+#[derive(Deserialize)]
+pub struct GradeRequest {
+    pub grade: u8,
+}
+
+#[derive(Deserialize)]
+pub struct AppendRequest {
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateGoalRequest {
+    pub word_goal: Option<u32>,
+    pub daily_word_goal: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct FocusRequest {
+    pub action: String,
+    pub note_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FocusSessionResponse {
+    pub id: String,
+    pub note_id: String,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub duration_seconds: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct FocusNoteTotal {
+    pub note_id: String,
+    pub sessions: i64,
+    pub total_seconds: i64,
+    pub today_seconds: i64,
+}
+
+#[derive(Serialize)]
+pub struct FocusReportResponse {
+    pub active: Option<FocusSessionResponse>,
+    pub total_seconds: i64,
+    pub today_seconds: i64,
+    pub notes: Vec<FocusNoteTotal>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+/// A non-fatal problem the client may want to surface, such as content the
+/// server had to interpret leniently.
+#[derive(Debug, Serialize)]
+pub struct Warning {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Wraps a response with a `warnings` array next to its fields. The array is
+/// left out when empty, so clients that don't look for it see no change.
+#[derive(Serialize)]
+pub struct WithWarnings<T> {
+    #[serde(flatten)]
+    pub data: T,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+}
+
+// Handlers
+pub async fn signup(
+    state: &Arc<AppState>,
+    body: &str,
+    client: &ClientInfo,
+) -> Result<String, (u16, String)> {
+    let req: SignupRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    // Validate
+    if req.email.is_empty() || !req.email.contains('@') {
+        return Err((400, json_error("Invalid email")));
+    }
+    validate_password(&req.password)?;
+    if let Some(current) = &state.config.terms_version {
+        if req.accepted_terms.as_deref() != Some(current) {
+            return Err((
+                400,
+                terms_error(state, "Accept the current terms to sign up"),
+            ));
+        }
+    }
+
+    // Check if user exists
+    let email = req.email.clone();
+    if state
+        .db
+        .run(move |db| db.get_user_by_email(&email))
+        .await
+        .map_err(db_error)?
+        .is_some()
+    {
+        return Err((409, json_error("Email already registered")));
+    }
+
+    let password_hash = hash_password(&req.password)?;
+
+    // Create user and session
+    let user_id = ids::new_id();
+    let expires_at = (chrono::Utc::now() + chrono::Duration::days(SESSION_TTL_DAYS)).to_rfc3339();
+    let session = new_session(&user_id, &expires_at, client);
+    let accepted_terms = state.config.terms_version.clone();
+    let token = state
+        .db
+        .run(move |db| {
+            db.create_user(&user_id, &req.email, &password_hash)?;
+            if let Some(version) = &accepted_terms {
+                db.accept_terms(&user_id, version)?;
+            }
+            open_session(db, &session)
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&AuthResponse { token }).unwrap())
+}
+
+pub async fn login(
+    state: &Arc<AppState>,
+    body: &str,
+    client: &ClientInfo,
+) -> Result<String, (u16, String)> {
+    let req: LoginRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    // Get user
+    let email = req.email.clone();
+    let user = state
+        .db
+        .run(move |db| db.get_user_by_email(&email))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("User not found")))?;
+
+    verify_password(&req.password, &user.password_hash)?;
+
+    // Create session
+    let expires_at = (chrono::Utc::now() + chrono::Duration::days(SESSION_TTL_DAYS)).to_rfc3339();
+    let session = new_session(&user.id, &expires_at, client);
+    let token = state
+        .db
+        .run(move |db| open_session(db, &session))
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&AuthResponse { token }).unwrap())
+}
+
+/// The user's active sessions, so they can spot ones they don't recognize.
+pub async fn list_sessions(
+    state: &Arc<AppState>,
+    user_id: &str,
+    current_token: &str,
+) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let sessions = state
+        .db
+        .run(move |db| db.list_sessions(&user_id, &now))
+        .await
+        .map_err(db_error)?;
+    Ok(serde_json::to_string(&SessionsResponse {
+        sessions: sessions
+            .into_iter()
+            .map(|session| SessionResponse {
+                token_prefix: token_prefix(&session.token),
+                current: session.token == current_token,
+                impersonated: session.impersonator_id.is_some(),
+                created_at: session.created_at,
+                expires_at: session.expires_at,
+                user_agent: session.user_agent,
+                ip: session.ip,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Sign out the session whose token starts with `prefix`, as shown by
+/// [`list_sessions`].
+pub async fn revoke_session(
+    state: &Arc<AppState>,
+    user_id: &str,
+    prefix: &str,
+) -> Result<String, (u16, String)> {
+    if prefix.chars().count() < TOKEN_PREFIX_CHARS {
+        return Err((
+            400,
+            json_error(&format!(
+                "token_prefix must be at least {} characters",
+                TOKEN_PREFIX_CHARS
+            )),
+        ));
+    }
+    let token = find_session(state, user_id, prefix).await?.token;
+    state
+        .db
+        .run(move |db| db.delete_session(&token))
+        .await
+        .map_err(db_error)?;
+    Ok("{}".to_string())
+}
+
+/// The user's one active session whose token starts with `prefix`.
+async fn find_session(
+    state: &Arc<AppState>,
+    user_id: &str,
+    prefix: &str,
+) -> Result<Session, (u16, String)> {
+    let user_id = user_id.to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let sessions = state
+        .db
+        .run(move |db| db.list_sessions(&user_id, &now))
+        .await
+        .map_err(db_error)?;
+    let mut matching = sessions
+        .into_iter()
+        .filter(|s| unversioned(&s.token).starts_with(prefix));
+    match (matching.next(), matching.next()) {
+        (Some(session), None) => Ok(session),
+        (None, _) => Err((404, json_error("Session not found"))),
+        (Some(_), Some(_)) => Err((
+            409,
+            json_error("token_prefix matches more than one session"),
+        )),
+    }
+}
+
+/// Daily request counts for one of the user's keys: a session, by the
+/// `token_prefix` [`list_sessions`] shows, or an export token, by id. Lets
+/// users find the script or device behind unexpected traffic.
+pub async fn get_key_usage(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<String, (u16, String)> {
+    let owner = user_id.to_string();
+    let tokens = state
+        .db
+        .run(move |db| db.list_export_tokens(&owner))
+        .await
+        .map_err(db_error)?;
+    let (kind, key_id) = if tokens.iter().any(|token| token.id == id) {
+        ("export_token", id.to_string())
+    } else if id.chars().count() >= TOKEN_PREFIX_CHARS {
+        match find_session(state, user_id, id).await {
+            Ok(session) => ("session", session_key_id(&session.token)),
+            Err((404, _)) => return Err((404, json_error("Key not found"))),
+            Err(err) => return Err(err),
+        }
+    } else {
+        return Err((404, json_error("Key not found")));
+    };
+
+    let since = (chrono::Utc::now() - chrono::Duration::days(KEY_USAGE_DAYS - 1))
+        .format("%Y-%m-%d")
+        .to_string();
+    // Include requests counted since the last flush
+    state.key_usage.flush(&state.db).await.map_err(db_error)?;
+    let days = state
+        .db
+        .run(move |db| db.get_key_usage(&key_id, &since))
+        .await
+        .map_err(db_error)?;
+    Ok(serde_json::to_string(&KeyUsageResponse {
+        id: id.to_string(),
+        kind,
+        requests: days.iter().map(|day| day.requests).sum(),
+        last_used_at: days.last().map(|day| day.last_used_at.clone()),
+        days: days
+            .into_iter()
+            .map(|day| DailyUsageResponse {
+                day: day.day,
+                requests: day.requests,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Sign out every session of the user, this one included.
+pub async fn revoke_all_sessions(
+    state: &Arc<AppState>,
+    user_id: &str,
+) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    state
+        .db
+        .run(move |db| db.delete_user_sessions(&user_id, None))
+        .await
+        .map_err(db_error)?;
+    Ok("{}".to_string())
+}
+
+pub async fn logout(state: &Arc<AppState>, token: &str) -> Result<String, (u16, String)> {
+    let token = token.to_string();
+    state
+        .db
+        .run(move |db| db.delete_session(&token))
+        .await
+        .map_err(db_error)?;
+    Ok("{}".to_string())
+}
+
+/// Change the password of the signed-in user. Other sessions are signed
+/// out; the one making the request stays valid.
+pub async fn change_password(
+    state: &Arc<AppState>,
+    user_id: &str,
+    token: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: ChangePasswordRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    validate_password(&req.new_password)?;
+
+    let id = user_id.to_string();
+    let user = state
+        .db
+        .run(move |db| db.get_user(&id))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("User not found")))?;
+    verify_password(&req.current_password, &user.password_hash)?;
+
+    let password_hash = hash_password(&req.new_password)?;
+    let token = token.to_string();
+    state
+        .db
+        .run(move |db| {
+            db.set_password_hash(&user.id, &password_hash)?;
+            db.delete_user_sessions(&user.id, Some(&token))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok("{}".to_string())
+}
+
+/// Start a password reset. The token goes out through the email hook; the
+/// response is the same whether or not the email belongs to an account.
+pub async fn request_password_reset(
+    state: &Arc<AppState>,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: ResetRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let email = req.email.clone();
+    let user = state
+        .db
+        .run(move |db| db.get_user_by_email(&email))
+        .await
+        .map_err(db_error)?;
+    let Some(user) = user else {
+        return Ok("{}".to_string());
+    };
+
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    let expires_at =
+        (chrono::Utc::now() + chrono::Duration::minutes(PASSWORD_RESET_TTL_MINUTES)).to_rfc3339();
+    let (user_id, expiry) = (user.id.clone(), expires_at.clone());
+    state
+        .db
+        .run(move |db| db.create_password_reset(&token_hash, &user_id, &expiry))
+        .await
+        .map_err(db_error)?;
+
+    // Send in the background so response timing doesn't reveal whether the
+    // account exists
+    let hook = state.config.email_hook.clone();
+    let message = serde_json::json!({
+        "kind": "password_reset",
+        "to": user.email,
+        "subject": "Reset your Trame password",
+        "text": format!(
+            "Use this token to choose a new password: {}\n\nIt expires at {}. If you didn't ask for a reset, ignore this email.",
+            token, expires_at
+        ),
+        "token": token,
+        "expires_at": expires_at,
+    });
+    tokio::spawn(async move { email::send(hook.as_deref(), message).await });
+
+    Ok("{}".to_string())
+}
+
+/// Finish a password reset with the emailed token. Every session of the
+/// account is signed out.
+pub async fn confirm_password_reset(
+    state: &Arc<AppState>,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: ResetConfirmRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    validate_password(&req.new_password)?;
+
+    let password_hash = hash_password(&req.new_password)?;
+    let token_hash = hash_token(&req.token);
+    let reset = state
+        .db
+        .run(move |db| {
+            let Some(user_id) = db.consume_password_reset(&token_hash)? else {
+                return Ok(false);
+            };
+            db.set_password_hash(&user_id, &password_hash)?;
+            db.delete_user_sessions(&user_id, None)?;
+            Ok(true)
+        })
+        .await
+        .map_err(db_error)?;
+
+    if !reset {
+        return Err((400, json_error("Invalid or expired reset token")));
+    }
+    Ok("{}".to_string())
+}
+
+/// Delete the account after re-checking the password. The response is a
+/// final export of the note and its revisions, taken just before deletion.
+pub async fn delete_account(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: DeleteAccountRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let id = user_id.to_string();
+    let user = state
+        .db
+        .run(move |db| db.get_user(&id))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("User not found")))?;
+
+    verify_password(&req.password, &user.password_hash)?;
+
+    let export = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user.id)?;
+            let meta = db.get_note_meta(&user.id, &note.id)?;
+            let goal = db.get_note_goal(&user.id, &note.id)?;
+            let revisions = db.get_revisions(&user.id, &note.id)?;
+            db.delete_user(&user.id)?;
+
+            Ok(AccountExport {
+                email: user.email.clone(),
+                created_at: user.created_at.clone(),
+                exported_at: chrono::Utc::now().to_rfc3339(),
+                note: NoteExport {
+                    id: note.id,
+                    content: note.content,
+                    created_at: note.created_at,
+                    updated_at: note.updated_at,
+                    meta: parse_meta(meta.as_deref()),
+                    word_goal: goal.word_goal,
+                    daily_word_goal: goal.daily_word_goal,
+                },
+                revisions: revisions
+                    .into_iter()
+                    .map(|r| RevisionExport {
+                        id: r.id,
+                        content: r.content,
+                        created_at: r.created_at,
+                    })
+                    .collect(),
+            })
+        })
+        .await
+        .map_err(db_error)?;
+
+    log::info("account deleted", serde_json::json!({ "user_id": user_id }));
+    Ok(serde_json::to_string(&export).unwrap())
+}
+
+pub async fn get_note(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&WithWarnings {
+        warnings: note_warnings(&note.content),
+        data: NoteResponse {
+            id: note.id,
+            content: note.content,
+            updated_at: note.updated_at,
+            meta: parse_meta(meta.as_deref()),
+            mode: note_mode(note.journal),
+            numbered_headings: note.numbered_headings,
+        },
+    })
+    .unwrap())
+}
+
+/// Move the note to the trash. The next read of `/api/note` starts a new one.
+pub async fn trash_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    note_id: &str,
+) -> Result<String, (u16, String)> {
+    let (user_id, note_id) = (user_id.to_string(), note_id.to_string());
+    let now = chrono::Utc::now().to_rfc3339();
+    let trashed = state
+        .db
+        .run(move |db| db.trash_note(&user_id, &note_id, &now))
+        .await
+        .map_err(db_error)?;
+    if !trashed {
+        return Err((404, json_error("Note not found")));
+    }
+    Ok("{}".to_string())
+}
+
+pub async fn list_trash(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let notes = state
+        .db
+        .run(move |db| db.list_trash(&user_id))
+        .await
+        .map_err(db_error)?;
+
+    let retention_days = state.config.trash_retention_days;
+    let notes = notes
+        .into_iter()
+        .map(|note| {
+            let deleted_at = note.deleted_at.unwrap_or_default();
+            let purge_at = chrono::DateTime::parse_from_rfc3339(&deleted_at)
+                .ok()
+                .filter(|_| retention_days > 0)
+                .map(|at| (at + chrono::Duration::days(retention_days as i64)).to_rfc3339());
+            TrashedNoteResponse {
+                id: note.id,
+                title: note.title,
+                preview: note.content.chars().take(TRASH_PREVIEW_CHARS).collect(),
+                updated_at: note.updated_at,
+                deleted_at,
+                purge_at,
+            }
+        })
+        .collect();
+    Ok(serde_json::to_string(&TrashResponse { notes }).unwrap())
+}
+
+/// Bring a note back from the trash as the user's note. The note it
+/// replaces goes to the trash, unless it's empty.
+pub async fn restore_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    note_id: &str,
+) -> Result<String, (u16, String)> {
+    let (user_id, note_id) = (user_id.to_string(), note_id.to_string());
+    let now = chrono::Utc::now().to_rfc3339();
+    let restored = state
+        .db
+        .run(move |db| {
+            let Some(note) = db.restore_note(&user_id, &note_id, &now)? else {
+                return Ok(None);
+            };
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok(Some((note, meta)))
+        })
+        .await
+        .map_err(db_error)?;
+    let Some((note, meta)) = restored else {
+        return Err((404, json_error("Note not in trash")));
+    };
+
+    Ok(serde_json::to_string(&WithWarnings {
+        warnings: note_warnings(&note.content),
+        data: note_response(note, meta),
+    })
+    .unwrap())
+}
+
+/// Save the note. With `dry_run` nothing is stored; the response is the
+/// chunk list the content would produce, along with any warnings.
+pub async fn update_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+    dry_run: bool,
+) -> Result<String, (u16, String)> {
+    let req: UpdateNoteRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    if dry_run {
+        return Ok(serde_json::to_string(&WithWarnings {
+            warnings: note_warnings(&req.content),
+            data: PreviewResponse {
+                chunks: chunk_and_hash(&req.content)
+                    .into_iter()
+                    .map(|c| ChunkPreview {
+                        chunk_type: c.chunk.chunk_type.as_str().to_string(),
+                        heading_level: c.chunk.heading_level,
+                        list_depth: c.chunk.list_depth,
+                        content: c.chunk.content,
+                        content_hash: c.content_hash,
+                        start_offset: c.chunk.start_offset,
+                        end_offset: c.chunk.end_offset,
+                    })
+                    .collect(),
+            },
+        })
+        .unwrap());
+    }
+
+    if req
+        .base_updated_at
+        .as_deref()
+        .is_some_and(|at| chrono::DateTime::parse_from_rfc3339(at).is_err())
+    {
+        return Err((400, json_error("Invalid base_updated_at")));
+    }
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let (note, meta, merged) = state
+        .db
+        .run(move |db| loop {
+            let current = db.get_or_create_note(&user_id)?;
+            let merged = match &req.base_updated_at {
+                Some(base_at) if *base_at != current.updated_at => {
+                    // Merging against anything but the version edited would
+                    // duplicate or drop chunks
+                    let Some(base) = db.revision_at(&user_id, &current.id, base_at)? else {
+                        return Ok(Err((
+                            "unknown_base",
+                            "No saved version of the note matches base_updated_at",
+                            current.updated_at,
+                        )));
+                    };
+                    let Some(merged) =
+                        merge::merge_notes(&base.content, &current.content, &req.content)
+                    else {
+                        return Ok(Err((
+                            "merge_too_large",
+                            "Note changed since your version and is too large to merge",
+                            current.updated_at,
+                        )));
+                    };
+                    if !merged.conflicts.is_empty() {
+                        // Nothing is saved until the client resolves them
+                        return Ok(Ok((current, None, Some(merged))));
+                    }
+                    Some(merged)
+                }
+                _ => None,
+            };
+            let content = merged.as_ref().map_or(&req.content, |m| &m.content);
+            // Saved over the version merged with only, so a save that comes
+            // in meanwhile is merged in turn instead of overwritten
+            let note = match &req.base_updated_at {
+                None => db.update_note(&user_id, content)?,
+                Some(_) => match db.update_note_if(&user_id, content, &current.updated_at)? {
+                    Some(note) => note,
+                    None => continue,
+                },
+            };
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            return Ok(Ok((note, meta, merged)));
+        })
+        .await
+        .map_err(db_error)?
+        .map_err(|(code, msg, updated_at)| {
+            let body = serde_json::json!({
+                "error": msg,
+                "code": code,
+                "updated_at": updated_at,
+            });
+            (409, body.to_string())
+        })?;
+
+    if let Some(merged) = merged.filter(|m| !m.conflicts.is_empty()) {
+        let body = serde_json::json!({
+            "error": "Note changed on both sides since your version",
+            "code": "merge_conflict",
+            "content": merged.content,
+            "updated_at": note.updated_at,
+            "conflicts": merged.conflicts,
+        });
+        return Err((409, body.to_string()));
+    }
+    Ok(serde_json::to_string(&WithWarnings {
+        warnings: note_warnings(&note.content),
+        data: note_response(note, meta),
+    })
+    .unwrap())
+}
+
+fn note_response(note: Note, meta: Option<String>) -> NoteResponse {
+    NoteResponse {
+        id: note.id,
+        content: note.content,
+        updated_at: note.updated_at,
+        meta: parse_meta(meta.as_deref()),
+        mode: note_mode(note.journal),
+        numbered_headings: note.numbered_headings,
+    }
+}
+
+fn note_mode(journal: bool) -> &'static str {
+    if journal {
+        "journal"
+    } else {
+        "standard"
+    }
+}
+
+/// Journal notes only grow through `POST /api/note/append`, so every other
+/// way of writing the content checks here first.
+async fn reject_journal(state: &Arc<AppState>, user_id: &str) -> Result<(), (u16, String)> {
+    let user_id = user_id.to_string();
+    let note = state
+        .db
+        .run(move |db| db.get_or_create_note(&user_id))
+        .await
+        .map_err(db_error)?;
+    if note.journal {
+        return Err((
+            409,
+            json_error("This note is a journal; add to it with POST /api/note/append"),
+        ));
+    }
+    Ok(())
+}
+
+/// Import a Markdown file or a JSON export (see [`NoteDocument`]), replacing
+/// the note (`mode=replace`, the default) or adding it after the current
+/// content (`mode=append`). Saves like a regular update, so the previous
+/// content stays in the revisions.
+///
+/// With a `source` system and the document's `external_id` there, the import
+/// is appended the first time and replaced in place on later imports.
+pub async fn import_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    mode: Option<&str>,
+    source: Option<&str>,
+    external_id: Option<&str>,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let external = match (source, external_id) {
+        (None, None) => None,
+        (Some(source), Some(external_id)) => {
+            let valid_source = !source.is_empty()
+                && source.len() <= MAX_SOURCE_CHARS
+                && source
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid_source {
+                return Err((
+                    400,
+                    json_error("source must be lowercase letters, digits and '-'"),
+                ));
+            }
+            if external_id.is_empty() || external_id.chars().count() > MAX_EXTERNAL_ID_CHARS {
+                return Err((
+                    400,
+                    json_error(&format!(
+                        "external_id must be 1 to {} characters",
+                        MAX_EXTERNAL_ID_CHARS
+                    )),
+                ));
+            }
+            Some((source.to_string(), external_id.to_string()))
+        }
+        _ => return Err((400, json_error("source and external_id go together"))),
+    };
+    let append = match mode {
+        Some("replace") if external.is_some() => {
+            return Err((
+                400,
+                json_error("external_id imports can't replace the note"),
+            ))
+        }
+        None => external.is_some(),
+        Some("replace") => false,
+        Some("append") => true,
+        _ => return Err((400, json_error("mode must be replace or append"))),
+    };
+    let imported = match serde_json::from_str::<ImportedDocument>(body) {
+        Ok(doc) if doc.format == "trame-note" => doc.content,
+        Ok(_) => return Err((400, json_error("Unsupported export format"))),
+        Err(_) => body.to_string(),
+    };
+    let imported = imported
+        .trim_start_matches('\u{feff}')
+        .replace("\r\n", "\n");
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            if let Some((source, external_id)) = &external {
+                let previous = db.get_external_import(&user_id, source, external_id)?;
+                // Merged into the note as saved, so a save in between is kept
+                let note = update_note_with(db, &user_id, |current| {
+                    imports::merge(
+                        &current.content,
+                        previous.as_ref().map(|p| p.content.as_str()),
+                        &imported,
+                    )
+                })?;
+                db.save_external_import(&ExternalImport {
+                    id: previous.map(|p| p.id).unwrap_or_else(ids::new_id),
+                    user_id: user_id.clone(),
+                    note_id: note.id.clone(),
+                    source: source.clone(),
+                    external_id: external_id.clone(),
+                    content: imported.trim().to_string(),
+                    imported_at: chrono::Utc::now().to_rfc3339(),
+                })?;
+                let meta = db.get_note_meta(&user_id, &note.id)?;
+                return Ok((note, meta));
+            }
+
+            let note = if append {
+                // Appended to the note as saved, so a save in between is kept
+                update_note_with(db, &user_id, |current| match current.content.trim_end() {
+                    "" => imported.clone(),
+                    current => format!("{}\n\n{}", current, imported),
+                })?
+            } else {
+                db.update_note(&user_id, &imported)?
+            };
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&WithWarnings {
+        warnings: note_warnings(&note.content),
+        data: NoteResponse {
+            id: note.id,
+            content: note.content,
+            updated_at: note.updated_at,
+            meta: parse_meta(meta.as_deref()),
+            mode: note_mode(note.journal),
+            numbered_headings: note.numbered_headings,
+        },
+    })
+    .unwrap())
+}
+
+/// Fetch a GitHub Gist and add it to the end of the note, each file in a code
+/// block tagged with its language. Importing the same gist again updates it.
+pub async fn import_gist(
+    state: &Arc<AppState>,
+    user_id: &str,
+    url: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let id = url
+        .and_then(gist::gist_id)
+        .ok_or_else(|| (400, json_error("url must be a GitHub Gist URL")))?;
+
+    let api_url = format!("{}/gists/{}", state.config.github_api_url, id);
+    let fetch_failed = |err: reqwest::Error| {
+        log::warn(
+            "gist fetch failed",
+            serde_json::json!({ "gist_id": id, "error": err.to_string() }),
+        );
+        (502, json_error("Couldn't fetch the gist"))
+    };
+    let response = reqwest::Client::new()
+        .get(&api_url)
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", "trame")
+        .timeout(std::time::Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(fetch_failed)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err((404, json_error("Gist not found")));
+    }
+    let gist: Gist = response
+        .error_for_status()
+        .map_err(fetch_failed)?
+        .json()
+        .await
+        .map_err(fetch_failed)?;
+    if gist.files.values().any(|f| f.truncated) {
+        return Err((422, json_error("Gist has files too large to import")));
+    }
+
+    import_note(
+        state,
+        user_id,
+        None,
+        Some("github-gist"),
+        Some(&id),
+        &gist::to_markdown(&id, &gist),
+    )
+    .await
+}
+
+/// Rename a heading, the note's title if it's the first, and rewrite the
+/// `[[links]]` to it, all in one save.
+pub async fn rename_heading(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: RenameHeadingRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    let to = req.to.trim().to_string();
+    if to.is_empty() || to.contains(['[', ']', '\n']) {
+        return Err((
+            400,
+            json_error("to must be one line without square brackets"),
+        ));
+    }
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let rewrite_links = req.rewrite_links.unwrap_or(true);
+    let renamed = state
+        .db
+        .run(move |db| loop {
+            let note = db.get_or_create_note(&user_id)?;
+            let Some((content, links_updated)) =
+                chunker::rename_heading(&note.content, &req.from, &to, rewrite_links)
+            else {
+                return Ok(None);
+            };
+            // Renamed in the note read, so a save in between is renamed again
+            let Some(note) = db.update_note_if(&user_id, &content, &note.updated_at)? else {
+                continue;
+            };
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            return Ok(Some((note, meta, links_updated)));
+        })
+        .await
+        .map_err(db_error)?;
+
+    let (note, meta, links_updated) =
+        renamed.ok_or_else(|| (404, json_error("Heading not found")))?;
+    Ok(serde_json::to_string(&RenameResponse {
+        note: note_response(note, meta),
+        links_updated,
+    })
+    .unwrap())
+}
+
+/// Pasted CSV, TSV or HTML as the Markdown to insert: a table for rows, the
+/// text and structure for HTML. Nothing is saved.
+pub async fn convert(body: &str) -> Result<String, (u16, String)> {
+    let req: ConvertRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    let format = match req.from.as_deref() {
+        Some(name) => {
+            Format::parse(name).ok_or_else(|| (400, json_error("from must be csv, tsv or html")))?
+        }
+        None => Format::detect(&req.text),
+    };
+    Ok(serde_json::to_string(&ConvertResponse {
+        from: format.name(),
+        markdown: convert::to_markdown(&req.text, format),
+    })
+    .unwrap())
+}
+
+/// Flip the `index`-th checkbox of a task list chunk and save the note. Only
+/// that chunk's text changes; the response is the chunk as re-saved, whose
+/// id changes along with its content.
+pub async fn toggle_task(
+    state: &Arc<AppState>,
+    user_id: &str,
+    chunk_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: ToggleTaskRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let chunk_id = chunk_id.to_string();
+    let outcome = state
+        .db
+        .run(move |db| loop {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            let Some(chunk) = chunks.into_iter().find(|c| c.id == chunk_id) else {
+                return Ok(Err((404, "Chunk not found")));
+            };
+            if chunk.chunk_type != ChunkType::TaskList.as_str() {
+                return Ok(Err((400, "Chunk is not a task list")));
+            }
+
+            // Read apart from the note, the chunks can be from a later save;
+            // reading both again lines them up
+            let chars: Vec<char> = note.content.chars().collect();
+            let (start, end) = (chunk.start_offset as usize, chunk.end_offset as usize);
+            let (Some(before), Some(text), Some(after)) =
+                (chars.get(..start), chars.get(start..end), chars.get(end..))
+            else {
+                continue;
+            };
+            let text = String::from_iter(text);
+            if text.trim() != chunk.content.trim() {
+                continue;
+            }
+            let Some(toggled) = chunker::toggle_task(&text, req.index) else {
+                return Ok(Err((400, "No task at that index")));
+            };
+
+            let content = format!(
+                "{}{}{}",
+                String::from_iter(before),
+                toggled,
+                String::from_iter(after)
+            );
+            // Over the note read, so a save in between isn't overwritten
+            if db
+                .update_note_if(&user_id, &content, &note.updated_at)?
+                .is_none()
+            {
+                continue;
+            }
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            return Ok(Ok(chunks
+                .into_iter()
+                .find(|c| c.sequence == chunk.sequence)));
+        })
+        .await
+        .map_err(db_error)?;
+
+    let chunk = outcome
+        .map_err(|(code, msg)| (code, json_error(msg)))?
+        .ok_or_else(|| (404, json_error("Chunk not found")))?;
+    Ok(serde_json::to_string(&ChunkResponse {
+        id: chunk.id,
+        sequence: chunk.sequence,
+        chunk_type: chunk.chunk_type,
+        content: chunk.content,
+        start_offset: chunk.start_offset,
+        end_offset: chunk.end_offset,
+        pinned: chunk.pinned,
+    })
+    .unwrap())
+}
+
+/// Pin a chunk to the top of the note, or unpin it. Pinning saves the note
+/// with the chunk moved up, after any already pinned; later saves keep
+/// pinned chunks there, however the rest of the note grows.
+pub async fn pin_chunk(
+    state: &Arc<AppState>,
+    user_id: &str,
+    chunk_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: PinChunkRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let chunk_id = chunk_id.to_string();
+    let chunk = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            let Some(chunk) = chunks.into_iter().find(|c| c.id == chunk_id) else {
+                return Ok(None);
+            };
+            db.set_chunk_pinned(&user_id, &note.id, &chunk.id, req.pinned)?;
+            if req.pinned {
+                // Saved as it is now, which moves the chunk up; text saved
+                // since it was read stays
+                update_note_with(db, &user_id, |note| note.content.clone())?;
+            }
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            Ok(chunks
+                .into_iter()
+                .find(|c| c.content_hash == chunk.content_hash))
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("Chunk not found")))?;
+
+    Ok(serde_json::to_string(&ChunkResponse {
+        id: chunk.id,
+        sequence: chunk.sequence,
+        chunk_type: chunk.chunk_type,
+        content: chunk.content,
+        start_offset: chunk.start_offset,
+        end_offset: chunk.end_offset,
+        pinned: chunk.pinned,
+    })
+    .unwrap())
+}
+
+/// Switch the note to `journal` mode, where it only grows through
+/// `POST /api/note/append`. There's no way back: a journal keeps its
+/// history as written, and a fresh note can be started by trashing it.
+pub async fn set_note_mode(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: NoteModeRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    let journal = match req.mode.as_str() {
+        "journal" => true,
+        "standard" => false,
+        _ => return Err((400, json_error("mode must be standard or journal"))),
+    };
+
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            if journal && !note.journal {
+                db.enable_journal(&user_id, &note.id)?;
+            }
+            let note = db.get_or_create_note(&user_id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    if note.journal != journal {
+        return Err((409, json_error("A journal note can't go back to standard")));
+    }
+    Ok(serde_json::to_string(&note_response(note, meta)).unwrap())
+}
+
+/// Turn heading numbering on or off. While on, every save numbers the
+/// headings after the title by their place in the outline; turning it off
+/// takes the numbers back off.
+pub async fn set_numbering(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: NumberingRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.set_numbered_headings(&user_id, req.numbered)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&note_response(note, meta)).unwrap())
+}
+
+/// Add text as a new block at the end of the note. For journal notes, the
+/// only way to write to them, the text is also recorded as an entry
+/// timestamped by the server.
+pub async fn append_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    let req: AppendRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    if req.text.trim().is_empty() {
+        return Err((400, json_error("text must not be empty")));
+    }
+
+    let user_id = user_id.to_string();
+    let (note, meta, entry, chunks) = state
+        .db
+        .run(move |db| {
+            let current = db.get_or_create_note(&user_id)?;
+            let (note, entry) = if current.journal {
+                let (note, entry) = db.append_journal_entry(&user_id, &req.text)?;
+                (note, Some(entry))
+            } else {
+                let append = |note: &Note| HookAction::Append.apply(&note.content, &req.text);
+                (update_note_with(db, &user_id, append)?, None)
+            };
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            Ok((note, meta, entry, chunks))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let entry = entry.map(|e| entry_response(&note.content, &chunks, e, caps));
+    Ok(serde_json::to_string(&AppendResponse {
+        note: note_response(note, meta),
+        entry,
+    })
+    .unwrap())
+}
+
+/// The journal's entries, oldest first. Empty for standard notes.
+pub async fn list_entries(
+    state: &Arc<AppState>,
+    user_id: &str,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, entries, chunks) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let entries = db.list_journal_entries(&user_id, &note.id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            Ok((note, entries, chunks))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&JournalEntriesResponse {
+        entries: entries
+            .into_iter()
+            .map(|e| entry_response(&note.content, &chunks, e, caps))
+            .collect(),
+    })
+    .unwrap())
+}
+
+fn entry_response(
+    content: &str,
+    chunks: &[Chunk],
+    entry: JournalEntry,
+    caps: &Capabilities,
+) -> JournalEntryResponse {
+    let (start, end) = (entry.start_offset as usize, entry.end_offset as usize);
+    JournalEntryResponse {
+        id: entry.id,
+        created_at: entry.created_at,
+        content: content
+            .chars()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect(),
+        start_offset: caps.offset(content, entry.start_offset),
+        end_offset: caps.offset(content, entry.end_offset),
+        chunk_ids: chunks
+            .iter()
+            .filter(|c| (start..end).contains(&(c.start_offset as usize)))
+            .map(|c| c.id.clone())
+            .collect(),
+    }
+}
+
+/// Replace the note's metadata. The body must be a JSON object no larger
+/// than `MAX_META_BYTES` once serialized.
+pub async fn update_meta(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let meta: serde_json::Value =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    if !meta.is_object() {
+        return Err((400, json_error("Metadata must be a JSON object")));
+    }
+
+    let data = meta.to_string();
+    if data.len() > state.config.max_meta_bytes {
+        return Err((
+            413,
+            json_error(&format!(
+                "Metadata exceeds {} bytes",
+                state.config.max_meta_bytes
+            )),
+        ));
+    }
+
+    let user_id = user_id.to_string();
+    state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            db.set_note_meta(&user_id, &note.id, &data)
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(meta.to_string())
+}
+
+/// Previous versions of the note's chunks, the latest first, `limit` at a
+/// time; `before` is the last version of the page before.
+pub async fn get_history(
+    state: &Arc<AppState>,
+    user_id: &str,
+    before: Option<String>,
+    limit: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let limit = match limit {
+        Some(limit) => limit
+            .parse::<u32>()
+            .ok()
+            .filter(|l| (1..=MAX_HISTORY_VERSIONS).contains(l))
+            .ok_or_else(|| {
+                (
+                    400,
+                    json_error(&format!("limit must be 1 to {}", MAX_HISTORY_VERSIONS)),
+                )
+            })?,
+        None => DEFAULT_HISTORY_VERSIONS,
+    };
+
+    let user_id = user_id.to_string();
+    let (note, versions) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let versions = db.get_chunk_versions(&user_id, &note.id, before.as_deref(), limit)?;
+            Ok((note, versions))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&HistoryResponse {
+        note_id: note.id,
+        versions: versions
+            .into_iter()
+            .map(|v| ChunkVersionResponse {
+                id: v.id,
+                sequence: v.sequence,
+                chunk_type: v.chunk_type,
+                heading_level: v.heading_level,
+                content: v.content,
+                content_hash: v.content_hash,
+                created_at: v.created_at,
+                replaced_at: v.replaced_at,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Put an earlier version of a chunk back into the note. If the save that
+/// retired it also wrote the chunk now at its position, that chunk is the
+/// edit and is replaced; otherwise the version was removed and goes back in
+/// before that chunk, or at the end. The response is the restored chunk.
+pub async fn restore_chunk_version(
+    state: &Arc<AppState>,
+    user_id: &str,
+    version_id: &str,
+) -> Result<String, (u16, String)> {
+    reject_journal(state, user_id).await?;
+
+    let user_id = user_id.to_string();
+    let version_id = version_id.to_string();
+    let chunk = state
+        .db
+        .run(move |db| loop {
+            let note = db.get_or_create_note(&user_id)?;
+            let Some(version) = db.get_chunk_version(&user_id, &note.id, &version_id)? else {
+                return Ok(Ok(None));
+            };
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            let at = chunks.iter().find(|c| c.sequence == version.sequence);
+
+            // Read apart from the note, the chunks can be from a later save
+            // whose offsets don't fit it
+            let chars: Vec<char> = note.content.chars().collect();
+            let content = match at {
+                Some(edit) if edit.updated_at == version.replaced_at => {
+                    let (start, end) = (edit.start_offset as usize, edit.end_offset as usize);
+                    let (Some(before), Some(text), Some(after)) =
+                        (chars.get(..start), chars.get(start..end), chars.get(end..))
+                    else {
+                        return Ok(Err(()));
+                    };
+                    // The line break ending the chunk stays
+                    let text = String::from_iter(text);
+                    format!(
+                        "{}{}{}{}",
+                        String::from_iter(before),
+                        version.content,
+                        &text[text.trim_end().len()..],
+                        String::from_iter(after)
+                    )
+                }
+                Some(next) => {
+                    let start = next.start_offset as usize;
+                    let (Some(before), Some(after)) = (chars.get(..start), chars.get(start..))
+                    else {
+                        return Ok(Err(()));
+                    };
+                    format!(
+                        "{}{}\n\n{}",
+                        String::from_iter(before),
+                        version.content,
+                        String::from_iter(after)
+                    )
+                }
+                None => match note.content.trim_end() {
+                    "" => version.content.clone(),
+                    current => format!("{}\n\n{}", current, version.content),
+                },
+            };
+            // Over the note read, so a save in between isn't overwritten
+            if db
+                .update_note_if(&user_id, &content, &note.updated_at)?
+                .is_none()
+            {
+                continue;
+            }
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            return Ok(Ok(chunks
+                .into_iter()
+                .filter(|c| c.content == version.content)
+                .min_by_key(|c| (c.sequence - version.sequence).abs())));
+        })
+        .await
+        .map_err(db_error)?
+        .map_err(|()| (409, json_error("Note changed while restoring, try again")))?
+        .ok_or_else(|| (404, json_error("Version not found")))?;
+
+    Ok(serde_json::to_string(&ChunkResponse {
+        id: chunk.id,
+        sequence: chunk.sequence,
+        chunk_type: chunk.chunk_type,
+        content: chunk.content,
+        start_offset: chunk.start_offset,
+        end_offset: chunk.end_offset,
+        pinned: chunk.pinned,
+    })
+    .unwrap())
+}
+
+/// Search the note's chunks. Offsets are char positions in the note unless
+/// the client declared `utf16-offsets` or `byte-offsets`.
+pub async fn search(
+    state: &Arc<AppState>,
+    user_id: &str,
+    query: Option<&str>,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    let query = query
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .ok_or_else(|| (400, json_error("Missing search query")))?
+        .to_string();
+
+    let user_id = user_id.to_string();
+    let (note, hits) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let hits = db.search_chunks(&user_id, &note.id, &query, 50)?;
+            Ok((note, hits))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&SearchResponse {
+        results: hits
+            .into_iter()
+            .map(|h| SearchHitResponse {
+                chunk_id: h.chunk_id,
+                sequence: h.sequence,
+                chunk_type: h.chunk_type,
+                snippet: h.snippet,
+                start_offset: caps.chunk_offset(&note.content, h.start_offset, h.start_byte),
+                end_offset: caps.chunk_offset(&note.content, h.end_offset, h.end_byte),
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+pub async fn list_tags(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let tags = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            db.list_tags(&user_id, &note.id)
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&TagsResponse {
+        tags: tags
+            .into_iter()
+            .map(|t| TagResponse {
+                tag: t.tag,
+                count: t.count,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+pub async fn get_tag_chunks(
+    state: &Arc<AppState>,
+    user_id: &str,
+    tag: &str,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    // Tags are stored lowercased and without the `#`
+    let tag = tag.trim().trim_start_matches('#').to_lowercase();
+    if tag.is_empty() {
+        return Err((400, json_error("Missing tag")));
+    }
+
+    let user_id = user_id.to_string();
+    let lookup = tag.clone();
+    let (note, chunks) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_tagged_chunks(&user_id, &note.id, &lookup)?;
+            Ok((note, chunks))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&TaggedChunksResponse {
+        tag,
+        chunks: chunks
+            .into_iter()
+            .map(|c| ChunkResponse {
+                id: c.id,
+                sequence: c.sequence,
+                chunk_type: c.chunk_type,
+                start_offset: caps.chunk_offset(&note.content, c.start_offset, c.start_byte),
+                end_offset: caps.chunk_offset(&note.content, c.end_offset, c.end_byte),
+                content: c.content,
+                pinned: c.pinned,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+pub async fn backlinks(
+    state: &Arc<AppState>,
+    user_id: &str,
+    chunk_id: Option<&str>,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    let chunk_id = chunk_id
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| (400, json_error("Missing chunk_id")))?
+        .to_string();
+
+    let user_id = user_id.to_string();
+    let target = chunk_id.clone();
+    let (note, backlinks) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            if !db
+                .get_chunks(&user_id, &note.id)?
+                .iter()
+                .any(|c| c.id == target)
+            {
+                return Ok((note, None));
+            }
+            let backlinks = db.get_backlinks(&user_id, &note.id, &target)?;
+            Ok((note, Some(backlinks)))
+        })
+        .await
+        .map_err(db_error)?;
+    let backlinks = backlinks.ok_or_else(|| (404, json_error("Chunk not found")))?;
+
+    Ok(serde_json::to_string(&BacklinksResponse {
+        chunk_id,
+        backlinks: backlinks
+            .into_iter()
+            .map(|c| ChunkResponse {
+                id: c.id,
+                sequence: c.sequence,
+                chunk_type: c.chunk_type,
+                start_offset: caps.chunk_offset(&note.content, c.start_offset, c.start_byte),
+                end_offset: caps.chunk_offset(&note.content, c.end_offset, c.end_byte),
+                content: c.content,
+                pinned: c.pinned,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Chunks left unedited for `days` (by default `REVIEW_AFTER_DAYS`), the
+/// stalest first, with `weights` (`tag:weight,...`) raising or, at 0, hiding
+/// tagged chunks.
+pub async fn review_queue(
+    state: &Arc<AppState>,
+    user_id: &str,
+    days: Option<&str>,
+    limit: Option<&str>,
+    weights: Option<&str>,
+    caps: &Capabilities,
+) -> Result<String, (u16, String)> {
+    let after_days = match days {
+        Some(days) => days
+            .parse::<u64>()
+            .map_err(|_| (400, json_error("days must be a whole number")))?,
+        None => state.config.review_after_days,
+    };
+    let limit = match limit {
+        Some(limit) => limit
+            .parse::<usize>()
+            .ok()
+            .filter(|l| (1..=MAX_REVIEW_CHUNKS).contains(l))
+            .ok_or_else(|| {
+                (
+                    400,
+                    json_error(&format!("limit must be 1 to {}", MAX_REVIEW_CHUNKS)),
+                )
+            })?,
+        None => DEFAULT_REVIEW_CHUNKS,
+    };
+    let weights =
+        review::parse_weights(weights.unwrap_or_default()).map_err(|e| (400, json_error(&e)))?;
+
+    let user_id = user_id.to_string();
+    let (note, chunks) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            Ok((note, chunks))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let items = review::queue(chunks, chrono::Utc::now(), after_days, &weights, limit);
+    Ok(serde_json::to_string(&ReviewResponse {
+        after_days,
+        chunks: items
+            .into_iter()
+            .map(|item| {
+                let c = item.chunk;
+                ReviewChunkResponse {
+                    start_offset: caps.chunk_offset(&note.content, c.start_offset, c.start_byte),
+                    end_offset: caps.chunk_offset(&note.content, c.end_offset, c.end_byte),
+                    id: c.id,
+                    sequence: c.sequence,
+                    chunk_type: c.chunk_type,
+                    content: c.content,
+                    updated_at: c.updated_at,
+                    days_stale: item.days_stale,
+                    tags: item.tags,
+                    score: item.score,
+                }
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+fn card_response(card: Card) -> CardResponse {
+    CardResponse {
+        id: card.id,
+        chunk_id: card.chunk_id,
+        question: card.question,
+        answer: card.answer,
+        ease: card.ease,
+        interval_days: card.interval_days,
+        repetitions: card.repetitions,
+        due_at: card.due_at,
+        last_reviewed_at: card.last_reviewed_at,
+    }
+}
+
+/// Flashcards due for review, the longest overdue first.
+pub async fn due_cards(
+    state: &Arc<AppState>,
+    user_id: &str,
+    limit: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let limit = match limit {
+        Some(limit) => limit
+            .parse::<u32>()
+            .ok()
+            .filter(|l| (1..=MAX_DUE_CARDS).contains(l))
+            .ok_or_else(|| {
+                (
+                    400,
+                    json_error(&format!("limit must be 1 to {}", MAX_DUE_CARDS)),
+                )
+            })?,
+        None => DEFAULT_DUE_CARDS,
+    };
+
+    let user_id = user_id.to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let cards = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            db.due_cards(&user_id, &note.id, &now, limit)
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&DueCardsResponse {
+        cards: cards.into_iter().map(card_response).collect(),
+    })
+    .unwrap())
+}
+
+/// Record a review of a flashcard, graded 0 (forgotten) to 5 (perfect), and
+/// schedule its next one.
+pub async fn grade_card(
+    state: &Arc<AppState>,
+    user_id: &str,
+    card_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: GradeRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    if req.grade > cards::MAX_GRADE {
+        return Err((
+            400,
+            json_error(&format!("grade must be 0 to {}", cards::MAX_GRADE)),
+        ));
+    }
+
+    let user_id = user_id.to_string();
+    let card_id = card_id.to_string();
+    let card = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let Some(mut card) = db.get_card(&user_id, &note.id, &card_id)? else {
+                return Ok(None);
+            };
+            cards::grade(&mut card, req.grade, chrono::Utc::now());
+            db.save_card_review(&user_id, &card)?;
+            Ok(Some(card))
+        })
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("Card not found")))?;
+
+    Ok(serde_json::to_string(&card_response(card)).unwrap())
+}
+
+pub async fn list_revisions(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, revisions) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let revisions = db.get_revisions(&user_id, &note.id)?;
+            Ok((note, revisions))
+        })
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&RevisionsResponse {
+        note_id: note.id,
+        revisions: revisions
+            .into_iter()
+            .map(|r| RevisionSummary {
+                id: r.id,
+                created_at: r.created_at,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Diff two revisions of the note. `to` defaults to the current content.
+pub async fn diff(
+    state: &Arc<AppState>,
+    user_id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let (from, to, changes) = load_diff(state, user_id, from, to).await?;
+    Ok(serde_json::to_string(&DiffResponse { from, to, changes }).unwrap())
+}
+
+/// Same diff as `diff`, rendered as an HTML page.
+pub async fn diff_html(
+    state: &Arc<AppState>,
+    user_id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let (from, to, changes) = load_diff(state, user_id, from, to).await?;
+
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+    let hint = LangHint::for_note(&parse_meta(meta.as_deref()), &note.content);
+
+    let title = format!("Changes from {} to {}", from, to);
+    Ok(render::diff_page(&title, &changes, &hint))
+}
+
+/// Export the note as a file: `md` (the default) is the raw Markdown, `json`
+/// adds chunk metadata and hashes, `html` is a rendered standalone page.
+pub async fn export_note(
+    state: &Arc<AppState>,
+    user_id: &str,
+    format: Option<&str>,
+) -> Result<Download, (u16, String)> {
+    let format = format.unwrap_or("md").to_string();
+    if !matches!(format.as_str(), "md" | "json" | "html") {
+        return Err((400, json_error("format must be md, json or html")));
+    }
+
+    let user_id = user_id.to_string();
+    let (note, chunks, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, chunks, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let filename = format!(
+        "note-{}.{}",
+        note.updated_at.get(..10).unwrap_or("export"),
+        format
+    );
+    let (content_type, body) = match format.as_str() {
+        "json" => (
+            "application/json",
+            serde_json::to_string_pretty(&NoteDocument {
+                format: "trame-note",
+                version: 1,
+                id: note.id,
+                content: note.content,
+                created_at: note.created_at,
+                updated_at: note.updated_at,
+                exported_at: chrono::Utc::now().to_rfc3339(),
+                chunks: chunks
+                    .into_iter()
+                    .map(|c| ExportedChunk {
+                        id: c.id,
+                        sequence: c.sequence,
+                        chunk_type: c.chunk_type,
+                        heading_level: c.heading_level,
+                        content: c.content,
+                        content_hash: c.content_hash,
+                        start_offset: c.start_offset,
+                        end_offset: c.end_offset,
+                        created_at: c.created_at,
+                        updated_at: c.updated_at,
+                        pinned: c.pinned,
+                    })
+                    .collect(),
+            })
+            .unwrap(),
+        ),
+        "html" => {
+            let parsed = parse_chunks(&note.content);
+            let title = note_title(&parsed);
+            let hint = LangHint::for_note(&parse_meta(meta.as_deref()), &note.content);
+            (
+                "text/html; charset=utf-8",
+                render::note_page(&title, &parsed, &hint),
+            )
+        }
+        _ => ("text/markdown; charset=utf-8", note.content),
+    };
+
+    Ok(Download {
+        content_type,
+        filename,
+        body,
+    })
+}
+
+/// The note as a standalone page styled for printing.
+pub async fn print_note(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let parsed = parse_chunks(&note.content);
+    let hint = LangHint::for_note(&parse_meta(meta.as_deref()), &note.content);
+    Ok(render::print_page(&note_title(&parsed), &parsed, &hint))
+}
+
+/// The note, or with `chunk_id` just that chunk, as a sanitized HTML
+/// fragment for clients that can't render Markdown themselves.
+pub async fn note_html(
+    state: &Arc<AppState>,
+    user_id: &str,
+    chunk_id: Option<&str>,
+) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, chunks) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let chunks = db.get_chunks(&user_id, &note.id)?;
+            Ok((note, chunks))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let parsed = parse_chunks(&note.content);
+    let Some(chunk_id) = chunk_id.filter(|id| !id.is_empty()) else {
+        return Ok(render::note_fragment(&parsed));
+    };
+    // Stored chunks are in the order the content parses into
+    let chunk = chunks
+        .iter()
+        .find(|c| c.id == chunk_id)
+        .and_then(|c| parsed.get(c.sequence as usize))
+        .ok_or_else(|| (404, json_error("Chunk not found")))?;
+    Ok(render::note_fragment(std::slice::from_ref(chunk)))
+}
+
+/// The terms users must accept, if the instance has any.
+pub async fn get_terms(state: &Arc<AppState>) -> Result<String, (u16, String)> {
+    Ok(serde_json::to_string(&TermsResponse {
+        version: state.config.terms_version.clone(),
+        url: state.config.terms_url.clone(),
+    })
+    .unwrap())
+}
+
+/// Accept the current terms, lifting the block on writes.
+pub async fn accept_terms(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: AcceptTermsRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+    if state.config.terms_version.as_deref() != Some(req.version.as_str()) {
+        return Err((400, terms_error(state, "Not the current terms version")));
+    }
+
+    let user_id = user_id.to_string();
+    state
+        .db
+        .run(move |db| db.accept_terms(&user_id, &req.version))
+        .await
+        .map_err(db_error)?;
+    Ok("{}".to_string())
+}
+
+/// Fail with 403 if the instance has terms and the requesting user hasn't
+/// accepted the current version. Requests that don't authenticate pass, so
+/// the route itself reports the auth error.
+pub async fn require_terms(
+    state: &Arc<AppState>,
+    auth_header: Option<&str>,
+) -> Result<(), (u16, String)> {
+    let Some(current) = state.config.terms_version.clone() else {
+        return Ok(());
+    };
+    let Ok(auth) = authenticate(state, auth_header).await else {
+        return Ok(());
+    };
+
+    let user = state
+        .db
+        .run(move |db| db.get_user(&auth.user_id))
+        .await
+        .map_err(db_error)?;
+    match user {
+        Some(user) if user.terms_version.as_deref() != Some(current.as_str()) => Err((
+            403,
+            terms_error(state, "Accept the updated terms to continue"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Announcements currently in their window. Public, so clients can show
+/// them before sign-in too.
+pub async fn list_announcements(state: &Arc<AppState>) -> Result<String, (u16, String)> {
+    let now = chrono::Utc::now().to_rfc3339();
+    let announcements = state
+        .db
+        .run(move |db| db.active_announcements(&now))
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&AnnouncementsResponse {
+        announcements: announcements
+            .into_iter()
+            .map(announcement_response)
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// Store the raw request body as an attachment. The type comes from the
+/// request's `Content-Type`, or from the filename's extension when the
+/// client didn't say.
+pub async fn upload_attachment(
+    state: &Arc<AppState>,
+    user_id: &str,
+    content_type: Option<&str>,
+    filename: Option<&str>,
+    body: &[u8],
+) -> Result<String, (u16, String)> {
+    if body.is_empty() {
+        return Err((400, json_error("Attachment is empty")));
+    }
+    let max = state.config.max_attachment_bytes;
+    if body.len() > max {
+        return Err((
+            413,
+            json_error(&format!("Attachment too large (limit {} bytes)", max)),
+        ));
+    }
+
+    let content_type = content_type.map(|t| t.trim().to_ascii_lowercase());
+    if content_type
+        .as_deref()
+        .is_some_and(|t| t.starts_with("multipart/"))
+    {
+        return Err((
+            415,
+            json_error("Send the file as the raw request body, not a multipart form"),
+        ));
+    }
+
+    // Generic types say nothing about the file; HTTP clients send them by default
+    let filename = filename.map(clean_filename).filter(|f| !f.is_empty());
+    let content_type = content_type
+        .filter(|t| {
+            !t.is_empty()
+                && t != "application/octet-stream"
+                && t != "application/x-www-form-urlencoded"
+        })
+        .unwrap_or_else(|| guess_content_type(filename.as_deref()).to_string());
+
+    let user_id = user_id.to_string();
+    let data = body.to_vec();
+    let attachment = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let attachment = Attachment {
+                id: ids::new_id(),
+                user_id: user_id.clone(),
+                note_id: note.id,
+                filename: filename.clone(),
+                content_type: content_type.clone(),
+                size: data.len() as i64,
+                data: data.clone(),
+                created_at: chrono::Utc::now().to_rfc3339(),
+            };
+            db.create_attachment(&attachment)?;
+            Ok(attachment)
+        })
+        .await
+        .map_err(db_error)?;
+
+    log::info(
+        "attachment uploaded",
+        serde_json::json!({ "attachment_id": attachment.id, "size": attachment.size }),
+    );
+    Ok(serde_json::to_string(&AttachmentResponse {
+        url: format!("/api/attachments/{}", attachment.id),
+        id: attachment.id,
+        filename: attachment.filename,
+        content_type: attachment.content_type,
+        size: attachment.size,
+        created_at: attachment.created_at,
+    })
+    .unwrap())
+}
+
+pub async fn get_attachment(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<Attachment, (u16, String)> {
+    let user_id = user_id.to_string();
+    let id = id.to_string();
+    state
+        .db
+        .run(move |db| db.get_attachment(&user_id, &id))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("Attachment not found")))
+}
+
+pub async fn create_announcement(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    require_admin(state, user_id).await?;
+    let req: AnnouncementRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let message = req.message.trim().to_string();
+    if message.is_empty() || message.chars().count() > MAX_ANNOUNCEMENT_CHARS {
+        return Err((
+            400,
+            json_error(&format!(
+                "message must be 1 to {} characters",
+                MAX_ANNOUNCEMENT_CHARS
+            )),
+        ));
+    }
+    let level = req.level.unwrap_or_else(|| "info".to_string());
+    if level != "info" && level != "warning" {
+        return Err((400, json_error("level must be info or warning")));
+    }
+    // Stored in UTC so they compare correctly as text
+    let parse_time = |time: Option<String>, field: &str| match time {
+        None => Ok(None),
+        Some(t) => chrono::DateTime::parse_from_rfc3339(&t)
+            .map(|t| Some(t.with_timezone(&chrono::Utc).to_rfc3339()))
+            .map_err(|_| {
+                (
+                    400,
+                    json_error(&format!("{} must be an RFC 3339 time", field)),
+                )
+            }),
+    };
+    let starts_at = parse_time(req.starts_at, "starts_at")?;
+    let ends_at = parse_time(req.ends_at, "ends_at")?;
+    if let (Some(start), Some(end)) = (&starts_at, &ends_at) {
+        if end <= start {
+            return Err((400, json_error("ends_at must be after starts_at")));
+        }
+    }
+
+    let announcement = Announcement {
+        id: ids::new_id(),
+        message,
+        level,
+        starts_at,
+        ends_at,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    let stored = announcement.clone();
+    state
+        .db
+        .run(move |db| db.create_announcement(&stored))
+        .await
+        .map_err(db_error)?;
+
+    log::info(
+        "announcement created",
+        serde_json::json!({ "id": announcement.id, "user_id": user_id }),
+    );
+    Ok(serde_json::to_string(&announcement_response(announcement)).unwrap())
+}
+
+pub async fn delete_announcement(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<String, (u16, String)> {
+    require_admin(state, user_id).await?;
+
+    let id = id.to_string();
+    let deleted = state
+        .db
+        .run(move |db| db.delete_announcement(&id))
+        .await
+        .map_err(db_error)?;
+    if !deleted {
+        return Err((404, json_error("Announcement not found")));
+    }
+    Ok("{}".to_string())
+}
+
+/// Open a short-lived, read-only session as another user so an admin can
+/// see what they see. The reason is kept in the audit log with every request
+/// the session makes.
+pub async fn impersonate(
+    state: &Arc<AppState>,
+    admin_id: &str,
+    body: &str,
+    client: &ClientInfo,
+) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let req: ImpersonateRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let reason = req.reason.trim().to_string();
+    if reason.is_empty() {
+        return Err((400, json_error("reason is required")));
+    }
+    let user = match (req.user_id, req.email) {
+        (Some(id), _) => state.db.run(move |db| db.get_user(&id)).await,
+        (None, Some(email)) => state.db.run(move |db| db.get_user_by_email(&email)).await,
+        (None, None) => return Err((400, json_error("user_id or email is required"))),
+    }
+    .map_err(db_error)?
+    .ok_or_else(|| (404, json_error("User not found")))?;
+    if user.id == admin_id {
+        return Err((400, json_error("Cannot impersonate yourself")));
+    }
+
+    let expires_at =
+        (chrono::Utc::now() + chrono::Duration::minutes(IMPERSONATION_TTL_MINUTES)).to_rfc3339();
+    let entry = AuditEntry {
+        id: ids::new_id(),
+        actor_id: admin_id.to_string(),
+        action: "impersonation.start".to_string(),
+        target_user_id: Some(user.id.clone()),
+        details: Some(
+            serde_json::json!({ "reason": reason, "expires_at": expires_at }).to_string(),
+        ),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    let session = Session {
+        impersonator_id: Some(admin_id.to_string()),
+        ..new_session(&user.id, &expires_at, client)
+    };
+    // The session only exists if its audit entry does
+    let token = state
+        .db
+        .run(move |db| {
+            db.record_audit(&entry)?;
+            open_session(db, &session)
+        })
+        .await
+        .map_err(db_error)?;
+
+    log::info(
+        "impersonation started",
+        serde_json::json!({ "admin_id": admin_id, "user_id": user.id }),
+    );
+    Ok(serde_json::to_string(&ImpersonateResponse {
+        token,
+        user_id: user.id,
+        expires_at,
+        read_only: true,
+    })
+    .unwrap())
+}
+
+/// Refuse writes from impersonation sessions.
+pub fn require_writable(auth: &AuthInfo) -> Result<(), (u16, String)> {
+    if auth.impersonator_id.is_some() {
+        return Err((403, json_error("Impersonation sessions are read-only")));
+    }
+    Ok(())
+}
+
+/// Record a request made through an impersonation session. Failures are
+/// logged rather than failing a request that has already been served.
+pub async fn audit_impersonated_request(
+    state: &Arc<AppState>,
+    auth: &AuthInfo,
+    method: &str,
+    path: &str,
+    status: u16,
+) {
+    let Some(admin_id) = auth.impersonator_id.clone() else {
+        return;
+    };
+    let entry = AuditEntry {
+        id: ids::new_id(),
+        actor_id: admin_id,
+        action: "impersonation.request".to_string(),
+        target_user_id: Some(auth.user_id.clone()),
+        details: Some(
+            serde_json::json!({ "method": method, "path": path, "status": status }).to_string(),
+        ),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    if let Err(err) = state.db.run(move |db| db.record_audit(&entry)).await {
+        log::warn(
+            "audit write failed",
+            serde_json::json!({ "error": err.to_string() }),
+        );
+    }
+}
+
+/// Newest audit entries, optionally only those about one user.
+pub async fn audit_log(
+    state: &Arc<AppState>,
+    admin_id: &str,
+    target_user_id: Option<String>,
+) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let entries = state
+        .db
+        .run(move |db| db.audit_entries(target_user_id.as_deref(), MAX_AUDIT_ENTRIES))
+        .await
+        .map_err(db_error)?;
+    Ok(serde_json::to_string(&AuditLogResponse {
+        entries: entries
+            .into_iter()
+            .map(|entry| AuditEntryResponse {
+                id: entry.id,
+                actor_id: entry.actor_id,
+                action: entry.action,
+                target_user_id: entry.target_user_id,
+                details: parse_meta(entry.details.as_deref()),
+                created_at: entry.created_at,
+            })
+            .collect(),
+    })
+    .unwrap())
+}
+
+/// The audit log as a file for retention and compliance reviews: entries by
+/// or about `user_id`, if given, from `from` up to `to` (RFC 3339 times or
+/// dates; a date `to` includes that day), oldest first, as `csv` (the
+/// default) or `ndjson`. The export is itself audited.
+pub async fn export_audit_log(
+    state: &Arc<AppState>,
+    admin_id: &str,
+    user_id: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    format: Option<&str>,
+) -> Result<Download, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let format = format.unwrap_or("csv").to_string();
+    if !matches!(format.as_str(), "csv" | "ndjson") {
+        return Err((400, json_error("format must be csv or ndjson")));
+    }
+    let since = audit_bound(from.as_deref(), false, "from")?;
+    let until = audit_bound(to.as_deref(), true, "to")?;
+
+    let record = AuditEntry {
+        id: ids::new_id(),
+        actor_id: admin_id.to_string(),
+        action: "audit.export".to_string(),
+        target_user_id: user_id.clone(),
+        details: Some(
+            serde_json::json!({ "format": format, "from": since, "to": until }).to_string(),
+        ),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    let entries = state
+        .db
+        .run(move |db| {
+            let entries =
+                db.export_audit_entries(user_id.as_deref(), since.as_deref(), until.as_deref())?;
+            db.record_audit(&record)?;
+            Ok(entries)
+        })
+        .await
+        .map_err(db_error)?;
+
+    let mut body = String::new();
+    if format == "csv" {
+        body.push_str("id,created_at,actor_id,action,target_user_id,details\n");
+    }
+    for entry in entries {
+        if format == "csv" {
+            let fields = [
+                entry.id.as_str(),
+                &entry.created_at,
+                &entry.actor_id,
+                &entry.action,
+                entry.target_user_id.as_deref().unwrap_or(""),
+                entry.details.as_deref().unwrap_or(""),
+            ];
+            let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            body.push_str(&row.join(","));
+        } else {
+            let line = AuditEntryResponse {
+                id: entry.id,
+                actor_id: entry.actor_id,
+                action: entry.action,
+                target_user_id: entry.target_user_id,
+                details: parse_meta(entry.details.as_deref()),
+                created_at: entry.created_at,
+            };
+            body.push_str(&serde_json::to_string(&line).unwrap());
+        }
+        body.push('\n');
+    }
+
+    let content_type = match format.as_str() {
+        "csv" => "text/csv; charset=utf-8",
+        _ => "application/x-ndjson",
+    };
+    Ok(Download {
+        content_type,
+        filename: format!("audit-{}.{}", chrono::Utc::now().format("%Y-%m-%d"), format),
+        body,
+    })
+}
+
+/// An audit export bound as stored: a UTC RFC 3339 time. A date is its
+/// start, or for the `end` bound, the start of the next day.
+fn audit_bound(
+    value: Option<&str>,
+    end: bool,
+    field: &str,
+) -> Result<Option<String>, (u16, String)> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let time = match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => {
+            let date = if end { date.succ_opt() } else { Some(date) };
+            date.and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|time| time.and_utc())
+        }
+        Err(_) => chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|time| time.with_timezone(&chrono::Utc)),
+    };
+    match time {
+        Some(time) => Ok(Some(time.to_rfc3339())),
+        None => Err((
+            400,
+            json_error(&format!("{} must be a date or an RFC 3339 time", field)),
+        )),
+    }
+}
+
+/// A CSV field, quoted when it holds a separator, quote or line break.
+/// Values a spreadsheet would take for a formula get a leading `'`, so
+/// opening the export doesn't run what a user put in their details.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// The startup report plus uptime, for operators and bug reports.
+pub async fn admin_info(state: &Arc<AppState>, admin_id: &str) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let uptime = chrono::Utc::now() - state.started_at;
+    Ok(serde_json::to_string(&AdminInfoResponse {
+        info: RuntimeInfo::new(&state.config),
+        started_at: state.started_at.to_rfc3339(),
+        uptime_secs: uptime.num_seconds(),
+    })
+    .unwrap())
+}
+
+/// Storage call counts and durations since startup.
+pub async fn admin_metrics(state: &Arc<AppState>, admin_id: &str) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+    Ok(serde_json::to_string(&MetricsResponse {
+        database: DbMetricsResponse {
+            slow_query_ms: state.config.slow_query_ms,
+            slow_queries: metrics::slow_queries_total(),
+            methods: state
+                .db_metrics
+                .snapshot()
+                .into_iter()
+                .map(|(method, stats)| MethodMetrics {
+                    method,
+                    calls: stats.calls,
+                    errors: stats.errors,
+                    total_ms: ms(stats.total),
+                    mean_ms: ms(stats.total) / stats.calls.max(1) as f64,
+                    max_ms: ms(stats.max),
+                })
+                .collect(),
+        },
+    })
+    .unwrap())
+}
+
+/// Flags in effect for the signed-in user, so clients can show
+/// experimental features.
+pub async fn get_flags(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let user_flags = state
+        .db
+        .run(move |db| db.list_user_flags(&user_id))
+        .await
+        .map_err(db_error)?;
+    Ok(serde_json::to_string(&FlagsResponse {
+        flags: flags::effective(&state.config.feature_flags, &user_flags),
+    })
+    .unwrap())
+}
+
+pub async fn get_user_flags(
+    state: &Arc<AppState>,
+    admin_id: &str,
+    target_id: &str,
+) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    let id = target_id.to_string();
+    let user_flags = state
+        .db
+        .run(move |db| db.list_user_flags(&id))
+        .await
+        .map_err(db_error)?;
+    Ok(serde_json::to_string(&UserFlagsResponse {
+        user_id: target_id.to_string(),
+        flags: user_flags,
+        global: state.config.feature_flags.clone(),
+    })
+    .unwrap())
+}
+
+/// Turn a flag on or off for one account. Changes are audited.
+pub async fn set_user_flag(
+    state: &Arc<AppState>,
+    admin_id: &str,
+    target_id: &str,
+    flag: &str,
+    enabled: bool,
+) -> Result<String, (u16, String)> {
+    require_admin(state, admin_id).await?;
+    if !flags::is_valid_name(flag) {
+        return Err((
+            400,
+            json_error("Flag names are lowercase letters, digits, '-' and '_'"),
+        ));
+    }
+
+    let id = target_id.to_string();
+    if state
+        .db
+        .run(move |db| db.get_user(&id))
+        .await
+        .map_err(db_error)?
+        .is_none()
+    {
+        return Err((404, json_error("User not found")));
+    }
+
+    let action = match enabled {
+        true => "flag.enable",
+        false => "flag.disable",
+    };
+    let entry = AuditEntry {
+        id: ids::new_id(),
+        actor_id: admin_id.to_string(),
+        action: action.to_string(),
+        target_user_id: Some(target_id.to_string()),
+        details: Some(serde_json::json!({ "flag": flag }).to_string()),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    let (id, name) = (target_id.to_string(), flag.to_string());
+    state
+        .db
+        .run(move |db| {
+            let changed = if enabled {
+                db.enable_user_flag(&id, &name)?
+            } else {
+                db.disable_user_flag(&id, &name)?
+            };
+            if changed {
+                db.record_audit(&entry)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(db_error)?;
+
+    get_user_flags(state, admin_id, target_id).await
+}
+
+/// Create an inbound webhook. Its URL carries a secret token, so services
+/// can write into the note without holding a session.
+pub async fn create_hook(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: CreateHookRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let name = req.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_HOOK_NAME_CHARS {
+        return Err((
+            400,
+            json_error(&format!(
+                "name must be 1 to {} characters",
+                MAX_HOOK_NAME_CHARS
+            )),
+        ));
+    }
+    let action = HookAction::parse(&req.action)
+        .ok_or_else(|| (400, json_error("action must be append or prepend")))?;
+    if req.template.trim().is_empty() || req.template.chars().count() > MAX_HOOK_TEMPLATE_CHARS {
+        return Err((
+            400,
+            json_error(&format!(
+                "template must be 1 to {} characters",
+                MAX_HOOK_TEMPLATE_CHARS
+            )),
+        ));
+    }
+
+    let token = generate_token();
+    let hook = InboundHook {
+        id: ids::new_id(),
+        user_id: user_id.to_string(),
+        name,
+        action: action.as_str().to_string(),
+        template: req.template,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        last_used_at: None,
+    };
+    let (stored, token_hash) = (hook.clone(), hash_token(&token));
+    state
+        .db
+        .run(move |db| db.create_inbound_hook(&stored, &token_hash))
+        .await
+        .map_err(db_error)?;
+
+    let mut response = hook_response(hook);
+    response.url = Some(format!("/hooks/{}", token));
+    Ok(serde_json::to_string(&response).unwrap())
 }
 
-#[derive(Deserialize)]
-pub struct LoginRequest {
-    pub email: String,
-    pub password: String,
+pub async fn list_hooks(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let hooks = state
+        .db
+        .run(move |db| db.list_inbound_hooks(&user_id))
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&HooksResponse {
+        hooks: hooks.into_iter().map(hook_response).collect(),
+    })
+    .unwrap())
 }
 
-#[derive(Serialize)]
-pub struct AuthResponse {
-    pub token: String,
+pub async fn delete_hook(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<String, (u16, String)> {
+    let (user_id, id) = (user_id.to_string(), id.to_string());
+    let deleted = state
+        .db
+        .run(move |db| db.delete_inbound_hook(&user_id, &id))
+        .await
+        .map_err(db_error)?;
+    if !deleted {
+        return Err((404, json_error("Hook not found")));
+    }
+    Ok("{}".to_string())
 }
 
-#[derive(Serialize)]
-pub struct NoteResponse {
-    pub id: String,
-    pub content: String,
-    pub updated_at: String,
+/// Handle a delivery to `/hooks/:token`: render the hook's template with the
+/// posted JSON and write the result into the owner's note.
+pub async fn run_hook(
+    state: &Arc<AppState>,
+    token: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let payload: serde_json::Value =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Body must be JSON")))?;
+
+    let token_hash = hash_token(token);
+    let hook = state
+        .db
+        .run(move |db| db.use_inbound_hook(&token_hash))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (404, json_error("Hook not found")))?;
+
+    // The hook writes on the owner's behalf, so their terms must be current
+    if let Some(current) = state.config.terms_version.clone() {
+        let owner = hook.user_id.clone();
+        let user = state
+            .db
+            .run(move |db| db.get_user(&owner))
+            .await
+            .map_err(db_error)?;
+        if user.is_some_and(|u| u.terms_version.as_deref() != Some(current.as_str())) {
+            return Err((
+                403,
+                terms_error(state, "The hook's owner must accept the updated terms"),
+            ));
+        }
+    }
+
+    let text = hooks::render_template(&hook.template, &payload);
+    if text.trim().is_empty() {
+        return Err((400, json_error("Template rendered to nothing")));
+    }
+    let action = HookAction::parse(&hook.action).unwrap_or(HookAction::Append);
+
+    let user_id = hook.user_id.clone();
+    let written = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            match (note.journal, action) {
+                (true, HookAction::Append) => {
+                    db.append_journal_entry(&user_id, &text).map(|_| true)
+                }
+                (true, HookAction::Prepend) => Ok(false),
+                (false, _) => {
+                    update_note_with(db, &user_id, |note| action.apply(&note.content, &text))
+                        .map(|_| true)
+                }
+            }
+        })
+        .await
+        .map_err(db_error)?;
+    if !written {
+        return Err((
+            409,
+            json_error("The note is a journal and only takes appends"),
+        ));
+    }
+
+    log::info(
+        "inbound hook delivered",
+        serde_json::json!({ "hook_id": hook.id, "user_id": hook.user_id }),
+    );
+    Ok("{}".to_string())
 }
 
-pub struct AuthInfo {
-    pub user_id: String,
+/// Create a public read-only link to the user's current note. An empty body
+/// makes a link that never expires.
+pub async fn create_share(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: CreateShareRequest = if body.trim().is_empty() {
+        CreateShareRequest::default()
+    } else {
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?
+    };
+    if req
+        .expires_in_days
+        .is_some_and(|days| days == 0 || days > MAX_SHARE_DAYS)
+    {
+        return Err((
+            400,
+            json_error(&format!("expires_in_days must be 1 to {}", MAX_SHARE_DAYS)),
+        ));
+    }
+
+    let now = chrono::Utc::now();
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    let (user_id, id) = (user_id.to_string(), ids::new_id());
+    let share = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let share = Share {
+                id: id.clone(),
+                user_id: user_id.clone(),
+                note_id: note.id,
+                created_at: now.to_rfc3339(),
+                expires_at: req
+                    .expires_in_days
+                    .map(|days| (now + chrono::Duration::days(days as i64)).to_rfc3339()),
+                last_viewed_at: None,
+            };
+            db.create_share(&share, &token_hash)?;
+            Ok(share)
+        })
+        .await
+        .map_err(db_error)?;
+
+    let mut response = share_response(share);
+    response.url = Some(format!("/s/{}", token));
+    Ok(serde_json::to_string(&response).unwrap())
 }
 
-#[derive(Deserialize)]
-pub struct UpdateNoteRequest {
-    pub content: String,
+/// The user's share links that still work.
+pub async fn list_shares(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let shares = state
+        .db
+        .run(move |db| db.list_shares(&user_id, &now))
+        .await
+        .map_err(db_error)?;
+
+    Ok(serde_json::to_string(&SharesResponse {
+        shares: shares.into_iter().map(share_response).collect(),
+    })
+    .unwrap())
 }
 
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    pub error: String,
+pub async fn revoke_share(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<String, (u16, String)> {
+    let (user_id, id) = (user_id.to_string(), id.to_string());
+    let deleted = state
+        .db
+        .run(move |db| db.delete_share(&user_id, &id))
+        .await
+        .map_err(db_error)?;
+    if !deleted {
+        return Err((404, json_error("Share not found")));
+    }
+    Ok("{}".to_string())
 }
 
-// Handlers
-pub fn signup(state: &Arc<AppState>, body: &str) -> Result<String, (u16, String)> {
-    let req: SignupRequest =
+/// Create a token for backup tools: it can export the note and nothing else,
+/// so it's safe to leave in a cron job or hand to a third-party service.
+pub async fn create_export_token(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: CreateExportTokenRequest =
         serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
 
-    // Validate
-    if req.email.is_empty() || !req.email.contains('@') {
-        return Err((400, json_error("Invalid email")));
-    }
-    if req.password.len() < 8 {
-        return Err((400, json_error("Password must be at least 8 characters")));
+    let name = req.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_EXPORT_TOKEN_NAME_CHARS {
+        return Err((
+            400,
+            json_error(&format!(
+                "name must be 1 to {} characters",
+                MAX_EXPORT_TOKEN_NAME_CHARS
+            )),
+        ));
     }
 
-    // Check if user exists
-    if state
+    let token = format!("{}{}", EXPORT_TOKEN_PREFIX, generate_token());
+    let export_token = ExportToken {
+        id: ids::new_id(),
+        user_id: user_id.to_string(),
+        name,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        last_used_at: None,
+    };
+    let (stored, token_hash) = (export_token.clone(), hash_token(&token));
+    state
         .db
-        .get_user_by_email(&req.email)
-        .map_err(db_error)?
-        .is_some()
-    {
-        return Err((409, json_error("Email already registered")));
-    }
+        .run(move |db| db.create_export_token(&stored, &token_hash))
+        .await
+        .map_err(db_error)?;
 
-    // Hash password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(req.password.as_bytes(), &salt)
-        .map_err(|_| (500, json_error("Failed to hash password")))?
-        .to_string();
+    let mut response = export_token_response(export_token);
+    response.token = Some(token);
+    Ok(serde_json::to_string(&response).unwrap())
+}
 
-    // Create user
-    let user_id = ulid::Ulid::new().to_string();
-    state
+pub async fn list_export_tokens(
+    state: &Arc<AppState>,
+    user_id: &str,
+) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let tokens = state
         .db
-        .create_user(&user_id, &req.email, &password_hash)
+        .run(move |db| db.list_export_tokens(&user_id))
+        .await
         .map_err(db_error)?;
 
-    // Create session
-    let token = generate_token();
-    let expires_at = (chrono::Utc::now() + chrono::Duration::days(30)).to_rfc3339();
-    state
+    Ok(serde_json::to_string(&ExportTokensResponse {
+        tokens: tokens.into_iter().map(export_token_response).collect(),
+    })
+    .unwrap())
+}
+
+pub async fn revoke_export_token(
+    state: &Arc<AppState>,
+    user_id: &str,
+    id: &str,
+) -> Result<String, (u16, String)> {
+    let (user_id, id) = (user_id.to_string(), id.to_string());
+    let deleted = state
+        .db
+        .run(move |db| db.delete_export_token(&user_id, &id))
+        .await
+        .map_err(db_error)?;
+    if !deleted {
+        return Err((404, json_error("Export token not found")));
+    }
+    Ok("{}".to_string())
+}
+
+/// The page behind `/s/:token`: the shared note rendered read-only. Expired
+/// and revoked links, and notes since moved to the trash, are all a 404.
+pub async fn view_share(state: &Arc<AppState>, token: &str) -> Result<String, (u16, String)> {
+    let token_hash = hash_token(token);
+    let now = chrono::Utc::now().to_rfc3339();
+    let shared = state
         .db
-        .create_session(&token, &user_id, &expires_at)
+        .run(move |db| {
+            let Some(share) = db.use_share(&token_hash, &now)? else {
+                return Ok(None);
+            };
+            let Some(note) = db.get_note(&share.user_id, &share.note_id)? else {
+                return Ok(None);
+            };
+            let meta = db.get_note_meta(&share.user_id, &note.id)?;
+            Ok(Some((note, meta)))
+        })
+        .await
         .map_err(db_error)?;
+    let (note, meta) = shared.ok_or_else(|| (404, json_error("Share not found")))?;
 
-    Ok(serde_json::to_string(&AuthResponse { token }).unwrap())
+    let parsed = parse_chunks(&note.content);
+    let hint = LangHint::for_note(&parse_meta(meta.as_deref()), &note.content);
+    Ok(render::note_page(&note_title(&parsed), &parsed, &hint))
 }
 
-pub fn login(state: &Arc<AppState>, body: &str) -> Result<String, (u16, String)> {
-    let req: LoginRequest =
-        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+async fn load_diff(
+    state: &Arc<AppState>,
+    user_id: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<(String, String, Vec<ChunkChange>), (u16, String)> {
+    let from = from
+        .ok_or_else(|| (400, json_error("Missing 'from' revision")))?
+        .to_string();
+    let to = to.map(str::to_string);
+    let user_id = user_id.to_string();
 
-    // Get user
-    let user = state
+    let (note, old, new) = state
         .db
-        .get_user_by_email(&req.email)
-        .map_err(db_error)?
-        .ok_or_else(|| (404, json_error("User not found")))?;
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            // Either a revision id or a time, meaning the revision current then
+            let revision = |point: &str| match chrono::DateTime::parse_from_rfc3339(point) {
+                Ok(at) => {
+                    let after = at.with_timezone(&chrono::Utc) + chrono::Duration::nanoseconds(1);
+                    let after = after.to_rfc3339_opts(chrono::SecondsFormat::Nanos, false);
+                    db.get_revision_before(&user_id, &note.id, &after)
+                }
+                Err(_) => db.get_revision(&user_id, &note.id, point),
+            };
+            let old = revision(&from)?;
+            let new = match &to {
+                Some(point) => revision(point)?.map(Some),
+                None => Some(None),
+            };
+            Ok((note, old, new))
+        })
+        .await
+        .map_err(db_error)?;
 
-    // Verify password
-    let parsed_hash =
-        PasswordHash::new(&user.password_hash).map_err(|_| (500, json_error("Internal error")))?;
+    let not_found = || (404, json_error("Revision not found"));
+    let old = old.ok_or_else(not_found)?;
+    let (to_id, new_content) = match new.ok_or_else(not_found)? {
+        Some(rev) => (rev.id, rev.content),
+        None => ("current".to_string(), note.content),
+    };
 
-    Argon2::default()
-        .verify_password(req.password.as_bytes(), &parsed_hash)
-        .map_err(|_| (401, json_error("Invalid credentials")))?;
+    let changes = diff_notes(&old.content, &new_content);
+    Ok((old.id, to_id, changes))
+}
 
-    // Create session
-    let token = generate_token();
-    let expires_at = (chrono::Utc::now() + chrono::Duration::days(30)).to_rfc3339();
-    state
+pub async fn get_stats(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let day_start = chrono::Utc::now()
+        .date_naive()
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        .to_rfc3339();
+
+    let user_id = user_id.to_string();
+    let (note, goal, day_start_revision) = state
         .db
-        .create_session(&token, &user.id, &expires_at)
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let goal = db.get_note_goal(&user_id, &note.id)?;
+            let revision = db.get_revision_before(&user_id, &note.id, &day_start)?;
+            Ok((note, goal, revision))
+        })
+        .await
         .map_err(db_error)?;
 
-    Ok(serde_json::to_string(&AuthResponse { token }).unwrap())
+    let words_at_day_start = day_start_revision
+        .map(|r| stats::word_count(&r.content))
+        .unwrap_or(0);
+
+    Ok(serde_json::to_string(&stats::note_stats(
+        &note.content,
+        words_at_day_start,
+        goal.word_goal,
+        goal.daily_word_goal,
+    ))
+    .unwrap())
 }
 
-pub fn logout(state: &Arc<AppState>, token: &str) -> Result<String, (u16, String)> {
-    state.db.delete_session(token).map_err(db_error)?;
-    Ok("{}".to_string())
+/// The instance's limits and how much of each the user is using, so
+/// clients can warn before a request gets rejected.
+pub async fn get_limits(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let user_id = user_id.to_string();
+    let (note, meta) = state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let meta = db.get_note_meta(&user_id, &note.id)?;
+            Ok((note, meta))
+        })
+        .await
+        .map_err(db_error)?;
+
+    let limits = vec![
+        LimitUsage {
+            name: "note_bytes",
+            limit: None,
+            used: note.content.len() as u64,
+        },
+        LimitUsage {
+            name: "meta_bytes",
+            limit: Some(state.config.max_meta_bytes as u64),
+            used: meta.map_or(0, |m| m.len() as u64),
+        },
+    ];
+    Ok(serde_json::to_string(&LimitsResponse { limits }).unwrap())
 }
 
-pub fn get_note(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
-    let note = state.db.get_or_create_note(user_id).map_err(db_error)?;
+/// Set or clear (with `null`) the note's total and daily word goals.
+pub async fn update_goal(
+    state: &Arc<AppState>,
+    user_id: &str,
+    body: &str,
+) -> Result<String, (u16, String)> {
+    let req: UpdateGoalRequest =
+        serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
+
+    let user_id = user_id.to_string();
+    state
+        .db
+        .run(move |db| {
+            let note = db.get_or_create_note(&user_id)?;
+            let goal = NoteGoal {
+                word_goal: req.word_goal,
+                daily_word_goal: req.daily_word_goal,
+            };
+            db.set_note_goal(&user_id, &note.id, &goal)
+        })
+        .await
+        .map_err(db_error)?;
 
-    Ok(serde_json::to_string(&NoteResponse {
-        id: note.id,
-        content: note.content,
-        updated_at: note.updated_at,
-    })
-    .unwrap())
+    Ok("{}".to_string())
 }
 
-pub fn update_note(
+/// Start or stop a focus session on the user's note.
+pub async fn focus(
     state: &Arc<AppState>,
     user_id: &str,
     body: &str,
 ) -> Result<String, (u16, String)> {
-    let req: UpdateNoteRequest =
+    let req: FocusRequest =
         serde_json::from_str(body).map_err(|_| (400, json_error("Invalid request body")))?;
 
-    let note = state
+    let user_id = user_id.to_string();
+    let session = match req.action.as_str() {
+        "start" => {
+            let note_id = req.note_id;
+            state
+                .db
+                .run(move |db| {
+                    let note = db.get_or_create_note(&user_id)?;
+                    if note_id.as_deref().is_some_and(|id| id != note.id) {
+                        return Ok(Err((404, json_error("Note not found"))));
+                    }
+                    Ok(db
+                        .start_focus(&user_id, &note.id)?
+                        .ok_or_else(|| (409, json_error("Focus session already running"))))
+                })
+                .await
+                .map_err(db_error)??
+        }
+        "stop" => state
+            .db
+            .run(move |db| db.stop_focus(&user_id))
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| (404, json_error("No focus session running")))?,
+        _ => return Err((400, json_error("Action must be 'start' or 'stop'"))),
+    };
+
+    Ok(serde_json::to_string(&focus_session_response(session)).unwrap())
+}
+
+pub async fn focus_report(state: &Arc<AppState>, user_id: &str) -> Result<String, (u16, String)> {
+    let day_start = chrono::Utc::now()
+        .date_naive()
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        .to_rfc3339();
+
+    let user_id = user_id.to_string();
+    let (active, all_time, today) = state
         .db
-        .update_note(user_id, &req.content)
+        .run(move |db| {
+            let active = db.get_active_focus(&user_id)?;
+            let all_time = db.focus_totals(&user_id, "")?;
+            let today = db.focus_totals(&user_id, &day_start)?;
+            Ok((active, all_time, today))
+        })
+        .await
         .map_err(db_error)?;
 
-    Ok(serde_json::to_string(&NoteResponse {
-        id: note.id,
-        content: note.content,
-        updated_at: note.updated_at,
+    let notes: Vec<FocusNoteTotal> = all_time
+        .into_iter()
+        .map(|t| FocusNoteTotal {
+            today_seconds: today
+                .iter()
+                .find(|d| d.note_id == t.note_id)
+                .map_or(0, |d| d.total_seconds),
+            note_id: t.note_id,
+            sessions: t.sessions,
+            total_seconds: t.total_seconds,
+        })
+        .collect();
+
+    Ok(serde_json::to_string(&FocusReportResponse {
+        active: active.map(focus_session_response),
+        total_seconds: notes.iter().map(|n| n.total_seconds).sum(),
+        today_seconds: notes.iter().map(|n| n.today_seconds).sum(),
+        notes,
+    })
+    .unwrap())
+}
+
+/// Readiness: whether the database answers. Any failure is a 503 so load
+/// balancers stop sending traffic until it recovers.
+pub async fn health_ready(state: &Arc<AppState>) -> Result<String, (u16, String)> {
+    let health = state.db.run(|db| db.health()).await.map_err(|err| {
+        log::error(
+            "readiness check failed",
+            serde_json::json!({ "error": err.to_string() }),
+        );
+        (503, r#"{"status":"unavailable"}"#.to_string())
+    })?;
+
+    Ok(serde_json::to_string(&ReadinessResponse {
+        status: "ok",
+        wal_bytes: health.wal_bytes,
+        active_sessions: health.active_sessions,
+        expired_tokens_purged: gc::purged_total(),
+        note_cache_hits: health.note_cache.map(|(hits, _)| hits),
+        note_cache_misses: health.note_cache.map(|(_, misses)| misses),
     })
     .unwrap())
 }
 
 // Auth middleware
-pub fn authenticate(
+pub async fn authenticate(
     state: &Arc<AppState>,
     auth_header: Option<&str>,
 ) -> Result<AuthInfo, (u16, String)> {
     let token = auth_header
         .and_then(|h| h.strip_prefix("Bearer "))
-        .ok_or_else(|| (401, json_error("Missing authorization")))?;
+        .ok_or_else(|| (401, json_error("Missing authorization")))?
+        .to_string();
+    if token.starts_with(EXPORT_TOKEN_PREFIX) {
+        return Err((403, json_error("Export tokens can only export the note")));
+    }
+    // Tokens from before versioning have no prefix and are still accepted
+    if token.starts_with(TOKEN_VERSION_MARKER) && !token.starts_with(SESSION_TOKEN_PREFIX) {
+        return Err((401, json_error("Unsupported token version")));
+    }
 
+    let lookup = token.clone();
     let session = state
         .db
-        .get_session(token)
+        .run(move |db| db.get_session(&lookup))
+        .await
         .map_err(db_error)?
         .ok_or_else(|| (401, json_error("Invalid token")))?;
 
@@ -183,22 +3908,295 @@ pub fn authenticate(
         .map_err(|_| (500, json_error("Internal error")))?;
 
     if expires_at < chrono::Utc::now() {
-        state.db.delete_session(token).ok();
+        state.db.run(move |db| db.delete_session(&token)).await.ok();
         return Err((401, json_error("Token expired")));
     }
 
     Ok(AuthInfo {
         user_id: session.user_id,
+        impersonator_id: session.impersonator_id,
+        key_id: session_key_id(&token),
+    })
+}
+
+/// Authenticate a request to the export endpoint, which also takes export
+/// tokens. Using one records when it was last used.
+pub async fn authenticate_export(
+    state: &Arc<AppState>,
+    auth_header: Option<&str>,
+) -> Result<AuthInfo, (u16, String)> {
+    let token = match auth_header.and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(token) if token.starts_with(EXPORT_TOKEN_PREFIX) => token,
+        _ => return authenticate(state, auth_header).await,
+    };
+
+    let token_hash = hash_token(token);
+    let export_token = state
+        .db
+        .run(move |db| db.use_export_token(&token_hash))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| (401, json_error("Invalid token")))?;
+
+    Ok(AuthInfo {
+        user_id: export_token.user_id,
+        impersonator_id: None,
+        key_id: export_token.id,
     })
 }
 
+/// Count a request towards the usage of the key it was made with. The
+/// router calls this once per request, however many checks authenticated
+/// it. Counts are kept in memory until the background purge writes them.
+pub fn record_key_use(state: &Arc<AppState>, auth: &AuthInfo) {
+    let now = chrono::Utc::now().to_rfc3339();
+    state.key_usage.record(&auth.key_id, &auth.user_id, &now);
+}
+
 // Helpers
-fn generate_token() -> String {
+/// Save `edit` of the note as it is now, over that version only. Reads the
+/// note again and redoes the edit whenever another save came in between, so
+/// neither overwrites the other.
+fn update_note_with(
+    db: &dyn Storage,
+    user_id: &str,
+    edit: impl Fn(&Note) -> String,
+) -> Result<Note, StorageError> {
+    loop {
+        let current = db.get_or_create_note(user_id)?;
+        if let Some(note) = db.update_note_if(user_id, &edit(&current), &current.updated_at)? {
+            return Ok(note);
+        }
+    }
+}
+
+fn parse_meta(data: Option<&str>) -> serde_json::Value {
+    data.and_then(|d| serde_json::from_str(d).ok())
+        .unwrap_or_else(|| serde_json::json!({}))
+}
+
+fn focus_session_response(session: FocusSession) -> FocusSessionResponse {
+    FocusSessionResponse {
+        id: session.id,
+        note_id: session.note_id,
+        started_at: session.started_at,
+        ended_at: session.ended_at,
+        duration_seconds: session.duration_seconds,
+    }
+}
+
+fn announcement_response(announcement: Announcement) -> AnnouncementResponse {
+    AnnouncementResponse {
+        id: announcement.id,
+        message: announcement.message,
+        level: announcement.level,
+        starts_at: announcement.starts_at,
+        ends_at: announcement.ends_at,
+        created_at: announcement.created_at,
+    }
+}
+
+fn share_response(share: Share) -> ShareResponse {
+    ShareResponse {
+        id: share.id,
+        note_id: share.note_id,
+        created_at: share.created_at,
+        expires_at: share.expires_at,
+        last_viewed_at: share.last_viewed_at,
+        url: None,
+    }
+}
+
+fn hook_response(hook: InboundHook) -> HookResponse {
+    HookResponse {
+        id: hook.id,
+        name: hook.name,
+        action: hook.action,
+        template: hook.template,
+        created_at: hook.created_at,
+        last_used_at: hook.last_used_at,
+        url: None,
+    }
+}
+
+fn export_token_response(token: ExportToken) -> ExportTokenResponse {
+    ExportTokenResponse {
+        id: token.id,
+        name: token.name,
+        created_at: token.created_at,
+        last_used_at: token.last_used_at,
+        token: None,
+    }
+}
+
+/// Allow only accounts listed in `ADMIN_EMAILS`.
+async fn require_admin(state: &Arc<AppState>, user_id: &str) -> Result<(), (u16, String)> {
+    let id = user_id.to_string();
+    let user = state
+        .db
+        .run(move |db| db.get_user(&id))
+        .await
+        .map_err(db_error)?;
+    let is_admin = user.is_some_and(|u| {
+        state
+            .config
+            .admin_emails
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(&u.email))
+    });
+    if !is_admin {
+        return Err((403, json_error("Admin access required")));
+    }
+    Ok(())
+}
+
+/// Error body pointing clients at the terms to accept.
+fn terms_error(state: &AppState, msg: &str) -> String {
+    serde_json::json!({
+        "error": msg,
+        "code": "terms_not_accepted",
+        "terms_version": state.config.terms_version,
+        "terms_url": state.config.terms_url,
+    })
+    .to_string()
+}
+
+pub(crate) fn validate_password(password: &str) -> Result<(), (u16, String)> {
+    if password.len() < 8 {
+        return Err((400, json_error("Password must be at least 8 characters")));
+    }
+    Ok(())
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, (u16, String)> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| (500, json_error("Failed to hash password")))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> Result<(), (u16, String)> {
+    let parsed_hash =
+        PasswordHash::new(password_hash).map_err(|_| (500, json_error("Internal error")))?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .map_err(|_| (401, json_error("Invalid credentials")))
+}
+
+/// Reset tokens are stored hashed, so a database leak doesn't expose them.
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A session for `user_id` from `client`, to be given a token by
+/// [`open_session`].
+fn new_session(user_id: &str, expires_at: &str, client: &ClientInfo) -> Session {
+    Session {
+        token: String::new(),
+        user_id: user_id.to_string(),
+        expires_at: expires_at.to_string(),
+        impersonator_id: None,
+        created_at: Some(chrono::Utc::now().to_rfc3339()),
+        user_agent: client
+            .user_agent
+            .as_ref()
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_CHARS).collect()),
+        ip: client.ip.clone(),
+    }
+}
+
+/// The token without its version prefix.
+fn unversioned(token: &str) -> &str {
+    token.strip_prefix(SESSION_TOKEN_PREFIX).unwrap_or(token)
+}
+
+/// The part of a token shown in session lists.
+fn token_prefix(token: &str) -> String {
+    unversioned(token)
+        .chars()
+        .take(TOKEN_PREFIX_CHARS)
+        .collect()
+}
+
+/// Store a session with a new token and return the token. Another token is
+/// drawn if one is already taken, however unlikely that is with 256 bits.
+fn open_session(db: &dyn Storage, session: &Session) -> Result<String, StorageError> {
+    for _ in 0..SESSION_TOKEN_ATTEMPTS {
+        let token = format!("{}{}", SESSION_TOKEN_PREFIX, generate_token());
+        let session = Session {
+            token: token.clone(),
+            ..session.clone()
+        };
+        if db.create_session(&session)? {
+            return Ok(token);
+        }
+        log::warn("session token collision", serde_json::json!({}));
+    }
+    Err(StorageError::Other(
+        "Couldn't generate an unused session token".to_string(),
+    ))
+}
+
+pub(crate) fn generate_token() -> String {
     let mut bytes = [0u8; 32];
     rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut bytes);
     base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
 }
 
+/// Keep only the last path component, without quotes or control characters,
+/// so the name is safe in a `Content-Disposition` header.
+fn clean_filename(name: &str) -> String {
+    name.rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn guess_content_type(filename: Option<&str>) -> &'static str {
+    let extension = filename
+        .and_then(|f| f.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Page title for a rendered note: its first heading, or "Note".
+fn note_title(chunks: &[chunker::ParsedChunk]) -> String {
+    chunks
+        .iter()
+        .find(|c| c.chunk_type == ChunkType::Heading)
+        .map(|c| chunker::heading_text(c).to_string())
+        .unwrap_or_else(|| "Note".to_string())
+}
+
+/// Warnings about how the note's content was parsed.
+fn note_warnings(content: &str) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    if has_unclosed_fence(&parse_chunks(content)) {
+        warnings.push(Warning {
+            code: "unclosed_code_fence",
+            message: "Code block is missing its closing fence and runs to the end of the note"
+                .to_string(),
+        });
+    }
+    warnings
+}
+
 fn json_error(msg: &str) -> String {
     serde_json::to_string(&ErrorResponse {
         error: msg.to_string(),
@@ -206,7 +4204,36 @@ fn json_error(msg: &str) -> String {
     .unwrap()
 }
 
-fn db_error(err: rusqlite::Error) -> (u16, String) {
-    eprintln!("Database error: {:?}", err);
-    (500, json_error("Database error"))
+fn db_error(err: StorageError) -> (u16, String) {
+    log::error(
+        "database error",
+        serde_json::json!({ "error": err.to_string() }),
+    );
+    if err.is_busy() {
+        (503, json_error("Database busy, try again"))
+    } else {
+        (500, json_error("Database error"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        // Formulas are defused
+        assert_eq!(csv_field("=1+1"), "'=1+1");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(
+            csv_field("=HYPERLINK(\"http://x\",\"y\")"),
+            "\"'=HYPERLINK(\"\"http://x\"\",\"\"y\"\")\""
+        );
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
 }
@@ -0,0 +1,120 @@
+//! Inbound webhooks: external services post JSON to a secret URL, and a
+//! per-hook template turns it into text written into the owner's note.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookAction {
+    /// Add the text as a new block at the end of the note.
+    Append,
+    /// Add the text as a new block at the start of the note.
+    Prepend,
+}
+
+impl HookAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "append" => Some(HookAction::Append),
+            "prepend" => Some(HookAction::Prepend),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookAction::Append => "append",
+            HookAction::Prepend => "prepend",
+        }
+    }
+
+    /// `content` with `text` added as its own block.
+    pub fn apply(&self, content: &str, text: &str) -> String {
+        let (content, text) = (content.trim(), text.trim());
+        if content.is_empty() {
+            return text.to_string();
+        }
+        match self {
+            HookAction::Append => format!("{}\n\n{}", content, text),
+            HookAction::Prepend => format!("{}\n\n{}", text, content),
+        }
+    }
+}
+
+/// Fill `{{path}}` placeholders from `payload`. Paths are dot-separated
+/// object keys or array indexes (`{{items.0.title}}`). Strings are inserted
+/// as they are, other values as JSON, and missing values as nothing. An
+/// unclosed `{{` is kept literally.
+pub fn render_template(template: &str, payload: &Value) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let path = rest[start + 2..start + end].trim();
+        out.push_str(&lookup(payload, path));
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn lookup(payload: &Value, path: &str) -> String {
+    let mut value = payload;
+    for key in path.split('.').filter(|k| !k.is_empty()) {
+        let next = match value {
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+            _ => value.get(key),
+        };
+        match next {
+            Some(next) => value = next,
+            None => return String::new(),
+        }
+    }
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_template() {
+        let payload = json!({
+            "title": "Build failed",
+            "run": { "id": 42, "ok": false },
+            "items": [{ "name": "lint" }],
+            "empty": null,
+        });
+
+        assert_eq!(
+            render_template("- {{ title }} (#{{run.id}}, ok: {{run.ok}})", &payload),
+            "- Build failed (#42, ok: false)"
+        );
+        assert_eq!(render_template("{{items.0.name}}", &payload), "lint");
+        assert_eq!(
+            render_template("[{{missing.key}}{{empty}}]", &payload),
+            "[]"
+        );
+        assert_eq!(
+            render_template("{{run}}", &payload),
+            r#"{"id":42,"ok":false}"#
+        );
+        assert_eq!(render_template("a {{ b", &payload), "a {{ b");
+    }
+
+    #[test]
+    fn test_apply_action() {
+        assert_eq!(HookAction::Append.apply("", "new"), "new");
+        assert_eq!(HookAction::Append.apply("# Log\n", " new "), "# Log\n\nnew");
+        assert_eq!(HookAction::Prepend.apply("old", "new"), "new\n\nold");
+        assert_eq!(HookAction::parse("prepend"), Some(HookAction::Prepend));
+        assert_eq!(HookAction::parse("replace"), None);
+    }
+}
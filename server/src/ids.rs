@@ -0,0 +1,91 @@
+//! Identifiers for new records. ULIDs by default; UUIDs for deployments
+//! whose other systems expect them. Both sort by creation time.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    /// 26-character Crockford base32, e.g. `01J9Z3K8W4M6QX2V7B5N0C1D8E`.
+    Ulid = 0,
+    /// Hyphenated lowercase UUIDv7.
+    Uuid = 1,
+}
+
+impl IdStrategy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ulid" => Some(IdStrategy::Ulid),
+            "uuid" => Some(IdStrategy::Uuid),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdStrategy::Ulid => "ulid",
+            IdStrategy::Uuid => "uuid",
+        }
+    }
+}
+
+static STRATEGY: AtomicU8 = AtomicU8::new(IdStrategy::Ulid as u8);
+
+pub fn set_strategy(strategy: IdStrategy) {
+    STRATEGY.store(strategy as u8, Ordering::Relaxed);
+}
+
+/// A new id in the configured scheme.
+pub fn new_id() -> String {
+    let ulid = ulid::Ulid::new();
+    match STRATEGY.load(Ordering::Relaxed) {
+        s if s == IdStrategy::Uuid as u8 => uuid_v7(ulid.0),
+        _ => ulid.to_string(),
+    }
+}
+
+/// Lay a ULID's 48-bit millisecond timestamp and random bits out as a
+/// UUIDv7 (RFC 9562), keeping its time ordering.
+fn uuid_v7(ulid: u128) -> String {
+    let timestamp = ulid >> 80;
+    let random = ulid & ((1 << 80) - 1);
+    let value = (timestamp << 80)
+        | (0x7 << 76)
+        | (((random >> 62) & 0xfff) << 64)
+        | (0b10 << 62)
+        | (random & ((1 << 62) - 1));
+    let hex = format!("{:032x}", value);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_v7() {
+        let ulid = ulid::Ulid::from_parts(0x0192_3f4e_5a6b, u128::MAX);
+        let uuid = uuid_v7(ulid.0);
+        assert_eq!(uuid, "01923f4e-5a6b-7fff-bfff-ffffffffffff");
+
+        let zero = uuid_v7(ulid::Ulid::from_parts(0x0192_3f4e_5a6b, 0).0);
+        assert_eq!(zero, "01923f4e-5a6b-7000-8000-000000000000");
+
+        // Later timestamps sort later, as ULIDs do
+        let later = uuid_v7(ulid::Ulid::from_parts(0x0192_3f4e_5a6c, 0).0);
+        assert!(later > uuid);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(IdStrategy::parse(" UUID "), Some(IdStrategy::Uuid));
+        assert_eq!(IdStrategy::parse("ulid"), Some(IdStrategy::Ulid));
+        assert_eq!(IdStrategy::parse("snowflake"), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! Re-importing documents from other systems. The text written by the last
+//! import of a document is replaced where it still stands in the note, so
+//! importing again updates it instead of adding a second copy.
+
+/// `content` with `imported` in place of `previous`, the text written by an
+/// earlier import of the same document. If there was none, or it has since
+/// been edited, `imported` is added as a new block at the end.
+pub fn merge(content: &str, previous: Option<&str>, imported: &str) -> String {
+    let imported = imported.trim();
+    if let Some(previous) = previous.map(str::trim).filter(|p| !p.is_empty()) {
+        if let Some(start) = content.find(previous) {
+            let end = start + previous.len();
+            return format!("{}{}{}", &content[..start], imported, &content[end..]);
+        }
+    }
+    match content.trim_end() {
+        "" => imported.to_string(),
+        current => format!("{}\n\n{}", current, imported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        // First import
+        assert_eq!(merge("", None, "# Page\n"), "# Page");
+        assert_eq!(merge("# Mine\n", None, "# Page"), "# Mine\n\n# Page");
+
+        // Re-import replaces the earlier text in place
+        assert_eq!(
+            merge(
+                "# Mine\n\n# Page\n\nold\n\n# After",
+                Some("# Page\n\nold"),
+                "# Page\n\nnew"
+            ),
+            "# Mine\n\n# Page\n\nnew\n\n# After"
+        );
+
+        // Edited since the last import: nothing to replace, so it is added
+        assert_eq!(
+            merge("# Page\n\nedited", Some("# Page\n\nold"), "# Page\n\nnew"),
+            "# Page\n\nedited\n\n# Page\n\nnew"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! What build this is and how it was started, logged once at startup and
+//! served to admins at `GET /api/admin/info` for bug reports.
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short hash of the commit the binary was built from, when the build could
+/// tell (see `build.rs`).
+pub const GIT_HASH: Option<&str> = option_env!("TRAME_GIT_HASH");
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeInfo {
+    pub version: &'static str,
+    pub git_hash: Option<&'static str>,
+    /// Optional subsystems this instance was configured with.
+    pub features: Vec<&'static str>,
+    pub feature_flags: Vec<String>,
+    pub id_strategy: &'static str,
+    pub database: String,
+    pub shard_dir: Option<String>,
+    pub listen: String,
+}
+
+impl RuntimeInfo {
+    pub fn new(config: &Config) -> Self {
+        let scheme = if config.tls_cert_path.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        Self {
+            version: VERSION,
+            git_hash: GIT_HASH,
+            features: features(config),
+            feature_flags: config.feature_flags.clone(),
+            id_strategy: config.id_strategy.as_str(),
+            database: config.database_url.clone(),
+            shard_dir: config.shard_dir.clone(),
+            listen: format!("{}://{}:{}", scheme, config.host, config.port),
+        }
+    }
+}
+
+fn features(config: &Config) -> Vec<&'static str> {
+    [
+        ("tls", config.tls_cert_path.is_some()),
+        ("shards", config.shard_dir.is_some()),
+        ("encryption", config.master_key.is_some()),
+        ("static_dir", config.static_dir.is_some()),
+        ("email_hook", config.email_hook.is_some()),
+        ("terms", config.terms_version.is_some()),
+        ("trust_proxy", config.trust_proxy),
+        ("session_cookies", config.session_cookies),
+        ("chaos", config.chaos.is_some()),
+        ("record_fixtures", config.record_fixtures.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let mut config = Config::from_env().unwrap();
+        config.tls_cert_path = None;
+        config.shard_dir = Some("/data/shards".to_string());
+        config.master_key = None;
+        config.static_dir = None;
+        config.email_hook = None;
+        config.terms_version = Some("2024-01".to_string());
+        config.trust_proxy = false;
+        config.session_cookies = false;
+        config.chaos = None;
+        config.record_fixtures = None;
+        assert_eq!(features(&config), vec!["shards", "terms"]);
+
+        config.host = "127.0.0.1".to_string();
+        config.port = 3000;
+        let info = RuntimeInfo::new(&config);
+        assert_eq!(info.listen, "http://127.0.0.1:3000");
+        assert_eq!(info.version, VERSION);
+    }
+}
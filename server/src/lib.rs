@@ -1,22 +1,113 @@
-pub mod chunker;
+pub mod assets;
+pub mod capabilities;
+pub mod cards;
+pub mod chaos;
+pub mod cli;
 pub mod config;
+pub mod convert;
+pub mod cookies;
+pub mod cors;
 pub mod db;
+pub mod diff;
+pub mod email;
+pub mod events;
+pub mod fixtures;
+pub mod flags;
+pub mod gc;
+pub mod gist;
 pub mod handlers;
+pub mod hooks;
+pub mod ids;
+pub mod imports;
+pub mod info;
+pub mod log;
+pub mod merge;
+pub mod pool;
+pub mod render;
+pub mod review;
 pub mod router;
+pub mod singleflight;
+pub mod stats;
+pub mod tls;
+pub mod usage;
+
+pub use trame_chunker as chunker;
 
 use config::Config;
-use db::Database;
+use db::metrics::{self, DbMetrics, Metered};
+use db::{Database, Storage, StorageError};
+use events::Event;
+use singleflight::SingleFlight;
 use std::sync::Arc;
+use usage::KeyUsage;
 
 pub struct AppState {
-    pub db: Database,
+    pub db: Arc<dyn Storage>,
+    /// Call counts and durations of every storage method.
+    pub db_metrics: Arc<DbMetrics>,
     pub config: Config,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    /// Set when `RECORD_FIXTURES` is, to record requests as fixtures.
+    pub recorder: Option<fixtures::Recorder>,
+    /// Concurrent identical note reads, answered by one query. Keys start
+    /// with the user id and a line break.
+    pub note_reads: Arc<SingleFlight<Result<String, (u16, String)>>>,
+    /// Requests per key not yet written to the database.
+    pub key_usage: Arc<KeyUsage>,
 }
 
 impl AppState {
-    pub fn new(config: Config) -> Result<Arc<Self>, rusqlite::Error> {
-        let db = Database::open(&config.database_url)?;
-        db.migrate()?;
-        Ok(Arc::new(Self { db, config }))
+    pub fn new(config: Config) -> Result<Arc<Self>, StorageError> {
+        metrics::set_slow_query_threshold(config.slow_query_ms);
+        let db = open_database(&config)?;
+        // A read that started before a write mustn't answer requests made after it
+        let note_reads = Arc::new(SingleFlight::default());
+        let reads = note_reads.clone();
+        db.events().subscribe(move |event| match event {
+            Event::NoteChanged { user_id } => reads.forget(&format!("{}\n", user_id)),
+        });
+        let db_metrics = Arc::new(DbMetrics::default());
+        let recorder = match &config.record_fixtures {
+            Some(dir) => Some(fixtures::Recorder::new(dir).map_err(|err| {
+                StorageError::Other(format!("Can't record fixtures in {}: {}", dir, err))
+            })?),
+            None => None,
+        };
+        Ok(Arc::new(Self {
+            db: Arc::new(Metered::new(db, db_metrics.clone())),
+            db_metrics,
+            config,
+            started_at: chrono::Utc::now(),
+            recorder,
+            note_reads,
+            key_usage: Arc::new(KeyUsage::default()),
+        }))
+    }
+}
+
+/// Open the configured database and bring its schema up to date. Shards are
+/// migrated as they are opened; see [`Database::migrate_shards`].
+pub fn open_database(config: &Config) -> Result<Database, StorageError> {
+    // Anything else is taken as a SQLite path, which would quietly create
+    // a file named after the URL
+    if config.database_url.starts_with("postgres://")
+        || config.database_url.starts_with("postgresql://")
+    {
+        return Err(StorageError::Other(
+            "Postgres DATABASE_URLs are not supported; use a SQLite file path".to_string(),
+        ));
+    }
+
+    let mut db = Database::open_pooled(&config.database_url, config.database_pool_size)?;
+    if let Some(dir) = &config.shard_dir {
+        db = db.with_shards(dir, config.shard_cache_size)?;
+    }
+    if config.note_cache_size > 0 {
+        db = db.with_note_cache(config.note_cache_size);
+    }
+    if let Some(master) = &config.master_key {
+        db = db.with_master_key(master);
     }
+    db.migrate()?;
+    Ok(db)
 }
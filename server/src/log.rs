@@ -0,0 +1,144 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Log to stderr instead, leaving stdout to a command's output.
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write one JSON line to stdout: timestamp, level, message, then `fields`
+/// (which should be a JSON object; anything else is stored under "data").
+pub fn emit(level: Level, msg: &str, fields: Value) {
+    if !enabled(level) {
+        return;
+    }
+    let line = format_line(level, msg, fields);
+    if TO_STDERR.load(Ordering::Relaxed) {
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    } else {
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+}
+
+fn format_line(level: Level, msg: &str, fields: Value) -> String {
+    let mut entry = Map::new();
+    entry.insert("ts".into(), json!(chrono::Utc::now().to_rfc3339()));
+    entry.insert("level".into(), json!(level.as_str()));
+    entry.insert("msg".into(), json!(msg));
+    match fields {
+        Value::Object(map) => entry.extend(map),
+        Value::Null => {}
+        other => {
+            entry.insert("data".into(), other);
+        }
+    }
+    Value::Object(entry).to_string()
+}
+
+pub fn error(msg: &str, fields: Value) {
+    emit(Level::Error, msg, fields);
+}
+
+pub fn warn(msg: &str, fields: Value) {
+    emit(Level::Warn, msg, fields);
+}
+
+pub fn info(msg: &str, fields: Value) {
+    emit(Level::Info, msg, fields);
+}
+
+pub fn debug(msg: &str, fields: Value) {
+    emit(Level::Debug, msg, fields);
+}
+
+/// Access log line for one handled request.
+pub fn request(method: &str, path: &str, status: u16, latency: Duration, user_id: Option<&str>) {
+    let level = if status >= 500 {
+        Level::Error
+    } else {
+        Level::Info
+    };
+    emit(
+        level,
+        "request",
+        json!({
+            "method": method,
+            "path": path,
+            "status": status,
+            "latency_ms": latency.as_micros() as f64 / 1000.0,
+            "user_id": user_id,
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+        assert_eq!(Level::parse(" debug "), Some(Level::Debug));
+        assert_eq!(Level::parse("verbose"), None);
+        assert!(Level::Error < Level::Info);
+    }
+
+    #[test]
+    fn test_format_line() {
+        let line = format_line(Level::Info, "request", json!({"status": 200}));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "info");
+        assert_eq!(value["msg"], "request");
+        assert_eq!(value["status"], 200);
+        assert!(value["ts"].is_string());
+    }
+}
@@ -1,41 +1,209 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use hyper::server::conn::http1;
+use hyper::body::Incoming;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 
-use trame::{config::Config, router::Router, AppState};
+use serde_json::json;
+use trame::cli::{self, Command};
+use trame::info::RuntimeInfo;
+use trame::{config::Config, gc, ids, log, router::Router, tls, AppState};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Load .env file (ignore if not found)
     dotenvy::dotenv().ok();
 
-    let config = Config::from_env();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, flags) = match Command::parse(&args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let mut config = match Config::load(&flags) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error("invalid configuration", json!({ "error": err.to_string() }));
+            std::process::exit(2);
+        }
+    };
+    log::set_level(config.log_level);
+    ids::set_strategy(config.id_strategy);
+
+    // Fault injection must never reach a real deployment
+    if let Some(chaos) = config.chaos {
+        if cfg!(debug_assertions) {
+            log::warn(
+                "chaos mode enabled",
+                json!({ "max_latency_ms": chaos.max_latency_ms, "error_rate": chaos.error_rate }),
+            );
+        } else {
+            log::warn("chaos settings ignored in release builds", json!({}));
+            config.chaos = None;
+        }
+    }
     let addr: SocketAddr = format!("{}:{}", config.host, config.port).parse()?;
 
-    println!("Database: {}", config.database_url);
+    if command != Command::Serve {
+        if let Err(err) = cli::run(command, config).await {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key)?),
+        (None, None) => None,
+        _ => return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".into()),
+    };
 
     let state = AppState::new(config)?;
     let listener = TcpListener::bind(addr).await?;
 
-    println!("Server running on http://{}", addr);
+    let info = RuntimeInfo::new(&state.config);
+    log::info("started", serde_json::to_value(&info)?);
+
+    // Schema changes are in place; big data changes finish while serving
+    let db = state.db.clone();
+    let batch_size = state.config.backfill_batch_size;
+    tokio::spawn(async move {
+        match db.run(move |db| db.run_backfills(batch_size)).await {
+            Ok(0) => {}
+            Ok(batches) => log::info("backfills complete", json!({ "batches": batches })),
+            Err(err) => log::error("backfill failed", json!({ "error": err.to_string() })),
+        }
+    });
+
+    if state.config.session_gc_interval_secs > 0 {
+        let interval = Duration::from_secs(state.config.session_gc_interval_secs);
+        let retention = gc::Retention {
+            trash_days: state.config.trash_retention_days,
+            revision_interval: Duration::from_secs(state.config.revision_interval_mins * 60),
+            history_limit: state.config.history_limit,
+        };
+        tokio::spawn(gc::run(
+            state.db.clone(),
+            state.key_usage.clone(),
+            interval,
+            retention,
+        ));
+    }
+
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn("accept error", json!({ "error": err.to_string() }));
+                    continue;
+                }
+            },
+            _ = shutdown.as_mut() => break,
+        };
+
         let state = state.clone();
+        let watcher = graceful.watcher();
+        let tls = tls.clone();
 
         tokio::task::spawn(async move {
-            let service = service_fn(move |req| {
-                let state = state.clone();
-                async move { Router::handle(req, state).await }
-            });
-
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                eprintln!("Error serving connection: {:?}", err);
+            match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => serve(stream, peer, state, watcher).await,
+                    Err(err) => {
+                        log::debug("tls handshake failed", json!({ "error": err.to_string() }))
+                    }
+                },
+                None => serve(stream, peer, state, watcher).await,
             }
         });
     }
+
+    // Stop accepting, then give open connections time to finish
+    drop(listener);
+    let timeout = Duration::from_secs(state.config.shutdown_timeout_secs);
+    log::info(
+        "shutting down",
+        json!({ "timeout_secs": state.config.shutdown_timeout_secs }),
+    );
+    tokio::select! {
+        _ = graceful.shutdown() => log::info("connections drained", json!({})),
+        _ = tokio::time::sleep(timeout) => {
+            log::warn("shutdown timeout reached, closing remaining connections", json!({}))
+        }
+    }
+
+    let db = state.db.clone();
+    if let Err(err) = state.key_usage.flush(&db).await {
+        log::warn(
+            "key usage not recorded",
+            json!({ "error": err.to_string() }),
+        );
+    }
+    match db.run(|db| db.checkpoint()).await {
+        Ok(()) => log::info("database checkpointed", json!({})),
+        Err(err) => log::error(
+            "checkpoint failed",
+            json!({ "error": format!("{:?}", err) }),
+        ),
+    }
+
+    Ok(())
+}
+
+/// Serve HTTP on one accepted (plain or TLS) stream until it closes. The
+/// protocol is HTTP/2 when the client negotiated it over TLS or opened with
+/// the HTTP/2 preface, and HTTP/1.1 otherwise.
+async fn serve<S>(stream: S, peer: SocketAddr, state: Arc<AppState>, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        // Lets the router see who it's talking to
+        req.extensions_mut().insert(peer);
+        let state = state.clone();
+        async move { Router::handle(req, state).await }
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection(TokioIo::new(stream), service);
+
+    if let Err(err) = watcher.watch(conn).await {
+        log::warn("connection error", json!({ "error": format!("{:?}", err) }));
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
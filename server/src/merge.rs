@@ -0,0 +1,222 @@
+//! Three-way merge of note contents at chunk granularity, for saves made
+//! against an older version of the note than the stored one.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::chunker::chunk_and_hash;
+use crate::diff::lcs_pairs;
+
+/// Above this many chunk comparisons between the base and either side,
+/// notes are too large to merge.
+const MAX_MERGE_CELLS: usize = 4_000_000;
+
+/// Blocks changed differently on both sides since the common base.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MergeConflict {
+    /// Position of the first conflicting chunk in the merged content.
+    pub index: usize,
+    pub base: String,
+    /// The stored version.
+    pub server: String,
+    /// The version being saved, which is what the merged content holds.
+    pub client: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Merge {
+    pub content: String,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// One side of the merge: its chunk hashes, and each chunk's text with the
+/// blank lines that follow it so untouched runs come out as they were.
+struct Side {
+    hashes: Vec<String>,
+    blocks: Vec<String>,
+}
+
+impl Side {
+    fn new(content: &str) -> Self {
+        let chunks = chunk_and_hash(content);
+        let chars: Vec<char> = content.chars().collect();
+        let blocks = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let end = chunks
+                    .get(i + 1)
+                    .map_or(chars.len(), |next| next.chunk.start_offset);
+                chars[c.chunk.start_offset..end].iter().collect()
+            })
+            .collect();
+        Self {
+            hashes: chunks.into_iter().map(|c| c.content_hash).collect(),
+            blocks,
+        }
+    }
+}
+
+/// Merge the changes from `base` to `server` and from `base` to `client`.
+///
+/// Chunks unchanged on both sides anchor the merge. Between two anchors, a
+/// side that kept the base loses to the side that changed it, and both
+/// sides making the same change is no conflict. Anything else is a conflict,
+/// resolved for the client. `None` when the changes are too large to align.
+pub fn merge_notes(base: &str, server: &str, client: &str) -> Option<Merge> {
+    if server == base || server == client {
+        return Some(Merge {
+            content: client.to_string(),
+            conflicts: Vec::new(),
+        });
+    }
+    if client == base {
+        return Some(Merge {
+            content: server.to_string(),
+            conflicts: Vec::new(),
+        });
+    }
+
+    let (base, server_side, client_side) = (Side::new(base), Side::new(server), Side::new(client));
+    let to_server = lcs_pairs(&base.hashes, &server_side.hashes, MAX_MERGE_CELLS)?;
+    let to_client: HashMap<usize, usize> =
+        lcs_pairs(&base.hashes, &client_side.hashes, MAX_MERGE_CELLS)?
+            .into_iter()
+            .collect();
+
+    // Base chunks both sides kept, with where each side has them
+    let anchors: Vec<(usize, usize, usize)> = to_server
+        .iter()
+        .filter_map(|&(b, s)| to_client.get(&b).map(|&c| (b, s, c)))
+        .chain(std::iter::once((
+            base.hashes.len(),
+            server_side.hashes.len(),
+            client_side.hashes.len(),
+        )))
+        .collect();
+
+    let mut blocks: Vec<String> = Vec::new();
+    let mut conflicts = Vec::new();
+    let (mut b, mut s, mut c) = (0, 0, 0);
+    for (next_b, next_s, next_c) in anchors {
+        let base_gap = &base.hashes[b..next_b];
+        let server_gap = &server_side.hashes[s..next_s];
+        let client_gap = &client_side.hashes[c..next_c];
+
+        if server_gap == base_gap || server_gap == client_gap {
+            blocks.extend_from_slice(&client_side.blocks[c..next_c]);
+        } else if client_gap == base_gap {
+            blocks.extend_from_slice(&server_side.blocks[s..next_s]);
+        } else {
+            conflicts.push(MergeConflict {
+                index: blocks.len(),
+                base: join(&base.blocks[b..next_b]),
+                server: join(&server_side.blocks[s..next_s]),
+                client: join(&client_side.blocks[c..next_c]),
+            });
+            blocks.extend_from_slice(&client_side.blocks[c..next_c]);
+        }
+
+        if next_c < client_side.blocks.len() {
+            blocks.push(client_side.blocks[next_c].clone());
+        }
+        (b, s, c) = (next_b + 1, next_s + 1, next_c + 1);
+    }
+
+    let mut content = String::new();
+    for block in &blocks {
+        if !content.is_empty() && !content.ends_with("\n\n") {
+            content.push_str(if content.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            });
+        }
+        content.push_str(block);
+    }
+    let mut content = content.trim_end().to_string();
+    if client.ends_with('\n') {
+        content.push('\n');
+    }
+    Some(Merge { content, conflicts })
+}
+
+fn join(blocks: &[String]) -> String {
+    blocks.concat().trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "# Plan\n\nIntro\n\n- one\n- two\n\nOutro\n";
+
+    #[test]
+    fn test_merge_disjoint_edits() {
+        let server = "# Plan\n\nIntro, revised\n\n- one\n- two\n\nOutro\n";
+        let client = "# Plan\n\nIntro\n\n- one\n- two\n\nOutro\n\nNew ending\n";
+        let merge = merge_notes(BASE, server, client).unwrap();
+        assert_eq!(merge.conflicts, vec![]);
+        assert_eq!(
+            merge.content,
+            "# Plan\n\nIntro, revised\n\n- one\n- two\n\nOutro\n\nNew ending\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_same_edit_on_both_sides() {
+        let edited = "# Plan\n\nIntro\n\n- one\n- two\n- three\n\nOutro\n";
+        let merge = merge_notes(BASE, &edited.replace("# Plan", "# Plans"), edited).unwrap();
+        assert_eq!(merge.conflicts, vec![]);
+        assert_eq!(
+            merge.content,
+            "# Plans\n\nIntro\n\n- one\n- two\n- three\n\nOutro\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let server = "# Plan\n\nIntro by server\n\n- one\n- two\n\nOutro\n";
+        let client = "# Plan\n\nIntro by client\n\n- one\n- two\n\nOutro, edited\n";
+        let merge = merge_notes(BASE, server, client).unwrap();
+        assert_eq!(
+            merge.conflicts,
+            vec![MergeConflict {
+                index: 1,
+                base: "Intro".to_string(),
+                server: "Intro by server".to_string(),
+                client: "Intro by client".to_string(),
+            }]
+        );
+        assert_eq!(merge.content, client);
+    }
+
+    #[test]
+    fn test_merge_deletion() {
+        let server = "# Plan\n\n- one\n- two\n\nOutro\n";
+        let client = "# Plan\n\nIntro\n\n- one\n- two\n\nOutro\n\nP.S.";
+        let merge = merge_notes(BASE, server, client).unwrap();
+        assert_eq!(merge.conflicts, vec![]);
+        assert_eq!(merge.content, "# Plan\n\n- one\n- two\n\nOutro\n\nP.S.");
+    }
+
+    #[test]
+    fn test_merge_too_large() {
+        let note = |word: &str| {
+            let body: Vec<String> = (0..3000).map(|i| format!("{} {}", word, i)).collect();
+            format!("# Plan\n\n{}\n", body.join("\n\n"))
+        };
+        assert_eq!(
+            merge_notes(&note("base"), &note("server"), &note("client")),
+            None
+        );
+
+        // Only the part that changed counts
+        let server = note("base").replace("base 10\n", "server 10\n");
+        let client = note("base").replace("base 2990\n", "client 2990\n");
+        let merge = merge_notes(&note("base"), &server, &client).unwrap();
+        assert_eq!(merge.conflicts, vec![]);
+        assert!(merge.content.contains("server 10\n") && merge.content.contains("client 2990\n"));
+    }
+}
@@ -0,0 +1,148 @@
+use rusqlite::Connection;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A small pool of SQLite connections to one database file.
+///
+/// Connections are opened lazily up to `size` and run in WAL mode, so readers
+/// don't block each other or the writer.
+pub struct Pool {
+    path: String,
+    size: usize,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+pub struct PooledConnection {
+    pool: Arc<Pool>,
+    conn: Option<Connection>,
+}
+
+impl Pool {
+    pub fn new(path: &str, size: usize) -> Arc<Self> {
+        // Every connection to ":memory:" is a separate database
+        let size = if path == ":memory:" { 1 } else { size.max(1) };
+        Arc::new(Self {
+            path: path.to_string(),
+            size,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            available: Condvar::new(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take a connection, opening a new one if the pool isn't full yet and
+    /// waiting for one to be returned otherwise.
+    pub fn get(self: &Arc<Self>) -> Result<PooledConnection, rusqlite::Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(self.wrap(conn));
+            }
+            if state.open < self.size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(conn) => Ok(self.wrap(conn)),
+                    Err(e) => {
+                        self.state.lock().unwrap().open -= 1;
+                        self.available.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let mut conn = Connection::open(&self.path)?;
+        conn.profile(Some(crate::db::metrics::statement_finished));
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // In-memory databases report "memory" and ignore the request
+        let _mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        conn.execute_batch("PRAGMA synchronous = NORMAL;")?;
+        Ok(conn)
+    }
+
+    fn wrap(self: &Arc<Self>, conn: Connection) -> PooledConnection {
+        PooledConnection {
+            pool: self.clone(),
+            conn: Some(conn),
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.state.lock().unwrap().idle.push(conn);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuses_and_waits_for_connections() {
+        let path = std::env::temp_dir().join(format!("trame-pool-{}.db", ulid::Ulid::new()));
+        let pool = Pool::new(path.to_str().unwrap(), 2);
+
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        let mode: String = a
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        // A third caller waits until a connection is returned
+        let waiter = {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.get().map(|_| ()))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(a);
+        waiter.join().unwrap().unwrap();
+        drop(b);
+
+        assert_eq!(pool.state.lock().unwrap().open, 2);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
+
+    #[test]
+    fn test_memory_pool_has_one_connection() {
+        assert_eq!(Pool::new(":memory:", 8).size(), 1);
+    }
+}
@@ -0,0 +1,489 @@
+use crate::chunker::{heading_text, ChunkType, ParsedChunk};
+use crate::diff::{ChangeKind, ChunkChange, SpanOp};
+
+const DIFF_STYLE: &str = "
+body { font-family: system-ui, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; color: #222; }
+header { color: #666; font-size: 0.875rem; margin-bottom: 1.5rem; }
+.chunk { white-space: pre-wrap; font-family: ui-monospace, monospace; font-size: 0.875rem; padding: 0.5rem 0.75rem; margin: 0.25rem 0; border-left: 3px solid transparent; }
+.chunk.unchanged { color: #777; }
+.chunk.added { border-color: #2da44e; background: #e6ffec; }
+.chunk.removed { border-color: #cf222e; background: #ffebe9; }
+.chunk.modified { border-color: #bf8700; }
+.chunk.moved { border-color: #0969da; border-left-style: dashed; }
+ins { background: #abf2bc; text-decoration: none; }
+del { background: #ffc1c0; }
+";
+
+const NOTE_STYLE: &str = "
+body { font-family: system-ui, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; line-height: 1.6; color: #222; }
+pre { background: #f6f8fa; padding: 0.75rem; overflow-x: auto; }
+code { font-family: ui-monospace, monospace; font-size: 0.875em; }
+hr { border: none; border-top: 1px solid #ddd; }
+li.task { list-style: none; }
+";
+
+const PRINT_STYLE: &str = "
+@page { margin: 2cm; }
+body { font-family: Georgia, serif; font-size: 11pt; line-height: 1.5; color: #000; }
+h1 { break-before: page; }
+h1:first-child { break-before: auto; }
+h1, h2, h3, h4, h5, h6 { break-after: avoid; }
+p, li { orphans: 3; widows: 3; }
+pre { white-space: pre-wrap; border: 1px solid #ccc; padding: 0.5rem; break-inside: avoid; }
+code { font-family: ui-monospace, monospace; font-size: 0.9em; }
+hr { border: none; border-top: 1px solid #999; }
+a { color: inherit; }
+li.task { list-style: none; }
+";
+
+/// Language and text direction of a note, for the `lang` and `dir`
+/// attributes of its rendered pages.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LangHint {
+    pub lang: Option<String>,
+    pub dir: Option<&'static str>,
+}
+
+/// Primary language subtags written right to left.
+const RTL_LANGUAGES: &[&str] = &[
+    "ar", "arc", "ckb", "dv", "fa", "he", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+
+impl LangHint {
+    /// Hint from the note's `lang` and `dir` metadata. Without a `dir`, the
+    /// direction follows the language, or else the first strongly
+    /// directional letter of `content`.
+    pub fn for_note(meta: &serde_json::Value, content: &str) -> Self {
+        let lang = meta["lang"]
+            .as_str()
+            .map(str::trim)
+            .filter(|l| {
+                !l.is_empty()
+                    && l.len() <= 35
+                    && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+            .map(str::to_string);
+        let dir = match meta["dir"].as_str() {
+            Some("ltr") => Some("ltr"),
+            Some("rtl") => Some("rtl"),
+            Some("auto") => Some("auto"),
+            _ => None,
+        };
+        let dir = dir.or_else(|| {
+            let primary = lang.as_deref()?.split('-').next()?.to_ascii_lowercase();
+            Some(if RTL_LANGUAGES.contains(&primary.as_str()) {
+                "rtl"
+            } else {
+                "ltr"
+            })
+        });
+        Self {
+            dir: dir.or_else(|| detect_dir(content)),
+            lang,
+        }
+    }
+
+    /// The hint as attributes for the `<html>` element, each with a leading
+    /// space.
+    fn attributes(&self) -> String {
+        let mut out = String::new();
+        if let Some(lang) = &self.lang {
+            out.push_str(&format!(" lang=\"{}\"", escape_html(lang)));
+        }
+        if let Some(dir) = self.dir {
+            out.push_str(&format!(" dir=\"{}\"", dir));
+        }
+        out
+    }
+}
+
+/// Direction of the first strongly directional letter in `text`, or `None`
+/// if it has no letters.
+fn detect_dir(text: &str) -> Option<&'static str> {
+    text.chars().filter(|c| c.is_alphabetic()).map(|c| {
+        let rtl = matches!(c as u32, 0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF);
+        if rtl { "rtl" } else { "ltr" }
+    }).next()
+}
+
+/// Escape text for use in HTML element content and quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Render a chunk diff as a standalone HTML page, with word-level
+/// insertions and deletions highlighted inside modified chunks.
+pub fn diff_page(title: &str, changes: &[ChunkChange], hint: &LangHint) -> String {
+    let mut body = String::new();
+
+    for change in changes {
+        let (class, inner) = match change.kind {
+            ChangeKind::Unchanged => (
+                "unchanged",
+                escape_html(change.new_content.as_deref().unwrap_or_default()),
+            ),
+            ChangeKind::Moved => (
+                "moved",
+                escape_html(change.new_content.as_deref().unwrap_or_default()),
+            ),
+            ChangeKind::Added => (
+                "added",
+                format!(
+                    "<ins>{}</ins>",
+                    escape_html(change.new_content.as_deref().unwrap_or_default())
+                ),
+            ),
+            ChangeKind::Removed => (
+                "removed",
+                format!(
+                    "<del>{}</del>",
+                    escape_html(change.old_content.as_deref().unwrap_or_default())
+                ),
+            ),
+            ChangeKind::Modified => {
+                let spans: String = change
+                    .text
+                    .iter()
+                    .map(|span| match span.op {
+                        SpanOp::Equal => escape_html(&span.text),
+                        SpanOp::Insert => format!("<ins>{}</ins>", escape_html(&span.text)),
+                        SpanOp::Delete => format!("<del>{}</del>", escape_html(&span.text)),
+                    })
+                    .collect();
+                ("modified", spans)
+            }
+        };
+        body.push_str(&format!(
+            "<div class=\"chunk {} {}\">{}</div>\n",
+            class,
+            escape_html(&change.chunk_type),
+            inner
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html{attrs}>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{title}</title>\n<style>{style}</style>\n</head>\n<body>\n<header>{title}</header>\n{body}</body>\n</html>\n",
+        title = escape_html(title),
+        attrs = hint.attributes(),
+        style = DIFF_STYLE,
+        body = body,
+    )
+}
+
+/// Render a note's chunks as a standalone HTML page. Covers the Markdown the
+/// chunker understands, plus code spans, emphasis and links inside text.
+pub fn note_page(title: &str, chunks: &[ParsedChunk], hint: &LangHint) -> String {
+    render_note(title, chunks, hint, NOTE_STYLE)
+}
+
+/// Same as [`note_page`], styled for paper: serif text, each top-level
+/// heading on a new page, and code blocks kept whole where they fit.
+pub fn print_page(title: &str, chunks: &[ParsedChunk], hint: &LangHint) -> String {
+    render_note(title, chunks, hint, PRINT_STYLE)
+}
+
+/// The blocks as an HTML fragment, with no page around them or styles, for
+/// embedding in other documents.
+pub fn note_fragment(chunks: &[ParsedChunk]) -> String {
+    let mut body = String::new();
+
+    for chunk in chunks {
+        match chunk.chunk_type {
+            ChunkType::Heading => {
+                let level = chunk.heading_level.unwrap_or(1);
+                let text = heading_text(chunk);
+                body.push_str(&format!("<h{0}>{1}</h{0}>\n", level, render_inline(text)));
+            }
+            ChunkType::Paragraph => {
+                body.push_str(&format!("<p>{}</p>\n", render_inline(&chunk.content)));
+            }
+            ChunkType::CodeBlock => body.push_str(&code_block(&chunk.content)),
+            ChunkType::List | ChunkType::TaskList => body.push_str(&list(&chunk.content)),
+            ChunkType::HorizontalRule => body.push_str("<hr>\n"),
+        }
+    }
+    body
+}
+
+fn render_note(title: &str, chunks: &[ParsedChunk], hint: &LangHint, style: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html{attrs}>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{title}</title>\n<style>{style}</style>\n</head>\n<body>\n{body}</body>\n</html>\n",
+        title = escape_html(title),
+        attrs = hint.attributes(),
+        style = style,
+        body = note_fragment(chunks),
+    )
+}
+
+fn code_block(content: &str) -> String {
+    let (info, rest) = content.split_once('\n').unwrap_or((content, ""));
+    let lang = info.trim_start_matches('`').trim();
+    // Drop the closing fence, if the block has one
+    let code = match rest.trim_end().rsplit_once('\n') {
+        Some((code, last)) if last.trim_start().starts_with("```") => code,
+        None if rest.trim_start().starts_with("```") => "",
+        _ => rest,
+    };
+    let class = if lang.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"language-{}\"", escape_html(lang))
+    };
+    format!(
+        "<pre><code{}>{}</code></pre>\n",
+        class,
+        escape_html(code.trim_end_matches('\n'))
+    )
+}
+
+/// Render a list chunk, nesting items by indentation. Lines without a marker
+/// continue the previous item.
+fn list(content: &str) -> String {
+    let mut out = String::new();
+    // Indentation and closing tag of each open list, outermost first
+    let mut open: Vec<(usize, &str)> = Vec::new();
+
+    for line in content.lines() {
+        let Some((indent, ordered, text)) = list_item(line) else {
+            if !line.trim().is_empty() && !open.is_empty() {
+                out.push(' ');
+                out.push_str(&render_inline(line.trim()));
+            }
+            continue;
+        };
+
+        while open.last().is_some_and(|&(i, _)| i > indent) {
+            let (_, close) = open.pop().unwrap();
+            out.push_str(&format!("</li>\n</{}>", close));
+        }
+        if open.last().is_some_and(|&(i, _)| i == indent) {
+            out.push_str("</li>\n");
+        } else {
+            let tag = if ordered { "ol" } else { "ul" };
+            if !open.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("<{}>\n", tag));
+            open.push((indent, tag));
+        }
+
+        let task = match text.as_bytes() {
+            [b'[', mark @ (b' ' | b'x' | b'X'), b']', rest @ ..]
+                if rest.is_empty() || rest[0] == b' ' =>
+            {
+                Some(*mark != b' ')
+            }
+            _ => None,
+        };
+        match task {
+            Some(done) => out.push_str(&format!(
+                "<li class=\"task\"><input type=\"checkbox\" disabled{}> {}",
+                if done { " checked" } else { "" },
+                render_inline(text[3..].trim_start())
+            )),
+            None => out.push_str(&format!("<li>{}", render_inline(text))),
+        }
+    }
+
+    while let Some((_, close)) = open.pop() {
+        out.push_str(&format!("</li>\n</{}>", close));
+        if open.is_empty() {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Indentation, whether the marker is numbered, and the text of a list item
+/// line, or `None` if the line doesn't start an item.
+fn list_item(line: &str) -> Option<(usize, bool, &str)> {
+    let rest = line.trim_start_matches([' ', '\t']);
+    let indent = line[..line.len() - rest.len()]
+        .chars()
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum();
+    if let Some(text) = rest
+        .strip_prefix(['-', '*', '+'])
+        .and_then(|r| r.strip_prefix(' '))
+    {
+        return Some((indent, false, text));
+    }
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let text = rest[digits..]
+        .strip_prefix(['.', ')'])
+        .and_then(|r| r.strip_prefix(' '))
+        .filter(|_| digits > 0)?;
+    Some((indent, true, text))
+}
+
+/// Render code spans, strong and emphasized text, and links; everything else
+/// is escaped. Unmatched markers are kept as text.
+fn render_inline(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                out.push_str(&format!("<code>{}</code>", escape_html(&rest[1..end + 1])));
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if rest.starts_with("**") || rest.starts_with("__") {
+            let marker = &rest[..2];
+            if let Some(end) = rest[2..].find(marker).filter(|&end| end > 0) {
+                out.push_str(&format!(
+                    "<strong>{}</strong>",
+                    render_inline(&rest[2..end + 2])
+                ));
+                rest = &rest[end + 4..];
+                continue;
+            }
+        } else if c == '*' || c == '_' {
+            if let Some(end) = rest[1..].find(c).filter(|&end| end > 0) {
+                out.push_str(&format!("<em>{}</em>", render_inline(&rest[1..end + 1])));
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if c == '[' {
+            if let Some((label, url, len)) = link(rest) {
+                out.push_str(&format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape_html(url),
+                    render_inline(label)
+                ));
+                rest = &rest[len..];
+                continue;
+            }
+        }
+
+        out.push_str(&escape_html(&rest[..c.len_utf8()]));
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Label, URL and byte length of a `[label](url)` link at the start of
+/// `text`. Only web, mail and relative URLs are linked.
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let close = text.find("](")?;
+    let end = close + 2 + text[close + 2..].find(')')?;
+    let (label, url) = (&text[1..close], text[close + 2..end].trim());
+    let scheme = url.split_once(':').map(|(s, _)| s.to_ascii_lowercase());
+    let allowed = match scheme.as_deref() {
+        Some("http" | "https" | "mailto") => true,
+        // A colon after a path separator isn't a scheme
+        Some(s) => s.contains(['/', '?', '#']),
+        None => true,
+    };
+    (allowed && !label.is_empty()).then_some((label, url, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::diff_notes;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_diff_page_highlights_words() {
+        let changes = diff_notes("The quick fox\n\n<b>old</b>", "The slow fox");
+        let html = diff_page("Changes", &changes, &LangHint::default());
+        assert!(html.contains("<del>quick</del><ins>slow</ins>"));
+        assert!(html.contains(
+            "<div class=\"chunk removed paragraph\"><del>&lt;b&gt;old&lt;/b&gt;</del></div>"
+        ));
+    }
+
+    #[test]
+    fn test_note_page_renders_blocks() {
+        let content = "# Title\n\nSome *soft* and **bold** `<code>` with [a link](https://example.com).\n\n- one\n  - nested\n- [x] done\n\n```rust\nfn main() {}\n```\n\n---\n";
+        let html = note_page(
+            "Title",
+            &crate::chunker::parse_chunks(content),
+            &LangHint::default(),
+        );
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains(
+            "<p>Some <em>soft</em> and <strong>bold</strong> <code>&lt;code&gt;</code> with <a href=\"https://example.com\">a link</a>.</p>"
+        ));
+        assert!(html.contains("<ul>\n<li>one\n<ul>\n<li>nested</li>\n</ul></li>\n<li class=\"task\"><input type=\"checkbox\" disabled checked> done</li>\n</ul>\n"));
+        assert!(html.contains("<pre><code class=\"language-rust\">fn main() {}</code></pre>"));
+        assert!(html.contains("<hr>"));
+    }
+
+    #[test]
+    fn test_note_fragment_has_no_page() {
+        let chunks = crate::chunker::parse_chunks("# One\n\n<script>x</script>");
+        assert_eq!(
+            note_fragment(&chunks),
+            "<h1>One</h1>\n<p>&lt;script&gt;x&lt;/script&gt;</p>\n"
+        );
+    }
+
+    #[test]
+    fn test_print_page_breaks_before_top_headings() {
+        let chunks = crate::chunker::parse_chunks("# One\n\ntext\n\n# Two");
+        let html = print_page("One", &chunks, &LangHint::default());
+        assert!(html.contains("h1 { break-before: page; }"));
+        assert!(html.contains("<h1>One</h1>\n<p>text</p>\n<h1>Two</h1>"));
+    }
+
+    #[test]
+    fn test_inline_rejects_script_links() {
+        assert_eq!(
+            render_inline("[x](javascript:alert(1)) 2*3"),
+            "[x](javascript:alert(1)) 2*3"
+        );
+        assert_eq!(render_inline("[x](/a:b)"), "<a href=\"/a:b\">x</a>");
+    }
+
+    #[test]
+    fn test_lang_hint() {
+        use serde_json::json;
+
+        let hint = LangHint::for_note(&json!({}), "# 123 שלום world");
+        assert_eq!(
+            hint,
+            LangHint {
+                lang: None,
+                dir: Some("rtl")
+            }
+        );
+        assert_eq!(LangHint::for_note(&json!({}), "123").dir, None);
+
+        let hint = LangHint::for_note(&json!({ "lang": "ar-EG" }), "Hello");
+        assert_eq!(hint.attributes(), " lang=\"ar-EG\" dir=\"rtl\"");
+        let hint = LangHint::for_note(&json!({ "lang": "he", "dir": "ltr" }), "");
+        assert_eq!(hint.dir, Some("ltr"));
+        let hint = LangHint::for_note(&json!({ "lang": "x\"><script>", "dir": "up" }), "Hello");
+        assert_eq!(
+            hint,
+            LangHint {
+                lang: None,
+                dir: Some("ltr")
+            }
+        );
+
+        let html = note_page("t", &[], &LangHint::for_note(&json!({ "lang": "fa" }), ""));
+        assert!(html.contains("<html lang=\"fa\" dir=\"rtl\">"));
+    }
+}
@@ -0,0 +1,156 @@
+//! The review queue: chunks nobody has touched for a while, ranked so that
+//! the longest-forgotten and the most important come up first. Chunks keep
+//! their `updated_at` across saves until their content changes, so it says
+//! when each was last edited.
+
+use std::collections::HashMap;
+
+use crate::chunker::{extract_tags, parse_chunks};
+use crate::db::Chunk;
+
+/// Weight of a chunk without a weighted tag.
+const DEFAULT_WEIGHT: f64 = 1.0;
+
+pub struct ReviewItem {
+    pub chunk: Chunk,
+    pub tags: Vec<String>,
+    /// Whole days since the chunk last changed.
+    pub days_stale: i64,
+    pub score: f64,
+}
+
+/// Tag weights from `tag:weight` pairs separated by commas, such as
+/// `evergreen:3,draft:0`. Tags are matched as stored: lowercase, without the
+/// `#`.
+pub fn parse_weights(spec: &str) -> Result<HashMap<String, f64>, String> {
+    let mut weights = HashMap::new();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (tag, weight) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("Expected tag:weight, got {}", pair))?;
+        let tag = tag.trim().trim_start_matches('#').to_lowercase();
+        let weight: f64 = weight
+            .trim()
+            .parse()
+            .ok()
+            .filter(|w: &f64| w.is_finite() && *w >= 0.0)
+            .ok_or_else(|| format!("Weight for {} must be a number of 0 or more", tag))?;
+        if tag.is_empty() {
+            return Err(format!("Expected tag:weight, got {}", pair));
+        }
+        weights.insert(tag, weight);
+    }
+    Ok(weights)
+}
+
+/// Chunks last changed at least `after_days` before `now`, highest score
+/// first: days stale times the largest weight among the chunk's tags. A
+/// weight of 0 keeps a tag's chunks out of the queue. Headings and rules
+/// aren't reviewed on their own.
+pub fn queue(
+    chunks: Vec<Chunk>,
+    now: chrono::DateTime<chrono::Utc>,
+    after_days: u64,
+    weights: &HashMap<String, f64>,
+    limit: usize,
+) -> Vec<ReviewItem> {
+    let mut items: Vec<ReviewItem> = chunks
+        .into_iter()
+        .filter(|chunk| !matches!(chunk.chunk_type.as_str(), "heading" | "hr"))
+        .filter_map(|chunk| {
+            let updated_at = chrono::DateTime::parse_from_rfc3339(&chunk.updated_at).ok()?;
+            let days_stale = (now - updated_at.with_timezone(&chrono::Utc)).num_days();
+            if days_stale < after_days as i64 {
+                return None;
+            }
+            // A stored chunk parses back to itself
+            let tags: Vec<String> = parse_chunks(&chunk.content)
+                .iter()
+                .flat_map(extract_tags)
+                .collect();
+            let weight = tags
+                .iter()
+                .filter_map(|tag| weights.get(tag).copied())
+                .reduce(f64::max)
+                .unwrap_or(DEFAULT_WEIGHT);
+            // Chunks just at the threshold still rank by weight
+            let score = (days_stale.max(1) as f64) * weight;
+            (score > 0.0).then_some(ReviewItem {
+                chunk,
+                tags,
+                days_stale,
+                score,
+            })
+        })
+        .collect();
+    items.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.chunk.sequence.cmp(&b.chunk.sequence))
+    });
+    items.truncate(limit);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(sequence: i32, chunk_type: &str, content: &str, updated_at: &str) -> Chunk {
+        Chunk {
+            id: format!("c{}", sequence),
+            note_id: "n1".to_string(),
+            sequence,
+            chunk_type: chunk_type.to_string(),
+            heading_level: None,
+            content: content.to_string(),
+            content_hash: String::new(),
+            start_offset: 0,
+            end_offset: 0,
+            created_at: updated_at.to_string(),
+            updated_at: updated_at.to_string(),
+            pinned: false,
+            start_byte: None,
+            end_byte: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_weights() {
+        let weights = parse_weights(" #Evergreen:3, draft:0 ,").unwrap();
+        assert_eq!(weights.get("evergreen"), Some(&3.0));
+        assert_eq!(weights.get("draft"), Some(&0.0));
+        assert!(parse_weights("").unwrap().is_empty());
+        assert!(parse_weights("evergreen").is_err());
+        assert!(parse_weights("evergreen:-1").is_err());
+        assert!(parse_weights(":2").is_err());
+    }
+
+    #[test]
+    fn test_queue() {
+        let now = chrono::DateTime::parse_from_rfc3339("2026-03-31T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let chunks = vec![
+            chunk(0, "heading", "# Old heading", "2025-01-01T00:00:00Z"),
+            chunk(1, "paragraph", "Old plain", "2026-01-30T12:00:00Z"),
+            chunk(2, "paragraph", "Idea #evergreen", "2026-03-01T12:00:00Z"),
+            chunk(3, "paragraph", "Fresh #evergreen", "2026-03-30T12:00:00Z"),
+            chunk(4, "list", "- wip #draft #evergreen", "2025-06-01T00:00:00Z"),
+            chunk(5, "paragraph", "Skip #draft", "2025-06-01T00:00:00Z"),
+        ];
+        let weights = parse_weights("evergreen:3,draft:0").unwrap();
+
+        let items = queue(chunks.clone(), now, 30, &weights, 10);
+        let ids: Vec<&str> = items.iter().map(|i| i.chunk.id.as_str()).collect();
+        // Highest weight wins: #draft doesn't hide a chunk also #evergreen
+        assert_eq!(ids, ["c4", "c2", "c1"]);
+        assert_eq!((items[1].days_stale, items[1].score), (30, 90.0));
+        assert_eq!(items[0].tags, ["draft", "evergreen"]);
+
+        assert_eq!(queue(chunks.clone(), now, 30, &weights, 1).len(), 1);
+        let all = queue(chunks, now, 0, &HashMap::new(), 10);
+        assert_eq!(all.len(), 5);
+        assert_eq!(all.last().unwrap().chunk.id, "c3");
+    }
+}
@@ -1,68 +1,888 @@
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
-use http_body_util::{BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Bytes};
 use hyper::{Method, Request, Response, StatusCode};
 
-use crate::handlers;
+use crate::assets::{self, Asset};
+use crate::capabilities::{self, Capabilities};
+use crate::chaos;
+use crate::chunker::compute_hash;
+use crate::cookies;
+use crate::cors::Grant;
+use crate::db::Attachment;
+use crate::fixtures::Exchange;
+use crate::handlers::{self, AuthInfo, ClientInfo, Download};
+use crate::log;
 use crate::AppState;
 
 pub struct Router;
 
+/// Names the admin behind an impersonation session on every response to it.
+const IMPERSONATED_BY_HEADER: &str = "x-trame-impersonated-by";
+
 impl Router {
-    pub async fn handle(
-        req: Request<Incoming>,
+    /// Answer one request. Generic over the body so fixtures can be replayed
+    /// without a connection; the server passes hyper's `Incoming`.
+    pub async fn handle<B>(
+        req: Request<B>,
         state: Arc<AppState>,
-    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        let method = req.method().clone();
+    ) -> Result<Response<Full<Bytes>>, hyper::Error>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let started = Instant::now();
+        let method = req.method().to_string();
         let path = req.uri().path().to_string();
-        let origin = &state.config.allowed_origin;
+        let recording = state
+            .recorder
+            .as_ref()
+            .map(|_| (req.uri().to_string(), req.headers().clone()));
+
+        let origin = req.headers().get("origin").and_then(|v| v.to_str().ok());
+        let cors = state.config.cors.grant(origin);
+
+        let mut authed = None;
+        let mut request_body = Bytes::new();
+        let chaos = state.config.chaos.filter(|_| chaos::applies_to(&path));
+        let mut response = match chaos {
+            Some(chaos) if chaos.inject().await => Ok(chaos_unavailable(&cors)),
+            _ => Self::route(req, state.clone(), &cors, &mut authed, &mut request_body).await,
+        };
+
+        if let Some(auth) = &authed {
+            handlers::record_key_use(&state, auth);
+        }
+
+        // Everything an impersonation session sees is audited and flagged
+        if let (Ok(res), Some(auth)) = (&mut response, &authed) {
+            if let Some(admin_id) = &auth.impersonator_id {
+                let status = res.status().as_u16();
+                handlers::audit_impersonated_request(&state, auth, &method, &path, status).await;
+                if let Ok(value) = admin_id.parse() {
+                    res.headers_mut().insert(IMPERSONATED_BY_HEADER, value);
+                }
+            }
+        }
+
+        if let (Some(recorder), Some((uri, headers)), Ok(res)) =
+            (&state.recorder, recording, &response)
+        {
+            let body = res.body().clone().collect().await.unwrap().to_bytes();
+            let exchange = Exchange {
+                method: &method,
+                uri: &uri,
+                headers: &headers,
+                request_body: &request_body,
+                status: res.status().as_u16(),
+                response_body: &body,
+            };
+            if let Err(err) = recorder.record(&exchange) {
+                log::warn(
+                    "fixture not recorded",
+                    serde_json::json!({ "error": err.to_string() }),
+                );
+            }
+        }
+
+        match &response {
+            Ok(res) => log::request(
+                &method,
+                &path,
+                res.status().as_u16(),
+                started.elapsed(),
+                authed.as_ref().map(|auth| auth.user_id.as_str()),
+            ),
+            Err(err) => log::warn(
+                "request failed",
+                serde_json::json!({ "method": method, "path": path, "error": err.to_string() }),
+            ),
+        }
+
+        response
+    }
+
+    async fn route<B>(
+        req: Request<B>,
+        state: Arc<AppState>,
+        cors: &Grant,
+        authed: &mut Option<AuthInfo>,
+        request_body: &mut Bytes,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let method = req.method().clone();
+        let path = normalize_path(req.uri().path());
+        let query = req.uri().query().map(|q| q.to_string());
         let auth_header = req
             .headers()
             .get("authorization")
             .and_then(|v| v.to_str().ok())
             .map(|s| s.to_string());
+        let if_none_match = req
+            .headers()
+            .get("if-none-match")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let content_type = req
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let cookie_header = req
+            .headers()
+            .get("cookie")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let csrf_header = req
+            .headers()
+            .get(cookies::CSRF_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let private_network = req
+            .headers()
+            .get("access-control-request-private-network")
+            .is_some_and(|v| v == "true");
+        let client = client_info(&req, state.config.trust_proxy);
+        let caps = Capabilities::parse(
+            req.headers()
+                .get(capabilities::HEADER)
+                .and_then(|v| v.to_str().ok()),
+        );
 
-        // Read body
-        let body = req.collect().await?.to_bytes();
+        // Read body, refusing oversized ones before buffering them
+        let max_body = state.config.max_body_bytes;
+        let declared = req
+            .headers()
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|len| len > max_body as u64) {
+            return Ok(body_too_large(max_body, cors));
+        }
+        let body = match Limited::new(req.into_body(), max_body).collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(err) => match err.downcast::<hyper::Error>() {
+                Ok(err) => return Err(*err),
+                // Chunked bodies only reveal their size while streaming
+                Err(_) => return Ok(body_too_large(max_body, cors)),
+            },
+        };
         let body_str = String::from_utf8_lossy(&body).to_string();
+        *request_body = body.clone();
+
+        // Routes with path parameters are matched by pattern, others as they are
+        let route = find_route(&path).unwrap_or_default();
+        let route_path = match route.pattern {
+            "" => path.as_str(),
+            pattern => pattern,
+        };
+
+        // Set by routes whose responses clients may cache
+        let mut etag = None;
+        let mut set_cookies = Vec::new();
+
+        // In cookie mode, browsers send the session as a cookie instead
+        let session_cookie = cookies::get(cookie_header.as_deref(), cookies::SESSION_COOKIE)
+            .filter(|_| state.config.session_cookies && auth_header.is_none())
+            .map(|token| format!("Bearer {}", token));
+        let cookie_auth = session_cookie.is_some();
+        let auth_header = auth_header.or(session_cookie);
+
+        // Other sites can make the browser send the cookie too, but can't read
+        // the CSRF cookie to repeat it in a header
+        if cookie_auth
+            && is_write(&method)
+            && !is_csrf_exempt(&path)
+            && !cookies::csrf_matches(cookie_header.as_deref(), csrf_header.as_deref())
+        {
+            let body = r#"{"error":"Missing or invalid CSRF token"}"#;
+            return Ok(json_response(StatusCode::FORBIDDEN, body, cors));
+        }
 
-        let result = match (method, path.as_str()) {
+        // Impersonation sessions can look but not change anything
+        if is_write(&method) && path != "/api/logout" {
+            let auth = authenticate(&state, auth_header.as_deref(), authed).await;
+            if let Some(err) = auth
+                .ok()
+                .and_then(|auth| handlers::require_writable(&auth).err())
+            {
+                return Ok(error_response(err, cors));
+            }
+        }
+
+        // Writes wait until the user has accepted the current terms
+        if is_terms_gated(&method, &path) {
+            if let Err(err) = handlers::require_terms(&state, auth_header.as_deref()).await {
+                return Ok(error_response(err, cors));
+            }
+        }
+
+        let result = match (method, route_path) {
             // Public routes
-            (Method::POST, "/api/signup") => handlers::signup(&state, &body_str),
-            (Method::POST, "/api/login") => handlers::login(&state, &body_str),
+            (Method::POST, "/api/signup") => {
+                let result = handlers::signup(&state, &body_str, &client).await;
+                set_cookies = session_cookies(&state, &result);
+                result
+            }
+            (Method::POST, "/api/login") => {
+                let result = handlers::login(&state, &body_str, &client).await;
+                set_cookies = session_cookies(&state, &result);
+                result
+            }
+
+            (Method::POST, "/api/password/reset/request") => {
+                handlers::request_password_reset(&state, &body_str).await
+            }
+            (Method::POST, "/api/password/reset/confirm") => {
+                handlers::confirm_password_reset(&state, &body_str).await
+            }
+
+            (Method::GET, "/api/terms") => handlers::get_terms(&state).await,
+            // Authenticated by the secret token in the URL
+            (Method::POST, "/hooks/:token") => {
+                let token = route.param("token");
+                handlers::run_hook(&state, &token, &body_str).await
+            }
+            (Method::GET, "/s/:token") => {
+                let token = route.param("token");
+                match handlers::view_share(&state, &token).await {
+                    Ok(html) => return Ok(share_page_response(html)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/announcements") => handlers::list_announcements(&state).await,
 
             // Protected routes
+            (Method::POST, "/api/account/accept-terms") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::accept_terms(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/announcements") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::create_announcement(&state, &auth.user_id, &body_str).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/admin/impersonate") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::impersonate(&state, &auth.user_id, &body_str, &client).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/admin/audit") => {
+                let target = query_param(query.as_deref(), "user_id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::audit_log(&state, &auth.user_id, target).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/admin/audit/export") => {
+                let download = match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::export_audit_log(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "user_id"),
+                            query_param(query.as_deref(), "from"),
+                            query_param(query.as_deref(), "to"),
+                            query_param(query.as_deref(), "format").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match download {
+                    Ok(download) => return Ok(download_response(download, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/admin/info") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::admin_info(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/admin/metrics") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::admin_metrics(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/admin/users/:id/flags") => {
+                let target = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_user_flags(&state, &auth.user_id, &target).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/admin/users/:id/flags/:flag") => {
+                let (target, flag) = (route.param("id"), route.param("flag"));
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::set_user_flag(&state, &auth.user_id, &target, &flag, true).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/admin/users/:id/flags/:flag") => {
+                let (target, flag) = (route.param("id"), route.param("flag"));
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::set_user_flag(&state, &auth.user_id, &target, &flag, false).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/announcements/:id") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::delete_announcement(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
             (Method::POST, "/api/logout") => {
                 let token = auth_header
                     .as_ref()
                     .and_then(|h| h.strip_prefix("Bearer "))
                     .unwrap_or("");
-                handlers::logout(&state, token)
+                if state.config.session_cookies {
+                    set_cookies = cookies::clear().to_vec();
+                }
+                handlers::logout(&state, token).await
+            }
+            (Method::GET, "/api/sessions") => {
+                let token = auth_header
+                    .as_ref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .unwrap_or("");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_sessions(&state, &auth.user_id, token).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/sessions") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::revoke_all_sessions(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/sessions/:token_prefix") => {
+                let prefix = route.param("token_prefix");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::revoke_session(&state, &auth.user_id, &prefix).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/password/change") => {
+                let token = auth_header
+                    .as_ref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .unwrap_or("");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::change_password(&state, &auth.user_id, token, &body_str).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/account") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::delete_account(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
             }
             (Method::GET, "/api/note") => {
-                match handlers::authenticate(&state, auth_header.as_deref()) {
-                    Ok(auth) => handlers::get_note(&state, &auth.user_id),
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        // Polling clients often ask at once; one read answers them all
+                        let key = format!(
+                            "{}\n{}\n{}",
+                            auth.user_id,
+                            route_path,
+                            if_none_match.as_deref().unwrap_or_default()
+                        );
+                        let note = state
+                            .note_reads
+                            .run(&key, || handlers::get_note(&state, &auth.user_id))
+                            .await;
+                        if let Ok(body) = &note {
+                            etag = Some(format!("\"{}\"", compute_hash(body)));
+                        }
+                        note
+                    }
                     Err(e) => Err(e),
                 }
             }
             (Method::PUT, "/api/note") => {
-                match handlers::authenticate(&state, auth_header.as_deref()) {
-                    Ok(auth) => handlers::update_note(&state, &auth.user_id, &body_str),
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        let dry_run = matches!(
+                            query_param(query.as_deref(), "dry_run").as_deref(),
+                            Some("true" | "1")
+                        );
+                        handlers::update_note(&state, &auth.user_id, &body_str, dry_run).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/import") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::import_note(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "mode").as_deref(),
+                            query_param(query.as_deref(), "source").as_deref(),
+                            query_param(query.as_deref(), "external_id").as_deref(),
+                            &body_str,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/note/meta") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::update_meta(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/convert") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(_) => handlers::convert(&body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/rename") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::rename_heading(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/tasks/:id/toggle") => {
+                let chunk_id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::toggle_task(&state, &auth.user_id, &chunk_id, &body_str).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/note/mode") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::set_note_mode(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/note/numbering") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::set_numbering(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/append") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::append_note(&state, &auth.user_id, &body_str, &caps).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/entries") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_entries(&state, &auth.user_id, &caps).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/note/chunks/:id/pin") => {
+                let chunk_id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::pin_chunk(&state, &auth.user_id, &chunk_id, &body_str).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/history") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::get_history(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "before"),
+                            query_param(query.as_deref(), "limit").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/history/:id/restore") => {
+                let version_id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::restore_chunk_version(&state, &auth.user_id, &version_id).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/revisions") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_revisions(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/diff") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::diff(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "from").as_deref(),
+                            query_param(query.as_deref(), "to").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/diff/html") => {
+                let page = match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::diff_html(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "from").as_deref(),
+                            query_param(query.as_deref(), "to").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match page {
+                    Ok(html) => return Ok(html_response(StatusCode::OK, html, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            // Also takes export tokens, which open nothing else
+            (Method::GET, "/api/note/export") => {
+                let auth = handlers::authenticate_export(&state, auth_header.as_deref()).await;
+                *authed = auth.as_ref().ok().cloned();
+                let download = match auth {
+                    Ok(auth) => {
+                        handlers::export_note(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "format").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match download {
+                    Ok(download) => return Ok(download_response(download, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/print") => {
+                let page = match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::print_note(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                };
+                match page {
+                    Ok(html) => return Ok(html_response(StatusCode::OK, html, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/note/share") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::create_share(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/shares") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_shares(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/note/shares/:id") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::revoke_share(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/html") => {
+                let fragment = match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::note_html(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "chunk_id").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match fragment {
+                    Ok(html) => return Ok(html_response(StatusCode::OK, html, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/stats") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_stats(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/flags") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_flags(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/limits") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_limits(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::PUT, "/api/note/goal") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::update_goal(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/focus") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::focus(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/focus") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::focus_report(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/search") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::search(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "q").as_deref(),
+                            &caps,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/note/backlinks") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::backlinks(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "chunk_id").as_deref(),
+                            &caps,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/attachments") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::upload_attachment(
+                            &state,
+                            &auth.user_id,
+                            content_type.as_deref(),
+                            query_param(query.as_deref(), "filename").as_deref(),
+                            &body,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/attachments/:id") => {
+                let id = route.param("id");
+                let attachment = match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_attachment(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                };
+                match attachment {
+                    Ok(attachment) => return Ok(attachment_response(attachment, cors)),
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/hooks") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_hooks(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/import/gist") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::import_gist(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "url").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/hooks") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::create_hook(&state, &auth.user_id, &body_str).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/hooks/:id") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::delete_hook(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/export-tokens") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_export_tokens(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/export-tokens") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::create_export_token(&state, &auth.user_id, &body_str).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/export-tokens/:id") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::revoke_export_token(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/keys/:id/usage") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_key_usage(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::DELETE, "/api/notes/:id") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::trash_note(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/trash") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_trash(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/trash/:id/restore") => {
+                let id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::restore_note(&state, &auth.user_id, &id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/tags") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::list_tags(&state, &auth.user_id).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/tags/:tag/chunks") => {
+                let tag = route.param("tag");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => handlers::get_tag_chunks(&state, &auth.user_id, &tag, &caps).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/review") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::review_queue(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "days").as_deref(),
+                            query_param(query.as_deref(), "limit").as_deref(),
+                            query_param(query.as_deref(), "weights").as_deref(),
+                            &caps,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::GET, "/api/cards/due") => {
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::due_cards(
+                            &state,
+                            &auth.user_id,
+                            query_param(query.as_deref(), "limit").as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::POST, "/api/cards/:id/grade") => {
+                let card_id = route.param("id");
+                match authenticate(&state, auth_header.as_deref(), authed).await {
+                    Ok(auth) => {
+                        handlers::grade_card(&state, &auth.user_id, &card_id, &body_str).await
+                    }
                     Err(e) => Err(e),
                 }
             }
 
-            // Health check
-            (Method::GET, "/api/health") => Ok(r#"{"status":"ok"}"#.to_string()),
+            // Health checks: liveness needs only the process, readiness the database
+            (Method::GET, "/api/health") | (Method::GET, "/api/health/live") => {
+                Ok(r#"{"status":"ok"}"#.to_string())
+            }
+            (Method::GET, "/api/health/ready") => handlers::health_ready(&state).await,
 
             // CORS preflight
-            (Method::OPTIONS, _) => return Ok(cors_preflight(origin)),
+            (Method::OPTIONS, _) => return Ok(cors_preflight(cors, private_network)),
 
             // Serve frontend
-            (Method::GET, "/") | (Method::GET, "/index.html") => {
-                return Ok(serve_html());
-            }
+            (Method::GET, _) if !path.starts_with("/api/") => match &state.config.static_dir {
+                Some(dir) => match assets::load(Path::new(dir), &path).await {
+                    Some(asset) => return Ok(asset_response(asset)),
+                    None => Err((404, r#"{"error":"Not found"}"#.to_string())),
+                },
+                None if path == "/" || path == "/index.html" => return Ok(serve_html()),
+                None => Err((404, r#"{"error":"Not found"}"#.to_string())),
+            },
 
             // Not found
             _ => Err((404, r#"{"error":"Not found"}"#.to_string())),
@@ -76,37 +896,389 @@ impl Router {
             ),
         };
 
-        Ok(json_response(status, &body, origin))
+        let mut response = match &etag {
+            // Polling clients send the ETag back and skip unchanged responses
+            Some(tag) if etag_matches(if_none_match.as_deref(), tag) => {
+                json_response(StatusCode::NOT_MODIFIED, "", cors)
+            }
+            _ => json_response(status, &body, cors),
+        };
+        if let Some(tag) = etag {
+            response.headers_mut().insert("etag", tag.parse().unwrap());
+        }
+        for cookie in set_cookies {
+            response
+                .headers_mut()
+                .append("set-cookie", cookie.parse().unwrap());
+        }
+        if let Some(value) = caps.header_value() {
+            response
+                .headers_mut()
+                .insert(capabilities::HEADER, value.parse().unwrap());
+        }
+        Ok(response)
     }
 }
 
-fn json_response(status: StatusCode, body: &str, origin: &str) -> Response<Full<Bytes>> {
-    Response::builder()
+/// The client's user agent and address. The address is the connection's
+/// peer, or with `trust_proxy` the last hop in `X-Forwarded-For`.
+fn client_info<B>(req: &Request<B>, trust_proxy: bool) -> ClientInfo {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    let forwarded = header("x-forwarded-for")
+        .filter(|_| trust_proxy)
+        .and_then(|list| list.rsplit(',').next().map(|ip| ip.trim().to_string()));
+    ClientInfo {
+        user_agent: header("user-agent"),
+        ip: forwarded.or_else(|| {
+            req.extensions()
+                .get::<SocketAddr>()
+                .map(|peer| peer.ip().to_string())
+        }),
+    }
+}
+
+/// Routes with `:name` segments standing for a parameter.
+const PARAM_ROUTES: &[&str] = &[
+    "/hooks/:token",
+    "/s/:token",
+    "/api/admin/users/:id/flags",
+    "/api/admin/users/:id/flags/:flag",
+    "/api/announcements/:id",
+    "/api/attachments/:id",
+    "/api/cards/:id/grade",
+    "/api/export-tokens/:id",
+    "/api/hooks/:id",
+    "/api/keys/:id/usage",
+    "/api/note/chunks/:id/pin",
+    "/api/note/history/:id/restore",
+    "/api/note/shares/:id",
+    "/api/note/tasks/:id/toggle",
+    "/api/notes/:id",
+    "/api/sessions/:token_prefix",
+    "/api/tags/:tag/chunks",
+    "/api/trash/:id/restore",
+];
+
+/// The route pattern a path matched, and the parameters taken from it.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RouteMatch {
+    pub(crate) pattern: &'static str,
+    pub(crate) params: Vec<(&'static str, String)>,
+}
+
+impl RouteMatch {
+    /// Percent-decoded value of the `:name` segment.
+    fn param(&self, name: &str) -> String {
+        self.params
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) fn find_route(path: &str) -> Option<RouteMatch> {
+    PARAM_ROUTES
+        .iter()
+        .find_map(|pattern| match_route(pattern, path))
+}
+
+/// Match `path` segment by segment against `pattern`. Parameters match any
+/// non-empty segment.
+fn match_route(pattern: &'static str, path: &str) -> Option<RouteMatch> {
+    let mut params = Vec::new();
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+        let segment = segments.next()?;
+        match expected.strip_prefix(':') {
+            Some(name) if !segment.is_empty() => params.push((name, percent_decode(segment))),
+            None if expected == segment => {}
+            _ => return None,
+        }
+    }
+    if segments.next().is_some() {
+        return None;
+    }
+    Some(RouteMatch { pattern, params })
+}
+
+/// Authenticate the request and remember who made it, for the access log
+/// and the impersonation audit.
+async fn authenticate(
+    state: &Arc<AppState>,
+    auth_header: Option<&str>,
+    authed: &mut Option<AuthInfo>,
+) -> Result<AuthInfo, (u16, String)> {
+    let auth = handlers::authenticate(state, auth_header).await?;
+    *authed = Some(auth.clone());
+    Ok(auth)
+}
+
+fn is_write(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Writes that don't act on a session, and so need no CSRF token.
+fn is_csrf_exempt(path: &str) -> bool {
+    matches!(
+        path,
+        "/api/signup"
+            | "/api/login"
+            | "/api/password/reset/request"
+            | "/api/password/reset/confirm"
+    ) || path.starts_with("/hooks/")
+}
+
+/// Cookies opening a browser session for the token in a signup or login
+/// response, when cookie sessions are on.
+fn session_cookies(state: &AppState, result: &Result<String, (u16, String)>) -> Vec<String> {
+    let token = match result {
+        Ok(body) if state.config.session_cookies => serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|v| v["token"].as_str().map(str::to_string)),
+        _ => None,
+    };
+    token
+        .map(|token| {
+            let max_age = handlers::SESSION_TTL_DAYS * 24 * 60 * 60;
+            cookies::issue(&token, &handlers::generate_token(), max_age).to_vec()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether the request writes user data, and so requires accepted terms.
+/// Signing in and out, revoking sessions, password changes and deleting the
+/// account stay open.
+fn is_terms_gated(method: &Method, path: &str) -> bool {
+    match *method {
+        Method::POST | Method::PUT => !matches!(
+            path,
+            "/api/signup"
+                | "/api/login"
+                | "/api/logout"
+                | "/api/password/change"
+                | "/api/password/reset/request"
+                | "/api/password/reset/confirm"
+                | "/api/account/accept-terms"
+        ),
+        Method::DELETE => path != "/api/account" && !path.starts_with("/api/sessions"),
+        _ => false,
+    }
+}
+
+/// Whether an `If-None-Match` header lists `etag`, or is `*`. Comparison is
+/// weak, as RFC 9110 requires for this header.
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|header| {
+        header.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        })
+    })
+}
+
+/// Canonical form of a request path, so `/api//note/` routes like
+/// `/api/note`: empty and `.` segments are dropped, `..` removes the segment
+/// before it, the trailing slash goes, and escaped unreserved characters
+/// (`%61` for `a`) are decoded. Other escapes stay, so an id containing `%2F`
+/// is still one segment when its route decodes it.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<String> = Vec::new();
+    for segment in path.split('/') {
+        let segment = decode_unreserved(segment);
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Decode `%XX` escapes of letters, digits, `-`, `.`, `_` and `~` only.
+fn decode_unreserved(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(start) = rest.find('%') {
+        out.push_str(&rest[..start]);
+        let escape = rest.get(start + 1..start + 3);
+        match escape.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(b) if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => {
+                out.push(b as char);
+                rest = &rest[start + 3..];
+            }
+            _ => {
+                out.push('%');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Value of `key` in a URL query string, form-decoded.
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    query?.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        (form_decode(k) == key).then(|| form_decode(v))
+    })
+}
+
+/// Query strings also spell spaces as `+`; paths keep it as it is.
+fn form_decode(s: &str) -> String {
+    percent_decode(&s.replace('+', " "))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn json_response(status: StatusCode, body: &str, cors: &Grant) -> Response<Full<Bytes>> {
+    cors.apply(Response::builder())
         .status(status)
         .header("Content-Type", "application/json")
-        .header("Access-Control-Allow-Origin", origin)
-        .header("Access-Control-Allow-Methods", "GET, POST, PUT, OPTIONS")
+        .header(
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, DELETE, OPTIONS",
+        )
         .header(
             "Access-Control-Allow-Headers",
-            "Content-Type, Authorization",
+            "Content-Type, Authorization, If-None-Match, X-Trame-Capabilities, X-CSRF-Token",
         )
         .body(Full::new(Bytes::from(body.to_string())))
         .unwrap()
 }
 
-fn cors_preflight(origin: &str) -> Response<Full<Bytes>> {
+/// A handler's error as a response.
+fn error_response((code, body): (u16, String), cors: &Grant) -> Response<Full<Bytes>> {
+    let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    json_response(status, &body, cors)
+}
+
+fn body_too_large(max_body: usize, cors: &Grant) -> Response<Full<Bytes>> {
+    let body = serde_json::json!({
+        "error": format!("Request body too large (limit {} bytes)", max_body)
+    });
+    json_response(StatusCode::PAYLOAD_TOO_LARGE, &body.to_string(), cors)
+}
+
+/// The failure chaos mode answers with, shaped like a real outage.
+fn chaos_unavailable(cors: &Grant) -> Response<Full<Bytes>> {
+    let body = serde_json::json!({ "error": "Service unavailable (injected)" });
+    let mut res = json_response(StatusCode::SERVICE_UNAVAILABLE, &body.to_string(), cors);
+    res.headers_mut()
+        .insert("Retry-After", hyper::header::HeaderValue::from_static("1"));
+    res
+}
+
+fn html_response(status: StatusCode, body: String, cors: &Grant) -> Response<Full<Bytes>> {
+    cors.apply(Response::builder())
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+/// A shared note, for anyone holding the link. Revocation must take effect
+/// at once, and the token in the URL must not leak through referrers or
+/// search engines.
+fn share_page_response(body: String) -> Response<Full<Bytes>> {
     Response::builder()
         .status(StatusCode::OK)
-        .header("Access-Control-Allow-Origin", origin)
-        .header("Access-Control-Allow-Methods", "GET, POST, PUT, OPTIONS")
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .header("Content-Security-Policy", "script-src 'none'")
+        .header("Referrer-Policy", "no-referrer")
+        .header("X-Robots-Tag", "noindex")
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn download_response(download: Download, cors: &Grant) -> Response<Full<Bytes>> {
+    cors.apply(Response::builder())
+        .status(StatusCode::OK)
+        .header("Content-Type", download.content_type)
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", download.filename),
+        )
+        .body(Full::new(Bytes::from(download.body)))
+        .unwrap()
+}
+
+/// Serve an uploaded file inline so notes can embed it. Browsers must not
+/// second-guess the type or run scripts from it (an SVG, say).
+fn attachment_response(attachment: Attachment, cors: &Grant) -> Response<Full<Bytes>> {
+    let disposition = match &attachment.filename {
+        Some(name) => format!("inline; filename=\"{}\"", name),
+        None => "inline".to_string(),
+    };
+    cors.apply(Response::builder())
+        .status(StatusCode::OK)
+        .header("Content-Type", attachment.content_type)
+        .header("Content-Disposition", disposition)
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Content-Security-Policy", "sandbox")
+        // Attachments never change once uploaded
+        .header("Cache-Control", "private, max-age=31536000, immutable")
+        .body(Full::new(Bytes::from(attachment.data)))
+        .unwrap()
+}
+
+fn cors_preflight(cors: &Grant, private_network: bool) -> Response<Full<Bytes>> {
+    cors.apply_preflight(cors.apply(Response::builder()), private_network)
+        .status(StatusCode::OK)
+        .header(
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, DELETE, OPTIONS",
+        )
         .header(
             "Access-Control-Allow-Headers",
-            "Content-Type, Authorization",
+            "Content-Type, Authorization, If-None-Match, X-Trame-Capabilities, X-CSRF-Token",
         )
         .body(Full::new(Bytes::new()))
         .unwrap()
 }
 
+fn asset_response(asset: Asset) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", asset.content_type)
+        .header("Cache-Control", asset.cache_control)
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Full::new(Bytes::from(asset.body)))
+        .unwrap()
+}
+
 fn serve_html() -> Response<Full<Bytes>> {
     const HTML: &str = include_str!("../../web/index.html");
     Response::builder()
@@ -115,3 +1287,480 @@ fn serve_html() -> Response<Full<Bytes>> {
         .body(Full::new(Bytes::from(HTML)))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::session_key_id;
+
+    fn state() -> Arc<AppState> {
+        let mut config = Config::from_env().unwrap();
+        config.database_url = ":memory:".to_string();
+        config.shard_dir = None;
+        config.chaos = None;
+        config.record_fixtures = None;
+        config.terms_version = Some("v1".to_string());
+        AppState::new(config).unwrap()
+    }
+
+    async fn send(
+        state: &Arc<AppState>,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> serde_json::Value {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header("authorization", format!("Bearer {}", token));
+        }
+        let mut req = req.body(Full::new(Bytes::from(body.to_string()))).unwrap();
+        req.extensions_mut()
+            .insert(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        let res = Router::handle(req, state.clone()).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap_or_default()
+    }
+
+    async fn sign_up(state: &Arc<AppState>) -> String {
+        let signup = send(
+            state,
+            "POST",
+            "/api/signup",
+            None,
+            r#"{"email":"alice@example.org","password":"correct horse","accepted_terms":"v1"}"#,
+        )
+        .await;
+        signup["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_key_use_counted_once() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let requests = |state: Arc<AppState>, token: String| async move {
+            state.key_usage.flush(&state.db).await.unwrap();
+            state
+                .db
+                .run(move |db| db.get_key_usage(&session_key_id(&token), ""))
+                .await
+                .unwrap()
+                .iter()
+                .map(|day| day.requests)
+                .sum::<i64>()
+        };
+
+        // The terms and read-only checks authenticate a write before its route
+        let saved = send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r##"{"content":"# Hello"}"##,
+        )
+        .await;
+        assert_eq!(saved["content"], "# Hello");
+        assert_eq!(requests(state.clone(), token.clone()).await, 1);
+
+        send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert_eq!(requests(state.clone(), token.clone()).await, 2);
+
+        // Counted under a hash, never the token itself
+        let raw = token.clone();
+        let stored = state.db.run(move |db| db.get_key_usage(&raw, "")).await;
+        assert!(stored.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_hook_deliveries_all_land() {
+        let path = std::env::temp_dir().join(format!("trame-hooks-{}.db", ulid::Ulid::new()));
+        let mut config = Config::from_env().unwrap();
+        config.database_url = path.to_str().unwrap().to_string();
+        config.shard_dir = None;
+        config.chaos = None;
+        config.record_fixtures = None;
+        config.terms_version = Some("v1".to_string());
+        let state = AppState::new(config).unwrap();
+        let token = sign_up(&state).await;
+        let hook = send(
+            &state,
+            "POST",
+            "/api/hooks",
+            Some(&token),
+            r#"{"name":"zap","action":"append","template":"{{text}}"}"#,
+        )
+        .await;
+        let url = hook["url"].as_str().unwrap().to_string();
+
+        let deliveries = (0..8).map(|i| {
+            let (state, url) = (state.clone(), url.clone());
+            tokio::spawn(async move {
+                let body = format!(r#"{{"text":"Delivery {}"}}"#, i);
+                send(&state, "POST", &url, None, &body).await
+            })
+        });
+        for delivery in deliveries.collect::<Vec<_>>() {
+            delivery.await.unwrap();
+        }
+
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        let content = note["content"].as_str().unwrap();
+        for i in 0..8 {
+            assert!(content.contains(&format!("Delivery {}", i)), "{}", content);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_toggle_task_during_edits() {
+        let path = std::env::temp_dir().join(format!("trame-tasks-{}.db", ulid::Ulid::new()));
+        let mut config = Config::from_env().unwrap();
+        config.database_url = path.to_str().unwrap().to_string();
+        config.shard_dir = None;
+        config.chaos = None;
+        config.record_fixtures = None;
+        config.terms_version = Some("v1".to_string());
+        let state = AppState::new(config).unwrap();
+        let token = sign_up(&state).await;
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r#"{"content":"Intro\n\n- [ ] one\n- [ ] two"}"#,
+        )
+        .await;
+        let hits = send(&state, "GET", "/api/search?q=two", Some(&token), "").await;
+        let tasks = hits["results"][0]["chunk_id"].as_str().unwrap().to_string();
+
+        // Appends land while the task is toggled, each over the other's save
+        let uri = format!("/api/note/tasks/{}/toggle", tasks);
+        let toggle = {
+            let (state, token) = (state.clone(), token.clone());
+            tokio::spawn(
+                async move { send(&state, "POST", &uri, Some(&token), r#"{"index":1}"#).await },
+            )
+        };
+        let appends = (0..6).map(|i| {
+            let (state, token) = (state.clone(), token.clone());
+            tokio::spawn(async move {
+                let body = format!(r#"{{"text":"Extra {}"}}"#, i);
+                send(&state, "POST", "/api/note/append", Some(&token), &body).await
+            })
+        });
+        for append in appends.collect::<Vec<_>>() {
+            append.await.unwrap();
+        }
+        assert_eq!(toggle.await.unwrap()["content"], "- [ ] one\n- [x] two");
+
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        let content = note["content"].as_str().unwrap();
+        assert!(
+            content.starts_with("Intro\n\n- [ ] one\n- [x] two\n"),
+            "{}",
+            content
+        );
+        for i in 0..6 {
+            assert!(content.contains(&format!("Extra {}", i)), "{}", content);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_restore_chunk_version() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let save = |content: &str| format!(r#"{{"content":{:?}}}"#, content);
+        let restore = |state: Arc<AppState>, token: String, content: &'static str| async move {
+            let history = send(&state, "GET", "/api/note/history", Some(&token), "").await;
+            let version = history["versions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["content"] == content)
+                .unwrap()["id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            let uri = format!("/api/note/history/{}/restore", version);
+            send(&state, "POST", &uri, Some(&token), "").await
+        };
+
+        // An edited paragraph is put back in place of the edit
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("# T\n\nOld\n\nEnd"),
+        )
+        .await;
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("# T\n\nNew\n\nEnd"),
+        )
+        .await;
+        let chunk = restore(state.clone(), token.clone(), "Old").await;
+        assert_eq!(chunk["content"], "Old");
+        assert_eq!(chunk["sequence"], 1);
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert_eq!(note["content"], "# T\n\nOld\n\nEnd");
+
+        // A removed one goes back where it was
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("# T\n\nEnd"),
+        )
+        .await;
+        restore(state.clone(), token.clone(), "Old").await;
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert_eq!(note["content"], "# T\n\nOld\n\nEnd");
+
+        let missing = send(
+            &state,
+            "POST",
+            "/api/note/history/nope/restore",
+            Some(&token),
+            "",
+        )
+        .await;
+        assert_eq!(missing["error"], "Version not found");
+    }
+
+    #[tokio::test]
+    async fn test_large_stale_save_not_merged() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let save = |word: &str, base: Option<&str>| {
+            let body: Vec<String> = (0..3000).map(|i| format!("{} {}", word, i)).collect();
+            serde_json::json!({ "content": body.join("\n\n"), "base_updated_at": base }).to_string()
+        };
+
+        let base = send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("base", None),
+        )
+        .await;
+        let base_at = base["updated_at"].as_str().unwrap();
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("server", None),
+        )
+        .await;
+        let stale = save("client", Some(base_at));
+        let refused = send(&state, "PUT", "/api/note", Some(&token), &stale).await;
+        assert_eq!(refused["code"], "merge_too_large");
+
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert!(note["content"].as_str().unwrap().starts_with("server 0"));
+        assert_eq!(refused["updated_at"], note["updated_at"]);
+    }
+
+    #[tokio::test]
+    async fn test_save_from_unknown_base() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let save = |content: &str, base: &str| {
+            serde_json::json!({ "content": content, "base_updated_at": base }).to_string()
+        };
+
+        let first = send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r#"{"content":"A"}"#,
+        )
+        .await;
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r#"{"content":"A\n\nB"}"#,
+        )
+        .await;
+
+        // Older than any saved version: nothing to merge against
+        let stale = save("A\n\nC", "2000-01-01T00:00:00+00:00");
+        let refused = send(&state, "PUT", "/api/note", Some(&token), &stale).await;
+        assert_eq!(refused["code"], "unknown_base");
+        let garbage = send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            &save("C", "yesterday"),
+        )
+        .await;
+        assert_eq!(garbage["error"], "Invalid base_updated_at");
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert_eq!(note["content"], "A\n\nB");
+
+        let stale = save("Z\n\nA", first["updated_at"].as_str().unwrap());
+        let merged = send(&state, "PUT", "/api/note", Some(&token), &stale).await;
+        assert_eq!(merged["content"], "Z\n\nA\n\nB");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_stale_saves_all_merged() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let paragraphs: Vec<String> = (0..16).map(|i| format!("Part {}", i)).collect();
+        let body = serde_json::json!({ "content": paragraphs.join("\n\n") }).to_string();
+        let base = send(&state, "PUT", "/api/note", Some(&token), &body).await;
+        let base_at = base["updated_at"].as_str().unwrap().to_string();
+
+        // Each save edits its own paragraph of the same version, with one
+        // left as it was between any two
+        let saves = (0..8).map(|i| {
+            let mut edited = paragraphs.clone();
+            edited[i * 2] = format!("Part {} edited", i * 2);
+            let body = serde_json::json!({
+                "content": edited.join("\n\n"),
+                "base_updated_at": base_at,
+            })
+            .to_string();
+            let (state, token) = (state.clone(), token.clone());
+            tokio::spawn(async move { send(&state, "PUT", "/api/note", Some(&token), &body).await })
+        });
+        for save in saves.collect::<Vec<_>>() {
+            assert_eq!(save.await.unwrap()["code"], serde_json::Value::Null);
+        }
+
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        let expected: Vec<String> = (0..16)
+            .map(|i| match i % 2 {
+                0 => format!("Part {} edited", i),
+                _ => format!("Part {}", i),
+            })
+            .collect();
+        assert_eq!(note["content"], expected.join("\n\n"));
+    }
+
+    #[tokio::test]
+    async fn test_read_after_write_not_joined_to_older_read() {
+        let state = state();
+        let token = sign_up(&state).await;
+        let session_token = token.clone();
+        let user_id = state
+            .db
+            .run(move |db| db.get_session(&session_token))
+            .await
+            .unwrap()
+            .unwrap()
+            .user_id;
+
+        // A read of the note as it was, still in flight when the write lands
+        let key = format!("{}\n/api/note\n", user_id);
+        let reads = state.note_reads.clone();
+        let stale = tokio::spawn(async move {
+            reads
+                .run(&key, || async {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    Ok(r#"{"content":"old"}"#.to_string())
+                })
+                .await
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        send(
+            &state,
+            "PUT",
+            "/api/note",
+            Some(&token),
+            r#"{"content":"new"}"#,
+        )
+        .await;
+        let note = send(&state, "GET", "/api/note", Some(&token), "").await;
+        assert_eq!(note["content"], "new");
+        assert!(stale.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_match_route() {
+        let route = match_route("/api/tags/:tag/chunks", "/api/tags/caf%C3%A9/chunks").unwrap();
+        assert_eq!(route.pattern, "/api/tags/:tag/chunks");
+        assert_eq!(route.param("tag"), "café");
+        assert_eq!(route.param("missing"), "");
+
+        assert!(match_route("/api/tags/:tag/chunks", "/api/tags//chunks").is_none());
+        assert!(match_route("/api/tags/:tag/chunks", "/api/tags/a/chunks/more").is_none());
+        assert!(match_route("/api/tags/:tag/chunks", "/api/tags/a").is_none());
+        assert!(match_route("/api/hooks/:id", "/api/hooks").is_none());
+
+        // An escaped slash stays inside its parameter
+        let route = match_route("/api/attachments/:id", "/api/attachments/a%2Fb").unwrap();
+        assert_eq!(route.param("id"), "a/b");
+
+        // A plus sign is only a space in query strings
+        let route = match_route("/s/:token", "/s/ab+cd%2B").unwrap();
+        assert_eq!(route.param("token"), "ab+cd+");
+        assert_eq!(
+            query_param(Some("q=ab+cd%2B"), "q").as_deref(),
+            Some("ab cd+")
+        );
+    }
+
+    #[test]
+    fn test_find_route() {
+        let route = find_route("/api/admin/users/u1/flags/beta").unwrap();
+        assert_eq!(route.pattern, "/api/admin/users/:id/flags/:flag");
+        assert_eq!(route.param("id"), "u1");
+        assert_eq!(route.param("flag"), "beta");
+        assert_eq!(
+            find_route("/api/admin/users/u1/flags").unwrap().pattern,
+            "/api/admin/users/:id/flags"
+        );
+        assert!(find_route("/api/note").is_none());
+        assert!(find_route("/api/hooks").is_none());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/api/note"), "/api/note");
+        assert_eq!(normalize_path("/api/note/"), "/api/note");
+        assert_eq!(normalize_path("//api///note//"), "/api/note");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("///"), "/");
+    }
+
+    #[test]
+    fn test_normalize_path_dot_segments() {
+        assert_eq!(normalize_path("/api/./note"), "/api/note");
+        assert_eq!(normalize_path("/api/tags/../note"), "/api/note");
+        assert_eq!(normalize_path("/../../api/note"), "/api/note");
+        assert_eq!(normalize_path("/api/%2e%2E/note"), "/note");
+        assert_eq!(normalize_path("/assets/.env"), "/assets/.env");
+    }
+
+    #[test]
+    fn test_normalize_path_escapes() {
+        assert_eq!(normalize_path("/api/%6Eote"), "/api/note");
+        assert_eq!(normalize_path("/api/n%6f%74e/"), "/api/note");
+        // Reserved and non-ASCII escapes stay for the route to decode
+        assert_eq!(
+            normalize_path("/api/tags/a%2Fb/chunks"),
+            "/api/tags/a%2Fb/chunks"
+        );
+        assert_eq!(normalize_path("/api/tags/caf%C3%A9"), "/api/tags/caf%C3%A9");
+        assert_eq!(normalize_path("/api/tags/%20x"), "/api/tags/%20x");
+        // Malformed escapes are kept as they are
+        assert_eq!(normalize_path("/a%zz/b%4"), "/a%zz/b%4");
+        assert_eq!(normalize_path("/100%"), "/100%");
+    }
+}
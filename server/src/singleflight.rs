@@ -0,0 +1,108 @@
+//! Shares one run of a piece of work among concurrent callers asking for
+//! the same thing, so a burst of identical polls costs one database read.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+pub struct SingleFlight<V> {
+    calls: Mutex<HashMap<String, Arc<OnceCell<V>>>>,
+}
+
+impl<V: Clone> Default for SingleFlight<V> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<V: Clone> SingleFlight<V> {
+    /// The result of `work`, run once for all callers that arrive with `key`
+    /// while it's in flight. Callers after it finishes start a new run. If
+    /// the caller running it goes away, one of the others takes over.
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let call = self
+            .calls
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let value = call.get_or_init(work).await.clone();
+
+        let mut calls = self.calls.lock().unwrap();
+        if calls.get(key).is_some_and(|c| Arc::ptr_eq(c, &call)) {
+            calls.remove(key);
+        }
+        value
+    }
+
+    /// Make callers with a key starting with `prefix` start a new run from
+    /// now on, rather than join one that may have read what has since
+    /// changed. Callers already waiting on it still share it.
+    pub fn forget(&self, prefix: &str) {
+        self.calls
+            .lock()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_calls_share_one_run() {
+        let flight = SingleFlight::default();
+        let runs = AtomicUsize::new(0);
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            "note".to_string()
+        };
+
+        let (a, b, c) = tokio::join!(
+            flight.run("u1", work),
+            flight.run("u1", work),
+            flight.run("u2", work)
+        );
+        assert_eq!(
+            (a.as_str(), b.as_str(), c.as_str()),
+            ("note", "note", "note")
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // Finished runs aren't reused
+        flight.run("u1", work).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forgotten_calls_are_not_joined() {
+        let flight = SingleFlight::default();
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            "old".to_string()
+        };
+        let fresh = || async { "new".to_string() };
+
+        let (before, after) = tokio::join!(flight.run("u1\nnote", slow), async {
+            tokio::task::yield_now().await;
+            flight.forget("u1\n");
+            flight.run("u1\nnote", fresh).await
+        });
+        assert_eq!((before.as_str(), after.as_str()), ("old", "new"));
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+}
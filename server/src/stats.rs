@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+use crate::chunker::parse_chunks;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NoteStats {
+    pub words: u32,
+    pub characters: u32,
+    pub chunks: u32,
+    pub word_goal: Option<u32>,
+    pub word_goal_progress: Option<f64>,
+    pub words_today: i64,
+    pub daily_word_goal: Option<u32>,
+    pub daily_goal_progress: Option<f64>,
+}
+
+/// Count whitespace-separated words, ignoring Markdown markers such as
+/// `#`, `-`, `*` or `>` that stand on their own.
+pub fn word_count(content: &str) -> u32 {
+    content
+        .split_whitespace()
+        .filter(|w| w.chars().any(|c| c.is_alphanumeric()))
+        .count() as u32
+}
+
+/// Stats for `content`, given its word count at the start of the day and the
+/// note's goals. Progress values are capped at 1.0.
+pub fn note_stats(
+    content: &str,
+    words_at_day_start: u32,
+    word_goal: Option<u32>,
+    daily_word_goal: Option<u32>,
+) -> NoteStats {
+    let words = word_count(content);
+    let words_today = words as i64 - words_at_day_start as i64;
+    let progress = |done: i64, goal: u32| {
+        if goal == 0 {
+            1.0
+        } else {
+            (done.max(0) as f64 / goal as f64).min(1.0)
+        }
+    };
+
+    NoteStats {
+        words,
+        characters: content.chars().count() as u32,
+        chunks: parse_chunks(content).len() as u32,
+        word_goal,
+        word_goal_progress: word_goal.map(|g| progress(words as i64, g)),
+        words_today,
+        daily_word_goal,
+        daily_goal_progress: daily_word_goal.map(|g| progress(words_today, g)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_count_skips_markers() {
+        assert_eq!(word_count(""), 0);
+        assert_eq!(word_count("# Title\n\n- one two\n- three"), 4);
+        assert_eq!(word_count("> quoted --- text"), 2);
+    }
+
+    #[test]
+    fn test_goal_progress() {
+        let stats = note_stats("one two three four", 2, Some(8), Some(4));
+        assert_eq!(stats.words, 4);
+        assert_eq!(stats.words_today, 2);
+        assert_eq!(stats.word_goal_progress, Some(0.5));
+        assert_eq!(stats.daily_goal_progress, Some(0.5));
+
+        let stats = note_stats("one two three four", 0, Some(2), None);
+        assert_eq!(stats.word_goal_progress, Some(1.0));
+        assert_eq!(stats.daily_goal_progress, None);
+
+        // Deleting text doesn't produce negative progress
+        let stats = note_stats("one", 3, None, Some(5));
+        assert_eq!(stats.words_today, -2);
+        assert_eq!(stats.daily_goal_progress, Some(0.0));
+    }
+}
@@ -0,0 +1,100 @@
+//! Requests counted per key in memory, so counting one doesn't write to the
+//! database on its way out. The background purge writes the counts down;
+//! see [`crate::gc`].
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::db::{KeyUse, Storage, StorageResult};
+
+#[derive(Default)]
+pub struct KeyUsage {
+    /// By key id and day.
+    counts: Mutex<HashMap<(String, String), KeyUse>>,
+}
+
+impl KeyUsage {
+    /// Count a request made at `now` (RFC 3339) with `key_id`.
+    pub fn record(&self, key_id: &str, user_id: &str, now: &str) {
+        self.add(KeyUse {
+            key_id: key_id.to_string(),
+            user_id: user_id.to_string(),
+            day: now.get(..10).unwrap_or(now).to_string(),
+            requests: 1,
+            last_used_at: now.to_string(),
+        });
+    }
+
+    /// Write the counts so far to the database. Counts that can't be written
+    /// are kept for the next flush.
+    pub async fn flush(&self, db: &Arc<dyn Storage>) -> StorageResult<()> {
+        let uses: Vec<KeyUse> = self
+            .counts
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, u)| u)
+            .collect();
+        if uses.is_empty() {
+            return Ok(());
+        }
+        let batch = Arc::new(uses);
+        let written = batch.clone();
+        let result = db.run(move |db| db.record_key_uses(&written)).await;
+        if result.is_err() {
+            for key_use in batch.iter() {
+                self.add(key_use.clone());
+            }
+        }
+        result
+    }
+
+    fn add(&self, key_use: KeyUse) {
+        let mut counts = self.counts.lock().unwrap();
+        let key = (key_use.key_id.clone(), key_use.day.clone());
+        match counts.get_mut(&key) {
+            Some(count) => {
+                count.requests += key_use.requests;
+                if key_use.last_used_at > count.last_used_at {
+                    count.last_used_at = key_use.last_used_at;
+                }
+            }
+            None => {
+                counts.insert(key, key_use);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    #[tokio::test]
+    async fn test_flush_writes_counts_once() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        db.create_user("user1", "test@example.com", "hash").unwrap();
+        let db: Arc<dyn Storage> = Arc::new(db);
+
+        let usage = KeyUsage::default();
+        usage.record("key1", "user1", "2026-01-02T17:30:00+00:00");
+        usage.record("key1", "user1", "2026-01-02T09:00:00+00:00");
+        usage.record("key1", "user1", "2026-01-03T08:00:00+00:00");
+        usage.flush(&db).await.unwrap();
+        // Nothing left to write twice
+        usage.flush(&db).await.unwrap();
+
+        let days = db.get_key_usage("key1", "2026-01-01").unwrap();
+        assert_eq!(
+            days.iter()
+                .map(|d| (d.day.as_str(), d.requests, d.last_used_at.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("2026-01-02", 2, "2026-01-02T17:30:00+00:00"),
+                ("2026-01-03", 1, "2026-01-03T08:00:00+00:00"),
+            ]
+        );
+    }
+}